@@ -0,0 +1,56 @@
+#![no_main]
+
+use std::fs;
+use libfuzzer_sys::fuzz_target;
+use fragtk::bed::{Anchor, CoordinateSystem, PeakFormat, Resize};
+use fragtk::f2m::{self, BedOptions, GroupOrder};
+use fragtk::matrix::FeaturesFormat;
+
+// a peak file read by `fragtk matrix -b`. The first byte picks the options, so one corpus covers
+// plain, narrowPeak, grouped and merged BED files; the rest is the file. Errors are fine, panics are not.
+fuzz_target!(|data: &[u8]| {
+    let Some((&flags, bed)) = data.split_first() else {
+        return;
+    };
+    let dir = std::env::temp_dir();
+    let bed_path = dir.join(format!("fragtk-fuzz-bed-{}", std::process::id()));
+    let features_path = dir.join(format!("fragtk-fuzz-features-{}.tsv.gz", std::process::id()));
+    fs::write(&bed_path, bed).unwrap();
+
+    let options = BedOptions {
+        group: match flags & 0x03 {
+            1 => Some(GroupOrder::Appearance),
+            2 => Some(GroupOrder::Sorted),
+            _ => None,
+        },
+        resize: match (flags >> 2) & 0x03 {
+            1 => Some(Resize { width: 501, anchor: Anchor::Center }),
+            2 => Some(Resize { width: 501, anchor: Anchor::Summit(9) }),
+            3 => Some(Resize { width: u32::MAX, anchor: Anchor::Summit(4) }),
+            _ => None,
+        },
+        format: match (flags >> 4) & 0x01 {
+            1 => PeakFormat::NarrowPeak,
+            _ => PeakFormat::Bed,
+        },
+        min_qvalue: None,
+        coords: match (flags >> 5) & 0x01 {
+            1 => CoordinateSystem::Gff,
+            _ => CoordinateSystem::Bed,
+        },
+        extend: match (flags >> 6) & 0x01 {
+            1 => (2000, u32::MAX),
+            _ => (0, 0),
+        },
+        // an unused group value merges overlapping peaks
+        merge_overlapping: flags & 0x03 == 3,
+    };
+    let format = match flags >> 7 {
+        1 => FeaturesFormat::Bed,
+        _ => FeaturesFormat::Names,
+    };
+    let _ = f2m::peak_intervals(&bed_path, &options, &features_path, format, 1);
+
+    let _ = fs::remove_file(&bed_path);
+    let _ = fs::remove_file(&features_path);
+});
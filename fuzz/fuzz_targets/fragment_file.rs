@@ -0,0 +1,25 @@
+#![no_main]
+
+use std::fs;
+use libfuzzer_sys::fuzz_target;
+use rust_lapper::{Interval, Lapper};
+use rustc_hash::FxHashMap;
+use fragtk::cellindex::CellIndex;
+use fragtk::f2m::{self, PeakTrees};
+
+// a whole fragment file: compression detected from the first bytes, header lines, and the
+// fragment lines counted into peaks by `fragtk matrix`. Errors are fine, panics are not.
+fuzz_target!(|data: &[u8]| {
+    let path = std::env::temp_dir().join(format!("fragtk-fuzz-fragments-{}", std::process::id()));
+    fs::write(&path, data).unwrap();
+
+    let mut peaks: PeakTrees = FxHashMap::default();
+    peaks.insert("chr1".to_string(), Lapper::new(vec![
+        Interval { start: 0, stop: 100, val: 0 },
+        Interval { start: 50, stop: 4294967295, val: 1 },
+    ]));
+    let cells = CellIndex::from_barcodes(vec!["A".to_string(), "AAACGAAAGACTCGGA-1".to_string()]);
+    let _ = f2m::count_fragments(&path, &peaks, &cells, 2);
+
+    let _ = fs::remove_file(&path);
+});
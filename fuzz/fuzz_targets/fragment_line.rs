@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use fragtk::fragments::parse_fragment;
+
+// a single fragment line, as read by the subcommands that parse whole fragments
+fuzz_target!(|data: &[u8]| {
+    if let Ok(line) = std::str::from_utf8(data) {
+        if let Some(fragment) = parse_fragment(line) {
+            assert!(line.starts_with(fragment.chrom));
+        }
+    }
+});
@@ -0,0 +1,197 @@
+use std::{
+    io,
+    fs,
+    fs::File,
+    path::Path,
+    error::Error,
+    io::BufRead,
+    io::BufReader,
+    io::Write,
+};
+use gzp::ZWriter;
+use log::{info, warn};
+use rust_lapper::{Interval, Lapper};
+use rustc_hash::{FxHashMap, FxHashSet};
+use crate::cellindex::CellIndex;
+use crate::f2m::{count_fragments, PeakTrees};
+use crate::fragments::ReadOptions;
+use crate::matrix::{check_output_dir, find_file, for_each_entry, compressed_writer, mtx_header, read_header, read_names, open_text};
+use crate::progress::Progress;
+use crate::threads::ThreadConfig;
+
+/// Count a new fragment file against the features of an existing matrix directory
+/// and append the new cells as extra columns. Existing entries are streamed, not recounted.
+pub fn run(matches: &clap::ArgMatches, progress: &Progress) -> Result<(), Box<dyn Error>> {
+
+    let matrix_dir = Path::new(matches.get_one::<String>("matrix").unwrap());
+    info!("Received matrix directory: {:?}", matrix_dir);
+
+    let frag_file = Path::new(matches.get_one::<String>("fragments").unwrap());
+    info!("Received fragment file: {:?}", frag_file);
+    let read_options = ReadOptions::from_matches(matches)?;
+
+    let cell_file = Path::new(matches.get_one::<String>("cells").unwrap());
+    info!("Received cell file: {:?}", cell_file);
+
+    let output = match matches.get_one::<String>("outdir") {
+        Some(outdir) => Path::new(outdir),
+        None => matrix_dir,
+    };
+    let num_threads = ThreadConfig::from_matches(matches).compress;
+    if output != matrix_dir {
+        check_output_dir(output, matches.get_flag("force"))?;
+    }
+
+    let matrix_path = find_file(matrix_dir, &["matrix.mtx.gz", "matrix.mtx.zst", "matrix.mtx"])?;
+    let feature_path = find_file(matrix_dir, &["features.tsv.gz", "features.tsv.zst", "features.tsv"])?;
+    let barcode_path = find_file(matrix_dir, &["barcodes.tsv.gz", "barcodes.tsv"])?;
+
+    let features = read_names(&feature_path)?;
+    let (total_peaks, peaks) = feature_trees(&features)?;
+    info!("Loaded {} features", total_peaks);
+
+    // new barcodes, numbered from zero; barcodes already in the matrix are skipped
+    let barcodes = read_names(&barcode_path)?;
+    let existing: FxHashSet<&str> = barcodes.iter().map(|x| x.as_str()).collect();
+    let mut new_cells: Vec<String> = Vec::new();
+    for line in BufReader::new(File::open(cell_file)?).lines() {
+        let line = line?;
+        if existing.contains(line.as_str()) {
+            warn!("Cell {} is already present in the matrix, skipping", line);
+            continue;
+        }
+        new_cells.push(line);
+    }
+    let cells = CellIndex::from_barcodes(new_cells);
+    if cells.is_empty() {
+        return Err("No new cells to append".into());
+    }
+    info!("Appending {} cells to {} existing cells", cells.len(), barcodes.len());
+
+    let peak_cell_counts = count_fragments(frag_file, &read_options, &peaks, &cells, total_peaks, progress)?;
+
+    fs::create_dir_all(output)?;
+
+    // write to a temporary file so the input can be replaced in place
+    let counts_path = output.join("matrix.mtx.gz");
+    let tmp_path = output.join("matrix.mtx.gz.tmp");
+    info!("Writing output counts file: {:?}", &counts_path);
+    append_entries(&matrix_path, &tmp_path, &peak_cell_counts, barcodes.len(), cells.len(), num_threads)?;
+    fs::rename(&tmp_path, &counts_path)?;
+    // remove a stale uncompressed input when updating in place
+    if matrix_path != counts_path && matrix_path.parent() == counts_path.parent() {
+        fs::remove_file(&matrix_path)?;
+    }
+
+    let out_features = output.join(feature_path.file_name().unwrap());
+    if out_features != feature_path {
+        fs::copy(&feature_path, &out_features)?;
+    }
+
+    let cell_path = output.join("barcodes.tsv");
+    info!("Writing output cells file: {:?}", &cell_path);
+    let tmp_path = output.join("barcodes.tsv.tmp");
+    let mut writer = io::BufWriter::new(File::create(&tmp_path)?);
+    for barcode in &barcodes {
+        writeln!(writer, "{}", barcode)?;
+    }
+    for i in 0..cells.len() {
+        writeln!(writer, "{}", cells.barcode(i))?;
+    }
+    writer.flush()?;
+    drop(writer);
+    fs::rename(&tmp_path, &cell_path)?;
+    // a gzipped barcode file would otherwise take precedence over the new one
+    if barcode_path != cell_path && barcode_path.parent() == cell_path.parent() {
+        fs::remove_file(&barcode_path)?;
+    }
+
+    Ok(())
+}
+
+/// Rebuild feature intervals from chrom-start-end feature names
+fn feature_trees(features: &[String]) -> io::Result<(usize, PeakTrees)> {
+    let mut chromosome_trees: FxHashMap<String, Vec<Interval<u32, usize>>> = FxHashMap::default();
+    for (index, feature) in features.iter().enumerate() {
+        // chromosome names may themselves contain '-'
+        let mut fields = feature.rsplitn(3, '-');
+        let coords = (fields.next(), fields.next(), fields.next());
+        let (end, start, chrom) = match coords {
+            (Some(end), Some(start), Some(chrom)) => (end, start, chrom),
+            _ => return Err(invalid_feature(feature)),
+        };
+        let (start, stop): (u32, u32) = match (start.parse(), end.parse()) {
+            (Ok(start), Ok(stop)) => (start, stop),
+            _ => return Err(invalid_feature(feature)),
+        };
+        chromosome_trees.entry(chrom.to_string()).or_default().push(Interval { start, stop, val: index });
+    }
+
+    let trees = chromosome_trees.into_iter()
+        .map(|(chr, intervals)| (chr, Lapper::new(intervals)))
+        .collect();
+
+    Ok((features.len(), trees))
+}
+
+fn invalid_feature(feature: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Feature {} is not a chrom-start-end region; grouped matrices cannot be appended to", feature),
+    )
+}
+
+/// Copy the existing entries to `outfile` followed by the new counts,
+/// with new cell columns offset by the existing number of cells
+fn append_entries(
+    matrix_path: &Path,
+    outfile: &Path,
+    peak_cell_counts: &[FxHashMap<u32, u32>],
+    ncol: usize,
+    new_cols: usize,
+    num_threads: usize,
+) -> io::Result<()> {
+
+    let mut reader = open_text(matrix_path)?;
+    let (integer, nrow, old_ncol, nnz) = read_header(&mut reader)?;
+    if nrow != peak_cell_counts.len() || old_ncol != ncol {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Matrix is {} x {} but found {} features and {} barcodes", nrow, old_ncol, peak_cell_counts.len(), ncol),
+        ));
+    }
+
+    let new_nnz: usize = peak_cell_counts.iter().map(|x| x.len()).sum();
+
+    let mut encoder = compressed_writer(outfile, num_threads)?;
+    let field = if integer { "integer" } else { "real" };
+    let mut output = mtx_header(field, nrow, ncol + new_cols, nnz + new_nnz);
+
+    let mut result: io::Result<()> = Ok(());
+    for_each_entry(&mut reader, nrow, ncol, |row, col, value| {
+        if integer {
+            output.push_str(&format!("{} {} {}\n", row + 1, col + 1, value as i64));
+        } else {
+            output.push_str(&format!("{} {} {}\n", row + 1, col + 1, value));
+        }
+        if output.len() > 1 << 20 && result.is_ok() {
+            result = encoder.write_all(output.as_bytes());
+            output.clear();
+        }
+    })?;
+    result?;
+
+    for (peak_index, cell_counts) in peak_cell_counts.iter().enumerate() {
+        for (cell_index, count) in cell_counts {
+            output.push_str(&format!("{} {} {}\n", peak_index + 1, ncol + *cell_index as usize + 1, count));
+        }
+        if output.len() > 1 << 20 {
+            encoder.write_all(output.as_bytes())?;
+            output.clear();
+        }
+    }
+    encoder.write_all(output.as_bytes())?;
+    encoder.finish().map_err(io::Error::other)?;
+
+    Ok(())
+}
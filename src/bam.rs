@@ -0,0 +1,330 @@
+use std::{
+    io,
+    fs::File,
+    path::{Path, PathBuf},
+    sync::Arc,
+    sync::atomic::{AtomicBool, Ordering},
+    io::Read,
+    io::Write,
+};
+use flate2::Compression;
+use gzp::{
+    deflate::Bgzf,
+    par::compress::{ParCompress, ParCompressBuilder},
+    ZWriter,
+};
+use log::info;
+use rustc_hash::FxHashMap;
+
+/// Flags of reads that never contribute a fragment:
+/// unmapped, mate unmapped, secondary, QC fail, and supplementary
+const SKIP_FLAGS: u16 = 0x4 | 0x8 | 0x100 | 0x200 | 0x800;
+
+/// Paired and properly paired
+const REQUIRED_FLAGS: u16 = 0x1 | 0x2;
+
+/// How read pairs in a BAM file are turned into fragments
+#[derive(Clone, Debug)]
+pub struct BamOptions {
+    /// aux tag holding the cell barcode
+    pub barcode_tag: [u8; 2],
+    pub min_mapq: u8,
+    /// also write the fragments to this BGZF-compressed file
+    pub fragments_out: Option<PathBuf>,
+    /// reference FASTA used to decode CRAM files
+    pub reference: Option<PathBuf>,
+    /// whether `fragments_out` has been written, shared by clones of these options so that a BAM
+    /// file read more than once in a run is only written once
+    written: Arc<AtomicBool>,
+}
+
+impl Default for BamOptions {
+    fn default() -> BamOptions {
+        BamOptions {
+            barcode_tag: *b"CB",
+            min_mapq: 30,
+            fragments_out: None,
+            reference: None,
+            written: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+impl BamOptions {
+    pub fn from_matches(matches: &clap::ArgMatches) -> Result<BamOptions, String> {
+        let mut options = BamOptions::default();
+        if let Some(tag) = matches.try_get_one::<String>("bam_barcode_tag").ok().flatten() {
+            options.barcode_tag = tag
+                .as_bytes()
+                .try_into()
+                .map_err(|_| format!("BAM barcode tag must be two characters: {}", tag))?;
+        }
+        if let Some(mapq) = matches.try_get_one::<u8>("bam_min_mapq").ok().flatten() {
+            options.min_mapq = *mapq;
+        }
+        options.fragments_out = matches
+            .try_get_one::<String>("bam_fragments")
+            .ok()
+            .flatten()
+            .map(PathBuf::from);
+        options.reference = matches
+            .try_get_one::<String>("reference")
+            .ok()
+            .flatten()
+            .map(PathBuf::from);
+        Ok(options)
+    }
+}
+
+/// Whether an input path is a BAM file rather than a fragment file
+pub fn is_bam(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("bam"))
+}
+
+/// Whether an input path is a CRAM file
+pub fn is_cram(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("cram"))
+}
+
+/// Alignments of a CRAM file as an uncompressed BAM stream, decoded against the `--reference` FASTA
+#[cfg(feature = "cram")]
+pub fn open_cram(path: &Path, options: &BamOptions, io_threads: usize) -> io::Result<Box<dyn Read + Send>> {
+    Ok(Box::new(crate::cram::CramRecords::open(path, options.reference.as_deref(), io_threads)?))
+}
+
+#[cfg(not(feature = "cram"))]
+pub fn open_cram(path: &Path, _options: &BamOptions, _io_threads: usize) -> io::Result<Box<dyn Read + Send>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("{:?}: CRAM input requires CRAM support; rebuild fragtk with `--features cram`", path),
+    ))
+}
+
+/// Fragment file lines from a coordinate-sorted BAM file of paired-end reads.
+/// Each properly paired read pair is one fragment, from the leftmost read start to the
+/// mate end, shifted +4/-5 for the Tn5 insertion offset as in Cell Ranger ATAC.
+/// Read pairs with the same coordinates and barcode are collapsed, with the number of
+/// pairs in the fifth column.
+pub struct BamFragments<R> {
+    inner: R,
+    options: BamOptions,
+    refs: Vec<String>,
+    /// fragments starting at the current position: (end, barcode) -> read pairs
+    pending: FxHashMap<(u32, Vec<u8>), u32>,
+    current: (i32, u32),
+    /// position of the last record, to check the sort order
+    last_pos: (i32, i32),
+    /// formatted lines not yet read
+    out: Vec<u8>,
+    out_pos: usize,
+    record: Vec<u8>,
+    tee: Option<ParCompress<Bgzf>>,
+    done: bool,
+}
+
+impl<R: Read> BamFragments<R> {
+    /// Read the BAM header from a decompressed stream. The fragments are also written to
+    /// `options.fragments_out`, with `io_threads` compression threads, if these options have not written them yet.
+    pub fn new(mut inner: R, options: &BamOptions, io_threads: usize) -> io::Result<BamFragments<R>> {
+        let options = options.clone();
+
+        let mut magic = [0u8; 4];
+        inner.read_exact(&mut magic)?;
+        if &magic != b"BAM\x01" {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a BAM file"));
+        }
+        let l_text = read_u32(&mut inner)? as u64;
+        io::copy(&mut (&mut inner).take(l_text), &mut io::sink())?;
+        let n_ref = read_u32(&mut inner)?;
+        let mut refs = Vec::with_capacity(n_ref as usize);
+        for _ in 0..n_ref {
+            let l_name = read_u32(&mut inner)? as usize;
+            let mut name = vec![0u8; l_name];
+            inner.read_exact(&mut name)?;
+            read_u32(&mut inner)?;
+            refs.push(String::from_utf8_lossy(&name[..l_name.saturating_sub(1)]).into_owned());
+        }
+
+        let tee = match &options.fragments_out {
+            Some(path) if !options.written.swap(true, Ordering::Relaxed) => {
+                info!("Writing fragments from BAM to {:?}", path);
+                let writer: ParCompress<Bgzf> = ParCompressBuilder::new()
+                    .compression_level(Compression::default())
+                    .num_threads(io_threads.max(1))
+                    .map_err(io::Error::other)?
+                    .from_writer(File::create(path)?);
+                Some(writer)
+            }
+            _ => None,
+        };
+
+        Ok(BamFragments {
+            inner,
+            options,
+            refs,
+            pending: FxHashMap::default(),
+            current: (-1, 0),
+            last_pos: (-1, 0),
+            out: Vec::new(),
+            out_pos: 0,
+            record: Vec::new(),
+            tee,
+            done: false,
+        })
+    }
+
+    /// Read the next alignment record into `self.record`, returning false at the end of the file
+    fn next_record(&mut self) -> io::Result<bool> {
+        let mut size = [0u8; 4];
+        match self.inner.read(&mut size)? {
+            0 => return Ok(false),
+            4 => {}
+            n => self.inner.read_exact(&mut size[n..])?,
+        }
+        self.record.resize(u32::from_le_bytes(size) as usize, 0);
+        self.inner.read_exact(&mut self.record)?;
+        Ok(true)
+    }
+
+    /// Add the fragment of the current record, if it is the leftmost read of a usable pair
+    fn add_record(&mut self) -> io::Result<()> {
+        let rec = &self.record;
+        if rec.len() < 32 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Truncated BAM record"));
+        }
+        let ref_id = i32::from_le_bytes(rec[0..4].try_into().unwrap());
+        let pos = i32::from_le_bytes(rec[4..8].try_into().unwrap());
+        let l_read_name = rec[8] as usize;
+        let mapq = rec[9];
+        let n_cigar = u16::from_le_bytes(rec[12..14].try_into().unwrap()) as usize;
+        let flag = u16::from_le_bytes(rec[14..16].try_into().unwrap());
+        let l_seq = u32::from_le_bytes(rec[16..20].try_into().unwrap()) as usize;
+        let mate_ref_id = i32::from_le_bytes(rec[20..24].try_into().unwrap());
+        let tlen = i32::from_le_bytes(rec[28..32].try_into().unwrap());
+
+        if ref_id < 0 || pos < 0 {
+            return Ok(());
+        }
+        if (ref_id, pos) < self.last_pos {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "BAM file must be sorted by coordinate"));
+        }
+        self.last_pos = (ref_id, pos);
+        if flag & SKIP_FLAGS != 0 || flag & REQUIRED_FLAGS != REQUIRED_FLAGS
+            || mapq < self.options.min_mapq || mate_ref_id != ref_id || tlen <= 0
+        {
+            return Ok(());
+        }
+
+        let aux_start = 32 + l_read_name + 4 * n_cigar + l_seq.div_ceil(2) + l_seq;
+        let barcode = match rec.get(aux_start..).and_then(|aux| find_string_tag(aux, self.options.barcode_tag)) {
+            Some(barcode) => barcode.to_vec(),
+            None => return Ok(()),
+        };
+
+        // Tn5 insertion offsets
+        let start = pos as u32 + 4;
+        let end = (pos as u32 + tlen as u32).saturating_sub(5);
+        if end <= start {
+            return Ok(());
+        }
+
+        if (ref_id, start) != self.current {
+            self.flush_pending();
+            self.current = (ref_id, start);
+        }
+        *self.pending.entry((end, barcode)).or_insert(0) += 1;
+        Ok(())
+    }
+
+    /// Format the fragments starting at the current position, sorted by end and barcode
+    fn flush_pending(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let chrom = &self.refs[self.current.0 as usize];
+        let mut fragments: Vec<((u32, Vec<u8>), u32)> = self.pending.drain().collect();
+        fragments.sort_unstable();
+        for ((end, barcode), count) in fragments {
+            self.out.extend_from_slice(
+                format!("{}\t{}\t{}\t{}\t{}\n", chrom, self.current.1, end, String::from_utf8_lossy(&barcode), count).as_bytes(),
+            );
+        }
+    }
+
+    /// Fill the output buffer with formatted fragments
+    fn fill(&mut self) -> io::Result<()> {
+        self.out.clear();
+        self.out_pos = 0;
+        while self.out.len() < 1 << 16 {
+            if !self.next_record()? {
+                self.flush_pending();
+                self.done = true;
+                break;
+            }
+            self.add_record()?;
+        }
+        if let Some(tee) = self.tee.as_mut() {
+            tee.write_all(&self.out)?;
+        }
+        if self.done {
+            if let Some(mut tee) = self.tee.take() {
+                tee.finish().map_err(io::Error::other)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for BamFragments<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.out_pos == self.out.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.fill()?;
+        }
+        let n = buf.len().min(self.out.len() - self.out_pos);
+        buf[..n].copy_from_slice(&self.out[self.out_pos..self.out_pos + n]);
+        self.out_pos += n;
+        Ok(n)
+    }
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+/// Value of a string (Z) aux tag, or None if the tag is missing or the fields are malformed
+fn find_string_tag(mut aux: &[u8], tag: [u8; 2]) -> Option<&[u8]> {
+    while aux.len() >= 3 {
+        let (name, kind) = ([aux[0], aux[1]], aux[2]);
+        aux = &aux[3..];
+        let size = match kind {
+            b'A' | b'c' | b'C' => 1,
+            b's' | b'S' => 2,
+            b'i' | b'I' | b'f' => 4,
+            b'Z' | b'H' => {
+                let len = aux.iter().position(|&b| b == 0)?;
+                if name == tag && kind == b'Z' {
+                    return Some(&aux[..len]);
+                }
+                len + 1
+            }
+            b'B' => {
+                let element = match *aux.first()? {
+                    b'c' | b'C' => 1,
+                    b's' | b'S' => 2,
+                    b'i' | b'I' | b'f' => 4,
+                    _ => return None,
+                };
+                let count = u32::from_le_bytes(aux.get(1..5)?.try_into().ok()?) as usize;
+                5 + element * count
+            }
+            _ => return None,
+        };
+        aux = aux.get(size..)?;
+    }
+    None
+}
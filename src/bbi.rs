@@ -0,0 +1,70 @@
+use std::{
+    io,
+    collections::HashMap,
+    error::Error,
+    fs::File,
+    path::Path,
+    io::BufRead,
+    io::BufReader,
+};
+use bigtools::{beddata::BedParserStreamingIterator, BigBedWrite, BigWigWrite, Value};
+use log::info;
+
+/// Convert a sorted BED file into a bigBed file.
+/// `autosql` describes the columns; the default BED3 schema is used if not given.
+pub fn bed_to_bigbed(
+    bed_file: &Path,
+    outfile: &Path,
+    chrom_sizes: &[(String, u32)],
+    autosql: Option<&str>,
+    num_threads: usize,
+) -> Result<(), Box<dyn Error>> {
+    info!("Writing bigBed file: {:?}", outfile);
+    let chrom_map: HashMap<String, u32> = chrom_sizes.iter().cloned().collect();
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(num_threads.max(1))
+        .build()?;
+
+    let mut writer = BigBedWrite::create_file(outfile, chrom_map)?;
+    writer.autosql = autosql.map(|x| x.to_string());
+    let data = BedParserStreamingIterator::from_bed_file(File::open(bed_file)?, true);
+    writer.write(data, runtime)?;
+
+    Ok(())
+}
+
+/// Convert a bedGraph file into a bigWig file, multiplying values by `scale`.
+/// Chromosomes must be contiguous but need not be sorted.
+pub fn bedgraph_to_bigwig(
+    bedgraph: &Path,
+    outfile: &Path,
+    chrom_sizes: &[(String, u32)],
+    scale: f64,
+    num_threads: usize,
+) -> Result<(), Box<dyn Error>> {
+    info!("Writing bigWig file: {:?}", outfile);
+    let chrom_map: HashMap<String, u32> = chrom_sizes.iter().cloned().collect();
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(num_threads.max(1))
+        .build()?;
+
+    let values = BufReader::new(File::open(bedgraph)?).lines().map(move |line| {
+        let line = line?;
+        let fields: Vec<&str> = line.split('\t').collect();
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("Invalid bedGraph line: {}", line));
+        if fields.len() < 4 {
+            return Err(invalid());
+        }
+        let start: u32 = fields[1].parse().map_err(|_| invalid())?;
+        let end: u32 = fields[2].parse().map_err(|_| invalid())?;
+        let value: f64 = fields[3].parse().map_err(|_| invalid())?;
+        Ok((fields[0].to_string(), Value { start, end, value: (value * scale) as f32 }))
+    });
+
+    let writer = BigWigWrite::create_file(outfile, chrom_map)?;
+    writer.write(BedParserStreamingIterator::wrap_iter(values, true), runtime)?;
+
+    Ok(())
+}
@@ -0,0 +1,330 @@
+use std::{
+    io,
+    fs::File,
+    path::Path,
+    io::BufRead,
+    io::BufReader,
+};
+use rust_lapper::{Interval, Lapper};
+use rustc_hash::FxHashMap;
+use log::warn;
+use crate::fasta;
+use crate::fragments::decode_line;
+use crate::matrix;
+
+/// Intervals for each chromosome, with the value giving the region index
+pub type RegionTrees = FxHashMap<String, Lapper<u32, usize>>;
+
+/// A BED region with optional name and strand columns
+pub struct Region {
+    pub chrom: String,
+    pub start: u32,
+    pub end: u32,
+    pub name: String,
+    pub strand: char,
+}
+
+/// Peak file formats accepted as features
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PeakFormat {
+    Bed,
+    /// ENCODE narrowPeak (BED6+4, with q-value and summit offset)
+    NarrowPeak,
+    /// ENCODE broadPeak (BED6+3, with q-value)
+    BroadPeak,
+}
+
+impl PeakFormat {
+    pub fn from_name(name: &str) -> Option<PeakFormat> {
+        match name.to_ascii_lowercase().as_str() {
+            "bed" => Some(PeakFormat::Bed),
+            "narrowpeak" => Some(PeakFormat::NarrowPeak),
+            "broadpeak" => Some(PeakFormat::BroadPeak),
+            _ => None,
+        }
+    }
+
+    /// Detect the format from the file extension, ignoring a trailing .gz
+    pub fn detect(path: &Path) -> PeakFormat {
+        let name = path.file_name().map(|x| x.to_string_lossy().to_ascii_lowercase()).unwrap_or_default();
+        let name = name.trim_end_matches(".gz");
+        if name.ends_with(".narrowpeak") {
+            PeakFormat::NarrowPeak
+        } else if name.ends_with(".broadpeak") {
+            PeakFormat::BroadPeak
+        } else {
+            PeakFormat::Bed
+        }
+    }
+
+    pub fn min_fields(&self) -> usize {
+        match self {
+            PeakFormat::Bed => 3,
+            PeakFormat::NarrowPeak => 10,
+            PeakFormat::BroadPeak => 9,
+        }
+    }
+
+    /// -log10 q-value of a peak line, or None for BED or a missing value (-1)
+    pub fn qvalue(&self, fields: &[&str]) -> Option<f64> {
+        if *self == PeakFormat::Bed {
+            return None;
+        }
+        fields.get(8)?.trim().parse::<f64>().ok().filter(|x| *x >= 0.0)
+    }
+}
+
+/// Coordinate convention of a feature file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CoordinateSystem {
+    /// 0-based start, end excluded (BED)
+    Bed,
+    /// 1-based start, end included (GFF and GTF, and BED files converted from them without adjustment)
+    Gff,
+}
+
+impl CoordinateSystem {
+    pub fn from_name(name: &str) -> Option<CoordinateSystem> {
+        match name {
+            "bed" => Some(CoordinateSystem::Bed),
+            "gff" => Some(CoordinateSystem::Gff),
+            _ => None,
+        }
+    }
+
+    /// Convert a region to 0-based half-open coordinates, or None if the start is invalid
+    pub fn to_half_open(self, start: u32, end: u32) -> Option<(u32, u32)> {
+        match self {
+            CoordinateSystem::Bed => Some((start, end)),
+            CoordinateSystem::Gff => start.checked_sub(1).map(|start| (start, end)),
+        }
+    }
+}
+
+/// Position that resized features are centered on
+#[derive(Clone, Copy, Debug)]
+pub enum Anchor {
+    /// midpoint of the region
+    Center,
+    /// summit given as an offset from the region start in this column (0-based), e.g. narrowPeak column 10
+    Summit(usize),
+}
+
+/// Resize regions to a fixed width around an anchor, from a spec such as
+/// `width=501,anchor=center`, `width=501,anchor=summit` (narrowPeak) or `width=501,summit-col=10`
+#[derive(Clone, Copy, Debug)]
+pub struct Resize {
+    pub width: u32,
+    pub anchor: Anchor,
+}
+
+impl Resize {
+    pub fn parse(spec: &str) -> io::Result<Resize> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        let mut width: Option<u32> = None;
+        let mut anchor = Anchor::Center;
+        for option in spec.split(',') {
+            let (key, value) = option
+                .split_once('=')
+                .ok_or_else(|| invalid(format!("Invalid resize option {:?}, expected key=value", option)))?;
+            match (key.trim(), value.trim()) {
+                ("width", value) => match value.parse() {
+                    Ok(w) if w > 0 => width = Some(w),
+                    _ => return Err(invalid(format!("Invalid resize width: {}", value))),
+                },
+                ("anchor", "center") => anchor = Anchor::Center,
+                // narrowPeak summit offset column
+                ("anchor", "summit") => anchor = Anchor::Summit(9),
+                ("summit-col", value) => match value.parse::<usize>() {
+                    Ok(col) if col > 3 => anchor = Anchor::Summit(col - 1),
+                    _ => return Err(invalid(format!("Invalid summit column: {}, must be after column 3", value))),
+                },
+                _ => return Err(invalid(format!("Unknown resize option: {}", option))),
+            }
+        }
+        let width = width.ok_or_else(|| invalid("Resize spec must include width=N".to_string()))?;
+        Ok(Resize { width, anchor })
+    }
+
+    /// Resized coordinates of a BED line, or None if the summit column is missing or invalid.
+    /// Regions near the chromosome start are shifted rather than clipped.
+    pub fn apply(&self, start: u32, end: u32, fields: &[&str]) -> Option<(u32, u32)> {
+        let anchor = match self.anchor {
+            Anchor::Center => start + (end - start.min(end)) / 2,
+            Anchor::Summit(col) => {
+                // narrowPeak uses -1 when no summit was called
+                match fields.get(col)?.trim().parse::<i64>().ok()? {
+                    offset if offset >= 0 => start.saturating_add(u32::try_from(offset).unwrap_or(u32::MAX)),
+                    _ => start + (end - start.min(end)) / 2,
+                }
+            }
+        };
+        let new_start = anchor.saturating_sub(self.width / 2);
+        Some((new_start, new_start.saturating_add(self.width)))
+    }
+}
+
+/// Read all regions from a BED file.
+/// Missing name and strand columns are set to "."
+pub fn read_regions(bed_file: &Path) -> io::Result<Vec<Region>> {
+    let reader = BufReader::new(File::open(bed_file)?);
+    let mut regions = Vec::new();
+
+    for (index, line) in reader.split(b'\n').enumerate() {
+        let line = line?;
+        let line = match decode_line(&line) {
+            Some(line) => line,
+            None => {
+                warn!("Line {}: Not valid UTF-8", index + 1);
+                continue;
+            }
+        };
+        if line.starts_with('#') || line.starts_with("track") || line.starts_with("browser") || line.is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 3 {
+            warn!("Line {}: Less than three fields", index + 1);
+            continue;
+        }
+        let (start, end): (u32, u32) = match (fields[1].parse(), fields[2].parse()) {
+            (Ok(start), Ok(end)) => (start, end),
+            _ => {
+                warn!("Line {}: Failed to parse region coordinates", index + 1);
+                continue;
+            }
+        };
+        regions.push(Region {
+            chrom: fields[0].to_string(),
+            start,
+            end,
+            name: fields.get(3).unwrap_or(&".").to_string(),
+            strand: fields.get(5).and_then(|x| x.chars().next()).unwrap_or('.'),
+        });
+    }
+
+    Ok(regions)
+}
+
+/// Read a BED file into per-chromosome interval trees.
+/// Returns the number of regions read and the trees.
+pub fn region_trees(bed_file: &Path) -> io::Result<(usize, RegionTrees)> {
+    let regions = read_regions(bed_file)?;
+    let mut intervals: FxHashMap<String, Vec<Interval<u32, usize>>> = FxHashMap::default();
+    for (index, region) in regions.iter().enumerate() {
+        intervals
+            .entry(region.chrom.clone())
+            .or_default()
+            .push(Interval { start: region.start, stop: region.end, val: index });
+    }
+
+    let trees = intervals
+        .into_iter()
+        .map(|(chr, intervals)| (chr, Lapper::new(intervals)))
+        .collect();
+
+    Ok((regions.len(), trees))
+}
+
+/// Read a chrom.sizes file (chromosome name and length, tab-separated). A FASTA index (.fai) starts
+/// with the same columns and can be used directly; for a FASTA file, the sequence lengths are used.
+pub fn read_chrom_sizes(path: &Path) -> io::Result<Vec<(String, u32)>> {
+    let mut reader = matrix::open_text(path)?;
+    if reader.fill_buf()?.starts_with(b">") {
+        return fasta::sequence_lengths(path);
+    }
+    let mut sizes = Vec::new();
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.starts_with('#') || line.is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        let size: u32 = match fields.get(1).map(|x| x.trim().parse()) {
+            Some(Ok(size)) => size,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Line {}: Failed to parse chromosome size", index + 1),
+                ));
+            }
+        };
+        sizes.push((fields[0].to_string(), size));
+    }
+    Ok(sizes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gff_coordinates_become_half_open() {
+        assert_eq!(CoordinateSystem::Bed.to_half_open(0, 100), Some((0, 100)));
+        // 1-based closed 1..100 is the same 100 bases as BED 0..100
+        assert_eq!(CoordinateSystem::Gff.to_half_open(1, 100), Some((0, 100)));
+        assert_eq!(CoordinateSystem::Gff.to_half_open(500, 500), Some((499, 500)));
+        // there is no base 0 in 1-based coordinates
+        assert_eq!(CoordinateSystem::Gff.to_half_open(0, 100), None);
+        assert_eq!(CoordinateSystem::from_name("gff"), Some(CoordinateSystem::Gff));
+        assert_eq!(CoordinateSystem::from_name("gtf"), None);
+    }
+
+    #[test]
+    fn resize_centers_on_the_midpoint_or_summit() {
+        let center = Resize::parse("width=101,anchor=center").unwrap();
+        assert_eq!(center.apply(1000, 1200, &[]), Some((1050, 1151)));
+        // odd lengths round the midpoint down
+        assert_eq!(center.apply(1000, 1201, &[]), Some((1050, 1151)));
+
+        let summit = Resize::parse("width=100,anchor=summit").unwrap();
+        let fields = ["chr1", "1000", "1200", "peak", "0", ".", "5", "4", "3", "20"];
+        assert_eq!(summit.apply(1000, 1200, &fields), Some((970, 1070)));
+        // -1 means no summit was called, so the midpoint is used
+        let mut no_summit = fields;
+        no_summit[9] = "-1";
+        assert_eq!(summit.apply(1000, 1200, &no_summit), Some((1050, 1150)));
+        // a missing or invalid summit column is an error
+        assert_eq!(summit.apply(1000, 1200, &fields[..9]), None);
+        no_summit[9] = "x";
+        assert_eq!(summit.apply(1000, 1200, &no_summit), None);
+
+        let column = Resize::parse("width=10,summit-col=5").unwrap();
+        assert_eq!(column.apply(1000, 1200, &fields), Some((995, 1005)));
+    }
+
+    #[test]
+    fn resize_shifts_features_at_the_chromosome_start() {
+        let resize = Resize::parse("width=501").unwrap();
+        // the feature keeps its width instead of being clipped at 0
+        assert_eq!(resize.apply(0, 100, &[]), Some((0, 501)));
+        assert_eq!(resize.apply(300, 300, &[]), Some((50, 551)));
+    }
+
+    #[test]
+    fn invalid_resize_specs_are_rejected() {
+        for spec in ["", "width=0", "width=x", "anchor=center", "width=10,summit-col=3", "width=10,anchor=edge", "width"] {
+            assert_eq!(Resize::parse(spec).unwrap_err().kind(), io::ErrorKind::InvalidInput, "{:?}", spec);
+        }
+    }
+
+
+    #[test]
+    fn qvalues_are_read_from_the_ninth_column() {
+        let fields = ["chr1", "100", "200", "peak", "0", ".", "5", "4", "3.5", "20"];
+        assert_eq!(PeakFormat::NarrowPeak.qvalue(&fields), Some(3.5));
+        assert_eq!(PeakFormat::BroadPeak.qvalue(&fields[..9]), Some(3.5));
+        assert_eq!(PeakFormat::Bed.qvalue(&fields), None);
+        let mut missing = fields;
+        missing[8] = "-1";
+        assert_eq!(PeakFormat::NarrowPeak.qvalue(&missing), None);
+        assert_eq!(PeakFormat::NarrowPeak.qvalue(&fields[..8]), None);
+    }
+
+    #[test]
+    fn peak_formats_are_detected_from_the_extension() {
+        assert_eq!(PeakFormat::detect(Path::new("peaks.narrowPeak.gz")), PeakFormat::NarrowPeak);
+        assert_eq!(PeakFormat::detect(Path::new("peaks.broadPeak")), PeakFormat::BroadPeak);
+        assert_eq!(PeakFormat::detect(Path::new("peaks.bed.gz")), PeakFormat::Bed);
+    }
+}
@@ -0,0 +1,318 @@
+use std::{
+    io,
+    fs,
+    fs::File,
+    path::Path,
+    error::Error,
+    hash::{Hash, Hasher},
+    io::Write,
+};
+use log::{info, warn};
+use rayon::prelude::*;
+use rustc_hash::{FxHashMap, FxHashSet, FxHasher};
+use crate::bed::read_chrom_sizes;
+use crate::cells::CellGroups;
+use crate::fragments::{for_each_fragment_with_progress, ReadOptions};
+use crate::progress::Progress;
+
+/// Peak calling settings shared by all groups
+struct PeakParams {
+    bin_size: u32,
+    /// half-width of the local background window, in bins
+    local_bins: usize,
+    /// -log10 p-value threshold
+    min_score: f64,
+}
+
+/// A called peak on the current chromosome
+struct Peak {
+    start: u32,
+    end: u32,
+    summit: u32,
+    count: u32,
+    score: f64,
+}
+
+pub fn run(matches: &clap::ArgMatches, progress: &Progress) -> Result<(), Box<dyn Error>> {
+
+    let frag_file = Path::new(matches.get_one::<String>("fragments").unwrap());
+    info!("Received fragment file: {:?}", frag_file);
+    let read_options = ReadOptions::from_matches(matches)?;
+
+    let output = Path::new(matches.get_one::<String>("outdir").unwrap());
+    let chrom_sizes: FxHashMap<String, u32> = read_chrom_sizes(Path::new(matches.get_one::<String>("chrom_sizes").unwrap()))?
+        .into_iter()
+        .collect();
+
+    let bin_size = *matches.get_one::<u32>("bin_size").unwrap();
+    let local_window = *matches.get_one::<u32>("local_window").unwrap();
+    let pvalue = *matches.get_one::<f64>("pvalue").unwrap();
+    let n_reps = *matches.get_one::<usize>("pseudo_replicates").unwrap();
+    let min_reproducible = match matches.get_one::<usize>("min_reproducible") {
+        Some(n) => *n,
+        None => n_reps,
+    };
+    if bin_size == 0 {
+        return Err("--bin-size must be greater than zero".into());
+    }
+    if !(pvalue > 0.0 && pvalue < 1.0) {
+        return Err("--pvalue must be between 0 and 1".into());
+    }
+    if min_reproducible > n_reps {
+        return Err(format!("--min-reproducible ({}) cannot exceed --pseudo-replicates ({})", min_reproducible, n_reps).into());
+    }
+
+    let params = PeakParams {
+        bin_size,
+        local_bins: (local_window / bin_size / 2) as usize,
+        min_score: -pvalue.log10(),
+    };
+
+    let groups = CellGroups::from_matches(matches, "all")?;
+    fs::create_dir_all(output)?;
+
+    let mut writers = Vec::with_capacity(groups.names.len());
+    for name in &groups.names {
+        let path = output.join(format!("{}.narrowPeak", name));
+        info!("Writing peaks: {:?}", path);
+        writers.push(io::BufWriter::new(File::create(path)?));
+    }
+    let union_path = output.join("union.bed");
+    info!("Writing union peak set: {:?}", union_path);
+    let mut union_writer = io::BufWriter::new(File::create(union_path)?);
+
+    // insertion positions on the current chromosome for each group and pseudo-replicate
+    let n_slots = n_reps.max(1);
+    let mut positions: Vec<Vec<Vec<u32>>> = vec![vec![Vec::new(); n_slots]; groups.names.len()];
+    let mut peak_counts: Vec<usize> = vec![0; groups.names.len()];
+    let mut current_chrom = String::new();
+    let mut finished_chroms: FxHashSet<String> = FxHashSet::default();
+    let mut missing_chroms: FxHashSet<String> = FxHashSet::default();
+
+    let mut flush = |chrom: &str, positions: &mut Vec<Vec<Vec<u32>>>| -> io::Result<()> {
+        let size = match chrom_sizes.get(chrom) {
+            Some(size) => *size,
+            None => {
+                positions.iter_mut().flatten().for_each(|x| x.clear());
+                return Ok(());
+            }
+        };
+        // groups are called in parallel on the compute pool
+        let group_peaks: Vec<Vec<Peak>> = positions
+            .par_iter()
+            .map(|group_positions| reproducible_peaks(group_positions, size, &params, n_reps, min_reproducible))
+            .collect();
+
+        let mut union: Vec<(u32, u32)> = Vec::new();
+        for (group, peaks) in group_peaks.iter().enumerate() {
+            for peak in peaks {
+                peak_counts[group] += 1;
+                writeln!(
+                    writers[group],
+                    "{}\t{}\t{}\t{}_peak_{}\t{}\t.\t{}\t{:.5}\t-1\t{}",
+                    chrom, peak.start, peak.end, groups.names[group], peak_counts[group],
+                    ((peak.score * 10.0) as u32).min(1000), peak.count, peak.score, peak.summit - peak.start,
+                )?;
+                union.push((peak.start, peak.end));
+            }
+        }
+        positions.iter_mut().flatten().for_each(|x| x.clear());
+        for (start, end) in merge_intervals(union) {
+            writeln!(union_writer, "{}\t{}\t{}", chrom, start, end)?;
+        }
+        Ok(())
+    };
+
+    for_each_fragment_with_progress(frag_file, &read_options, progress, |fragment| {
+        let group = match groups.get(fragment.barcode) {
+            Some(group) => group,
+            None => return Ok(()),
+        };
+
+        if fragment.chrom != current_chrom {
+            flush(&current_chrom, &mut positions)?;
+            if !finished_chroms.insert(fragment.chrom.to_string()) {
+                warn!("Fragment file is not sorted by chromosome, peaks on {} will be called separately for each block", fragment.chrom);
+            }
+            if !chrom_sizes.contains_key(fragment.chrom) && missing_chroms.insert(fragment.chrom.to_string()) {
+                warn!("Chromosome {} not found in chromosome sizes, skipping", fragment.chrom);
+            }
+            current_chrom = fragment.chrom.to_string();
+        }
+
+        positions[group][replicate(fragment.barcode, n_slots)].extend([fragment.start, fragment.end]);
+        Ok(())
+    })?;
+    flush(&current_chrom, &mut positions)?;
+
+    for writer in writers.iter_mut() {
+        writer.flush()?;
+    }
+    union_writer.flush()?;
+
+    for (name, count) in groups.names.iter().zip(peak_counts.iter()) {
+        info!("Group {}: {} peaks", name, count);
+    }
+
+    Ok(())
+}
+
+/// Deterministic pseudo-replicate for a cell barcode
+fn replicate(barcode: &str, n_reps: usize) -> usize {
+    if n_reps <= 1 {
+        return 0;
+    }
+    let mut hasher = FxHasher::default();
+    barcode.hash(&mut hasher);
+    (hasher.finish() % n_reps as u64) as usize
+}
+
+/// Call peaks on the pooled insertions of a group, keeping those overlapping
+/// peaks called in at least `min_reproducible` pseudo-replicates
+fn reproducible_peaks(
+    replicates: &[Vec<u32>],
+    size: u32,
+    params: &PeakParams,
+    n_reps: usize,
+    min_reproducible: usize,
+) -> Vec<Peak> {
+    let n_bins = (size / params.bin_size) as usize + 1;
+    let bin_counts = |positions: &[u32], counts: &mut [u32]| {
+        for &pos in positions {
+            if pos < size {
+                counts[(pos / params.bin_size) as usize] += 1;
+            }
+        }
+    };
+
+    let mut pooled: Vec<u32> = vec![0; n_bins];
+    for positions in replicates {
+        bin_counts(positions, &mut pooled);
+    }
+    let peaks = call_peaks(&pooled, params);
+    if n_reps <= 1 || min_reproducible == 0 || peaks.is_empty() {
+        return peaks;
+    }
+
+    // number of replicates with a peak overlapping each pooled peak
+    let mut support: Vec<usize> = vec![0; peaks.len()];
+    let mut counts: Vec<u32> = vec![0; n_bins];
+    for positions in replicates {
+        counts.iter_mut().for_each(|x| *x = 0);
+        bin_counts(positions, &mut counts);
+        let rep_peaks = call_peaks(&counts, params);
+        // both peak lists are sorted and non-overlapping
+        let mut j = 0;
+        for (i, peak) in peaks.iter().enumerate() {
+            while j < rep_peaks.len() && rep_peaks[j].end <= peak.start {
+                j += 1;
+            }
+            if j < rep_peaks.len() && rep_peaks[j].start < peak.end {
+                support[i] += 1;
+            }
+        }
+    }
+
+    peaks
+        .into_iter()
+        .zip(support)
+        .filter(|(_, support)| *support >= min_reproducible)
+        .map(|(peak, _)| peak)
+        .collect()
+}
+
+/// Find runs of bins with significant insertion enrichment over a Poisson background.
+/// The background is the larger of the chromosome-wide and local mean bin counts.
+fn call_peaks(counts: &[u32], params: &PeakParams) -> Vec<Peak> {
+    let n_bins = counts.len();
+    let total: u64 = counts.iter().map(|x| *x as u64).sum();
+    if total == 0 {
+        return Vec::new();
+    }
+    let chrom_lambda = total as f64 / n_bins as f64;
+
+    let mut cumsum: Vec<u64> = vec![0; n_bins + 1];
+    for i in 0..n_bins {
+        cumsum[i + 1] = cumsum[i] + counts[i] as u64;
+    }
+
+    let mut peaks: Vec<Peak> = Vec::new();
+    let mut current: Option<Peak> = None;
+    for (i, &count) in counts.iter().enumerate() {
+        let mut score = 0.0;
+        if count as f64 > chrom_lambda {
+            let lo = i.saturating_sub(params.local_bins);
+            let hi = (i + params.local_bins + 1).min(n_bins);
+            let local_lambda = (cumsum[hi] - cumsum[lo]) as f64 / (hi - lo) as f64;
+            score = poisson_score(count, chrom_lambda.max(local_lambda));
+        }
+
+        if score >= params.min_score {
+            let start = i as u32 * params.bin_size;
+            match current.as_mut() {
+                Some(peak) => {
+                    peak.end = start + params.bin_size;
+                    if count > peak.count {
+                        peak.count = count;
+                        peak.summit = start + params.bin_size / 2;
+                        peak.score = score;
+                    }
+                }
+                None => {
+                    current = Some(Peak {
+                        start,
+                        end: start + params.bin_size,
+                        summit: start + params.bin_size / 2,
+                        count,
+                        score,
+                    });
+                }
+            }
+        } else if let Some(peak) = current.take() {
+            peaks.push(peak);
+        }
+    }
+    peaks.extend(current);
+    peaks
+}
+
+/// -log10 of the Poisson upper tail probability P(X >= k)
+fn poisson_score(k: u32, lambda: f64) -> f64 {
+    if k == 0 {
+        return 0.0;
+    }
+    // sum terms from k upwards in log space, relative to the first term
+    let k = k as f64;
+    let log_first = -lambda + k * lambda.ln() - ln_factorial(k);
+    let mut term = 1.0;
+    let mut sum = 1.0;
+    let mut i = k;
+    while term > 1e-12 * sum {
+        i += 1.0;
+        term *= lambda / i;
+        sum += term;
+    }
+    -(log_first + sum.ln()) / std::f64::consts::LN_10
+}
+
+fn ln_factorial(n: f64) -> f64 {
+    if n < 20.0 {
+        (2..=n as u32).map(|x| (x as f64).ln()).sum()
+    } else {
+        // Stirling series
+        n * n.ln() - n + 0.5 * (2.0 * std::f64::consts::PI * n).ln() + 1.0 / (12.0 * n)
+    }
+}
+
+/// Merge overlapping or book-ended intervals
+fn merge_intervals(mut intervals: Vec<(u32, u32)>) -> Vec<(u32, u32)> {
+    intervals.sort_unstable();
+    let mut merged: Vec<(u32, u32)> = Vec::with_capacity(intervals.len());
+    for (start, end) in intervals {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
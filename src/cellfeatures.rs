@@ -0,0 +1,207 @@
+use std::{
+    io,
+    fs::File,
+    path::Path,
+    error::Error,
+    io::Write,
+};
+use log::{info, warn};
+use rust_lapper::{Interval, Lapper};
+use rustc_hash::FxHashMap;
+use crate::bed::{read_regions, region_trees};
+use crate::cells::cells_from_matches;
+use crate::fragments::{for_each_fragment_with_progress, ReadOptions};
+use crate::progress::Progress;
+use crate::qc::{DINUCLEOSOME, MONONUCLEOSOME, NUCLEOSOME_FREE};
+
+/// Insertions within this distance of a TSS are in the center of the TSS enrichment score
+const TSS_CENTER: u32 = 50;
+
+/// Insertions this far from a TSS (up to `TSS_FLANK_END`) are the background of the TSS enrichment score
+const TSS_FLANK_START: u32 = 1900;
+const TSS_FLANK_END: u32 = 2000;
+
+/// Fragment summary of one cell
+#[derive(Clone, Default)]
+struct CellFeatures {
+    fragments: u64,
+    /// fragments that are nucleosome-free, and span one, two, or more nucleosomes
+    sizes: [u64; 4],
+    /// fragments with an insertion in a peak
+    peak_fragments: u64,
+    tss_center: u64,
+    tss_flank: u64,
+}
+
+/// Write a compact per-cell feature vector (fragment size and chromosome fractions, FRiP, and TSS
+/// enrichment) for QC clustering and outlier detection without a count matrix
+pub fn run(matches: &clap::ArgMatches, progress: &Progress) -> Result<(), Box<dyn Error>> {
+
+    let frag_file = Path::new(matches.get_one::<String>("fragments").unwrap());
+    info!("Received fragment file: {:?}", frag_file);
+    let read_options = ReadOptions::from_matches(matches)?;
+
+    let output = Path::new(matches.get_one::<String>("outfile").unwrap());
+    let min_fragments = *matches.get_one::<u64>("min_fragments").unwrap();
+
+    let peaks = match matches.get_one::<String>("peaks") {
+        Some(path) => {
+            info!("Received peak file: {:?}", path);
+            Some(region_trees(Path::new(path))?.1)
+        }
+        None => None,
+    };
+    let tss = match matches.get_one::<String>("tss") {
+        Some(path) => {
+            info!("Received TSS file: {:?}", path);
+            Some(tss_windows(Path::new(path))?)
+        }
+        None => None,
+    };
+
+    // listed cells in order, or every barcode in order of first appearance
+    let listed = cells_from_matches(matches)?;
+    let mut barcodes: Vec<String> = listed.clone().unwrap_or_default();
+    let mut index: FxHashMap<String, usize> = barcodes.iter().enumerate().map(|(i, x)| (x.clone(), i)).collect();
+    let mut cells: Vec<CellFeatures> = vec![CellFeatures::default(); barcodes.len()];
+
+    let mut chroms: Vec<String> = Vec::new();
+    // fragments of each cell on each chromosome
+    let mut cell_chroms: FxHashMap<(usize, usize), u64> = FxHashMap::default();
+    let mut current_chrom: Option<usize> = None;
+    let mut current_peaks: Option<&Lapper<u32, usize>> = None;
+    let mut current_tss: Option<&Lapper<u32, u32>> = None;
+
+    for_each_fragment_with_progress(frag_file, &read_options, progress, |fragment| {
+        let cell = match index.get(fragment.barcode) {
+            Some(cell) => *cell,
+            None if listed.is_some() => return Ok(()),
+            None => {
+                index.insert(fragment.barcode.to_string(), barcodes.len());
+                barcodes.push(fragment.barcode.to_string());
+                cells.push(CellFeatures::default());
+                barcodes.len() - 1
+            }
+        };
+        if current_chrom.is_none_or(|chrom| chroms[chrom] != fragment.chrom) {
+            let chrom = match chroms.iter().position(|x| x == fragment.chrom) {
+                Some(chrom) => chrom,
+                None => {
+                    chroms.push(fragment.chrom.to_string());
+                    chroms.len() - 1
+                }
+            };
+            current_chrom = Some(chrom);
+            current_peaks = peaks.as_ref().and_then(|peaks| peaks.get(fragment.chrom));
+            current_tss = tss.as_ref().and_then(|tss| tss.get(fragment.chrom));
+        }
+        *cell_chroms.entry((cell, current_chrom.unwrap())).or_insert(0) += 1;
+
+        let features = &mut cells[cell];
+        features.fragments += 1;
+        let size = fragment.end.saturating_sub(fragment.start);
+        let class = if size < NUCLEOSOME_FREE {
+            0
+        } else if size < MONONUCLEOSOME {
+            1
+        } else if size < DINUCLEOSOME {
+            2
+        } else {
+            3
+        };
+        features.sizes[class] += 1;
+        if let Some(lapper) = current_peaks {
+            let in_peak = |pos: u32| lapper.find(pos, pos + 1).next().is_some();
+            if in_peak(fragment.start) || in_peak(fragment.end) {
+                features.peak_fragments += 1;
+            }
+        }
+        if let Some(lapper) = current_tss {
+            for pos in [fragment.start, fragment.end] {
+                for window in lapper.find(pos, pos + 1) {
+                    match pos.abs_diff(window.val) {
+                        distance if distance <= TSS_CENTER => features.tss_center += 1,
+                        distance if distance >= TSS_FLANK_START => features.tss_flank += 1,
+                        _ => {}
+                    }
+                }
+            }
+        }
+        Ok(())
+    })?;
+
+    let empty = cells.iter().filter(|x| x.fragments == 0).count();
+    if listed.is_some() && empty > 0 {
+        warn!("{} cells have no fragments", empty);
+    }
+
+    info!("Writing cell features: {:?}", output);
+    let mut writer = io::BufWriter::new(File::create(output)?);
+    write!(
+        writer,
+        "barcode\tfragments\tnucleosome_free_fraction\tmononucleosome_fraction\tdinucleosome_fraction\tmultinucleosome_fraction",
+    )?;
+    if peaks.is_some() {
+        write!(writer, "\tfrip")?;
+    }
+    if tss.is_some() {
+        write!(writer, "\ttss_enrichment")?;
+    }
+    for chrom in &chroms {
+        write!(writer, "\t{}_fraction", chrom)?;
+    }
+    writeln!(writer)?;
+
+    let mut written: usize = 0;
+    for (cell, (barcode, features)) in barcodes.iter().zip(cells.iter()).enumerate() {
+        // every listed cell is written, other barcodes only with enough fragments
+        if listed.is_none() && features.fragments < min_fragments {
+            continue;
+        }
+        let fraction = |x: u64| x as f64 / features.fragments.max(1) as f64;
+        write!(writer, "{}\t{}", barcode, features.fragments)?;
+        for count in features.sizes {
+            write!(writer, "\t{:.4}", fraction(count))?;
+        }
+        if peaks.is_some() {
+            write!(writer, "\t{:.4}", fraction(features.peak_fragments))?;
+        }
+        if tss.is_some() {
+            write!(writer, "\t{:.4}", tss_enrichment(features))?;
+        }
+        for chrom in 0..chroms.len() {
+            write!(writer, "\t{:.4}", fraction(*cell_chroms.get(&(cell, chrom)).unwrap_or(&0)))?;
+        }
+        writeln!(writer)?;
+        written += 1;
+    }
+    writer.flush()?;
+    info!("Wrote features for {} cells", written);
+
+    Ok(())
+}
+
+/// Windows of `TSS_FLANK_END` bp on each side of each TSS, with the TSS position as the value.
+/// The TSS is the region start, or the end for minus-strand regions.
+fn tss_windows(path: &Path) -> io::Result<FxHashMap<String, Lapper<u32, u32>>> {
+    let mut intervals: FxHashMap<String, Vec<Interval<u32, u32>>> = FxHashMap::default();
+    for region in read_regions(path)? {
+        let tss = if region.strand == '-' { region.end.saturating_sub(1) } else { region.start };
+        intervals.entry(region.chrom).or_default().push(Interval {
+            start: tss.saturating_sub(TSS_FLANK_END),
+            stop: tss + TSS_FLANK_END + 1,
+            val: tss,
+        });
+    }
+    Ok(intervals
+        .into_iter()
+        .map(|(chrom, intervals)| (chrom, Lapper::new(intervals)))
+        .collect())
+}
+
+/// Insertions per bp at the TSS center over insertions per bp in the flanks, with a pseudocount of one
+fn tss_enrichment(features: &CellFeatures) -> f64 {
+    let center_bp = (2 * TSS_CENTER + 1) as f64;
+    let flank_bp = (2 * (TSS_FLANK_END - TSS_FLANK_START + 1)) as f64;
+    ((features.tss_center + 1) as f64 / center_bp) / ((features.tss_flank + 1) as f64 / flank_bp)
+}
@@ -0,0 +1,375 @@
+use std::{
+    io,
+    fs::File,
+    path::Path,
+    io::Write,
+};
+use log::{info, warn};
+use memmap2::Mmap;
+use rustc_hash::FxHashMap;
+
+const MAGIC: &[u8; 8] = b"FTKCELL1";
+const HEADER_LEN: usize = 24;
+
+/// Barcode -> column index lookup for the cells being counted
+pub enum CellIndex {
+    /// barcodes read from a text file
+    Table {
+        index: FxHashMap<String, u32>,
+        barcodes: Vec<String>,
+    },
+    /// serialized index, memory-mapped and queried in place
+    Mapped(MappedCells),
+    /// every barcode counted in a single column of this name, for bulk counts
+    All(String),
+}
+
+impl CellIndex {
+    /// Index barcodes in order.
+    /// Repeated barcodes are skipped so that indices match the written barcodes.
+    pub fn from_barcodes(barcodes: Vec<String>) -> CellIndex {
+        let mut index: FxHashMap<String, u32> = FxHashMap::default();
+        let mut unique: Vec<String> = Vec::with_capacity(barcodes.len());
+        for barcode in barcodes {
+            if index.contains_key(&barcode) {
+                warn!("Cell barcode {} is repeated, skipping", barcode);
+                continue;
+            }
+            index.insert(barcode.clone(), unique.len() as u32);
+            unique.push(barcode);
+        }
+        CellIndex::Table { index, barcodes: unique }
+    }
+
+    /// Memory-map an index written by `save`
+    pub fn open(path: &Path) -> io::Result<CellIndex> {
+        let file = File::open(path)?;
+        // SAFETY: the index file is not expected to be modified while it is mapped
+        let mmap = unsafe { Mmap::map(&file)? };
+        Ok(CellIndex::Mapped(MappedCells::new(mmap)?))
+    }
+
+    pub fn get(&self, barcode: &str) -> Option<u32> {
+        match self {
+            CellIndex::Table { index, .. } => index.get(barcode).copied(),
+            CellIndex::Mapped(mapped) => mapped.get(barcode),
+            CellIndex::All(_) => Some(0),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            CellIndex::Table { barcodes, .. } => barcodes.len(),
+            CellIndex::Mapped(mapped) => mapped.n_cells,
+            CellIndex::All(_) => 1,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Barcode of the cell at `index`
+    pub fn barcode(&self, index: usize) -> &str {
+        match self {
+            CellIndex::Table { barcodes, .. } => &barcodes[index],
+            CellIndex::Mapped(mapped) => mapped.barcode(index),
+            CellIndex::All(name) => name,
+        }
+    }
+
+    /// Index of the cells of one sample in a merged matrix, looked up by barcode without the sample
+    /// suffix but keeping the merged column indices. Cells without the suffix are not indexed.
+    pub fn strip_suffix(&self, suffix: &str) -> CellIndex {
+        if let CellIndex::All(name) = self {
+            return CellIndex::All(name.clone());
+        }
+        let barcodes: Vec<String> = (0..self.len()).map(|i| self.barcode(i).to_string()).collect();
+        let index: FxHashMap<String, u32> = barcodes
+            .iter()
+            .enumerate()
+            .filter_map(|(i, barcode)| barcode.strip_suffix(suffix).map(|x| (x.to_string(), i as u32)))
+            .collect();
+        CellIndex::Table { index, barcodes }
+    }
+
+    /// Index of the same cells looked up by their ATAC barcodes, named by their paired GEX barcodes,
+    /// from a multiome translation table (ATAC -> GEX). Cells may be listed by either barcode;
+    /// cells missing from the table keep their barcode.
+    pub fn translate(&self, atac_to_gex: &FxHashMap<String, String>) -> CellIndex {
+        let gex_to_atac: FxHashMap<&str, &str> = atac_to_gex.iter().map(|(atac, gex)| (gex.as_str(), atac.as_str())).collect();
+        let mut index: FxHashMap<String, u32> = FxHashMap::default();
+        let mut barcodes: Vec<String> = Vec::with_capacity(self.len());
+        let mut missing: usize = 0;
+        for i in 0..self.len() {
+            let barcode = self.barcode(i);
+            let (atac, gex) = match (atac_to_gex.get(barcode), gex_to_atac.get(barcode)) {
+                (Some(gex), _) => (barcode, gex.as_str()),
+                (None, Some(atac)) => (*atac, barcode),
+                (None, None) => {
+                    missing += 1;
+                    (barcode, barcode)
+                }
+            };
+            index.insert(atac.to_string(), i as u32);
+            barcodes.push(gex.to_string());
+        }
+        if missing > 0 {
+            warn!("{} cell barcodes are not in the barcode translation table and are not renamed", missing);
+        }
+        CellIndex::Table { index, barcodes }
+    }
+
+    /// Index of the same cells, each counted in the column of its group (e.g. cluster) so that counts
+    /// are summed by group as they are counted. Columns are named by the groups. Cells are grouped by
+    /// their barcode (the paired GEX barcode of translated cells); cells without a group are not indexed.
+    pub fn group(&self, groups: &FxHashMap<String, usize>, names: Vec<String>) -> CellIndex {
+        let lookups: Vec<(String, u32)> = match self {
+            CellIndex::Table { index, .. } => index.iter().map(|(key, cell)| (key.clone(), *cell)).collect(),
+            CellIndex::Mapped(mapped) => (0..mapped.n_cells).map(|cell| (mapped.barcode(cell).to_string(), cell as u32)).collect(),
+            CellIndex::All(_) => Vec::new(),
+        };
+        let mut index: FxHashMap<String, u32> = FxHashMap::default();
+        for (key, cell) in lookups {
+            if let Some(group) = groups.get(self.barcode(cell as usize)) {
+                index.insert(key, *group as u32);
+            }
+        }
+        let ungrouped = self.len() - (0..self.len()).filter(|cell| groups.contains_key(self.barcode(*cell))).count();
+        if ungrouped > 0 {
+            warn!("{} cells are not in any group and are not counted", ungrouped);
+        }
+        CellIndex::Table { index, barcodes: names }
+    }
+
+    /// Write the barcodes in index order, one per line
+    pub fn write_barcodes(&self, path: &Path) -> io::Result<()> {
+        let mut writer = io::BufWriter::new(File::create(path)?);
+        for i in 0..self.len() {
+            writeln!(writer, "{}", self.barcode(i))?;
+        }
+        writer.flush()
+    }
+
+    /// Serialize the index as an open-addressing hash table that can be queried without rebuilding.
+    /// Layout (little-endian): magic, cell count, slot count, barcode offsets,
+    /// slots holding cell index + 1 (0 = empty), and the concatenated barcodes.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let n_cells = self.len();
+        let n_slots = (n_cells * 2).next_power_of_two().max(2);
+
+        let mut slots: Vec<u32> = vec![0; n_slots];
+        let mut offsets: Vec<u64> = Vec::with_capacity(n_cells + 1);
+        let mut blob: Vec<u8> = Vec::new();
+        offsets.push(0);
+        for i in 0..n_cells {
+            let barcode = self.barcode(i);
+            let mut slot = (hash(barcode.as_bytes()) as usize) & (n_slots - 1);
+            while slots[slot] != 0 {
+                slot = (slot + 1) & (n_slots - 1);
+            }
+            slots[slot] = i as u32 + 1;
+            blob.extend_from_slice(barcode.as_bytes());
+            offsets.push(blob.len() as u64);
+        }
+
+        let mut writer = io::BufWriter::new(File::create(path)?);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&(n_cells as u64).to_le_bytes())?;
+        writer.write_all(&(n_slots as u64).to_le_bytes())?;
+        for offset in &offsets {
+            writer.write_all(&offset.to_le_bytes())?;
+        }
+        for slot in &slots {
+            writer.write_all(&slot.to_le_bytes())?;
+        }
+        writer.write_all(&blob)?;
+        writer.flush()?;
+
+        info!("Saved index of {} cells: {:?}", n_cells, path);
+        Ok(())
+    }
+}
+
+/// Read-only view of a serialized cell index
+pub struct MappedCells {
+    mmap: Mmap,
+    n_cells: usize,
+    n_slots: usize,
+    slots_start: usize,
+    blob_start: usize,
+}
+
+impl MappedCells {
+    fn new(mmap: Mmap) -> io::Result<MappedCells> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid cell index: {}", msg));
+        if mmap.len() < HEADER_LEN || &mmap[..8] != MAGIC {
+            return Err(invalid("missing header"));
+        }
+        let n_cells = usize::try_from(read_u64(&mmap, 8)).map_err(|_| invalid("bad cell count"))?;
+        let n_slots = usize::try_from(read_u64(&mmap, 16)).map_err(|_| invalid("bad slot count"))?;
+        if !n_slots.is_power_of_two() || n_slots <= n_cells {
+            return Err(invalid("bad slot count"));
+        }
+        let slots_start = n_cells
+            .checked_add(1)
+            .and_then(|n| n.checked_mul(8))
+            .and_then(|n| n.checked_add(HEADER_LEN))
+            .ok_or_else(|| invalid("bad cell count"))?;
+        let blob_start = n_slots
+            .checked_mul(4)
+            .and_then(|n| n.checked_add(slots_start))
+            .filter(|start| *start <= mmap.len())
+            .ok_or_else(|| invalid("truncated file"))?;
+        let blob_len = (mmap.len() - blob_start) as u64;
+
+        // barcode offsets start at 0, never decrease, and end at the end of the file
+        let mut previous = 0;
+        for index in 0..=n_cells {
+            let offset = read_u64(&mmap, HEADER_LEN + 8 * index);
+            if (index == 0 && offset != 0) || offset < previous || offset > blob_len {
+                return Err(invalid("bad barcode offsets"));
+            }
+            previous = offset;
+        }
+        if previous != blob_len {
+            return Err(invalid("truncated file"));
+        }
+        // slots hold a cell index + 1 and leave at least one empty slot, which ends every lookup
+        let mut filled = 0;
+        for slot in 0..n_slots {
+            let pos = slots_start + 4 * slot;
+            let value = u32::from_le_bytes(mmap[pos..pos + 4].try_into().unwrap()) as usize;
+            if value > n_cells {
+                return Err(invalid("bad slot"));
+            }
+            filled += (value != 0) as usize;
+        }
+        if filled != n_cells {
+            return Err(invalid("bad slot"));
+        }
+        Ok(MappedCells { mmap, n_cells, n_slots, slots_start, blob_start })
+    }
+
+    fn barcode_bytes(&self, index: usize) -> &[u8] {
+        let start = read_u64(&self.mmap, HEADER_LEN + 8 * index) as usize;
+        let end = read_u64(&self.mmap, HEADER_LEN + 8 * (index + 1)) as usize;
+        &self.mmap[self.blob_start + start..self.blob_start + end]
+    }
+
+    fn barcode(&self, index: usize) -> &str {
+        std::str::from_utf8(self.barcode_bytes(index)).unwrap_or("")
+    }
+
+    fn get(&self, barcode: &str) -> Option<u32> {
+        let mut slot = (hash(barcode.as_bytes()) as usize) & (self.n_slots - 1);
+        loop {
+            let pos = self.slots_start + 4 * slot;
+            let value = u32::from_le_bytes(self.mmap[pos..pos + 4].try_into().unwrap());
+            if value == 0 {
+                return None;
+            }
+            if self.barcode_bytes(value as usize - 1) == barcode.as_bytes() {
+                return Some(value - 1);
+            }
+            slot = (slot + 1) & (self.n_slots - 1);
+        }
+    }
+}
+
+fn read_u64(bytes: &[u8], pos: usize) -> u64 {
+    u64::from_le_bytes(bytes[pos..pos + 8].try_into().unwrap())
+}
+
+/// FNV-1a, fixed so that saved indexes stay valid across builds
+fn hash(bytes: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for &b in bytes {
+        h ^= b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn saved(name: &str, cells: &CellIndex) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("fragtk_{}_{}.idx", name, std::process::id()));
+        cells.save(&path).unwrap();
+        path
+    }
+
+    #[test]
+    fn saved_index_round_trips() {
+        let barcodes: Vec<String> = (0..100).map(|i| format!("CELL{}-1", i)).collect();
+        let cells = CellIndex::from_barcodes(barcodes.clone());
+        let path = saved("round_trip", &cells);
+        let mapped = CellIndex::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(mapped.len(), barcodes.len());
+        for (i, barcode) in barcodes.iter().enumerate() {
+            assert_eq!(mapped.barcode(i), barcode);
+            assert_eq!(mapped.get(barcode), Some(i as u32));
+        }
+        assert_eq!(mapped.get("CELL100-1"), None);
+        assert_eq!(mapped.get(""), None);
+    }
+
+    #[test]
+    fn empty_index_round_trips() {
+        let path = saved("empty", &CellIndex::from_barcodes(Vec::new()));
+        let mapped = CellIndex::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(mapped.is_empty());
+        assert_eq!(mapped.get("CELL0-1"), None);
+    }
+
+    #[test]
+    fn corrupt_index_is_rejected() {
+        let cells = CellIndex::from_barcodes(vec!["AAAC-1".to_string(), "AAAG-1".to_string(), "AAAT-1".to_string()]);
+        let path = saved("corrupt", &cells);
+        let bytes = std::fs::read(&path).unwrap();
+        let n_slots = 8;
+        let slots_start = HEADER_LEN + 8 * 4;
+        let write_u64 = |bytes: &mut Vec<u8>, pos: usize, value: u64| bytes[pos..pos + 8].copy_from_slice(&value.to_le_bytes());
+
+        let mut corrupt: Vec<Vec<u8>> = Vec::new();
+        // offset past the end of the barcodes
+        let mut x = bytes.clone();
+        write_u64(&mut x, HEADER_LEN + 8, 1000);
+        corrupt.push(x);
+        // decreasing offsets
+        let mut x = bytes.clone();
+        write_u64(&mut x, HEADER_LEN + 16, 2);
+        corrupt.push(x);
+        // cell count that overflows the section sizes
+        let mut x = bytes.clone();
+        write_u64(&mut x, 8, u64::MAX / 4);
+        write_u64(&mut x, 16, 1 << 62);
+        corrupt.push(x);
+        // slot pointing past the last cell
+        let mut x = bytes.clone();
+        let slot = (0..n_slots).map(|i| slots_start + 4 * i).find(|pos| x[*pos..*pos + 4] == [0; 4]).unwrap();
+        x[slot..slot + 4].copy_from_slice(&9u32.to_le_bytes());
+        corrupt.push(x);
+        // every slot filled, so a missing barcode would never be found
+        let mut x = bytes.clone();
+        for i in 0..n_slots {
+            let pos = slots_start + 4 * i;
+            if x[pos..pos + 4] == [0; 4] {
+                x[pos..pos + 4].copy_from_slice(&1u32.to_le_bytes());
+            }
+        }
+        corrupt.push(x);
+
+        for (i, x) in corrupt.iter().enumerate() {
+            std::fs::write(&path, x).unwrap();
+            let error = CellIndex::open(&path).err().unwrap_or_else(|| panic!("corrupt index {} was accepted", i));
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "corrupt index {}", i);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,422 @@
+use std::{
+    io,
+    fs::File,
+    path::{Path, PathBuf},
+    io::BufRead,
+    io::Write,
+};
+use log::{info, warn};
+use rustc_hash::{FxHashMap, FxHashSet};
+use crate::matrix::open_text;
+
+/// How barcodes are combined when `--cells` is given more than once
+#[derive(Clone, Copy, Debug)]
+pub enum CellsOp {
+    Union,
+    Intersect,
+    /// barcodes in the first file but not in any of the others
+    Subtract,
+}
+
+impl CellsOp {
+    pub fn from_name(name: &str) -> Option<CellsOp> {
+        match name {
+            "union" => Some(CellsOp::Union),
+            "intersect" => Some(CellsOp::Intersect),
+            "subtract" => Some(CellsOp::Subtract),
+            _ => None,
+        }
+    }
+}
+
+/// A column of a cell or group table, by header name or 1-based index
+#[derive(Clone, Debug)]
+pub enum Column {
+    Name(String),
+    Index(usize),
+}
+
+impl Column {
+    pub fn parse(spec: &str) -> Option<Column> {
+        match spec.parse::<usize>() {
+            Ok(0) => None,
+            Ok(index) => Some(Column::Index(index - 1)),
+            Err(_) if !spec.is_empty() => Some(Column::Name(spec.to_string())),
+            Err(_) => None,
+        }
+    }
+}
+
+/// How cell and group tables are parsed, from `--delimiter`, `--cells-column`, and `--group-column`.
+/// Fields may be quoted, and a header row is read when a column is selected by name,
+/// so metadata tables such as a metadata.csv can be used directly.
+#[derive(Clone, Debug)]
+pub struct TableFormat {
+    /// field delimiter, or None to use commas for .csv files and tabs otherwise
+    pub delimiter: Option<u8>,
+    pub barcode_column: Column,
+    pub group_column: Column,
+}
+
+impl Default for TableFormat {
+    fn default() -> TableFormat {
+        TableFormat { delimiter: None, barcode_column: Column::Index(0), group_column: Column::Index(1) }
+    }
+}
+
+impl TableFormat {
+    pub fn from_matches(matches: &clap::ArgMatches) -> io::Result<TableFormat> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+        let mut format = TableFormat::default();
+        if let Some(delimiter) = matches.try_get_one::<String>("delimiter").ok().flatten() {
+            format.delimiter = Some(match delimiter.as_str() {
+                "tab" => b'\t',
+                "comma" => b',',
+                "semicolon" => b';',
+                "space" => b' ',
+                x if x.len() == 1 => x.as_bytes()[0],
+                x => return Err(invalid(format!("Invalid delimiter: {}", x))),
+            });
+        }
+        if let Some(column) = matches.try_get_one::<String>("cells_column").ok().flatten() {
+            format.barcode_column = Column::parse(column).ok_or_else(|| invalid(format!("Invalid column: {}", column)))?;
+        }
+        if let Some(column) = matches.try_get_one::<String>("group_column").ok().flatten() {
+            format.group_column = Column::parse(column).ok_or_else(|| invalid(format!("Invalid column: {}", column)))?;
+        }
+        Ok(format)
+    }
+
+    /// Read the given columns of each row, skipping blank lines, comment lines, and rows missing a column
+    fn read_columns(&self, path: &Path, columns: &[&Column]) -> io::Result<Vec<Vec<String>>> {
+        let delimiter = self.delimiter.unwrap_or_else(|| {
+            let name = path.to_string_lossy().to_lowercase();
+            if name.ends_with(".csv") || name.ends_with(".csv.gz") { b',' } else { b'\t' }
+        });
+        let has_header = columns.iter().any(|column| matches!(column, Column::Name(_)));
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(delimiter)
+            .has_headers(has_header)
+            .flexible(true)
+            .comment(Some(b'#'))
+            .trim(csv::Trim::All)
+            .from_reader(open_text(path)?);
+
+        let mut indices = Vec::with_capacity(columns.len());
+        for column in columns {
+            indices.push(match column {
+                Column::Index(index) => *index,
+                Column::Name(name) => reader.headers()?.iter().position(|x| x == name).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, format!("Column {} not found in the header of {:?}", name, path))
+                })?,
+            });
+        }
+
+        let mut rows = Vec::new();
+        let mut missing: usize = 0;
+        for record in reader.records() {
+            let record = record?;
+            if record.iter().all(str::is_empty) {
+                continue;
+            }
+            match indices.iter().map(|i| record.get(*i).filter(|x| !x.is_empty())).collect::<Option<Vec<&str>>>() {
+                Some(fields) => rows.push(fields.into_iter().map(str::to_string).collect()),
+                None => missing += 1,
+            }
+        }
+        if missing > 0 {
+            warn!("{} rows of {:?} are missing a selected column and were skipped", missing, path);
+        }
+        Ok(rows)
+    }
+}
+
+/// Read a file of cell barcodes in file order: one per line, or a column of a delimited table
+pub fn read_barcodes(path: &Path, format: &TableFormat) -> io::Result<Vec<String>> {
+    let barcodes: Vec<String> = format
+        .read_columns(path, &[&format.barcode_column])?
+        .into_iter()
+        .map(|mut row| row.swap_remove(0))
+        .collect();
+    if barcodes.is_empty() {
+        warn!("No cell barcodes in {:?}", path);
+    }
+    Ok(barcodes)
+}
+
+/// Read a multiome barcode translation table of ATAC barcode and paired GEX barcode,
+/// tab- or comma-separated. A header line is skipped.
+pub fn read_barcode_map(path: &Path) -> io::Result<FxHashMap<String, String>> {
+    let mut map: FxHashMap<String, String> = FxHashMap::default();
+    for (index, line) in open_text(path)?.lines().enumerate() {
+        let line = line?;
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let separator = if line.contains('\t') { '\t' } else { ',' };
+        let fields: Vec<&str> = line.split(separator).map(str::trim).collect();
+        if fields.len() < 2 {
+            warn!("Line {}: Expected ATAC and GEX barcodes", index + 1);
+            continue;
+        }
+        if index == 0 && fields[0].eq_ignore_ascii_case("atac") {
+            continue;
+        }
+        map.insert(fields[0].to_string(), fields[1].to_string());
+    }
+    if map.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("No barcode pairs in {:?}", path)));
+    }
+    info!("Loaded {} ATAC to GEX barcode pairs", map.len());
+    Ok(map)
+}
+
+/// Combine barcode lists. The union keeps barcodes in order of first appearance;
+/// intersect and subtract keep the order of the first list.
+pub fn combine_barcodes(mut lists: Vec<Vec<String>>, op: CellsOp) -> Vec<String> {
+    if lists.len() == 1 {
+        return lists.pop().unwrap();
+    }
+    let rest = lists.split_off(1);
+    let first = lists.pop().unwrap_or_default();
+    match op {
+        CellsOp::Union => {
+            let mut seen: FxHashSet<String> = FxHashSet::default();
+            std::iter::once(first)
+                .chain(rest)
+                .flatten()
+                .filter(|barcode| seen.insert(barcode.clone()))
+                .collect()
+        }
+        CellsOp::Intersect => {
+            let others: Vec<FxHashSet<String>> = rest.into_iter().map(|x| x.into_iter().collect()).collect();
+            first.into_iter().filter(|barcode| others.iter().all(|x| x.contains(barcode))).collect()
+        }
+        CellsOp::Subtract => {
+            let others: FxHashSet<String> = rest.into_iter().flatten().collect();
+            first.into_iter().filter(|barcode| !others.contains(barcode)).collect()
+        }
+    }
+}
+
+/// Cell barcodes from one or more `--cells` files, combined with `--cells-op`,
+/// or None if no cell file was given
+pub fn cells_from_matches(matches: &clap::ArgMatches) -> io::Result<Option<Vec<String>>> {
+    let files: Vec<&String> = match matches.get_many::<String>("cells") {
+        Some(files) => files.collect(),
+        None => return Ok(None),
+    };
+    let op = CellsOp::from_name(matches.get_one::<String>("cells_op").unwrap()).unwrap();
+    let format = TableFormat::from_matches(matches)?;
+
+    let mut lists = Vec::with_capacity(files.len());
+    for file in &files {
+        info!("Received cell file: {:?}", file);
+        lists.push(read_barcodes(Path::new(file), &format)?);
+    }
+    let barcodes = combine_barcodes(lists, op);
+    if files.len() > 1 {
+        info!("Combined {} cell files ({:?}): {} cells", files.len(), op, barcodes.len());
+    }
+    if barcodes.is_empty() {
+        warn!("No cell barcodes selected");
+    }
+    Ok(Some(barcodes))
+}
+
+/// Barcode set from the `--cells` arguments, or None to include all cells
+pub fn load_cells(matches: &clap::ArgMatches) -> io::Result<Option<FxHashSet<String>>> {
+    Ok(cells_from_matches(matches)?.map(|barcodes| barcodes.into_iter().collect()))
+}
+
+/// What to do with a barcode listed under more than one group, or grouped but missing from `--cells`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    /// stop with an error naming the barcode
+    Error,
+    /// leave the barcode out of every group, with a warning
+    WarnSkip,
+    /// use the last group listed for the barcode; barcodes missing from `--cells` are skipped
+    LastWins,
+}
+
+impl DuplicatePolicy {
+    pub fn from_name(name: &str) -> Option<DuplicatePolicy> {
+        match name {
+            "error" => Some(DuplicatePolicy::Error),
+            "warn-skip" => Some(DuplicatePolicy::WarnSkip),
+            "last-wins" => Some(DuplicatePolicy::LastWins),
+            _ => None,
+        }
+    }
+}
+
+/// How a group table is reconciled with itself and the cell list, from `--duplicate-barcodes`
+/// and `--reconcile-report`
+#[derive(Clone, Debug)]
+pub struct Reconcile {
+    pub policy: DuplicatePolicy,
+    /// TSV of each barcode that needed reconciling and what was done with it
+    pub report: Option<PathBuf>,
+}
+
+impl Default for Reconcile {
+    fn default() -> Reconcile {
+        Reconcile { policy: DuplicatePolicy::Error, report: None }
+    }
+}
+
+impl Reconcile {
+    pub fn from_matches(matches: &clap::ArgMatches) -> Reconcile {
+        let policy = matches
+            .try_get_one::<String>("duplicate_barcodes")
+            .ok()
+            .flatten()
+            .map_or(DuplicatePolicy::Error, |name| DuplicatePolicy::from_name(name).unwrap());
+        let report = matches.try_get_one::<String>("reconcile_report").ok().flatten().map(PathBuf::from);
+        Reconcile { policy, report }
+    }
+}
+
+/// Read a barcode -> group table, by default tab-separated barcode and group columns.
+/// Returns group names in order of first appearance and the group index of each barcode.
+/// Barcodes listed under different groups, and barcodes missing from `cells` if given, are
+/// handled by the reconcile policy; listing a barcode twice under the same group is harmless.
+pub fn load_groups(
+    path: &Path,
+    format: &TableFormat,
+    reconcile: &Reconcile,
+    cells: Option<&FxHashSet<String>>,
+) -> io::Result<(Vec<String>, FxHashMap<String, usize>)> {
+    let mut group_names: Vec<String> = Vec::new();
+    let mut group_index: FxHashMap<String, usize> = FxHashMap::default();
+    let mut cell_groups: FxHashMap<String, usize> = FxHashMap::default();
+    // barcodes in order of first appearance, and every group listed for conflicting barcodes
+    let mut order: Vec<String> = Vec::new();
+    let mut conflicts: FxHashMap<String, Vec<usize>> = FxHashMap::default();
+
+    for row in format.read_columns(path, &[&format.barcode_column, &format.group_column])? {
+        let (barcode, group) = (&row[0], &row[1]);
+        let idx = *group_index.entry(group.to_string()).or_insert_with(|| {
+            group_names.push(group.to_string());
+            group_names.len() - 1
+        });
+        match cell_groups.insert(barcode.to_string(), idx) {
+            None => order.push(barcode.to_string()),
+            Some(previous) if previous != idx => {
+                conflicts.entry(barcode.to_string()).or_insert_with(|| vec![previous]).push(idx);
+            }
+            Some(_) => {}
+        }
+    }
+
+    // barcode, issue, groups, and action for each barcode in the report
+    let mut issues: Vec<(String, &str, String, &str)> = Vec::new();
+    let policy = reconcile.policy;
+    let mut n_conflicts: usize = 0;
+    let mut n_missing: usize = 0;
+    for barcode in &order {
+        if let Some(groups) = conflicts.get(barcode) {
+            n_conflicts += 1;
+            let names: Vec<&str> = groups.iter().map(|x| group_names[*x].as_str()).collect();
+            let action = match policy {
+                DuplicatePolicy::Error => "error",
+                DuplicatePolicy::WarnSkip => "skipped",
+                DuplicatePolicy::LastWins => "last_group",
+            };
+            issues.push((barcode.clone(), "conflicting_groups", names.join(","), action));
+        }
+        if cells.is_some_and(|cells| !cells.contains(barcode)) {
+            n_missing += 1;
+            let action = if policy == DuplicatePolicy::Error { "error" } else { "skipped" };
+            issues.push((barcode.clone(), "not_in_cells", group_names[cell_groups[barcode]].clone(), action));
+        }
+    }
+    let mut n_ungrouped: usize = 0;
+    if let Some(cells) = cells {
+        let mut ungrouped: Vec<&String> = cells.iter().filter(|barcode| !cell_groups.contains_key(*barcode)).collect();
+        ungrouped.sort_unstable();
+        n_ungrouped = ungrouped.len();
+        issues.extend(ungrouped.into_iter().map(|barcode| (barcode.clone(), "not_in_groups", "NA".to_string(), "excluded")));
+    }
+
+    if let Some(report) = &reconcile.report {
+        info!("Writing barcode reconciliation report: {:?}", report);
+        let mut writer = io::BufWriter::new(File::create(report)?);
+        writeln!(writer, "barcode\tissue\tgroups\taction")?;
+        for (barcode, issue, groups, action) in &issues {
+            writeln!(writer, "{}\t{}\t{}\t{}", barcode, issue, groups, action)?;
+        }
+        writer.flush()?;
+    }
+
+    if policy == DuplicatePolicy::Error {
+        if let Some((barcode, issue, groups, _)) = issues.iter().find(|issue| issue.3 == "error") {
+            let problem = match *issue {
+                "conflicting_groups" => format!("Barcode {} is listed under groups {}", barcode, groups),
+                _ => format!("Barcode {} (group {}) is not in the cell list", barcode, groups),
+            };
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} in {:?} ({} conflicting, {} not in the cell list); use --duplicate-barcodes warn-skip or last-wins, \
+                     and --reconcile-report to list them",
+                    problem, path, n_conflicts, n_missing,
+                ),
+            ));
+        }
+    }
+    for (barcode, _, _, action) in &issues {
+        if *action == "skipped" {
+            cell_groups.remove(barcode);
+        }
+    }
+    if n_conflicts > 0 {
+        match policy {
+            DuplicatePolicy::WarnSkip => warn!("{} barcodes listed under more than one group were skipped", n_conflicts),
+            _ => warn!("{} barcodes listed under more than one group were assigned to their last group", n_conflicts),
+        }
+    }
+    if n_missing > 0 {
+        warn!("{} grouped barcodes not in the cell list were skipped", n_missing);
+    }
+    if n_ungrouped > 0 {
+        info!("{} cells are not in any group and are excluded", n_ungrouped);
+    }
+
+    Ok((group_names, cell_groups))
+}
+
+/// Assigns cell barcodes to output groups from the `--groups` and `--cells` arguments; with both,
+/// only grouped barcodes in the cell list are included. Without either, all barcodes belong to a single group.
+pub struct CellGroups {
+    pub names: Vec<String>,
+    groups: Option<FxHashMap<String, usize>>,
+    cells: Option<FxHashSet<String>>,
+}
+
+impl CellGroups {
+    pub fn from_matches(matches: &clap::ArgMatches, default_name: &str) -> io::Result<CellGroups> {
+        let cells = load_cells(matches)?;
+        if let Some(group_file) = matches.get_one::<String>("groups") {
+            let (names, groups) = load_groups(
+                Path::new(group_file), &TableFormat::from_matches(matches)?, &Reconcile::from_matches(matches), cells.as_ref(),
+            )?;
+            return Ok(CellGroups { names, groups: Some(groups), cells: None });
+        }
+        Ok(CellGroups { names: vec![default_name.to_string()], groups: None, cells })
+    }
+
+    pub fn is_grouped(&self) -> bool {
+        self.groups.is_some()
+    }
+
+    /// Group index for a barcode, or None if the cell is not included
+    pub fn get(&self, barcode: &str) -> Option<usize> {
+        match (&self.groups, &self.cells) {
+            (Some(groups), _) => groups.get(barcode).copied(),
+            (None, Some(cells)) => cells.contains(barcode).then_some(0),
+            (None, None) => Some(0),
+        }
+    }
+}
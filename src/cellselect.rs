@@ -2,21 +2,19 @@ use std::io;
 use std::error::Error;
 use std::path::Path;
 use std::fs::File;
-use std::io::BufReader;
-use std::io::BufRead;
 use std::io::Write;
-use std::thread;
-use std::sync::mpsc;
-use flate2::read::MultiGzDecoder;
 use rustc_hash::FxHashMap;
 use log::info;
+use crate::fragments::{parse_fragment, resolve_path, LineStream, ReadOptions};
+use crate::output::PartialOutput;
+use crate::progress::Progress;
+use crate::stats::StatsCollector;
 
-pub fn cellselect(matches: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+pub fn cellselect(matches: &clap::ArgMatches, progress: &Progress) -> Result<(), Box<dyn Error>> {
 
-    let frag_file = Path::new(matches.get_one::<String>("fragments").unwrap())
-        .canonicalize()
-        .expect("Can't find path to input fragment file");
+    let frag_file = resolve_path(matches.get_one::<String>("fragments").unwrap())?;
     info!("Received fragment file: {:?}", frag_file);
+    let read_options = ReadOptions::from_matches(matches)?;
 
     let output_file = matches.get_one::<String>("outfile").unwrap();
     info!("Output file: {:?}", output_file);
@@ -31,17 +29,21 @@ pub fn cellselect(matches: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
         });
     info!("Cell count cutoff: {:?}", threshold);
 
-    let bc_count = count_barcodes(&frag_file)?;
+    let mut stats = StatsCollector::default();
+    let bc_count = count_barcodes(&frag_file, &mut stats, &read_options, progress)?;
+    stats.finish(bc_count.len() as u64).try_save(&frag_file);
     let selected = select_barcodes(&bc_count, &threshold)?;
 
     // Output results to the specified file
-    let mut writer = File::create(output_file)?;
+    let mut partial = PartialOutput::new();
+    let mut writer = File::create(partial.file(output_file.into()))?;
     let mut output = String::new();
 
     for (barcode, count) in &bc_count {
         output.push_str(&format!("{}\t{}\n", barcode, count));
     }
     writer.write_all(output.as_bytes())?;
+    partial.finish();
 
     // print selected cells to stdout
     for cell in selected {
@@ -67,31 +69,18 @@ fn select_barcodes(
     Ok(filtered_cells)
 }
 
-fn count_barcodes(frag_file: &Path,) -> io::Result<FxHashMap<String, usize>> {
+fn count_barcodes(frag_file: &Path, stats: &mut StatsCollector, read_options: &ReadOptions, progress: &Progress) -> io::Result<FxHashMap<String, usize>> {
 
     // hashmap for cell barcode counts
     let mut cells: FxHashMap<String, usize> = FxHashMap::default();
 
-    // Create a channel for communication between the decompression and processing threads
-    let (tx, rx) = mpsc::sync_channel(500);
-
-    // Spawn the decompression thread
-    let frag_file = frag_file.to_path_buf();
-    let decompress_handle = thread::spawn(move || {
-        let reader = BufReader::new(MultiGzDecoder::new(File::open(frag_file).expect("Failed to open fragment file")));
-        for line in reader.lines() {
-            let line = line.expect("Failed to read line");
-            if tx.send(line).is_err() {
-                break;
-            }
-        }
-    });
+    // lines are decompressed on a separate thread
+    let lines = LineStream::spawn(frag_file, read_options);
 
-    // Progress counter
     let mut line_count: u64 = 0;
-    let update_interval = 1_000_000;
 
-    for line in rx {
+    for line in lines {
+        let line = line?;
 
         // Skip header lines that start with #
         if line.starts_with('#') {
@@ -99,9 +88,10 @@ fn count_barcodes(frag_file: &Path,) -> io::Result<FxHashMap<String, usize>> {
         }
 
         line_count += 1;
-        if line_count % update_interval == 0 {
-            eprint!("\rProcessed {} M fragments", line_count / 1_000_000 );
-            std::io::stdout().flush().expect("Can't flush output");
+        progress.update(line_count, None)?;
+
+        if let Some(fragment) = parse_fragment(&line) {
+            stats.add(&fragment);
         }
 
         // parse bed entry
@@ -113,10 +103,7 @@ fn count_barcodes(frag_file: &Path,) -> io::Result<FxHashMap<String, usize>> {
             *cells.entry(cell_barcode).or_insert(0) += 1;
         }
     }
-    eprintln!();
-
-    // Join thread to ensure it completes
-    decompress_handle.join().expect("Failed to join decompression thread");
+    progress.finish(line_count, None);
 
     Ok(cells)
 }
\ No newline at end of file
@@ -0,0 +1,296 @@
+use std::{
+    fs,
+    path::Path,
+    error::Error,
+};
+use log::info;
+use crate::matrix::{self, Columns, MtxColumns, SparseMatrix, SpilledMatrix};
+use crate::progress::Progress;
+use crate::threads::ThreadConfig;
+
+/// Entries held in memory at once when converting, as one batch of columns
+const BATCH_ENTRIES: u64 = 1 << 24;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MatrixFormat {
+    Mtx,
+    TenxH5,
+    H5ad,
+    Loom,
+}
+
+impl MatrixFormat {
+    pub fn from_name(name: &str) -> Option<MatrixFormat> {
+        match name {
+            "mtx" => Some(MatrixFormat::Mtx),
+            "h5" => Some(MatrixFormat::TenxH5),
+            "h5ad" => Some(MatrixFormat::H5ad),
+            "loom" => Some(MatrixFormat::Loom),
+            _ => None,
+        }
+    }
+
+    /// File name of the matrix in a fragtk matrix output directory
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            MatrixFormat::Mtx => "matrix.mtx.gz",
+            MatrixFormat::TenxH5 => "filtered_peak_bc_matrix.h5",
+            MatrixFormat::H5ad => "matrix.h5ad",
+            MatrixFormat::Loom => "matrix.loom",
+        }
+    }
+
+    /// Guess the format from a path: a directory is mtx, otherwise use the extension
+    pub fn detect(path: &Path) -> Option<MatrixFormat> {
+        if path.is_dir() {
+            return Some(MatrixFormat::Mtx);
+        }
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("h5") => Some(MatrixFormat::TenxH5),
+            Some("h5ad") => Some(MatrixFormat::H5ad),
+            Some("loom") => Some(MatrixFormat::Loom),
+            Some(_) => None,
+            // no extension, treat as output directory
+            None => Some(MatrixFormat::Mtx),
+        }
+    }
+}
+
+pub fn run(matches: &clap::ArgMatches, progress: &Progress) -> Result<(), Box<dyn Error>> {
+
+    let input = Path::new(matches.get_one::<String>("input").unwrap());
+    let output = Path::new(matches.get_one::<String>("output").unwrap());
+    let num_threads = ThreadConfig::from_matches(matches).compress;
+
+    let from = resolve_format(matches.get_one::<String>("from"), input)?;
+    let to = resolve_format(matches.get_one::<String>("to"), output)?;
+    info!("Converting {:?} ({:?}) to {:?} ({:?})", input, from, output, to);
+
+    let force = matches.get_flag("force");
+    if to == MatrixFormat::Mtx {
+        matrix::check_output_dir(output, force)?;
+        fs::create_dir_all(output)?;
+    } else if output.exists() && !force {
+        return Err(format!("Output file {:?} already exists. Use --force to overwrite", output).into());
+    }
+
+    convert(input, from, output, to, BATCH_ENTRIES, num_threads, progress)
+}
+
+/// Convert a matrix a batch of columns (cells) at a time. The input is read once into temporary
+/// files of at most `max_entries` entries each, next to the output, and each batch is then read
+/// back and written.
+pub fn convert(
+    input: &Path,
+    from: MatrixFormat,
+    output: &Path,
+    to: MatrixFormat,
+    max_entries: u64,
+    num_threads: usize,
+    progress: &Progress,
+) -> Result<(), Box<dyn Error>> {
+    let prefix = match to {
+        MatrixFormat::Mtx => output.join("matrix"),
+        _ => output.to_path_buf(),
+    };
+    let matrix = spill_matrix(input, from, &prefix, max_entries)?;
+    info!(
+        "Read {} features x {} cells with {} nonzero entries",
+        matrix.features.len(), matrix.barcodes.len(), matrix.indptr.last().copied().unwrap_or(0)
+    );
+    write_columns(output, to, &Columns::Spilled(&matrix, progress), &[], num_threads)
+}
+
+fn resolve_format(name: Option<&String>, path: &Path) -> Result<MatrixFormat, Box<dyn Error>> {
+    match name {
+        Some(name) => MatrixFormat::from_name(name)
+            .ok_or_else(|| format!("Unknown matrix format: {}", name).into()),
+        None => MatrixFormat::detect(path)
+            .ok_or_else(|| format!("Cannot detect matrix format for {:?}, please specify it", path).into()),
+    }
+}
+
+pub fn read_matrix(path: &Path, format: MatrixFormat) -> Result<SparseMatrix, Box<dyn Error>> {
+    match format {
+        MatrixFormat::Mtx => Ok(matrix::read_mtx_dir(path)?),
+        #[cfg(feature = "hdf5")]
+        MatrixFormat::TenxH5 => crate::h5::read_10x_h5(path),
+        #[cfg(feature = "hdf5")]
+        MatrixFormat::H5ad => crate::h5::read_h5ad(path),
+        #[cfg(feature = "hdf5")]
+        MatrixFormat::Loom => crate::h5::read_loom(path),
+        #[cfg(not(feature = "hdf5"))]
+        _ => Err(no_hdf5(format)),
+    }
+}
+
+/// Write a matrix to temporary files named `prefix`.<batch>.tmp, holding at most `max_entries`
+/// entries each
+pub fn spill_matrix(
+    path: &Path,
+    format: MatrixFormat,
+    prefix: &Path,
+    max_entries: u64,
+) -> Result<SpilledMatrix, Box<dyn Error>> {
+    match format {
+        MatrixFormat::Mtx => Ok(MtxColumns::open(path)?.spill(prefix, max_entries)?),
+        #[cfg(feature = "hdf5")]
+        _ => crate::h5::spill_matrix(path, format, prefix, max_entries),
+        #[cfg(not(feature = "hdf5"))]
+        _ => Err(no_hdf5(format)),
+    }
+}
+
+/// Write a matrix in any format; `parameters` of the run that made it are kept in h5ad files
+pub fn write_matrix(
+    path: &Path,
+    format: MatrixFormat,
+    matrix: &SparseMatrix,
+    parameters: &[(String, String)],
+    num_threads: usize,
+) -> Result<(), Box<dyn Error>> {
+    if format == MatrixFormat::Mtx {
+        fs::create_dir_all(path)?;
+    }
+    write_columns(path, format, &Columns::Memory(matrix), parameters, num_threads)
+}
+
+/// Write a matrix a batch of columns at a time
+#[cfg_attr(not(feature = "hdf5"), allow(unused_variables))]
+fn write_columns(
+    path: &Path,
+    format: MatrixFormat,
+    matrix: &Columns,
+    parameters: &[(String, String)],
+    num_threads: usize,
+) -> Result<(), Box<dyn Error>> {
+    match format {
+        MatrixFormat::Mtx => Ok(matrix::write_mtx_columns(path, matrix, num_threads)?),
+        #[cfg(feature = "hdf5")]
+        MatrixFormat::TenxH5 => crate::h5::write_10x_h5(path, matrix),
+        #[cfg(feature = "hdf5")]
+        MatrixFormat::H5ad => crate::h5::write_h5ad(path, matrix, parameters),
+        #[cfg(feature = "hdf5")]
+        MatrixFormat::Loom => crate::h5::write_loom(path, matrix),
+        #[cfg(not(feature = "hdf5"))]
+        _ => Err(no_hdf5(format)),
+    }
+}
+
+#[cfg(not(feature = "hdf5"))]
+pub fn no_hdf5(format: MatrixFormat) -> Box<dyn Error> {
+    format!(
+        "{:?} format requires HDF5 support; rebuild fragtk with `--features hdf5`",
+        format
+    ).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::path::PathBuf;
+    use crate::matrix::ColumnSpill;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("fragtk_convert_{}_{}", std::process::id(), name));
+        fs::create_dir_all(dir.join("input")).unwrap();
+        dir
+    }
+
+    /// 3 features x 4 cells, with entries out of order and a cell without entries
+    fn write_input(dir: &Path) -> PathBuf {
+        let input = dir.join("input");
+        fs::write(
+            input.join("matrix.mtx"),
+            "%%MatrixMarket matrix coordinate integer general\n3 4 5\n3 4 2\n1 1 1\n2 4 7\n3 1 5\n2 2 1\n",
+        ).unwrap();
+        fs::write(input.join("features.tsv"), "f1\nf2\nf3\n").unwrap();
+        fs::write(input.join("barcodes.tsv"), "A\nB\nC\nD\n").unwrap();
+        input
+    }
+
+    fn assert_same(matrix: &SparseMatrix, expected: &SparseMatrix) {
+        assert_eq!(matrix.features, expected.features);
+        assert_eq!(matrix.barcodes, expected.barcodes);
+        assert_eq!(matrix.indptr, expected.indptr);
+        assert_eq!(matrix.indices, expected.indices);
+        assert_eq!(matrix.data, expected.data);
+        assert_eq!(matrix.integer, expected.integer);
+    }
+
+    #[test]
+    fn mtx_is_converted_in_batches_of_cells() {
+        let dir = temp_dir("mtx");
+        let input = write_input(&dir);
+        let output = dir.join("output");
+        fs::create_dir_all(&output).unwrap();
+        // at most 2 entries per batch: cells A, B-C, and D
+        convert(&input, MatrixFormat::Mtx, &output, MatrixFormat::Mtx, 2, 1, &Progress::default()).unwrap();
+
+        let expected = matrix::read_mtx_dir(&input).unwrap();
+        assert_eq!(expected.indptr, [0, 2, 3, 3, 5]);
+        assert_eq!(expected.indices, [0, 2, 1, 1, 2]);
+        assert_eq!(expected.data, [1.0, 5.0, 1.0, 7.0, 2.0]);
+        assert_same(&matrix::read_mtx_dir(&output).unwrap(), &expected);
+        // the batch files are removed
+        let names: Vec<String> = fs::read_dir(&output).unwrap().map(|x| x.unwrap().file_name().to_string_lossy().to_string()).collect();
+        assert!(names.iter().all(|name| !name.ends_with(".tmp")), "{:?}", names);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn spilled_entries_must_match_the_declared_columns() {
+        let dir = temp_dir("spill");
+        let prefix = dir.join("matrix");
+        let names = |n: usize| (0..n).map(|i| i.to_string()).collect::<Vec<String>>();
+
+        let mut spill = ColumnSpill::create(&prefix, names(2), names(2), vec![0, 1, 1], true, 10).unwrap();
+        assert!(spill.push(2, 0, 1.0).is_err());
+        spill.push(0, 1, 1.0).unwrap();
+        let spilled = spill.finish().unwrap();
+        let result = Columns::Spilled(&spilled, &Progress::default()).for_each_batch(|_, _, _| -> io::Result<()> { Ok(()) });
+        assert_eq!(result.unwrap_err().to_string(), "Column 2 has more entries than declared");
+        drop(spilled);
+
+        let spill = ColumnSpill::create(&prefix, names(2), names(2), vec![0, 1, 2], true, 10).unwrap();
+        assert_eq!(spill.finish().err().unwrap().to_string(), "Header declares 2 entries but 0 were found");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Convert the test matrix to `format` and back to mtx, in batches of 2 entries
+    #[cfg(feature = "hdf5")]
+    fn round_trip(format: MatrixFormat, name: &str) {
+        let dir = temp_dir(name);
+        let input = write_input(&dir);
+        let expected = matrix::read_mtx_dir(&input).unwrap();
+        let path = dir.join(name);
+        convert(&input, MatrixFormat::Mtx, &path, format, 2, 1, &Progress::default()).unwrap();
+        assert_same(&read_matrix(&path, format).unwrap(), &expected);
+
+        let output = dir.join("output");
+        fs::create_dir_all(&output).unwrap();
+        convert(&path, format, &output, MatrixFormat::Mtx, 2, 1, &Progress::default()).unwrap();
+        assert_same(&matrix::read_mtx_dir(&output).unwrap(), &expected);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "hdf5")]
+    fn mtx_round_trips_through_10x_h5() {
+        round_trip(MatrixFormat::TenxH5, "matrix.h5");
+    }
+
+    #[test]
+    #[cfg(feature = "hdf5")]
+    fn mtx_round_trips_through_h5ad() {
+        round_trip(MatrixFormat::H5ad, "matrix.h5ad");
+    }
+
+    #[test]
+    #[cfg(feature = "hdf5")]
+    fn mtx_round_trips_through_loom() {
+        round_trip(MatrixFormat::Loom, "matrix.loom");
+    }
+}
@@ -0,0 +1,160 @@
+use std::{
+    io,
+    ffi::{CStr, CString},
+    os::raw::c_int,
+    path::Path,
+    ptr,
+    slice,
+    io::Read,
+};
+use hts_sys::{
+    bam1_t, bam_destroy1, bam_init1, hts_close, hts_open, hts_set_fai_filename, hts_set_threads, htsFile,
+    sam_hdr_destroy, sam_hdr_read, sam_hdr_t, sam_read1,
+};
+
+/// Alignments of a CRAM file decoded by htslib, re-encoded as an uncompressed BAM stream
+/// so they are turned into fragments by `BamFragments` like any BAM file
+pub struct CramRecords {
+    file: *mut htsFile,
+    header: *mut sam_hdr_t,
+    record: *mut bam1_t,
+    /// encoded header or records not yet read
+    out: Vec<u8>,
+    out_pos: usize,
+    done: bool,
+}
+
+// SAFETY: the htslib handles are owned by the reader and never shared, so it can move between threads
+unsafe impl Send for CramRecords {}
+
+impl CramRecords {
+    /// Open a CRAM file, decoding sequences against `reference` if given,
+    /// otherwise against the reference named in the CRAM header, with `io_threads` decoding threads
+    pub fn open(path: &Path, reference: Option<&Path>, io_threads: usize) -> io::Result<CramRecords> {
+        let file_name = c_path(path)?;
+        // SAFETY: the path and mode are NUL-terminated, and the handles are checked before use
+        // and released in `drop`
+        unsafe {
+            let file = hts_open(file_name.as_ptr(), c"r".as_ptr());
+            if file.is_null() {
+                return Err(io::Error::other(format!("Failed to open CRAM file {:?}", path)));
+            }
+            let mut reader = CramRecords {
+                file,
+                header: ptr::null_mut(),
+                record: ptr::null_mut(),
+                out: Vec::new(),
+                out_pos: 0,
+                done: false,
+            };
+            if let Some(reference) = reference {
+                if hts_set_fai_filename(file, c_path(reference)?.as_ptr()) != 0 {
+                    return Err(io::Error::other(format!("Failed to load CRAM reference {:?}", reference)));
+                }
+            }
+            if io_threads > 1 {
+                hts_set_threads(file, io_threads as c_int);
+            }
+            reader.header = sam_hdr_read(file);
+            if reader.header.is_null() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Failed to read CRAM header: {:?}", path)));
+            }
+            reader.record = bam_init1();
+            reader.encode_header();
+            Ok(reader)
+        }
+    }
+
+    /// BAM magic, an empty header text, and the reference sequences
+    unsafe fn encode_header(&mut self) {
+        let header = &*self.header;
+        self.out.extend_from_slice(b"BAM\x01");
+        self.out.extend_from_slice(&0u32.to_le_bytes());
+        self.out.extend_from_slice(&(header.n_targets as u32).to_le_bytes());
+        for i in 0..header.n_targets.max(0) as usize {
+            let name = CStr::from_ptr(*header.target_name.add(i)).to_bytes_with_nul();
+            self.out.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            self.out.extend_from_slice(name);
+            self.out.extend_from_slice(&(*header.target_len.add(i)).to_le_bytes());
+        }
+    }
+
+    /// Decode the next record and append it in BAM encoding, returning false at the end of the file
+    fn encode_record(&mut self) -> io::Result<bool> {
+        // SAFETY: the handles are valid for the life of the reader, and htslib keeps
+        // `l_data` bytes of record data at `data`
+        unsafe {
+            match sam_read1(self.file, self.header, self.record) {
+                -1 => return Ok(false),
+                ret if ret < -1 => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Failed to decode CRAM record (check --reference is the reference the reads were aligned to)",
+                    ))
+                }
+                _ => {}
+            }
+            let record = &*self.record;
+            let core = &record.core;
+            let data = slice::from_raw_parts(record.data, record.l_data as usize);
+            // htslib pads the read name with extra NULs to align the CIGAR, which BAM does not
+            let l_qname = core.l_qname as usize;
+            let name = &data[..l_qname - core.l_extranul as usize];
+            let rest = &data[l_qname..];
+
+            let block_size = (32 + name.len() + rest.len()) as u32;
+            self.out.extend_from_slice(&block_size.to_le_bytes());
+            self.out.extend_from_slice(&core.tid.to_le_bytes());
+            self.out.extend_from_slice(&(core.pos as i32).to_le_bytes());
+            self.out.push(name.len() as u8);
+            self.out.push(core.qual);
+            self.out.extend_from_slice(&core.bin.to_le_bytes());
+            self.out.extend_from_slice(&(core.n_cigar as u16).to_le_bytes());
+            self.out.extend_from_slice(&core.flag.to_le_bytes());
+            self.out.extend_from_slice(&(core.l_qseq as u32).to_le_bytes());
+            self.out.extend_from_slice(&core.mtid.to_le_bytes());
+            self.out.extend_from_slice(&(core.mpos as i32).to_le_bytes());
+            self.out.extend_from_slice(&(core.isize_ as i32).to_le_bytes());
+            self.out.extend_from_slice(name);
+            self.out.extend_from_slice(rest);
+        }
+        Ok(true)
+    }
+}
+
+impl Read for CramRecords {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.out_pos == self.out.len() {
+            self.out.clear();
+            self.out_pos = 0;
+            while !self.done && self.out.len() < 1 << 16 {
+                self.done = !self.encode_record()?;
+            }
+        }
+        let n = buf.len().min(self.out.len() - self.out_pos);
+        buf[..n].copy_from_slice(&self.out[self.out_pos..self.out_pos + n]);
+        self.out_pos += n;
+        Ok(n)
+    }
+}
+
+impl Drop for CramRecords {
+    fn drop(&mut self) {
+        // SAFETY: each handle is released once, and only if it was created
+        unsafe {
+            if !self.record.is_null() {
+                bam_destroy1(self.record);
+            }
+            if !self.header.is_null() {
+                sam_hdr_destroy(self.header);
+            }
+            hts_close(self.file);
+        }
+    }
+}
+
+fn c_path(path: &Path) -> io::Result<CString> {
+    path.to_str()
+        .and_then(|x| CString::new(x).ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Unsupported path: {:?}", path)))
+}
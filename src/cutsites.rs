@@ -0,0 +1,188 @@
+use std::{
+    io,
+    fs,
+    fs::File,
+    path::{Path, PathBuf},
+    error::Error,
+    io::Write,
+};
+use flate2::Compression;
+use gzp::{deflate::Bgzf, ZBuilder, ZWriter};
+use log::{info, warn};
+use rustc_hash::FxHashSet;
+use crate::bbi::bed_to_bigbed;
+use crate::bed::read_chrom_sizes;
+use crate::cells::CellGroups;
+use crate::fragments::{for_each_fragment_with_progress, ReadOptions};
+use crate::progress::Progress;
+use crate::threads::ThreadConfig;
+
+/// Columns of the cut-site BED: position and number of insertions at that position
+const CUTSITE_AUTOSQL: &str = r#"table cutsites
+"Tn5 insertion sites"
+(
+string chrom;      "Reference sequence chromosome or scaffold"
+uint   chromStart; "Insertion position"
+uint   chromEnd;   "Insertion position + 1"
+uint   count;      "Number of insertions at this position"
+)
+"#;
+
+pub fn run(matches: &clap::ArgMatches, progress: &Progress) -> Result<(), Box<dyn Error>> {
+
+    let frag_file = Path::new(matches.get_one::<String>("fragments").unwrap());
+    info!("Received fragment file: {:?}", frag_file);
+    let read_options = ReadOptions::from_matches(matches)?;
+
+    let output = Path::new(matches.get_one::<String>("outfile").unwrap());
+    let bigbed = matches.get_one::<String>("format").unwrap() == "bigbed";
+    let num_threads = ThreadConfig::from_matches(matches).compress;
+
+    let chrom_sizes = match matches.get_one::<String>("chrom_sizes") {
+        Some(path) => Some(read_chrom_sizes(Path::new(path))?),
+        None if bigbed => return Err("--chrom-sizes is required for bigBed output".into()),
+        None => None,
+    };
+
+    // cell barcode -> output index
+    let groups = CellGroups::from_matches(matches, "")?;
+    if groups.is_grouped() {
+        info!("Writing cut sites for {} groups", groups.names.len());
+        fs::create_dir_all(output)?;
+    }
+
+    // per-group BED paths; bigBed output goes through a temporary BED file
+    let ext = if bigbed { "bed.tmp" } else { "bed.gz" };
+    let bed_paths: Vec<PathBuf> = if groups.is_grouped() {
+        groups.names.iter().map(|name| output.join(format!("{}.{}", name, ext))).collect()
+    } else if bigbed {
+        vec![output.with_extension("bed.tmp")]
+    } else {
+        vec![output.to_path_buf()]
+    };
+
+    write_cutsites(frag_file, &bed_paths, bigbed, &groups, num_threads, &read_options, progress)?;
+
+    if bigbed {
+        let chrom_sizes = chrom_sizes.unwrap();
+        for (i, bed_path) in bed_paths.iter().enumerate() {
+            let bb_path = if groups.is_grouped() {
+                output.join(format!("{}.bb", groups.names[i]))
+            } else {
+                output.to_path_buf()
+            };
+            bed_to_bigbed(bed_path, &bb_path, &chrom_sizes, Some(CUTSITE_AUTOSQL), num_threads)?;
+            fs::remove_file(bed_path)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Stream fragments and write sorted, collapsed insertion positions for each group.
+/// Fragments are expected to be grouped by chromosome; positions are sorted per chromosome.
+fn write_cutsites(
+    frag_file: &Path,
+    outfiles: &[PathBuf],
+    plain: bool,
+    groups: &CellGroups,
+    num_threads: usize,
+    read_options: &ReadOptions,
+    progress: &Progress,
+) -> io::Result<()> {
+
+    let mut writers: Vec<Box<dyn ZWriter>> = Vec::with_capacity(outfiles.len());
+    for path in outfiles {
+        info!("Writing cut sites: {:?}", path);
+        let file = File::create(path)?;
+        let writer: Box<dyn ZWriter> = if plain {
+            Box::new(PlainWriter(io::BufWriter::new(file)))
+        } else {
+            ZBuilder::<Bgzf, _>::new()
+                .compression_level(Compression::default())
+                .num_threads(if outfiles.len() == 1 { num_threads } else { 1 })
+                .from_writer(file)
+        };
+        writers.push(writer);
+    }
+
+    let mut positions: Vec<Vec<u32>> = vec![Vec::new(); outfiles.len()];
+    let mut current_chrom = String::new();
+    let mut finished_chroms: FxHashSet<String> = FxHashSet::default();
+
+    for_each_fragment_with_progress(frag_file, read_options, progress, |fragment| {
+        let group = match groups.get(fragment.barcode) {
+            Some(group) => group,
+            None => return Ok(()),
+        };
+
+        if fragment.chrom != current_chrom {
+            flush_chrom(&current_chrom, &mut positions, &mut writers)?;
+            if !finished_chroms.insert(fragment.chrom.to_string()) {
+                warn!("Fragment file is not sorted by chromosome, output will not be sorted");
+            }
+            current_chrom = fragment.chrom.to_string();
+        }
+
+        // insertions at both fragment ends, as counted by fragtk matrix
+        positions[group].push(fragment.start);
+        positions[group].push(fragment.end);
+        Ok(())
+    })?;
+    flush_chrom(&current_chrom, &mut positions, &mut writers)?;
+
+    for mut writer in writers {
+        writer.finish().map_err(io::Error::other)?;
+    }
+
+    Ok(())
+}
+
+fn flush_chrom(
+    chrom: &str,
+    positions: &mut [Vec<u32>],
+    writers: &mut [Box<dyn ZWriter>],
+) -> io::Result<()> {
+    let mut output = String::new();
+    for (group_positions, writer) in positions.iter_mut().zip(writers.iter_mut()) {
+        group_positions.sort_unstable();
+        let mut i = 0;
+        while i < group_positions.len() {
+            let pos = group_positions[i];
+            let mut count = 0;
+            while i < group_positions.len() && group_positions[i] == pos {
+                count += 1;
+                i += 1;
+            }
+            output.push_str(&format!("{}\t{}\t{}\t{}\n", chrom, pos, pos + 1, count));
+            if output.len() > 1 << 20 {
+                writer.write_all(output.as_bytes())?;
+                output.clear();
+            }
+        }
+        writer.write_all(output.as_bytes())?;
+        output.clear();
+        group_positions.clear();
+    }
+    Ok(())
+}
+
+/// Uncompressed writer usable where a `ZWriter` is expected
+struct PlainWriter(io::BufWriter<File>);
+
+impl Write for PlainWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl ZWriter for PlainWriter {
+    fn finish(&mut self) -> Result<(), gzp::GzpError> {
+        self.0.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,490 @@
+use std::{
+    io,
+    fs::File,
+    path::Path,
+    error::Error,
+    io::Write,
+};
+use log::{info, warn};
+use rayon::prelude::*;
+use crate::cells::{load_groups, Column, Reconcile, TableFormat};
+use crate::convert::{self, MatrixFormat};
+use crate::sketch::mix;
+
+/// CPM added to the group means before taking the log2 fold change
+const PRIOR_CPM: f64 = 1.0;
+/// Largest number of pseudobulks for which the Wilcoxon p-value is computed exactly
+const MAX_EXACT_WILCOXON: usize = 20;
+
+/// Per-feature test between the pseudobulks of two groups
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Test {
+    /// conditional negative binomial test on summed counts with a common dispersion, as edgeR exactTest
+    Exact,
+    /// Wilcoxon rank-sum test on the CPM of the pseudobulks
+    Wilcoxon,
+}
+
+/// Result for one tested feature
+struct FeatureResult {
+    feature: usize,
+    mean_cpm: (f64, f64),
+    log2fc: f64,
+    pvalue: f64,
+}
+
+/// Test each feature for differential accessibility between two cell groups, from pseudobulks
+/// of pseudo-replicates (or of the replicates given in the group table) of each group
+pub fn run(matches: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+
+    let input = Path::new(matches.get_one::<String>("input").unwrap());
+    let format = match matches.get_one::<String>("from") {
+        Some(name) => MatrixFormat::from_name(name),
+        None => MatrixFormat::detect(input),
+    }
+    .ok_or_else(|| format!("Cannot detect matrix format for {:?}, please specify it", input))?;
+    let group_file = Path::new(matches.get_one::<String>("groups").unwrap());
+    let output = Path::new(matches.get_one::<String>("output").unwrap());
+    let compared = [
+        matches.get_one::<String>("group1").unwrap(),
+        matches.get_one::<String>("group2").unwrap(),
+    ];
+    if compared[0] == compared[1] {
+        return Err("--group1 and --group2 must be different groups".into());
+    }
+    let test = match matches.get_one::<String>("test").unwrap().as_str() {
+        "wilcoxon" => Test::Wilcoxon,
+        _ => Test::Exact,
+    };
+    let min_count = *matches.get_one::<u64>("min_count").unwrap() as f64;
+    info!("Testing {:?} for differential accessibility between {} and {}", input, compared[0], compared[1]);
+
+    let matrix = convert::read_matrix(input, format)?;
+    if test == Test::Exact && !matrix.integer {
+        return Err("The exact test needs integer counts; use --test wilcoxon for normalized matrices".into());
+    }
+    let table_format = TableFormat::from_matches(matches)?;
+    let (group_names, cell_groups) = load_groups(group_file, &table_format, &Reconcile::from_matches(matches), None)?;
+    let mut group_of = [0usize; 2];
+    for (slot, name) in group_of.iter_mut().zip(compared) {
+        *slot = group_names
+            .iter()
+            .position(|x| x == name)
+            .ok_or_else(|| format!("Group {} is not in {:?}", name, group_file))?;
+    }
+
+    // pseudobulk of each matrix column in the compared groups: the replicate given in the table,
+    // or a pseudo-replicate drawn at random within the group
+    let columns: Vec<Option<usize>> = matrix
+        .barcodes
+        .iter()
+        .map(|barcode| cell_groups.get(barcode).and_then(|group| group_of.iter().position(|x| x == group)))
+        .collect();
+    let (replicate_names, replicates): ([Vec<String>; 2], Vec<Option<usize>>) = match matches.get_one::<String>("replicate_column") {
+        Some(column) => {
+            let replicate_format = TableFormat {
+                group_column: Column::parse(column).ok_or_else(|| format!("Invalid column: {}", column))?,
+                ..table_format.clone()
+            };
+            let (names, cell_replicates) = load_groups(group_file, &replicate_format, &Reconcile::default(), None)?;
+            table_replicates(&matrix.barcodes, &columns, &names, &cell_replicates)
+        }
+        None => {
+            let n = *matches.get_one::<u32>("replicates").unwrap() as usize;
+            let seed = *matches.get_one::<u64>("seed").unwrap();
+            let names: Vec<String> = (1..=n).map(|i| format!("rep{}", i)).collect();
+            ([names.clone(), names], pseudo_replicates(&columns, n, seed))
+        }
+    };
+
+    // counts of each feature in each pseudobulk, group 1 first
+    let offsets = [0, replicate_names[0].len()];
+    let n_bulks = offsets[1] + replicate_names[1].len();
+    let mut cells: Vec<u64> = vec![0; n_bulks];
+    let mut counts: Vec<f64> = vec![0.0; matrix.nrow() * n_bulks];
+    for (col, (group, replicate)) in columns.iter().zip(replicates.iter()).enumerate() {
+        let bulk = match (group, replicate) {
+            (Some(group), Some(replicate)) => offsets[*group] + replicate,
+            _ => continue,
+        };
+        cells[bulk] += 1;
+        for pos in matrix.indptr[col] as usize..matrix.indptr[col + 1] as usize {
+            counts[matrix.indices[pos] as usize * n_bulks + bulk] += matrix.data[pos];
+        }
+    }
+    let mut library: Vec<f64> = vec![0.0; n_bulks];
+    for row in counts.chunks(n_bulks) {
+        for (total, count) in library.iter_mut().zip(row) {
+            *total += count;
+        }
+    }
+    for group in 0..2 {
+        let range = offsets[group]..offsets[group] + replicate_names[group].len();
+        for bulk in range.clone() {
+            let name = &replicate_names[group][bulk - offsets[group]];
+            info!("{} {}: {} cells, {} counts", compared[group], name, cells[bulk], library[bulk]);
+            if library[bulk] == 0.0 {
+                return Err(format!("Pseudobulk {} of group {} has no counts", name, compared[group]).into());
+            }
+        }
+        if range.len() < 2 {
+            return Err(format!("Group {} needs at least two replicates", compared[group]).into());
+        }
+    }
+    let groups: Vec<usize> = (0..n_bulks).map(|bulk| usize::from(bulk >= offsets[1])).collect();
+
+    let tested: Vec<usize> = (0..matrix.nrow())
+        .filter(|row| counts[row * n_bulks..(row + 1) * n_bulks].iter().sum::<f64>() >= min_count)
+        .collect();
+    info!("Testing {} of {} features with at least {} counts", tested.len(), matrix.nrow(), min_count);
+    if tested.is_empty() {
+        warn!("No features have enough counts to test");
+    }
+
+    let results: Vec<FeatureResult> = match test {
+        Test::Exact => {
+            // counts scaled to the geometric mean library size, as edgeR pseudo-counts
+            let common = (library.iter().map(|x| x.ln()).sum::<f64>() / n_bulks as f64).exp();
+            let scaled: Vec<f64> = counts
+                .iter()
+                .enumerate()
+                .map(|(i, count)| (count * common / library[i % n_bulks]).round())
+                .collect();
+            let dispersion = common_dispersion(&scaled, &tested, &groups, n_bulks);
+            info!("Common dispersion: {:.4}", dispersion);
+            let sizes = [offsets[1], n_bulks - offsets[1]];
+            tested
+                .par_iter()
+                .map(|row| {
+                    let values = &scaled[row * n_bulks..(row + 1) * n_bulks];
+                    let sums = group_sums(values, &groups);
+                    let pvalue = exact_test(sums.0 as u64, sums.1 as u64, sizes, dispersion);
+                    feature_result(*row, &counts[row * n_bulks..(row + 1) * n_bulks], &library, &groups, pvalue)
+                })
+                .collect()
+        }
+        Test::Wilcoxon => tested
+            .par_iter()
+            .map(|row| {
+                let values = &counts[row * n_bulks..(row + 1) * n_bulks];
+                let cpm: Vec<f64> = values.iter().zip(library.iter()).map(|(x, total)| x / total * 1e6).collect();
+                feature_result(*row, values, &library, &groups, wilcoxon(&cpm, &groups))
+            })
+            .collect(),
+    };
+    let fdr = benjamini_hochberg(&results.iter().map(|x| x.pvalue).collect::<Vec<f64>>());
+    info!("{} features differ at an FDR below 0.05", fdr.iter().filter(|x| **x < 0.05).count());
+
+    info!("Writing differential accessibility: {:?}", output);
+    let mut writer = io::BufWriter::new(File::create(output)?);
+    writeln!(writer, "feature\tmean_cpm_{}\tmean_cpm_{}\tlog2fc\tpvalue\tfdr", compared[0], compared[1])?;
+    for (result, fdr) in results.iter().zip(fdr) {
+        writeln!(
+            writer,
+            "{}\t{:.4}\t{:.4}\t{:.4}\t{:.6e}\t{:.6e}",
+            matrix.features[result.feature], result.mean_cpm.0, result.mean_cpm.1, result.log2fc, result.pvalue, fdr,
+        )?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Replicate of each column from the replicate column of the group table, numbered within each group
+fn table_replicates(
+    barcodes: &[String],
+    columns: &[Option<usize>],
+    names: &[String],
+    cell_replicates: &rustc_hash::FxHashMap<String, usize>,
+) -> ([Vec<String>; 2], Vec<Option<usize>>) {
+    let mut replicate_names: [Vec<String>; 2] = [Vec::new(), Vec::new()];
+    let mut numbering: [Vec<Option<usize>>; 2] = [vec![None; names.len()], vec![None; names.len()]];
+    let replicates = barcodes
+        .iter()
+        .zip(columns)
+        .map(|(barcode, group)| {
+            let group = (*group)?;
+            let replicate = *cell_replicates.get(barcode)?;
+            Some(*numbering[group][replicate].get_or_insert_with(|| {
+                replicate_names[group].push(names[replicate].clone());
+                replicate_names[group].len() - 1
+            }))
+        })
+        .collect();
+    (replicate_names, replicates)
+}
+
+/// Split the cells of each group into `n` pseudo-replicates of equal size, at random
+fn pseudo_replicates(columns: &[Option<usize>], n: usize, seed: u64) -> Vec<Option<usize>> {
+    let mut replicates: Vec<Option<usize>> = vec![None; columns.len()];
+    for group in 0..2 {
+        let mut members: Vec<(u64, usize)> = columns
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| **x == Some(group))
+            .map(|(col, _)| (mix(seed ^ mix(col as u64)), col))
+            .collect();
+        members.sort_unstable();
+        for (rank, (_, col)) in members.iter().enumerate() {
+            replicates[*col] = Some(rank % n);
+        }
+    }
+    replicates
+}
+
+fn group_sums(values: &[f64], groups: &[usize]) -> (f64, f64) {
+    let mut sums = (0.0, 0.0);
+    for (value, group) in values.iter().zip(groups) {
+        match group {
+            0 => sums.0 += value,
+            _ => sums.1 += value,
+        }
+    }
+    sums
+}
+
+/// Mean CPM of each group and the log2 fold change of group 1 over group 2
+fn feature_result(feature: usize, values: &[f64], library: &[f64], groups: &[usize], pvalue: f64) -> FeatureResult {
+    let mut sums = (0.0, 0.0);
+    let mut sizes = (0.0, 0.0);
+    for ((value, total), group) in values.iter().zip(library).zip(groups) {
+        let cpm = value / total * 1e6;
+        if *group == 0 {
+            sums.0 += cpm;
+            sizes.0 += 1.0;
+        } else {
+            sums.1 += cpm;
+            sizes.1 += 1.0;
+        }
+    }
+    let mean_cpm = (sums.0 / sizes.0, sums.1 / sizes.1);
+    let log2fc = ((mean_cpm.0 + PRIOR_CPM) / (mean_cpm.1 + PRIOR_CPM)).log2();
+    FeatureResult { feature, mean_cpm, log2fc, pvalue }
+}
+
+/// Method of moments estimate of a negative binomial dispersion shared by all tested features,
+/// from the variance of the scaled counts within each group in excess of the mean
+fn common_dispersion(scaled: &[f64], tested: &[usize], groups: &[usize], n_bulks: usize) -> f64 {
+    let (mut excess, mut squares) = (0.0, 0.0);
+    for row in tested {
+        let values = &scaled[row * n_bulks..(row + 1) * n_bulks];
+        for group in 0..2 {
+            let members: Vec<f64> = values.iter().zip(groups).filter(|(_, x)| **x == group).map(|(v, _)| *v).collect();
+            let n = members.len() as f64;
+            let mean = members.iter().sum::<f64>() / n;
+            let variance = members.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (n - 1.0);
+            excess += variance - mean;
+            squares += mean * mean;
+        }
+    }
+    match squares > 0.0 {
+        true => (excess / squares).max(0.0),
+        false => 0.0,
+    }
+}
+
+/// Two-sided p-value of `x1` of the `x1 + x2` counts falling in group 1, when the summed counts of
+/// the `sizes` pseudobulks of each group are negative binomial with equal means per pseudobulk and
+/// the given dispersion (binomial without dispersion). Sums the probabilities of every split no
+/// more likely than the observed one, as edgeR's exactTest with rejection region "smallp".
+fn exact_test(x1: u64, x2: u64, sizes: [usize; 2], dispersion: f64) -> f64 {
+    let n = x1 + x2;
+    if n == 0 {
+        return 1.0;
+    }
+    let (r1, r2) = (sizes[0] as f64, sizes[1] as f64);
+    let mean = n as f64 / (r1 + r2);
+    // the sum of r NB(mean, 1 / dispersion) is NB(r mean, r / dispersion)
+    let poisson = dispersion < 1e-8;
+    let (size1, size2) = (r1 / dispersion, r2 / dispersion);
+    let log_q1 = (r1 * mean / (size1 + r1 * mean)).ln();
+    let log_q2 = (r2 * mean / (size2 + r2 * mean)).ln();
+    // log ratio of the probability of k + 1 to that of k counts in group 1
+    let step = |k: f64| {
+        let rest = n as f64 - k;
+        match poisson {
+            true => (rest / (k + 1.0)).ln() + (r1 / r2).ln(),
+            false => ((k + size1) / (k + 1.0)).ln() + log_q1 + (rest / (rest - 1.0 + size2)).ln() - log_q2,
+        }
+    };
+    let mut log_probs: Vec<f64> = Vec::with_capacity(n as usize + 1);
+    let mut current = 0.0;
+    log_probs.push(current);
+    for k in 0..n {
+        current += step(k as f64);
+        log_probs.push(current);
+    }
+    let max = log_probs.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    let observed = log_probs[x1 as usize];
+    let (mut total, mut tail) = (0.0, 0.0);
+    for log_prob in &log_probs {
+        let prob = (log_prob - max).exp();
+        total += prob;
+        if *log_prob <= observed + 1e-7 {
+            tail += prob;
+        }
+    }
+    (tail / total).min(1.0)
+}
+
+/// Two-sided Wilcoxon rank-sum p-value of group 1 against group 2: exact for few pseudobulks
+/// without ties, otherwise from the normal approximation with tie and continuity corrections
+fn wilcoxon(values: &[f64], groups: &[usize]) -> f64 {
+    let n = values.len();
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by(|a, b| values[*a].total_cmp(&values[*b]));
+    let mut ranks: Vec<f64> = vec![0.0; n];
+    let mut ties: f64 = 0.0;
+    let mut i = 0;
+    while i < n {
+        let mut j = i;
+        while j + 1 < n && values[order[j + 1]] == values[order[i]] {
+            j += 1;
+        }
+        let t = (j - i + 1) as f64;
+        ties += t * t * t - t;
+        for position in &order[i..=j] {
+            ranks[*position] = (i + j) as f64 / 2.0 + 1.0;
+        }
+        i = j + 1;
+    }
+    let n1 = groups.iter().filter(|x| **x == 0).count();
+    let n2 = n - n1;
+    let rank_sum: f64 = ranks.iter().zip(groups).filter(|(_, x)| **x == 0).map(|(rank, _)| rank).sum();
+    let u = rank_sum - (n1 * (n1 + 1)) as f64 / 2.0;
+
+    if ties == 0.0 && n <= MAX_EXACT_WILCOXON {
+        // number of arrangements with each value of U
+        let max_u = n1 * n2;
+        let mut ways: Vec<Vec<f64>> = vec![vec![0.0; max_u + 1]; n1 + 1];
+        ways[0][0] = 1.0;
+        for m in 1..=n {
+            for k in (1..=n1.min(m)).rev() {
+                // the k-th group 1 value is placed after m - k group 2 values
+                let shift = m - k;
+                if shift > n2 {
+                    continue;
+                }
+                for total in (shift..=max_u).rev() {
+                    ways[k][total] += ways[k - 1][total - shift];
+                }
+            }
+        }
+        let all: f64 = ways[n1].iter().sum();
+        let u = u.round() as usize;
+        let lower: f64 = ways[n1][..=u].iter().sum::<f64>() / all;
+        let upper: f64 = ways[n1][u..].iter().sum::<f64>() / all;
+        return (2.0 * lower.min(upper)).min(1.0);
+    }
+
+    let (n1, n2) = (n1 as f64, n2 as f64);
+    let mean = n1 * n2 / 2.0;
+    let variance = n1 * n2 / 12.0 * ((n1 + n2 + 1.0) - ties / ((n1 + n2) * (n1 + n2 - 1.0)));
+    if variance <= 0.0 {
+        return 1.0;
+    }
+    let z = ((u - mean).abs() - 0.5).max(0.0) / variance.sqrt();
+    (2.0 * normal_upper_tail(z)).min(1.0)
+}
+
+/// P(Z > z) for a standard normal, from the complementary error function (Numerical Recipes erfcc)
+fn normal_upper_tail(z: f64) -> f64 {
+    let x = z / std::f64::consts::SQRT_2;
+    let t = 1.0 / (1.0 + 0.5 * x.abs());
+    let erfc = t * (-x * x - 1.26551223
+        + t * (1.00002368 + t * (0.37409196 + t * (0.09678418 + t * (-0.18628806
+        + t * (0.27886807 + t * (-1.13520398 + t * (1.48851587 + t * (-0.82215223 + t * 0.17087277)))))))))
+        .exp();
+    let erfc = if x >= 0.0 { erfc } else { 2.0 - erfc };
+    erfc / 2.0
+}
+
+/// Benjamini-Hochberg adjusted p-values, in the order given
+fn benjamini_hochberg(pvalues: &[f64]) -> Vec<f64> {
+    let m = pvalues.len();
+    let mut order: Vec<usize> = (0..m).collect();
+    order.sort_by(|a, b| pvalues[*b].total_cmp(&pvalues[*a]));
+    let mut adjusted: Vec<f64> = vec![1.0; m];
+    let mut running = 1.0f64;
+    for (i, index) in order.iter().enumerate() {
+        let rank = (m - i) as f64;
+        running = running.min(pvalues[*index] * m as f64 / rank);
+        adjusted[*index] = running;
+    }
+    adjusted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64, tolerance: f64) {
+        assert!((actual - expected).abs() <= tolerance, "{} is not {}", actual, expected);
+    }
+
+    #[test]
+    fn exact_test_without_dispersion_is_binomial() {
+        // binom.test(7, 10)$p.value, binom.test(10, 10)$p.value
+        assert_close(exact_test(7, 3, [2, 2], 0.0), 0.34375, 1e-12);
+        assert_close(exact_test(10, 0, [3, 3], 0.0), 0.001953125, 1e-12);
+        // unequal groups: binom.test(5, 8, p = 0.25)$p.value
+        assert_close(exact_test(5, 3, [1, 3], 0.0), 0.0272979736328125, 1e-12);
+        assert_close(exact_test(2, 8, [1, 3], 0.0), 1.0, 1e-12);
+        assert_eq!(exact_test(0, 0, [2, 2], 0.0), 1.0);
+    }
+
+    #[test]
+    fn exact_test_with_dispersion() {
+        // the summed probabilities of every split no more likely than the observed one,
+        // from the negative binomial densities of the two group sums
+        assert_close(exact_test(30, 10, [2, 2], 0.1), 0.028932118985746363, 1e-9);
+        assert_close(exact_test(12, 3, [3, 2], 0.05), 0.21710340775265835, 1e-9);
+        // dispersion widens the null distribution
+        assert!(exact_test(30, 10, [2, 2], 0.1) > exact_test(30, 10, [2, 2], 0.0));
+    }
+
+    #[test]
+    fn wilcoxon_exact_for_small_groups() {
+        // wilcox.test(c(1, 2, 3), c(4, 5, 6))$p.value
+        assert_close(wilcoxon(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0], &[0, 0, 0, 1, 1, 1]), 0.1, 1e-12);
+        // wilcox.test(c(1, 3, 5), c(2, 4, 6, 7))$p.value
+        assert_close(wilcoxon(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0], &[0, 1, 0, 1, 0, 1, 1]), 0.4, 1e-12);
+        // wilcox.test(c(5, 8, 9, 12), c(1, 2, 4))$p.value
+        assert_close(wilcoxon(&[5.0, 1.0, 8.0, 2.0, 9.0, 4.0, 12.0], &[0, 1, 0, 1, 0, 1, 0]), 4.0 / 70.0, 1e-12);
+    }
+
+    #[test]
+    fn wilcoxon_normal_approximation_with_ties() {
+        // wilcox.test(c(1, 2, 2, 3), c(3, 4, 5, 5), exact = FALSE)$p.value
+        let p = wilcoxon(&[1.0, 2.0, 2.0, 3.0, 3.0, 4.0, 5.0, 5.0], &[0, 0, 0, 0, 1, 1, 1, 1]);
+        assert_close(p, 0.03960870463975983, 1e-6);
+    }
+
+    #[test]
+    fn benjamini_hochberg_matches_p_adjust() {
+        // p.adjust(c(0.039, 0.001, 0.205, 0.042, 0.008, 0.074, 0.041, 0.06), "BH")
+        let pvalues = [0.039, 0.001, 0.205, 0.042, 0.008, 0.074, 0.041, 0.06];
+        let expected = [0.0672, 0.008, 0.205, 0.0672, 0.032, 0.08457142857142857, 0.0672, 0.08];
+        for (adjusted, expected) in benjamini_hochberg(&pvalues).iter().zip(expected) {
+            assert_close(*adjusted, expected, 1e-12);
+        }
+        // p.adjust(c(0.01, 0.02, 0.03, 0.04, 0.05), "BH")
+        for adjusted in benjamini_hochberg(&[0.01, 0.02, 0.03, 0.04, 0.05]) {
+            assert_close(adjusted, 0.05, 1e-12);
+        }
+        assert!(benjamini_hochberg(&[]).is_empty());
+    }
+
+    #[test]
+    fn common_dispersion_from_excess_variance() {
+        // group means 5 and 10, both with variance 50: (45 + 40) / (25 + 100)
+        let scaled = [0.0, 10.0, 5.0, 15.0];
+        assert_close(common_dispersion(&scaled, &[0], &[0, 0, 1, 1], 4), 0.68, 1e-12);
+        // less variance than the mean gives no dispersion
+        let scaled = [10.0, 10.0, 20.0, 20.0];
+        assert_eq!(common_dispersion(&scaled, &[0], &[0, 0, 1, 1], 4), 0.0);
+        // only tested rows count
+        let scaled = [10.0, 10.0, 20.0, 20.0, 0.0, 10.0, 5.0, 15.0];
+        assert_close(common_dispersion(&scaled, &[1], &[0, 0, 1, 1], 4), 0.68, 1e-12);
+        assert_eq!(common_dispersion(&scaled, &[], &[0, 0, 1, 1], 4), 0.0);
+    }
+}
@@ -0,0 +1,207 @@
+use std::{
+    io,
+    fs::File,
+    path::Path,
+    io::BufRead,
+    io::Write,
+};
+use log::{info, warn};
+use rustc_hash::FxHashMap;
+use crate::matrix::open_text;
+
+/// Hashtag call for a single cell barcode
+#[derive(Clone, Copy)]
+pub enum TagCall {
+    Sample(usize),
+    Doublet,
+    Negative,
+}
+
+/// Sample assignments from per-cell hashtag (HTO/CellPlex) counts, from barcode suffixes,
+/// or from the condition of each merged sample
+pub struct SampleAssignment {
+    pub samples: Vec<String>,
+    /// call, top tag count, and fraction of tag counts in the top tag for each barcode
+    calls: FxHashMap<String, (TagCall, u32, f64)>,
+    /// whether the calls come from tag counts, and so have a table of assignments to write
+    from_tags: bool,
+    /// samplesheet column of the conditions and the merged samples in each, when split by condition
+    conditions: Option<(String, Vec<Vec<String>>)>,
+}
+
+impl SampleAssignment {
+    /// Assign cells to samples from a tag count table.
+    /// The table is tab-separated with a header line of `barcode` followed by one column per tag.
+    /// A cell is assigned to its top tag when it has at least `min_count` tag counts
+    /// and the top tag holds at least `min_fraction` of them, otherwise it is a doublet.
+    /// Cells below `min_count` are negative.
+    pub fn from_tag_counts(path: &Path, min_count: u32, min_fraction: f64) -> io::Result<SampleAssignment> {
+        let mut lines = open_text(path)?.lines();
+        let header = match lines.next() {
+            Some(line) => line?,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData, "Tag count file is empty")),
+        };
+        let samples: Vec<String> = header.split('\t').skip(1).map(|x| x.to_string()).collect();
+        if samples.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Tag count header must contain a barcode column followed by tag columns",
+            ));
+        }
+
+        let mut calls: FxHashMap<String, (TagCall, u32, f64)> = FxHashMap::default();
+        for (index, line) in lines.enumerate() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            let mut fields = line.split('\t');
+            let barcode = fields.next().unwrap();
+            let counts: Vec<u32> = match fields.map(|x| x.trim().parse()).collect() {
+                Ok(counts) => counts,
+                Err(_) => {
+                    warn!("Line {}: Failed to parse tag counts", index + 2);
+                    continue;
+                }
+            };
+            if counts.len() != samples.len() {
+                warn!("Line {}: Expected {} tag counts, found {}", index + 2, samples.len(), counts.len());
+                continue;
+            }
+
+            let total: u32 = counts.iter().sum();
+            let (top, &top_count) = counts.iter().enumerate().max_by_key(|x| x.1).unwrap();
+            let fraction = if total > 0 { top_count as f64 / total as f64 } else { 0.0 };
+            let call = if total < min_count {
+                TagCall::Negative
+            } else if fraction >= min_fraction {
+                TagCall::Sample(top)
+            } else {
+                TagCall::Doublet
+            };
+            calls.insert(barcode.to_string(), (call, top_count, fraction));
+        }
+        info!("Loaded tag counts for {} cells across {} samples", calls.len(), samples.len());
+
+        Ok(SampleAssignment { samples, calls, from_tags: true, conditions: None })
+    }
+
+    /// Assign cells to samples by the barcode suffix after the last `separator` (e.g. `1` in `BC-1`),
+    /// with samples in order of first appearance. Barcodes without a suffix are negative.
+    pub fn from_barcode_suffixes(barcodes: &[&str], separator: &str) -> SampleAssignment {
+        let mut samples: Vec<String> = Vec::new();
+        let mut calls: FxHashMap<String, (TagCall, u32, f64)> = FxHashMap::default();
+        let mut unsuffixed: usize = 0;
+        for barcode in barcodes {
+            let suffix = match barcode.rsplit_once(separator) {
+                Some((_, suffix)) if !suffix.is_empty() => suffix,
+                _ => {
+                    unsuffixed += 1;
+                    continue;
+                }
+            };
+            let sample = match samples.iter().position(|x| x == suffix) {
+                Some(sample) => sample,
+                None => {
+                    samples.push(suffix.to_string());
+                    samples.len() - 1
+                }
+            };
+            calls.insert(barcode.to_string(), (TagCall::Sample(sample), 0, 1.0));
+        }
+        if unsuffixed > 0 {
+            warn!("{} cell barcodes have no suffix after {:?} and are not written to any matrix", unsuffixed, separator);
+        }
+        info!("Splitting {} cells by barcode suffix into {} matrices", calls.len(), samples.len());
+
+        SampleAssignment { samples, calls, from_tags: false, conditions: None }
+    }
+
+    /// Assign cells to the condition (e.g. time point) of the merged sample they come from, by the
+    /// longest barcode suffix they carry. `sample_names`, `suffixes`, and `conditions` are given for
+    /// each sample; conditions are written in order of first appearance.
+    pub fn from_conditions(
+        barcodes: &[&str],
+        sample_names: &[String],
+        suffixes: &[&str],
+        column: &str,
+        conditions: &[&str],
+    ) -> io::Result<SampleAssignment> {
+        let mut names: Vec<String> = Vec::new();
+        let mut members: Vec<Vec<String>> = Vec::new();
+        let mut sample_condition: Vec<usize> = Vec::with_capacity(conditions.len());
+        for (sample, condition) in sample_names.iter().zip(conditions.iter()) {
+            if condition.is_empty() || condition.contains(['/', '\\']) || *condition == "." || *condition == ".." {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Condition {:?} of sample {} can't be used as a directory name", condition, sample),
+                ));
+            }
+            let index = match names.iter().position(|x| x == condition) {
+                Some(index) => index,
+                None => {
+                    names.push(condition.to_string());
+                    members.push(Vec::new());
+                    names.len() - 1
+                }
+            };
+            members[index].push(sample.clone());
+            sample_condition.push(index);
+        }
+
+        let mut calls: FxHashMap<String, (TagCall, u32, f64)> = FxHashMap::default();
+        for barcode in barcodes {
+            let sample = suffixes
+                .iter()
+                .enumerate()
+                .filter(|(_, suffix)| barcode.ends_with(*suffix))
+                .max_by_key(|(_, suffix)| suffix.len());
+            if let Some((sample, _)) = sample {
+                calls.insert(barcode.to_string(), (TagCall::Sample(sample_condition[sample]), 0, 1.0));
+            }
+        }
+        if calls.len() < barcodes.len() {
+            warn!("{} cell barcodes have no sample suffix and are not written to any matrix", barcodes.len() - calls.len());
+        }
+        info!("Splitting {} cells by {} into {} matrices", calls.len(), column, names.len());
+
+        Ok(SampleAssignment {
+            samples: names,
+            calls,
+            from_tags: false,
+            conditions: Some((column.to_string(), members)),
+        })
+    }
+
+    /// Whether the assignments come from hashtag counts
+    pub fn from_tags(&self) -> bool {
+        self.from_tags
+    }
+
+    /// Samplesheet column and the merged samples of each condition, when split by condition
+    pub fn conditions(&self) -> Option<(&str, &[Vec<String>])> {
+        self.conditions.as_ref().map(|(column, members)| (column.as_str(), members.as_slice()))
+    }
+
+    /// Call for a barcode; cells missing from the tag table are negative
+    pub fn get(&self, barcode: &str) -> TagCall {
+        self.calls.get(barcode).map_or(TagCall::Negative, |x| x.0)
+    }
+
+    /// Write the call for each barcode, in the order given
+    pub fn write_assignments(&self, path: &Path, barcodes: &[&str]) -> io::Result<()> {
+        let mut writer = io::BufWriter::new(File::create(path)?);
+        writeln!(writer, "barcode\tassignment\ttop_count\ttop_fraction")?;
+        for barcode in barcodes {
+            let (call, top_count, fraction) = self.calls.get(*barcode).copied().unwrap_or((TagCall::Negative, 0, 0.0));
+            let label = match call {
+                TagCall::Sample(sample) => self.samples[sample].as_str(),
+                TagCall::Doublet => "Doublet",
+                TagCall::Negative => "Negative",
+            };
+            writeln!(writer, "{}\t{}\t{}\t{:.4}", barcode, label, top_count, fraction)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
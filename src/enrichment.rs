@@ -0,0 +1,289 @@
+use std::{
+    io,
+    fs::File,
+    path::Path,
+    error::Error,
+    io::Write,
+};
+use log::{info, warn};
+use rust_lapper::{Interval, Lapper};
+use rustc_hash::FxHashMap;
+use crate::bed::{read_chrom_sizes, read_regions, region_trees, Region, RegionTrees};
+use crate::cells::cells_from_matches;
+use crate::fasta::Fasta;
+use crate::fragments::{for_each_fragment_with_progress, ReadOptions};
+use crate::progress::Progress;
+use crate::sketch::mix;
+
+/// Random placements tried for each background region
+const MAX_TRIES: usize = 50;
+/// Difference in GC content from the peak at which a background placement is accepted
+const GC_TOLERANCE: f64 = 0.02;
+
+/// Insertions of one cell in peaks and in background regions
+#[derive(Clone, Copy, Default)]
+struct CellCounts {
+    fragments: u64,
+    peak: u64,
+    background: u64,
+}
+
+/// Enrichment of insertions in peaks over background regions, per bp of each, and a z-score for the
+/// share of insertions in peaks against the share expected from their widths (normal approximation
+/// to the binomial). None when there are no insertions in either.
+fn enrichment(peak: u64, background: u64, expected: f64) -> (Option<f64>, Option<f64>) {
+    let total = (peak + background) as f64;
+    if total == 0.0 {
+        return (None, None);
+    }
+    let ratio = (background > 0).then(|| (peak as f64 / expected) / (background as f64 / (1.0 - expected)));
+    let zscore = (peak as f64 - total * expected) / (total * expected * (1.0 - expected)).sqrt();
+    (ratio, Some(zscore))
+}
+
+fn format_value(value: Option<f64>) -> String {
+    value.map_or_else(|| "NA".to_string(), |x| format!("{:.4}", x))
+}
+
+/// Bases covered by the regions of each tree, counting overlapping regions once
+fn covered_bases(trees: &RegionTrees) -> u64 {
+    trees.values().map(|tree| tree.cov() as u64).sum()
+}
+
+/// Random regions of the same width as each peak on the same chromosome, not overlapping any peak.
+/// With a FASTA file, the placement closest in GC content to the peak is kept.
+fn generate_background(
+    peaks: &[Region],
+    peak_trees: &RegionTrees,
+    genome: &Path,
+    mut fasta: Option<Fasta>,
+    per_peak: usize,
+    seed: u64,
+) -> io::Result<Vec<Region>> {
+    let sizes: FxHashMap<String, u32> = read_chrom_sizes(genome)?.into_iter().collect();
+    let mut order: Vec<&Region> = peaks.iter().collect();
+    // one chromosome of the FASTA is loaded at a time
+    order.sort_by(|a, b| a.chrom.cmp(&b.chrom));
+
+    let mut counter: u64 = 0;
+    let mut draw = |bound: u32| {
+        counter += 1;
+        (mix(seed.wrapping_add(counter)) % bound as u64) as u32
+    };
+    let mut background: Vec<Region> = Vec::with_capacity(peaks.len() * per_peak);
+    let (mut missing, mut unplaced) = (0usize, 0usize);
+    for peak in order {
+        let width = peak.end.saturating_sub(peak.start);
+        let size = match sizes.get(&peak.chrom) {
+            Some(size) if *size > width && width > 0 => *size,
+            _ => {
+                missing += 1;
+                continue;
+            }
+        };
+        let peak_gc = match fasta.as_mut() {
+            Some(fasta) => match fasta.load(&peak.chrom)? {
+                true => fasta.gc_fraction(peak.start, peak.end),
+                false => None,
+            },
+            None => None,
+        };
+        for _ in 0..per_peak {
+            let mut best: Option<(u32, f64)> = None;
+            for _ in 0..MAX_TRIES {
+                let start = draw(size - width + 1);
+                if peak_trees.get(&peak.chrom).is_some_and(|tree| tree.find(start, start + width).next().is_some()) {
+                    continue;
+                }
+                let difference = match (peak_gc, fasta.as_ref()) {
+                    (Some(gc), Some(fasta)) => match fasta.gc_fraction(start, start + width) {
+                        Some(x) => (x - gc).abs(),
+                        None => continue,
+                    },
+                    _ => 0.0,
+                };
+                if best.is_none_or(|(_, best)| difference < best) {
+                    best = Some((start, difference));
+                }
+                if difference <= GC_TOLERANCE {
+                    break;
+                }
+            }
+            match best {
+                Some((start, _)) => background.push(Region {
+                    chrom: peak.chrom.clone(),
+                    start,
+                    end: start + width,
+                    name: ".".to_string(),
+                    strand: '.',
+                }),
+                None => unplaced += 1,
+            }
+        }
+    }
+    if missing > 0 {
+        warn!("{} peaks are on chromosomes missing from the genome file, or span a whole chromosome, and have no background", missing);
+    }
+    if unplaced > 0 {
+        warn!("{} background regions could not be placed outside the peaks", unplaced);
+    }
+    background.sort_by(|a, b| a.chrom.cmp(&b.chrom).then(a.start.cmp(&b.start)));
+    Ok(background)
+}
+
+fn to_trees(regions: &[Region]) -> RegionTrees {
+    let mut intervals: FxHashMap<String, Vec<Interval<u32, usize>>> = FxHashMap::default();
+    for (index, region) in regions.iter().enumerate() {
+        intervals
+            .entry(region.chrom.clone())
+            .or_default()
+            .push(Interval { start: region.start, stop: region.end, val: index });
+    }
+    intervals.into_iter().map(|(chrom, intervals)| (chrom, Lapper::new(intervals))).collect()
+}
+
+/// Compare Tn5 insertions in peaks with insertions in background regions, from a BED file or placed
+/// at random with the widths (and optionally GC content) of the peaks, for each cell and overall
+pub fn run(matches: &clap::ArgMatches, progress: &Progress) -> Result<(), Box<dyn Error>> {
+
+    let frag_file = Path::new(matches.get_one::<String>("fragments").unwrap());
+    info!("Received fragment file: {:?}", frag_file);
+    let read_options = ReadOptions::from_matches(matches)?;
+    let peak_file = Path::new(matches.get_one::<String>("peaks").unwrap());
+    info!("Received peak file: {:?}", peak_file);
+    let min_fragments = *matches.get_one::<u64>("min_fragments").unwrap();
+
+    let (n_peaks, peak_trees) = region_trees(peak_file)?;
+    let (n_background, background_trees) = match matches.get_one::<String>("background") {
+        Some(path) => {
+            info!("Received background file: {:?}", path);
+            region_trees(Path::new(path))?
+        }
+        None => {
+            let genome = Path::new(matches.get_one::<String>("genome").unwrap());
+            let per_peak = *matches.get_one::<usize>("background_ratio").unwrap();
+            let seed = *matches.get_one::<u64>("seed").unwrap();
+            let fasta = match matches.get_one::<String>("fasta") {
+                Some(path) => Some(Fasta::open(Path::new(path))?),
+                None => None,
+            };
+            info!(
+                "Placing {} background regions per peak, matched for width{}",
+                per_peak, if fasta.is_some() { " and GC content" } else { "" },
+            );
+            let background = generate_background(&read_regions(peak_file)?, &peak_trees, genome, fasta, per_peak, seed)?;
+            if let Some(path) = matches.get_one::<String>("save_background") {
+                info!("Writing background regions: {:?}", path);
+                let mut writer = io::BufWriter::new(File::create(path)?);
+                for region in &background {
+                    writeln!(writer, "{}\t{}\t{}", region.chrom, region.start, region.end)?;
+                }
+                writer.flush()?;
+            }
+            (background.len(), to_trees(&background))
+        }
+    };
+    let peak_bases = covered_bases(&peak_trees);
+    let background_bases = covered_bases(&background_trees);
+    if peak_bases == 0 || background_bases == 0 {
+        return Err("Peak and background regions must both cover at least one base".into());
+    }
+    // share of insertions expected in peaks if they were no more accessible than the background
+    let expected = peak_bases as f64 / (peak_bases + background_bases) as f64;
+    info!("{} peaks ({} bp) and {} background regions ({} bp)", n_peaks, peak_bases, n_background, background_bases);
+
+    // listed cells in order, or every barcode in order of first appearance
+    let listed = cells_from_matches(matches)?;
+    let mut barcodes: Vec<String> = listed.clone().unwrap_or_default();
+    let mut index: FxHashMap<String, usize> = barcodes.iter().enumerate().map(|(i, x)| (x.clone(), i)).collect();
+    let mut counts: Vec<CellCounts> = vec![CellCounts::default(); barcodes.len()];
+    let mut total = CellCounts::default();
+
+    let inside = |trees: &RegionTrees, chrom: &str, pos: u32| {
+        trees.get(chrom).is_some_and(|tree| tree.find(pos, pos.saturating_add(1)).next().is_some())
+    };
+    for_each_fragment_with_progress(frag_file, &read_options, progress, |fragment| {
+        let cell = match index.get(fragment.barcode) {
+            Some(cell) => *cell,
+            None if listed.is_some() => return Ok(()),
+            None => {
+                index.insert(fragment.barcode.to_string(), barcodes.len());
+                barcodes.push(fragment.barcode.to_string());
+                counts.push(CellCounts::default());
+                barcodes.len() - 1
+            }
+        };
+        let cell = &mut counts[cell];
+        cell.fragments += 1;
+        for pos in [fragment.start, fragment.end] {
+            if inside(&peak_trees, fragment.chrom, pos) {
+                cell.peak += 1;
+            }
+            if inside(&background_trees, fragment.chrom, pos) {
+                cell.background += 1;
+            }
+        }
+        Ok(())
+    })?;
+
+    // every listed cell is reported, other barcodes only with enough fragments
+    let cells: Vec<usize> = (0..barcodes.len())
+        .filter(|cell| listed.is_some() || counts[*cell].fragments >= min_fragments)
+        .collect();
+    let mut ratios: Vec<f64> = Vec::with_capacity(cells.len());
+    let mut writer = match matches.get_one::<String>("outfile") {
+        Some(path) => {
+            info!("Writing cell enrichment: {:?}", path);
+            let mut writer = io::BufWriter::new(File::create(path)?);
+            writeln!(writer, "barcode\tfragments\tpeak_insertions\tbackground_insertions\tenrichment\tzscore")?;
+            Some(writer)
+        }
+        None => None,
+    };
+    for cell in &cells {
+        let x = counts[*cell];
+        let (ratio, zscore) = enrichment(x.peak, x.background, expected);
+        ratios.extend(ratio);
+        if let Some(writer) = writer.as_mut() {
+            writeln!(
+                writer, "{}\t{}\t{}\t{}\t{}\t{}",
+                barcodes[*cell], x.fragments, x.peak, x.background, format_value(ratio), format_value(zscore),
+            )?;
+        }
+    }
+    if let Some(mut writer) = writer {
+        writer.flush()?;
+    }
+    for x in &counts {
+        total.fragments += x.fragments;
+        total.peak += x.peak;
+        total.background += x.background;
+    }
+
+    ratios.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let median = match ratios.len() {
+        0 => None,
+        n if n % 2 == 1 => Some(ratios[n / 2]),
+        n => Some((ratios[n / 2 - 1] + ratios[n / 2]) / 2.0),
+    };
+    let enriched = ratios.iter().filter(|x| **x > 1.0).count();
+    let (ratio, zscore) = enrichment(total.peak, total.background, expected);
+
+    let stdout = io::stdout();
+    let mut writer = io::BufWriter::new(stdout.lock());
+    writeln!(writer, "peaks\t{}", n_peaks)?;
+    writeln!(writer, "peak_bases\t{}", peak_bases)?;
+    writeln!(writer, "background_regions\t{}", n_background)?;
+    writeln!(writer, "background_bases\t{}", background_bases)?;
+    writeln!(writer, "fragments\t{}", total.fragments)?;
+    writeln!(writer, "peak_insertions\t{}", total.peak)?;
+    writeln!(writer, "background_insertions\t{}", total.background)?;
+    writeln!(writer, "expected_peak_fraction\t{:.4}", expected)?;
+    writeln!(writer, "enrichment\t{}", format_value(ratio))?;
+    writeln!(writer, "zscore\t{}", format_value(zscore))?;
+    writeln!(writer, "cells\t{}", cells.len())?;
+    writeln!(writer, "median_cell_enrichment\t{}", format_value(median))?;
+    writeln!(writer, "cells_enriched\t{}", enriched)?;
+    writer.flush()?;
+    Ok(())
+}
@@ -1,144 +1,1499 @@
 use std::{
     io,
     fs,
-    path::Path,
+    path::{Path, PathBuf},
     error::Error,
     fs::File,
-    io::BufReader,
     io::BufRead,
     io::Write,
 };
 use rust_lapper::{Interval, Lapper};
-use flate2::read::MultiGzDecoder;
-use flate2::Compression;
 use log::error;
 use log::info;
 use log::warn;
-use rustc_hash::FxHashMap;
-use gzp::{
-    deflate::Gzip,
-    ZWriter,
-    par::compress::{ParCompress, ParCompressBuilder},
-};
+use rustc_hash::{FxHashMap, FxHashSet};
+use crate::bed::{read_chrom_sizes, Anchor, CoordinateSystem, PeakFormat, Resize};
+use crate::cellindex::CellIndex;
+use crate::convert::{write_matrix, MatrixFormat};
+use crate::cells::{cells_from_matches, load_cells, load_groups, read_barcode_map, Reconcile, TableFormat};
+use crate::fragments::{decode_line, for_each_fragment_with_progress, open_fragments, resolve_path, ReadOptions};
+use crate::profile::{Profile, Stage};
+use crate::progress::Progress;
+use crate::gtf::{gene_intervals, GeneModel, GeneOptions};
+use crate::demux::{SampleAssignment, TagCall};
+use crate::matrix::{self, mtx_header, FeaturesFormat, MatrixValue, OutputCompression, Pattern};
+use crate::output::PartialOutput;
+use crate::perturb::{Perturbations, PerturbationColumns};
+use crate::promoters::{promoter_intervals, PromoterWindow};
+use crate::qc::QcCollector;
+use crate::shard::write_shards;
+use crate::skips::{SkipLog, SkipReason};
+use crate::spatial::SpatialPositions;
+use crate::spill::{CountStore, SpillCounts};
+use crate::stats::FragmentStats;
+use crate::strata::Strata;
+use crate::threads::ThreadConfig;
+use gzp::ZWriter;
+
+pub type PeakTrees = FxHashMap<String, Lapper<u32, usize>>;
+
+/// Counts for each feature; each element is a hashmap of cell index: count
+pub type FeatureCounts = Vec<FxHashMap<u32, u32>>;
 
-pub fn f2m(matches: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+/// Fraction of features without counts above which the features may not match the fragments
+const MAX_ZERO_FEATURES: f64 = 0.5;
 
-    let frag_file = Path::new(matches.get_one::<String>("fragments").unwrap())
-        .canonicalize()
-        .expect("Can't find path to input fragment file");
-    info!("Received fragment file: {:?}", frag_file);
+pub fn f2m(matches: &clap::ArgMatches, progress: &Progress) -> Result<(), Box<dyn Error>> {
+
+    let mut profile = Profile::new(matches.get_flag("profile"));
+    let (inputs, covariate_names) = fragment_inputs(matches)?;
+    let read_options = ReadOptions::from_matches(matches)?;
+    for input in &inputs {
+        info!("Received fragment file: {:?}", input.path);
+    }
 
-    let bed_file = Path::new(matches.get_one::<String>("bed").unwrap())
-        .canonicalize()
-        .expect("Can't find path to input BED file");
+    let bed_file = matches.get_one::<String>("bed").map(|bed| {
+        Path::new(bed)
+            .canonicalize()
+            .expect("Can't find path to input BED file")
+    });
     info!("Received BED file: {:?}", bed_file);
 
-    let cell_file = Path::new(matches.get_one::<String>("cells").unwrap())
-        .canonicalize()
-        .expect("Can't find path to input cell file");
-    info!("Received cell file: {:?}", cell_file);
+    // gene bodies from a GTF file, in place of the BED features
+    let gtf_file = matches.get_one::<String>("gtf").map(PathBuf::from);
+    let gene_options = GeneOptions {
+        upstream: *matches.get_one::<u32>("gene_upstream").unwrap(),
+        downstream: *matches.get_one::<u32>("gene_downstream").unwrap(),
+        model: GeneModel::from_name(matches.get_one::<String>("gene_model").unwrap()).unwrap(),
+    };
+    if let Some(gtf_file) = &gtf_file {
+        info!("Received GTF file: {:?}, counting gene activity with {:?}", gtf_file, gene_options);
+    }
+
+    // promoter windows around the TSSs of a BED file, in place of the BED features
+    let tss_file = matches.get_one::<String>("tss").map(PathBuf::from);
+    let window = PromoterWindow::parse(matches.get_one::<String>("promoter_window").unwrap())?;
+    if let Some(tss_file) = &tss_file {
+        info!("Received TSS file: {:?}, counting promoters with {:?}", tss_file, window);
+    }
+
+    // genome tiles at one or more resolutions
+    let tile_sizes: Vec<u32> = match matches.get_one::<String>("bins") {
+        Some(bins) => bins
+            .split(',')
+            .map(|x| x.trim().parse().ok().filter(|size| *size > 0))
+            .collect::<Option<Vec<u32>>>()
+            .ok_or_else(|| format!("Invalid bin sizes: {}", bins))?,
+        None => Vec::new(),
+    };
+    let chrom_sizes = match matches.get_one::<String>("chrom_sizes") {
+        Some(path) => read_chrom_sizes(Path::new(path))?,
+        None => Vec::new(),
+    };
+    if !tile_sizes.is_empty() {
+        info!("Counting genome bins of size {:?}", tile_sizes);
+    }
+
+    // optional perturbation assignments for CRISPR screens
+    let perturbations = match matches.get_one::<String>("perturbations") {
+        Some(path) => {
+            info!("Received perturbation file: {:?}", path);
+            Some(Perturbations::from_file(Path::new(path))?)
+        }
+        None => None,
+    };
+
+    // spot positions for spatial ATAC
+    let spatial = match matches.get_one::<String>("spatial") {
+        Some(path) => {
+            info!("Received spatial positions file: {:?}", path);
+            Some(SpatialPositions::from_file(Path::new(path))?)
+        }
+        None => None,
+    };
+
+    // cell groups (e.g. clusters) to sum counts over, limited to the listed cells
+    let groups = match matches.get_one::<String>("groups") {
+        Some(_) if inputs.len() > 1 || !inputs[0].suffix.is_empty() => {
+            return Err("--groups needs a single fragment file".into());
+        }
+        Some(path) => {
+            info!("Received cell groups: {:?}, counting one column per group", path);
+            let listed = load_cells(matches)?;
+            Some(load_groups(Path::new(path), &TableFormat::from_matches(matches)?, &Reconcile::from_matches(matches), listed.as_ref())?)
+        }
+        None => None,
+    };
+
+    // cell barcodes, from a text file, a saved index, or the perturbation assignments, cell groups, or spot positions
+    let cells = match matches.get_one::<String>("load_cells_index") {
+        // bulk counts: every fragment is counted, whatever its barcode
+        _ if matches.get_flag("bulk") => CellIndex::All("bulk".to_string()),
+        Some(index_file) => {
+            info!("Loading cell index: {:?}", index_file);
+            CellIndex::open(Path::new(index_file))?
+        }
+        None if !matches.contains_id("cells") => match (&perturbations, &groups) {
+            (Some(perturbations), _) => CellIndex::from_barcodes(perturbations.barcodes()),
+            (None, Some((_, groups))) => {
+                let mut barcodes: Vec<String> = groups.keys().cloned().collect();
+                barcodes.sort_unstable();
+                CellIndex::from_barcodes(barcodes)
+            }
+            (None, None) => CellIndex::from_barcodes(spatial.as_ref().unwrap().barcodes()),
+        },
+        None => CellIndex::from_barcodes(cells_from_matches(matches)?.unwrap()),
+    };
+    if cells.is_empty() {
+        return Err("No cell barcodes to count: the cell list is empty".into());
+    }
+    if let Some(index_file) = matches.get_one::<String>("save_cells_index") {
+        cells.save(Path::new(index_file))?;
+    }
+    // multiome: count fragments by ATAC barcode under the paired GEX barcode
+    let cells = match matches.get_one::<String>("barcode_map") {
+        Some(_) if inputs.len() > 1 => return Err("--barcode-map needs a single fragment file".into()),
+        Some(path) => {
+            info!("Received barcode translation table: {:?}", path);
+            cells.translate(&read_barcode_map(Path::new(path))?)
+        }
+        None => cells,
+    };
+    // counts are summed into the group columns as they are counted, so no per-cell matrix is built
+    let (cells, group_cells) = match groups {
+        Some((names, groups)) => {
+            let mut group_cells: Vec<u32> = vec![0; names.len()];
+            for cell in 0..cells.len() {
+                if let Some(group) = groups.get(cells.barcode(cell)) {
+                    group_cells[*group] += 1;
+                }
+            }
+            (cells.group(&groups, names.clone()), Some((names, group_cells)))
+        }
+        None => (cells, None),
+    };
 
     let output_directory = matches.get_one::<String>("outdir").unwrap();
     info!("Received output directory: {:?}", output_directory);
 
-    let group = matches.get_flag("group");
-    info!("Grouping peaks: {:?}", group);
+    let group = if matches.get_flag("group") {
+        let order = match matches.get_one::<String>("group_list") {
+            Some(list) => {
+                info!("Received peak group list: {:?}", list);
+                GroupOrder::List(read_group_list(Path::new(list))?)
+            }
+            None if matches.get_one::<String>("group_order").unwrap() == "sorted" => GroupOrder::Sorted,
+            None => GroupOrder::Appearance,
+        };
+        Some(order)
+    } else {
+        None
+    };
+    info!("Grouping peaks: {:?}", group.is_some());
+
+    let resize = match matches.get_one::<String>("resize_features") {
+        Some(spec) => Some(Resize::parse(spec)?),
+        None => None,
+    };
+    if let Some(resize) = &resize {
+        info!("Resizing features: {:?}", resize);
+    }
+
+    let format = match matches.get_one::<String>("peak_format") {
+        Some(name) => PeakFormat::from_name(name).unwrap(),
+        None => bed_file.as_deref().map_or(PeakFormat::Bed, PeakFormat::detect),
+    };
+    let min_qvalue = matches.get_one::<f64>("min_qvalue").copied();
+    if min_qvalue.is_some() && format == PeakFormat::Bed {
+        return Err("--min-qvalue requires narrowPeak or broadPeak features".into());
+    }
+    if matches!(resize, Some(Resize { anchor: Anchor::Summit(9), .. })) && format == PeakFormat::BroadPeak {
+        return Err("broadPeak files have no summit column, use anchor=center".into());
+    }
+    info!("Feature format: {:?}", format);
+    let coords = CoordinateSystem::from_name(matches.get_one::<String>("coordinate_system").unwrap()).unwrap();
+    if coords == CoordinateSystem::Gff {
+        info!("Converting 1-based closed feature coordinates to half-open");
+    }
+    let extend = (*matches.get_one::<u32>("extend_upstream").unwrap(), *matches.get_one::<u32>("extend_downstream").unwrap());
+    if extend != (0, 0) {
+        info!("Extending features by {} bp upstream and {} bp downstream", extend.0, extend.1);
+    }
+    let merge_overlapping = matches.get_flag("merge_overlapping");
+    let bed_options = BedOptions { group, resize, format, min_qvalue, coords, extend, merge_overlapping };
 
     let output_path = Path::new(output_directory);
 
-    let num_threads = *matches.get_one::<usize>("threads").unwrap();
+    let num_threads = ThreadConfig::from_matches(matches).compress;
+
+    // optional split into one matrix per sample, by hashtag demultiplexing, barcode suffix,
+    // or samplesheet condition
+    let samples = match matches.get_one::<String>("hashtags") {
+        Some(tag_file) => {
+            info!("Received hashtag count file: {:?}", tag_file);
+            let min_count = *matches.get_one::<u32>("min_tag_count").unwrap();
+            let min_fraction = *matches.get_one::<f64>("min_tag_fraction").unwrap();
+            Some(SampleAssignment::from_tag_counts(Path::new(tag_file), min_count, min_fraction)?)
+        }
+        None if matches.get_flag("split_barcode_suffix") => {
+            let barcodes: Vec<&str> = (0..cells.len()).map(|i| cells.barcode(i)).collect();
+            let separator = matches.get_one::<String>("suffix_separator").unwrap();
+            Some(SampleAssignment::from_barcode_suffixes(&barcodes, separator))
+        }
+        None => match matches.get_one::<String>("condition_column") {
+            Some(column) => {
+                let index = covariate_names
+                    .iter()
+                    .position(|x| x == column)
+                    .ok_or_else(|| format!("No column {} in the samplesheet, found: {}", column, covariate_names.join(", ")))?;
+                if inputs.len() < 2 {
+                    return Err("--condition-column requires more than one fragment file in the samplesheet".into());
+                }
+                let barcodes: Vec<&str> = (0..cells.len()).map(|i| cells.barcode(i)).collect();
+                let names: Vec<String> = inputs.iter().map(FragmentInput::sample_name).collect();
+                let suffixes: Vec<&str> = inputs.iter().map(|input| input.suffix.as_str()).collect();
+                let conditions: Vec<&str> = inputs.iter().map(|input| input.covariates[index].as_str()).collect();
+                Some(SampleAssignment::from_conditions(&barcodes, &names, &suffixes, column, &conditions)?)
+            }
+            None => None,
+        },
+    };
+
+    // optional fragment filtering and stratification
+    let mut strata = Strata::from_matches(matches)?;
+    if (strata.len() > 1 || bed_file.iter().len() + gtf_file.iter().len() + tss_file.iter().len() + tile_sizes.len() > 1) && samples.is_some() {
+        return Err("--hashtags, --split-barcode-suffix, and --condition-column cannot be combined with stratified or multiple matrices".into());
+    }
+    let pseudobulk = matches.get_flag("pseudobulk");
+    let columns = match perturbations {
+        Some(perturbations) => Some(perturbations.columns(&cells, pseudobulk)),
+        // one column per merged sample
+        None if pseudobulk && (inputs.len() > 1 || !inputs[0].suffix.is_empty()) => {
+            let suffixes: Vec<&str> = inputs.iter().map(|input| input.suffix.as_str()).collect();
+            Some(PerturbationColumns::from_samples(
+                &cells,
+                inputs.iter().map(FragmentInput::sample_name).collect(),
+                &suffixes,
+                covariate_names,
+                inputs.iter().map(|input| input.covariates.clone()).collect(),
+            ))
+        }
+        None if pseudobulk => return Err("--pseudobulk requires --perturbations or more than one fragment file".into()),
+        None => None,
+    };
+
+    if strata.scale().is_some() && samples.is_some() {
+        return Err("--hashtags, --split-barcode-suffix, and --condition-column cannot be combined with --scale-subsample".into());
+    }
+
+    let output_options = OutputOptions {
+        low_memory: matches.get_flag("low_memory"),
+        debug_skips: matches.get_flag("debug_skips"),
+        shards: matches.get_one::<u32>("shard_output").map(|x| *x as usize),
+        features_detected: matches.get_flag("features_detected"),
+        permute_seed: matches.get_one::<u64>("permute_cells").copied(),
+        all_outputs: matches.get_flag("all_outputs"),
+        raw_min_fragments: matches
+            .get_flag("raw_matrix")
+            .then(|| *matches.get_one::<u64>("raw_min_fragments").unwrap()),
+        top_features: matches.get_one::<usize>("top_features").map(|n| {
+            let rank = match matches.get_one::<String>("rank_by").unwrap().as_str() {
+                "cells-detected" => FeatureRank::CellsDetected,
+                _ => FeatureRank::Total,
+            };
+            (*n, rank)
+        }),
+        compression: OutputCompression::from_name(matches.get_one::<String>("compress").unwrap()).unwrap(),
+        counting: CountOptions::from_matches(matches)?,
+        binarize: matches.get_flag("binarize"),
+        group_fractions: matches.get_flag("group_fractions"),
+        features_format: FeaturesFormat::from_name(matches.get_one::<String>("features_format").unwrap()).unwrap(),
+        format: MatrixFormat::from_name(matches.get_one::<String>("format").unwrap()).unwrap(),
+        parameters: run_parameters(matches),
+        background_flanks: matches
+            .get_one::<u32>("background_flanks")
+            .map(|width| (*width, *matches.get_one::<u32>("background_gap").unwrap())),
+    };
+    if output_options.features_format == FeaturesFormat::Bed && bed_options.group.is_some() {
+        return Err("--features-format bed cannot be used with --group, as peak groups are not regions".into());
+    }
+    // fail before counting rather than when the matrix is written
+    #[cfg(not(feature = "hdf5"))]
+    if output_options.format != MatrixFormat::Mtx {
+        return Err(crate::convert::no_hdf5(output_options.format));
+    }
+
+    let feature_file = match (&bed_file, &gtf_file, &tss_file) {
+        (Some(bed_file), _, _) => Some(FeatureFile::Bed(bed_file, &bed_options)),
+        (None, Some(gtf_file), _) => Some(FeatureFile::Gtf(gtf_file, gene_options)),
+        (None, None, Some(tss_file)) => Some(FeatureFile::Tss(tss_file, window)),
+        (None, None, None) => None,
+    };
+
+    let set_names: Vec<String> = feature_file
+        .iter()
+        .map(|x| x.set_name().to_string())
+        .chain(tile_sizes.iter().map(|size| format!("bins_{}", size)))
+        .collect();
+    matrix::check_output_paths(
+        &output_paths(output_path, &set_names, &strata, samples.as_ref(), &output_options),
+        matches.get_flag("force"),
+    )?;
 
     // Create the directory if it does not exist
+    // output written by this run is removed again if it fails
+    let mut partial = PartialOutput::new();
     if !output_path.exists() {
-        if let Err(e) = fs::create_dir_all(output_path) {
-            eprintln!("Failed to create output directory: {}", e);
-            std::process::exit(1);
+        partial
+            .create_dir(output_path)
+            .map_err(|e| format!("Failed to create output directory: {}", e))?;
+    }
+
+    // make sure output is a directory
+    let metadata = fs::metadata(output_path)
+        .map_err(|e| format!("Failed to get metadata for {:?}: {}", output_path, e))?;
+    if !metadata.is_dir() {
+        return Err(format!("Provided output is not a directory: {}", output_path.display()).into());
+    }
+    info!("{:?} is a directory.", output_path);
+
+    fcount(
+        &inputs, &read_options, feature_file.as_ref(), &tile_sizes, &chrom_sizes, &cells,
+        output_path, samples.as_ref(), columns.as_ref(), &mut strata, &mut partial,
+        &output_options, num_threads, progress, &mut profile,
+    )?;
+    if let Some(spatial) = &spatial {
+        spatial.write(&output_path.join("spatial"), &cells, &mut partial)?;
+    }
+    if let Some((names, group_cells)) = &group_cells {
+        write_group_cells(&partial.file(output_path.join("groups.tsv")), names, group_cells)?;
+    }
+    if profile.is_enabled() {
+        profile.record(Stage::Write);
+        profile.write(&partial.file(output_path.join("summary.json")))?;
+    }
+    partial.finish();
+
+    Ok(())
+}
+
+/// Files of one matrix directory, relative to it
+fn matrix_files(output_options: &OutputOptions) -> Vec<String> {
+    let compression = output_options.compression;
+    let mut files = vec![compression.file_name("features.tsv")];
+    match (output_options.shards, output_options.format) {
+        (Some(_), _) => files.extend([format!("shard_0/{}", compression.file_name("matrix.mtx")), "manifest.json".to_string()]),
+        (None, MatrixFormat::Mtx) => files.extend([compression.file_name("matrix.mtx"), "barcodes.tsv".to_string()]),
+        (None, format) => files.push(format.file_name().to_string()),
+    }
+    files
+}
+
+/// Matrix files the run will write, with a directory for each feature set, stratum, sample, and
+/// control matrix, so existing output is found before counting rather than overwritten
+fn output_paths(
+    output: &Path,
+    set_names: &[String],
+    strata: &Strata,
+    samples: Option<&SampleAssignment>,
+    output_options: &OutputOptions,
+) -> Vec<PathBuf> {
+    let files = matrix_files(output_options);
+    let in_dir = |dir: &Path| files.iter().map(|file| dir.join(file)).collect::<Vec<PathBuf>>();
+    if let Some(samples) = samples {
+        let mut paths = vec![output.join(output_options.compression.file_name("features.tsv"))];
+        for name in &samples.samples {
+            paths.extend(in_dir(&output.join(name)));
+        }
+        return paths;
+    }
+
+    let mut paths: Vec<PathBuf> = Vec::new();
+    for (set, name) in set_names.iter().enumerate() {
+        let set_dir = if set_names.len() > 1 { output.join(name) } else { output.to_path_buf() };
+        for stratum in &strata.names {
+            let dir = set_dir.join(stratum);
+            paths.extend(in_dir(&dir));
+            let mut controls: Vec<&str> = Vec::new();
+            if output_options.permute_seed.is_some() {
+                controls.push("permuted");
+            }
+            if output_options.raw_min_fragments.is_some() {
+                controls.push("raw");
+            }
+            if output_options.top_features.is_some() {
+                controls.push("top_features");
+            }
+            if output_options.background_flanks.is_some() && set == 0 {
+                controls.extend(["background", "ratio"]);
+            }
+            for control in controls {
+                paths.extend(in_dir(&dir.join(control)));
+            }
+        }
+    }
+    paths
+}
+
+/// A fragment file counted into the matrices
+pub struct FragmentInput {
+    pub path: PathBuf,
+    /// appended to the barcodes of this file when several files are merged, empty otherwise
+    pub suffix: String,
+    /// values of the samplesheet covariate columns
+    pub covariates: Vec<String>,
+}
+
+impl FragmentInput {
+    /// Name of the sample's pseudobulk column: the barcode suffix without its separator
+    fn sample_name(&self) -> String {
+        let name = self.suffix.trim_start_matches(|c: char| !c.is_alphanumeric());
+        if name.is_empty() { self.suffix.clone() } else { name.to_string() }
+    }
+}
+
+/// Fragment files from --fragments, given one or more times, or from a samplesheet.
+/// When there are several files, -1, -2, ... is appended to the barcodes of each file
+/// unless the samplesheet gives a suffix in its second column. Further samplesheet columns are
+/// sample covariates, named by a header line starting with `path` (column_3, ... otherwise).
+/// Returns the inputs and the covariate names.
+fn fragment_inputs(matches: &clap::ArgMatches) -> Result<(Vec<FragmentInput>, Vec<String>), Box<dyn Error>> {
+    let mut inputs: Vec<(PathBuf, Option<String>, Vec<String>)> = Vec::new();
+    let mut covariate_names: Vec<String> = Vec::new();
+    if let Some(paths) = matches.get_many::<String>("fragments") {
+        for path in paths {
+            inputs.push((resolve_path(path)?, None, Vec::new()));
+        }
+    }
+    if let Some(sheet) = matches.get_one::<String>("samplesheet") {
+        info!("Received samplesheet: {:?}", sheet);
+        // paths are relative to the samplesheet
+        let sheet_dir = Path::new(sheet).parent().unwrap_or(Path::new(""));
+        for (index, line) in matrix::open_text(Path::new(sheet))?.lines().enumerate() {
+            let line = line?;
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            if index == 0 && fields[0].eq_ignore_ascii_case("path") {
+                covariate_names = fields.iter().skip(2).map(|x| x.to_string()).collect();
+                continue;
+            }
+            let path = sheet_dir.join(fields[0]);
+            let path = path.canonicalize().map_err(|e| format!("Can't find fragment file {:?}: {}", path, e))?;
+            let suffix = fields.get(1).filter(|x| !x.is_empty()).map(|x| x.to_string());
+            let covariates: Vec<String> = fields.iter().skip(2).map(|x| x.to_string()).collect();
+            inputs.push((path, suffix, covariates));
+        }
+        if inputs.is_empty() {
+            return Err(format!("No fragment files in samplesheet {:?}", sheet).into());
+        }
+        let n_covariates = inputs.iter().map(|(_, _, covariates)| covariates.len()).max().unwrap_or(0);
+        if covariate_names.len() < n_covariates {
+            covariate_names.extend((covariate_names.len()..n_covariates).map(|i| format!("column_{}", i + 3)));
+        }
+        for (path, _, covariates) in inputs.iter_mut() {
+            if covariates.len() != covariate_names.len() {
+                warn!("{:?}: Expected {} covariates in the samplesheet, missing values are NA", path, covariate_names.len());
+                covariates.resize(covariate_names.len(), "NA".to_string());
+            }
+        }
+    }
+
+    if inputs.len() == 1 && inputs[0].1.is_none() {
+        let (path, _, covariates) = inputs.pop().unwrap();
+        return Ok((vec![FragmentInput { path, suffix: String::new(), covariates }], covariate_names));
+    }
+    let inputs: Vec<FragmentInput> = inputs
+        .into_iter()
+        .enumerate()
+        .map(|(i, (path, suffix, covariates))| FragmentInput {
+            path,
+            suffix: suffix.unwrap_or_else(|| format!("-{}", i + 1)),
+            covariates,
+        })
+        .collect();
+    let mut seen: FxHashSet<&str> = FxHashSet::default();
+    for input in &inputs {
+        if !seen.insert(&input.suffix) {
+            return Err(format!("Barcode suffix {} is given to more than one fragment file", input.suffix).into());
+        }
+    }
+    info!("Merging {} fragment files with barcode suffixes", inputs.len());
+    Ok((inputs, covariate_names))
+}
+
+/// Value of each argument of the run, including defaults, in the order of the arguments
+fn run_parameters(matches: &clap::ArgMatches) -> Vec<(String, String)> {
+    matches
+        .ids()
+        .filter_map(|id| {
+            let values = matches.try_get_raw(id.as_str()).ok()??;
+            let values: Vec<String> = values.map(|x| x.to_string_lossy().into_owned()).collect();
+            Some((id.to_string(), values.join(",")))
+        })
+        .collect()
+}
+
+/// How counts are held and written
+pub struct OutputOptions {
+    /// spill counts to disk after each chromosome
+    pub low_memory: bool,
+    /// write fragments that were not counted to skipped.tsv.gz
+    pub debug_skips: bool,
+    /// number of column shards to write instead of a single matrix
+    pub shards: Option<usize>,
+    /// write the number of features detected in each cell
+    pub features_detected: bool,
+    /// seed for an additional matrix with cells permuted
+    pub permute_seed: Option<u64>,
+    /// write QC metrics, fragment sizes, and chromosome statistics from the counting pass
+    pub all_outputs: bool,
+    /// minimum fragments of the barcodes in an additional raw matrix
+    pub raw_min_fragments: Option<u64>,
+    /// number of features in an additional matrix of the top-ranked features
+    pub top_features: Option<(usize, FeatureRank)>,
+    /// compression of the matrix and feature files
+    pub compression: OutputCompression,
+    /// what is counted in each feature, and the shift applied to fragment ends
+    pub counting: CountOptions,
+    /// write 0/1 pattern matrices instead of counts
+    pub binarize: bool,
+    /// write the fraction of each cell's counts in each peak group
+    pub group_fractions: bool,
+    /// columns of the features.tsv files
+    pub features_format: FeaturesFormat,
+    /// file format of the count matrices
+    pub format: MatrixFormat,
+    /// arguments of the run, stored with h5ad matrices
+    pub parameters: Vec<(String, String)>,
+    /// width and gap of the flanks on each side of a BED feature counted as its local background
+    pub background_flanks: Option<(u32, u32)>,
+}
+
+/// What is counted in each feature
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum CountMode {
+    /// Tn5 insertions (fragment ends), up to two per fragment
+    #[default]
+    Insertions,
+    /// fragments overlapping the feature, once per fragment
+    Fragments,
+    /// paired insertion counting (PIC): fragments with either insertion in the feature, once per fragment
+    Pic,
+    /// the start insertion only, once per fragment
+    FivePrime,
+}
+
+/// Which features a fragment overlapping several of them is counted in
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Assign {
+    /// every feature it overlaps
+    #[default]
+    All,
+    /// only the feature with the largest overlap, the first feature on ties (as featureCounts --largestOverlap)
+    Best,
+}
+
+/// Which genes a stranded fragment (strand in column 6) is counted in
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Strandedness {
+    /// genes on either strand, ignoring column 6
+    #[default]
+    Unstranded,
+    /// genes on the same strand as the fragment
+    Forward,
+    /// genes on the opposite strand, as for dUTP libraries
+    Reverse,
+}
+
+impl Strandedness {
+    /// Whether a fragment on `strand` is counted in a gene on `gene_strand`; genes without a strand take either
+    fn keep(&self, strand: char, gene_strand: char) -> bool {
+        match self {
+            _ if gene_strand != '+' && gene_strand != '-' => true,
+            Strandedness::Unstranded => true,
+            Strandedness::Forward => strand == gene_strand,
+            Strandedness::Reverse => strand != gene_strand,
+        }
+    }
+}
+
+impl CountMode {
+    pub fn from_name(name: &str) -> Option<CountMode> {
+        match name {
+            "insertions" => Some(CountMode::Insertions),
+            "fragments" => Some(CountMode::Fragments),
+            "pic" => Some(CountMode::Pic),
+            _ => None,
+        }
+    }
+}
+
+/// How fragments are counted in the features
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CountOptions {
+    pub mode: CountMode,
+    /// bp added to fragment starts, e.g. 4 to move alignment ends to the Tn5 cut site
+    pub shift_plus: i32,
+    /// bp added to fragment ends, e.g. -5
+    pub shift_minus: i32,
+    /// fraction of a fragment that must lie within a feature for it to be counted there
+    pub min_overlap: Option<f64>,
+    /// count each fragment as many times as its duplicate count (column 5) instead of once
+    pub weight_by_duplicates: bool,
+    pub assign: Assign,
+    pub strandedness: Strandedness,
+}
+
+impl CountOptions {
+    pub fn from_matches(matches: &clap::ArgMatches) -> io::Result<CountOptions> {
+        let (shift_plus, shift_minus) = if matches.get_flag("tn5_shift") {
+            (4, -5)
+        } else {
+            (*matches.get_one::<i32>("shift_plus").unwrap(), *matches.get_one::<i32>("shift_minus").unwrap())
+        };
+        if shift_plus != 0 || shift_minus != 0 {
+            info!("Shifting fragment starts by {} bp and ends by {} bp", shift_plus, shift_minus);
+        }
+        let min_overlap = matches.get_one::<f64>("min_overlap").copied();
+        if let Some(fraction) = min_overlap {
+            if !(fraction > 0.0 && fraction <= 1.0) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "--min-overlap must be in (0, 1]"));
+            }
+            info!("Counting fragments only in features holding at least {:.0}% of the fragment", fraction * 100.0);
+        }
+        let mut mode = match matches.get_flag("five_prime_only") {
+            true => CountMode::FivePrime,
+            false => CountMode::from_name(matches.get_one::<String>("mode").unwrap()).unwrap(),
+        };
+        // a fragment with both insertions in a feature adds 1 there, which is paired insertion counting;
+        // the other modes already count each fragment at most once per feature
+        if mode == CountMode::Insertions && matches.get_flag("once_per_fragment") {
+            info!("Counting each fragment at most once per feature");
+            mode = CountMode::Pic;
+        }
+        let assign = match matches.get_one::<String>("assign").map(String::as_str) {
+            Some("best") => {
+                info!("Counting fragments overlapping several features only in the feature with the largest overlap");
+                Assign::Best
+            }
+            _ => Assign::All,
+        };
+        let strandedness = match matches.get_one::<String>("strandedness").map(String::as_str) {
+            Some("forward") => Strandedness::Forward,
+            Some("reverse") => Strandedness::Reverse,
+            _ => Strandedness::Unstranded,
+        };
+        if strandedness != Strandedness::Unstranded {
+            info!("Counting fragments only in genes on the {} strand", match strandedness {
+                Strandedness::Forward => "same",
+                _ => "opposite",
+            });
+        }
+        Ok(CountOptions {
+            mode,
+            shift_plus,
+            shift_minus,
+            min_overlap,
+            weight_by_duplicates: matches.get_flag("weight_by_duplicates"),
+            assign,
+            strandedness,
+        })
+    }
+
+    /// Bases of the fragment from `start` to `end` within the feature from `feature_start` to `feature_end`
+    fn overlap(start: u32, end: u32, feature_start: u32, feature_end: u32) -> u32 {
+        end.min(feature_end).saturating_sub(start.max(feature_start))
+    }
+
+    /// Whether enough of the fragment from `start` to `end` lies within the feature from `feature_start` to `feature_end`
+    fn enough_overlap(&self, start: u32, end: u32, feature_start: u32, feature_end: u32) -> bool {
+        match self.min_overlap {
+            Some(fraction) => {
+                let overlap = CountOptions::overlap(start, end, feature_start, feature_end);
+                overlap as f64 >= fraction * end.saturating_sub(start).max(1) as f64
+            }
+            None => true,
+        }
+    }
+}
+
+/// How features are ranked for the top features matrix
+#[derive(Clone, Copy)]
+pub enum FeatureRank {
+    /// total count over all cells
+    Total,
+    /// number of cells with a nonzero count
+    CellsDetected,
+}
+
+/// Regions counted into one matrix: the features of a BED file, the genes of a GTF file, or the
+/// promoters of a TSS file
+pub enum FeatureFile<'a> {
+    Bed(&'a Path, &'a BedOptions),
+    Gtf(&'a Path, GeneOptions),
+    Tss(&'a Path, PromoterWindow),
+}
+
+impl FeatureFile<'_> {
+    fn path(&self) -> &Path {
+        match self {
+            FeatureFile::Bed(path, _) | FeatureFile::Gtf(path, _) | FeatureFile::Tss(path, _) => path,
+        }
+    }
+
+    /// Subdirectory of the matrix when there are several feature sets
+    fn set_name(&self) -> &'static str {
+        match self {
+            FeatureFile::Bed(..) => "peaks",
+            FeatureFile::Gtf(..) => "genes",
+            FeatureFile::Tss(..) => "promoters",
+        }
+    }
+}
+
+/// How features are read from the BED file
+pub struct BedOptions {
+    pub group: Option<GroupOrder>,
+    pub resize: Option<Resize>,
+    pub format: PeakFormat,
+    /// minimum -log10 q-value of narrowPeak and broadPeak features
+    pub min_qvalue: Option<f64>,
+    pub coords: CoordinateSystem,
+    /// bp added upstream and downstream of each region, by its strand (column 6) if given
+    pub extend: (u32, u32),
+    /// merge overlapping regions (of the same group, with `group`) into one feature
+    pub merge_overlapping: bool,
+}
+
+/// How feature indices are assigned to peak groups with `--group`
+pub enum GroupOrder {
+    /// order in which groups first appear in the BED file
+    Appearance,
+    /// sorted by group name, independent of BED order
+    Sorted,
+    /// order given in a group list file
+    List(Vec<String>),
+}
+
+/// Read a group list, one name per line, rejecting duplicates
+fn read_group_list(path: &Path) -> io::Result<Vec<String>> {
+    let names: Vec<String> = matrix::read_names(path)?
+        .into_iter()
+        .filter(|x| !x.is_empty())
+        .collect();
+    let mut seen: FxHashSet<&str> = FxHashSet::default();
+    for name in &names {
+        if !seen.insert(name) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Duplicate group in group list: {}", name),
+            ));
+        }
+    }
+    Ok(names)
+}
+
+/// Fixed-width tiles across each chromosome
+pub struct Tiles {
+    pub size: u32,
+    chrom_sizes: Vec<(String, u32)>,
+    /// index of the first tile and number of tiles on each chromosome
+    chroms: FxHashMap<String, (usize, usize)>,
+    total: usize,
+}
+
+impl Tiles {
+    pub fn new(size: u32, chrom_sizes: &[(String, u32)]) -> Tiles {
+        let mut chroms = FxHashMap::default();
+        let mut total = 0;
+        for (chrom, length) in chrom_sizes {
+            let n = length.div_ceil(size) as usize;
+            chroms.insert(chrom.clone(), (total, n));
+            total += n;
+        }
+        Tiles { size, chrom_sizes: chrom_sizes.to_vec(), chroms, total }
+    }
+
+    /// Write tiles in index order, as names (chrom-start-end) or in another features format
+    fn write_features(&self, outfile: &Path, format: FeaturesFormat, num_threads: usize) -> io::Result<()> {
+        let mut writer = matrix::compressed_writer(outfile, num_threads)?;
+        let mut output = String::new();
+        for (chrom, length) in &self.chrom_sizes {
+            for start in (0..*length).step_by(self.size as usize) {
+                output.push_str(&format.region(chrom, start, (start + self.size).min(*length)));
+                output.push('\n');
+            }
+            writer.write_all(output.as_bytes())?;
+            output.clear();
+        }
+        writer.finish().map_err(io::Error::other)?;
+        Ok(())
+    }
+}
+
+/// A feature set counted into its own matrix
+pub enum Features<'a> {
+    /// BED regions and number of features
+    Peaks(&'a PeakTrees, usize),
+    /// regions which may overlap, such as promoters, and number of features
+    Overlapping(&'a PeakTrees, usize),
+    /// genes, looked up as overlapping features, with the number of genes and the strand of each
+    Genes(&'a PeakTrees, usize, &'a [char]),
+    Tiles(&'a Tiles),
+}
+
+impl Features<'_> {
+    /// BED regions, looked up as overlapping features if any of them overlap
+    fn from_peaks(trees: &PeakTrees, total: usize) -> Features<'_> {
+        match trees.values().any(|tree| overlapping_intervals(tree) > 0) {
+            true => Features::Overlapping(trees, total),
+            false => Features::Peaks(trees, total),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Features::Peaks(_, total) | Features::Overlapping(_, total) | Features::Genes(_, total, _) => *total,
+            Features::Tiles(tiles) => tiles.total,
+        }
+    }
+}
+
+/// Count fragments in the features of a BED or GTF file and/or genome tiles for each cell, and write
+/// the matrices, their features and barcodes, and any extra outputs to `output`.
+/// Several fragment files are counted into the same matrices, with their barcode suffixes.
+#[allow(clippy::too_many_arguments)]
+pub fn fcount(
+    inputs: &[FragmentInput],
+    read_options: &ReadOptions,
+    feature_file: Option<&FeatureFile>,
+    tile_sizes: &[u32],
+    chrom_sizes: &[(String, u32)],
+    cells: &CellIndex,
+    output: &Path,
+    samples: Option<&SampleAssignment>,
+    columns: Option<&PerturbationColumns>,
+    strata: &mut Strata,
+    partial: &mut PartialOutput,
+    output_options: &OutputOptions,
+    num_threads: usize,
+    progress: &Progress,
+    profile: &mut Profile,
+) -> io::Result<()> {
+    let frag_file = match inputs.first() {
+        Some(input) => input.path.as_path(),
+        None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "No fragment files to count")),
+    };
+    info!(
+        "Processing fragment file: {:?}, feature file: {:?}, {} cells",
+        frag_file, feature_file.map(FeatureFile::path), cells.len()
+    );
+    let merged = inputs.len() > 1 || !inputs[0].suffix.is_empty();
+    let matrix_name = output_options.compression.file_name("matrix.mtx");
+    let features_name = output_options.compression.file_name("features.tsv");
+    if merged && (output_options.low_memory || output_options.debug_skips || output_options.all_outputs
+        || output_options.raw_min_fragments.is_some())
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--low-memory, --debug-skips, --all-outputs, and --raw-matrix need a single fragment file",
+        ));
+    }
+
+    // each feature set is written to its own subdirectory when there are several
+    let n_sets = feature_file.iter().len() + tile_sizes.len();
+    let set_dir = |name: String| if n_sets > 1 { output.join(name) } else { output.to_path_buf() };
+    let mut set_dirs: Vec<PathBuf> = Vec::with_capacity(n_sets);
+
+    // create BED intervals for overlaps with fragment coordinates
+    // returns hashmap with each key being chromosome name
+    // each value is intervals for that chromosome
+    // interval value gives the index of the feature
+    // also writes features to output directory to avoid second iteration of file
+    // write features
+    let mut peaks: Option<(usize, PeakTrees)> = None;
+    let mut gene_strands: Vec<char> = Vec::new();
+    if let Some(feature_file) = feature_file {
+        let dir = set_dir(feature_file.set_name().to_string());
+        partial.create_dir(&dir)?;
+        let feature_path = partial.file(dir.join(&features_name));
+        info!("Writing output feature file: {:?}", &feature_path);
+        let trees = match feature_file {
+            FeatureFile::Bed(bed_file, bed_options) => {
+                peak_intervals(bed_file, bed_options, &feature_path, output_options.features_format, num_threads)
+            }
+            FeatureFile::Gtf(gtf_file, gene_options) => {
+                gene_intervals(gtf_file, gene_options, &feature_path, output_options.features_format, num_threads)
+                    .map(|(total, trees, strands)| {
+                        gene_strands = strands;
+                        (total, trees)
+                    })
+            }
+            FeatureFile::Tss(tss_file, window) => {
+                promoter_intervals(tss_file, window, &feature_path, output_options.features_format, num_threads)
+            }
+        };
+        peaks = match trees {
+            Ok(trees) => Some(trees),
+            Err(e) => {
+                error!("Failed to read feature file: {}", e);
+                return Err(e);
+            }
+        };
+        if matches!(peaks, Some((0, _))) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("No features to count in {:?}", feature_file.path()),
+            ));
+        }
+        set_dirs.push(dir);
+    }
+
+    let tiles: Vec<Tiles> = tile_sizes.iter().map(|size| Tiles::new(*size, chrom_sizes)).collect();
+    if tiles.iter().any(|tile_set| tile_set.total == 0) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "No genome bins to count: the chromosome sizes file is empty"));
+    }
+    for tile_set in &tiles {
+        let dir = set_dir(format!("bins_{}", tile_set.size));
+        partial.create_dir(&dir)?;
+        let feature_path = partial.file(dir.join(&features_name));
+        info!("Writing output feature file: {:?}", &feature_path);
+        tile_set.write_features(&feature_path, output_options.features_format, num_threads)?;
+        set_dirs.push(dir);
+    }
+
+    let mut features: Vec<Features> = Vec::with_capacity(n_sets);
+    match (&peaks, feature_file) {
+        (Some((total_genes, trees)), Some(FeatureFile::Gtf(..))) => features.push(Features::Genes(trees, *total_genes, &gene_strands)),
+        (Some((total_promoters, trees)), Some(FeatureFile::Tss(..))) => features.push(Features::Overlapping(trees, *total_promoters)),
+        (Some((total_peaks, trees)), _) => features.push(Features::from_peaks(trees, *total_peaks)),
+        (None, _) => {}
+    }
+    features.extend(tiles.iter().map(Features::Tiles));
+
+    // flanks of each BED feature, counted in the same pass as its local background
+    let background = match (&peaks, feature_file, output_options.background_flanks) {
+        (Some((total, trees)), Some(FeatureFile::Bed(..)), Some((width, gap))) => Some(flank_intervals(trees, *total, width, gap)),
+        _ => None,
+    };
+    if let Some((trees, _)) = &background {
+        features.push(Features::from_peaks(trees, features[0].len()));
+    }
+
+    // with --raw-matrix, every barcode with enough fragments is counted, after the listed cells
+    let raw_cells = match output_options.raw_min_fragments {
+        Some(min_fragments) => Some(raw_cell_index(frag_file, read_options, cells, min_fragments, progress)?),
+        None => None,
+    };
+    let count_cells = raw_cells.as_ref().unwrap_or(cells);
+
+    // counts held in memory, or spilled to temporary files after each chromosome with --low-memory
+    let mut skips = if output_options.debug_skips {
+        SkipLog::create(&partial.file(output.join("skipped.tsv.gz")), num_threads)?
+    } else {
+        SkipLog::new()
+    };
+    // QC metrics from the same pass with --all-outputs
+    let mut qc = if output_options.all_outputs {
+        let qc_peaks = match feature_file {
+            Some(FeatureFile::Bed(..)) => peaks.as_ref().map(|(_, trees)| trees),
+            _ => None,
+        };
+        QcCollector::new(count_cells.len(), qc_peaks)
+    } else {
+        QcCollector::disabled()
+    };
+    let mut set_counts: Vec<Vec<FeatureCounts>> = Vec::new();
+    let mut spilled: Vec<Vec<Option<SpillCounts>>> = Vec::new();
+    if output_options.low_memory {
+        let mut stores: Vec<Vec<SpillCounts>> = Vec::with_capacity(n_sets);
+        for dir in &set_dirs {
+            let mut set_stores = Vec::with_capacity(strata.len());
+            for stratum in 0..strata.len() {
+                set_stores.push(SpillCounts::create(partial.file(dir.join(format!(".counts_{}.tmp", stratum))))?);
+            }
+            stores.push(set_stores);
+        }
+        count_into(frag_file, read_options, &features, output_options.counting, count_cells, strata, &mut skips, &mut qc, progress, profile, &mut stores)?;
+        spilled = stores.into_iter().map(|x| x.into_iter().map(Some).collect()).collect();
+    } else if merged {
+        set_counts = features
+            .iter()
+            .map(|set| vec![vec![FxHashMap::<u32, u32>::default(); set.len()]; strata.len()])
+            .collect();
+        for input in inputs {
+            info!("Counting {:?} with barcode suffix {}", input.path, input.suffix);
+            let sample_cells = count_cells.strip_suffix(&input.suffix);
+            count_into(&input.path, read_options, &features, output_options.counting, &sample_cells, strata, &mut skips, &mut qc, progress, profile, &mut set_counts)?;
+        }
+    } else {
+        set_counts = count_features(frag_file, read_options, &features, output_options.counting, count_cells, strata, &mut skips, &mut qc, progress, profile)?;
+    }
+    // everything from here is timed as writing
+    profile.mark();
+    skips.finish()?;
+    qc.write(output, frag_file, count_cells, partial)?;
+
+    // raw matrices keep every counted barcode, the others only the listed cells
+    let mut raw_counts: Vec<Vec<FeatureCounts>> = Vec::new();
+    if raw_cells.is_some() {
+        raw_counts = set_counts.clone();
+        let n_cells = cells.len() as u32;
+        for map in set_counts.iter_mut().flatten().flatten() {
+            map.retain(|cell, _| *cell < n_cells);
+        }
+    }
+
+    for (dir, set_counts) in set_dirs.iter().zip(set_counts.iter()) {
+        for (name, counts) in strata.names.iter().zip(set_counts.iter()) {
+            report_feature_coverage(&dir.join(name), counts);
+        }
+    }
+
+    if let Some(samples) = samples {
+        return write_samples(output, samples, cells, &set_counts[0][0], features[0].len(), output_options, partial, num_threads);
+    }
+
+    // cell order for the permuted control matrix, the same for every feature set
+    let permutation = output_options.permute_seed.map(|seed| {
+        info!("Writing permuted control matrices with seed {}", seed);
+        cell_permutation(cells.len(), seed)
+    });
+
+    for (set, (dir, feature_set)) in set_dirs.iter().zip(features.iter()).enumerate() {
+        for (stratum, name) in strata.names.iter().enumerate() {
+            // each stratum is written to its own subdirectory
+            let stratum_dir = dir.join(name);
+            if &stratum_dir != dir {
+                partial.create_dir(&stratum_dir)?;
+                fs::copy(dir.join(&features_name), partial.file(stratum_dir.join(&features_name)))?;
+            }
+
+            if let Some(store) = spilled.get_mut(set).and_then(|x| x[stratum].take()) {
+                let counts_path = partial.file(stratum_dir.join(&matrix_name));
+                info!("Writing output counts file: {:?}", &counts_path);
+                let detected = store.write_matrix_market(&counts_path, feature_set.len(), cells.len(), strata.scale(), output_options.binarize, num_threads)?;
+                let cell_path = partial.file(stratum_dir.join("barcodes.tsv"));
+                info!("Writing output cells file: {:?}", &cell_path);
+                cells.write_barcodes(&cell_path)?;
+                if output_options.features_detected {
+                    let path = partial.file(stratum_dir.join("cells_features_detected.tsv"));
+                    write_features_detected(&path, &column_names(cells, None), &detected)?;
+                }
+            } else {
+                // permuted control, raw, and top feature matrices in subdirectories
+                let permuted;
+                let top;
+                let mut outputs: Vec<(PathBuf, &FeatureCounts, &CellIndex)> = vec![(stratum_dir.clone(), &set_counts[set][stratum], cells)];
+                if let Some(permutation) = &permutation {
+                    let permuted_dir = stratum_dir.join("permuted");
+                    partial.create_dir(&permuted_dir)?;
+                    fs::copy(stratum_dir.join(&features_name), partial.file(permuted_dir.join(&features_name)))?;
+                    permuted = permute_cells(&set_counts[set][stratum], permutation);
+                    outputs.push((permuted_dir, &permuted, cells));
+                }
+                if let Some(raw_cells) = &raw_cells {
+                    let raw_dir = stratum_dir.join("raw");
+                    partial.create_dir(&raw_dir)?;
+                    fs::copy(stratum_dir.join(&features_name), partial.file(raw_dir.join(&features_name)))?;
+                    outputs.push((raw_dir, &raw_counts[set][stratum], raw_cells));
+                }
+                if let Some((n, rank)) = output_options.top_features {
+                    let top_dir = stratum_dir.join("top_features");
+                    partial.create_dir(&top_dir)?;
+                    let ranked = rank_features(&set_counts[set][stratum], rank, n);
+                    write_feature_subset(
+                        &stratum_dir.join(&features_name),
+                        &partial.file(top_dir.join(&features_name)),
+                        &ranked,
+                        num_threads,
+                    )?;
+                    top = ranked.iter().map(|feature| set_counts[set][stratum][*feature].clone()).collect::<FeatureCounts>();
+                    outputs.push((top_dir, &top, cells));
+                }
+
+                for (out_dir, counts, cells) in outputs {
+                    // cell and perturbation pair columns
+                    let remapped;
+                    let peak_cell_counts = match columns {
+                        Some(columns) => {
+                            remapped = columns.remap(counts);
+                            &remapped
+                        }
+                        None => counts,
+                    };
+
+                    match strata.scale() {
+                        _ if output_options.binarize => {
+                            write_counts(&out_dir, &binarize(peak_cell_counts), cells, columns, output_options, partial, num_threads)
+                        }
+                        Some(scale) => {
+                            let scaled: Vec<FxHashMap<u32, f64>> = peak_cell_counts
+                                .iter()
+                                .map(|map| map.iter().map(|(cell, count)| (*cell, *count as f64 * scale)).collect())
+                                .collect();
+                            write_counts(&out_dir, &scaled, cells, columns, output_options, partial, num_threads)
+                        }
+                        None => write_counts(&out_dir, peak_cell_counts, cells, columns, output_options, partial, num_threads),
+                    }?;
+                    if output_options.features_detected {
+                        let names = column_names(cells, columns);
+                        let detected = features_detected(peak_cell_counts, names.len());
+                        let path = partial.file(out_dir.join("cells_features_detected.tsv"));
+                        write_features_detected(&path, &names, &detected)?;
+                    }
+                    if let Some(columns) = columns.filter(|x| x.pseudobulk) {
+                        columns.write_cell_counts(&partial.file(out_dir.join(columns.cell_counts_file())))?;
+                    }
+                }
+                // local background of the BED features, the first feature set, and the ratio to it
+                if let (Some((_, widths)), 0) = (&background, set) {
+                    let background_counts = &set_counts[features.len() - 1][stratum];
+                    let background_dir = stratum_dir.join("background");
+                    let ratio_dir = stratum_dir.join("ratio");
+                    for dir in [&background_dir, &ratio_dir] {
+                        partial.create_dir(dir)?;
+                        fs::copy(stratum_dir.join(&features_name), partial.file(dir.join(&features_name)))?;
+                    }
+                    let remapped;
+                    let (counts, background_counts) = match columns {
+                        Some(columns) => {
+                            remapped = (columns.remap(&set_counts[set][stratum]), columns.remap(background_counts));
+                            (&remapped.0, &remapped.1)
+                        }
+                        None => (&set_counts[set][stratum], background_counts),
+                    };
+                    write_counts(&background_dir, background_counts, cells, columns, output_options, partial, num_threads)?;
+                    let ratios = background_ratios(counts, background_counts, widths);
+                    write_counts(&ratio_dir, &ratios, cells, columns, output_options, partial, num_threads)?;
+                }
+                // peak groups are the features of the BED matrix, the first feature set
+                if output_options.group_fractions && set == 0 {
+                    let groups = matrix::read_names(&stratum_dir.join(&features_name))?;
+                    let path = partial.file(stratum_dir.join("group_fractions.tsv"));
+                    write_group_fractions(&path, &groups, &set_counts[set][stratum], &column_names(cells, None))?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Log how counts are spread over the features, warning when most features have none,
+/// which usually means the features and fragments use different assemblies or chromosome names
+fn report_feature_coverage(dir: &Path, counts: &[FxHashMap<u32, u32>]) {
+    if counts.is_empty() {
+        return;
+    }
+    let mut totals: Vec<u64> = counts.iter().map(|map| map.values().map(|x| *x as u64).sum()).collect();
+    totals.sort_unstable();
+    let quantile = |q: f64| totals[((totals.len() - 1) as f64 * q).round() as usize];
+    let zeros = totals.iter().take_while(|x| **x == 0).count();
+    let zero_fraction = zeros as f64 / totals.len() as f64;
+    info!(
+        "{:?}: {} of {} features ({:.1}%) have no counts; counts per feature: median {}, 90th percentile {}",
+        dir, zeros, totals.len(), 100.0 * zero_fraction, quantile(0.5), quantile(0.9),
+    );
+    if zero_fraction > MAX_ZERO_FEATURES && zeros < totals.len() {
+        warn!(
+            "{:.1}% of features have no counts; check the features use the same genome assembly and chromosome names as the fragments",
+            100.0 * zero_fraction,
+        );
+    }
+}
+
+/// Random permutation of cell indices from a seed (Fisher-Yates with a splitmix64 generator),
+/// so the same seed gives the same permutation on any platform
+fn cell_permutation(n: usize, seed: u64) -> Vec<u32> {
+    let mut state = seed;
+    let mut next = || {
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    };
+    let mut permutation: Vec<u32> = (0..n as u32).collect();
+    for i in (1..n).rev() {
+        let j = (next() % (i as u64 + 1)) as usize;
+        permutation.swap(i, j);
+    }
+    permutation
+}
+
+/// Move the counts of each cell to its permuted cell, keeping each cell's
+/// total depth and feature profile but breaking the link to its barcode
+fn permute_cells(counts: &[FxHashMap<u32, u32>], permutation: &[u32]) -> FeatureCounts {
+    counts
+        .iter()
+        .map(|map| map.iter().map(|(cell, count)| (permutation[*cell as usize], *count)).collect())
+        .collect()
+}
+
+/// Nonzero entries of each feature, for binary output
+fn binarize(counts: &[FxHashMap<u32, u32>]) -> Vec<FxHashMap<u32, Pattern>> {
+    counts.iter().map(|map| map.keys().map(|cell| (*cell, Pattern)).collect()).collect()
+}
+
+/// Indices of the `n` highest-ranked features, in rank order; ties keep the feature order
+fn rank_features(counts: &[FxHashMap<u32, u32>], rank: FeatureRank, n: usize) -> Vec<usize> {
+    let scores: Vec<u64> = counts
+        .iter()
+        .map(|map| match rank {
+            FeatureRank::Total => map.values().map(|x| *x as u64).sum(),
+            FeatureRank::CellsDetected => map.len() as u64,
+        })
+        .collect();
+    let mut ranked: Vec<usize> = (0..counts.len()).collect();
+    ranked.sort_by_key(|feature| std::cmp::Reverse(scores[*feature]));
+    ranked.truncate(n);
+    ranked
+}
+
+/// Write the lines of a features file at the given indices, in that order
+fn write_feature_subset(features: &Path, outfile: &Path, indices: &[usize], num_threads: usize) -> io::Result<()> {
+    info!("Writing top features: {:?}", outfile);
+    let lines: Vec<String> = matrix::open_text(features)?.lines().collect::<io::Result<_>>()?;
+    let mut writer = matrix::compressed_writer(outfile, num_threads)?;
+    for index in indices {
+        writeln!(writer, "{}", lines[*index])?;
+    }
+    writer.finish().map_err(io::Error::other)
+}
+
+/// Index of the listed cells followed by every other barcode with at least `min_fragments` fragments,
+/// in sorted order, from a pass counting the fragments of each barcode
+fn raw_cell_index(frag_file: &Path, read_options: &ReadOptions, cells: &CellIndex, min_fragments: u64, progress: &Progress) -> io::Result<CellIndex> {
+    info!("Counting fragments per barcode for the raw matrix");
+    let mut fragments: FxHashMap<String, u64> = FxHashMap::default();
+    for_each_fragment_with_progress(frag_file, read_options, progress, |fragment| {
+        if cells.get(fragment.barcode).is_none() {
+            match fragments.get_mut(fragment.barcode) {
+                Some(count) => *count += 1,
+                None => {
+                    fragments.insert(fragment.barcode.to_string(), 1);
+                }
+            }
+        }
+        Ok(())
+    })?;
+
+    let mut extra: Vec<String> = fragments
+        .into_iter()
+        .filter(|(_, count)| *count >= min_fragments)
+        .map(|(barcode, _)| barcode)
+        .collect();
+    extra.sort_unstable();
+    info!("Raw matrix: {} barcodes besides the cells have at least {} fragments", extra.len(), min_fragments);
+
+    let barcodes: Vec<String> = (0..cells.len()).map(|i| cells.barcode(i).to_string()).chain(extra).collect();
+    Ok(CellIndex::from_barcodes(barcodes))
+}
+
+/// Names of the output matrix columns: cell barcodes, or perturbation columns
+fn column_names<'a>(cells: &'a CellIndex, columns: Option<&'a PerturbationColumns>) -> Vec<&'a str> {
+    match columns {
+        Some(columns) => columns.names.iter().map(String::as_str).collect(),
+        None => (0..cells.len()).map(|i| cells.barcode(i)).collect(),
+    }
+}
+
+/// Number of features with a nonzero count in each column
+fn features_detected<T>(counts: &[FxHashMap<u32, T>], ncol: usize) -> Vec<u32> {
+    let mut detected: Vec<u32> = vec![0; ncol];
+    for map in counts {
+        for column in map.keys() {
+            detected[*column as usize] += 1;
+        }
+    }
+    detected
+}
+
+/// Write the number of features detected in each cell, for QC thresholds without loading the matrix
+fn write_features_detected(path: &Path, names: &[&str], detected: &[u32]) -> io::Result<()> {
+    info!("Writing features detected per cell: {:?}", path);
+    let mut writer = io::BufWriter::new(File::create(path)?);
+    writeln!(writer, "barcode\tfeatures_detected")?;
+    for (name, count) in names.iter().zip(detected.iter()) {
+        writeln!(writer, "{}\t{}", name, count)?;
+    }
+    writer.flush()
+}
+
+/// Write the number of cells summed into each group column, for normalizing pseudobulk counts
+fn write_group_cells(path: &Path, names: &[String], cells: &[u32]) -> io::Result<()> {
+    info!("Writing cells per group: {:?}", path);
+    let mut writer = io::BufWriter::new(File::create(path)?);
+    writeln!(writer, "group\tcells")?;
+    for (name, count) in names.iter().zip(cells.iter()) {
+        writeln!(writer, "{}\t{}", name, count)?;
+    }
+    writer.flush()
+}
+
+/// Write the fraction of each cell's counts in each peak group, so each cell's fractions sum to 1.
+/// Cells without counts in any group have NA fractions.
+fn write_group_fractions(path: &Path, groups: &[String], counts: &[FxHashMap<u32, u32>], names: &[&str]) -> io::Result<()> {
+    info!("Writing peak group fractions per cell: {:?}", path);
+    let mut totals: Vec<u64> = vec![0; names.len()];
+    for map in counts {
+        for (cell, count) in map {
+            totals[*cell as usize] += *count as u64;
         }
     }
-
-    // make sure output is a directory
-    match fs::metadata(output_path) {
-        Ok(metadata) => {
-            if metadata.is_dir() {
-                info!("{:?} is a directory.", output_path);
-            } else {
-                eprintln!("Provided output is not a directory: {}", output_path.display());
-                std::process::exit(1);
+    let mut writer = io::BufWriter::new(File::create(path)?);
+    writeln!(writer, "barcode\t{}", groups.join("\t"))?;
+    for (cell, (name, total)) in names.iter().zip(totals.iter()).enumerate() {
+        write!(writer, "{}", name)?;
+        for map in counts {
+            match total {
+                0 => write!(writer, "\tNA")?,
+                total => write!(writer, "\t{:.6}", *map.get(&(cell as u32)).unwrap_or(&0) as f64 / *total as f64)?,
             }
         }
-        Err(e) => {
-            eprintln!("Failed to get metadata for {:?}: {}", output_path, e);
-            std::process::exit(1);
-        }
+        writeln!(writer)?;
     }
-
-    fcount(&frag_file, &bed_file, &cell_file, output_path, group, num_threads)?;
-    
-    Ok(())
+    writer.flush()
 }
 
-fn fcount(
-    frag_file: &Path,
-    bed_file: &Path,
-    cell_file: &Path,
-    output: &Path,
-    group: bool,
+/// Write a feature x column count matrix and its column names to a matrix directory,
+/// as column shards, or as a single HDF5 file with the feature names from the directory
+fn write_counts<T: MatrixValue>(
+    dir: &Path,
+    counts: &[FxHashMap<u32, T>],
+    cells: &CellIndex,
+    columns: Option<&PerturbationColumns>,
+    output_options: &OutputOptions,
+    partial: &mut PartialOutput,
     num_threads: usize,
 ) -> io::Result<()> {
-    info!(
-        "Processing fragment file: {:?}, BED file: {:?}, Cell file: {:?}",
-        frag_file, bed_file, cell_file
-    );
+    let compression = output_options.compression;
+    if let Some(n_shards) = output_options.shards {
+        return write_shards(dir, counts, &column_names(cells, columns), n_shards, compression, partial, num_threads);
+    }
 
-    // create BED intervals for overlaps with fragment coordinates
-    // returns hashmap with each key being chromosome name
-    // each value is intervals for that chromosome
-    // interval value gives the index of the feature
-    // also writes features to output directory to avoid second iteration of file
-    // write features
-    let feature_path = output.join("features.tsv.gz");
-    info!("Writing output feature file: {:?}", &feature_path);
-    let (total_peaks, mut peaks) = match peak_intervals(bed_file, group, &feature_path, num_threads) {
-        Ok(trees) => trees,
-        Err(e) => {
-            error!("Failed to read BED file: {}", e);
-            return Err(e);
-        }
-    };
+    if output_options.format != MatrixFormat::Mtx {
+        let file_name = output_options.format.file_name();
+        let features = matrix::read_names(&dir.join(compression.file_name("features.tsv")))?;
+        let barcodes = column_names(cells, columns).into_iter().map(str::to_string).collect();
+        let path = partial.file(dir.join(file_name));
+        let matrix = matrix::sparse_matrix(counts, features, barcodes);
+        return write_matrix(&path, output_options.format, &matrix, &output_options.parameters, num_threads)
+            .map_err(|e| io::Error::other(e.to_string()));
+    }
 
-    // create hashmap for cell barcodes
-    let cellreader = File::open(cell_file)
-        .map(BufReader::new)?;
-    
-    let mut cells: FxHashMap<String, u32> = FxHashMap::default();
-    for (index, line) in cellreader.lines().enumerate() {
-        let line = line?;
-        let index_u32 = index as u32;
-        cells.insert(line, index_u32);
+    // write count matrix, features stored as rows
+    let counts_path = partial.file(dir.join(compression.file_name("matrix.mtx")));
+    info!("Writing output counts file: {:?}", &counts_path);
+    let ncol = columns.map_or(cells.len(), |x| x.len());
+    write_matrix_market(&counts_path, counts, counts.len(), ncol, num_threads)?;
+
+    // write cells
+    let cell_path = partial.file(dir.join("barcodes.tsv"));
+    info!("Writing output cells file: {:?}", &cell_path);
+    match columns {
+        Some(columns) => columns.write_names(&cell_path),
+        None => cells.write_barcodes(&cell_path),
     }
+}
+
+/// Count fragment insertions in each peak for each cell.
+/// Returns a vector over features; each element is a hashmap of cell index: count
+pub fn count_fragments(
+    frag_file: &Path,
+    read_options: &ReadOptions,
+    peaks: &PeakTrees,
+    cells: &CellIndex,
+    total_peaks: usize,
+    progress: &Progress,
+) -> io::Result<Vec<FxHashMap<u32, u32>>> {
+    let mut counts = count_features(
+        frag_file, read_options, &[Features::from_peaks(peaks, total_peaks)], CountOptions::default(), cells,
+        &mut Strata::none(), &mut SkipLog::new(), &mut QcCollector::disabled(), progress,
+        &mut Profile::disabled(),
+    )?;
+    Ok(counts.swap_remove(0).swap_remove(0))
+}
+
+/// Count fragment insertions (or fragments) in each feature for each cell, in a single pass over the fragments.
+/// Returns counts for each feature set and stratum; fragments that the strata filter out are not counted.
+/// Stops with a `Cancelled` error if `progress` is cancelled. Stage timings are added to an enabled `profile`.
+#[allow(clippy::too_many_arguments)]
+pub fn count_features(
+    frag_file: &Path,
+    read_options: &ReadOptions,
+    features: &[Features],
+    options: CountOptions,
+    cells: &CellIndex,
+    strata: &mut Strata,
+    skips: &mut SkipLog,
+    qc: &mut QcCollector,
+    progress: &Progress,
+    profile: &mut Profile,
+) -> io::Result<Vec<Vec<FeatureCounts>>> {
 
-    // vector of features
+    // vector of features for each feature set and stratum
     // each element is hashmap of cell: count
-    let mut peak_cell_counts: Vec<FxHashMap<u32, u32>> = vec![FxHashMap::<u32, u32>::default(); total_peaks];
+    let mut counts: Vec<Vec<FeatureCounts>> = features
+        .iter()
+        .map(|set| vec![vec![FxHashMap::<u32, u32>::default(); set.len()]; strata.len()])
+        .collect();
+    count_into(frag_file, read_options, features, options, cells, strata, skips, qc, progress, profile, &mut counts)?;
+    Ok(counts)
+}
+
+/// Count fragment insertions (or fragments) into a store for each feature set and stratum
+#[allow(clippy::too_many_arguments)]
+pub fn count_into<S: CountStore>(
+    frag_file: &Path,
+    read_options: &ReadOptions,
+    features: &[Features],
+    options: CountOptions,
+    cells: &CellIndex,
+    strata: &mut Strata,
+    skips: &mut SkipLog,
+    qc: &mut QcCollector,
+    progress: &Progress,
+    profile: &mut Profile,
+    counts: &mut [Vec<S>],
+) -> io::Result<()> {
+    let mode = options.mode;
 
     // frag file reading
-    let frag_file = File::open(frag_file)?;
-    let mut reader = BufReader::with_capacity(1024 * 1024, MultiGzDecoder::new(frag_file));
+    let mut reader = open_fragments(frag_file, read_options)?;
+
+    // cached fragment count, for reporting progress
+    let total_fragments = FragmentStats::load(frag_file).ok().flatten().map(|stats| stats.fragments);
 
     let mut line_count: u64 = 0;
-    let update_interval = 1_000_000;
-    let mut line_str = String::new();
+    // line in the file, including headers, for reporting skipped lines
+    let mut line_number: u64 = 0;
+    let mut line_bytes: Vec<u8> = Vec::new();
     let mut startpos: u32;
     let mut endpos: u32;
 
+    // current chromosome intervals and seek cursor, or first tile and tile count, for each feature set
     let mut current_chrom = String::new();
-    let mut current_lapper: Option<&mut Lapper<u32, usize>> = None;
-    let mut cursor: usize = 0;
-    let mut check_end: bool;
+    let mut current_lappers: Vec<Option<&Lapper<u32, usize>>> = vec![None; features.len()];
+    // span of the features on the current chromosome, from the first start to the last end
+    let mut bounds: Vec<(u32, u32)> = vec![(0, 0); features.len()];
+    let mut cursors: Vec<usize> = vec![0; features.len()];
+    let mut caches: Vec<InsertionCache> = (0..features.len()).map(|_| InsertionCache::default()).collect();
+    let mut current_tiles: Vec<Option<(usize, usize)>> = vec![None; features.len()];
+    let mut finished_chroms: FxHashSet<String> = FxHashSet::default();
+
+    // to explain empty matrices
+    let mut cell_fragments: u64 = 0;
+    let mut feature_chrom_fragments: u64 = 0;
+    let mut insertions: u64 = 0;
 
     loop {
 
-        match reader.read_line(&mut line_str) {
+        profile.mark();
+        match reader.read_until(b'\n', &mut line_bytes) {
             Ok(0) => break,
             Ok(_) => {},
             Err(e) => {
@@ -146,43 +1501,90 @@ fn fcount(
                 return Err(e);
             }
         }
-        let line = &line_str[..line_str.len() - 1];
+        profile.record(Stage::Decompress);
+        line_number += 1;
 
         // Skip header lines that start with #
-        if line.starts_with('#') {
-            line_str.clear();
+        if line_bytes.starts_with(b"#") {
+            line_bytes.clear();
             continue;
         }
 
         line_count += 1;
-        if line_count % update_interval == 0 {
-            print!("\rProcessed {} M fragments", line_count / 1_000_000);
-            std::io::stdout().flush().expect("Can't flush output");
+        progress.update(line_count, total_fragments)?;
+
+        let line = match decode_line(&line_bytes) {
+            Some(line) => line,
+            None => {
+                skips.record(line_number, SkipReason::InvalidUtf8, String::from_utf8_lossy(&line_bytes).trim_end())?;
+                line_bytes.clear();
+                continue;
+            }
+        };
+
+        qc.add(line, cells);
+
+        if !strata.keep(line) {
+            skips.record(line_number, SkipReason::Subsampled, line)?;
+            line_bytes.clear();
+            continue;
         }
 
         // Parse BED entry
         let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 4 {
+            skips.record(line_number, SkipReason::Malformed, line)?;
+            line_bytes.clear();
+            continue;
+        }
 
         // Check if cell is to be included
         let cell_barcode: &str = fields[3];
-        if let Some(&cell_index) = cells.get(cell_barcode) {
-            check_end = true;
+        if let Some(cell_index) = cells.get(cell_barcode) {
+            cell_fragments += 1;
 
             // create intervals from fragment entry
             let seqname: &str = fields[0];
 
             if seqname != current_chrom {
+                if S::SORTED_INPUT {
+                    for store in counts.iter_mut().flatten() {
+                        store.end_chrom()?;
+                    }
+                    if !finished_chroms.insert(seqname.to_string()) {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("Fragment file is not sorted by chromosome ({} seen twice)", seqname),
+                        ));
+                    }
+                }
                 current_chrom = seqname.to_string();
-                current_lapper = peaks.get_mut(&current_chrom);
-                cursor = 0;
+                for (set, feature_set) in features.iter().enumerate() {
+                    match feature_set {
+                        Features::Peaks(peaks, _) | Features::Overlapping(peaks, _) | Features::Genes(peaks, _, _) => {
+                            current_lappers[set] = peaks.get(&current_chrom);
+                            bounds[set] = current_lappers[set].map_or((0, 0), feature_bounds);
+                        }
+                        Features::Tiles(tiles) => current_tiles[set] = tiles.chroms.get(&current_chrom).copied(),
+                    }
+                    cursors[set] = 0;
+                    caches[set].coords = None;
+                }
+            }
+            if current_lappers.iter().all(Option::is_none) && current_tiles.iter().all(Option::is_none) {
+                skips.record(line_number, SkipReason::UnknownChrom, line)?;
+                line_bytes.clear();
+                continue;
             }
+            feature_chrom_fragments += 1;
 
             // try to parse the coordinates, skip the line if parsing fails
             startpos = match fields[1].trim().parse() {
                 Ok(num) => num,
                 Err(e) => {
                     warn!("Failed to parse start position: {:?}. Error: {}", line_count, e);
-                    line_str.clear();
+                    skips.record(line_number, SkipReason::InvalidStart, line)?;
+                    line_bytes.clear();
                     continue;
                 }
             };
@@ -191,70 +1593,472 @@ fn fcount(
                 Ok(num) => num,
                 Err(e) => {
                     warn!("Failed to parse end position: {:?}. Error: {}", line_count, e);
-                    line_str.clear();
+                    skips.record(line_number, SkipReason::InvalidEnd, line)?;
+                    line_bytes.clear();
                     continue;
                 }
             };
+            // reads supporting the fragment, for read-level counts
+            let weight: u32 = match options.weight_by_duplicates {
+                true => match fields.get(4).and_then(|x| x.trim().parse().ok()).filter(|x| *x > 0) {
+                    Some(count) => count,
+                    None => {
+                        skips.record(line_number, SkipReason::InvalidCount, line)?;
+                        line_bytes.clear();
+                        continue;
+                    }
+                },
+                false => 1,
+            };
+            // strand of RNA-like fragments, for counting in sense genes only
+            let strand = match options.strandedness {
+                Strandedness::Unstranded => '.',
+                _ => match fields.get(5).map(|x| x.trim()) {
+                    Some("+") => '+',
+                    Some("-") => '-',
+                    _ => {
+                        skips.record(line_number, SkipReason::MissingStrand, line)?;
+                        line_bytes.clear();
+                        continue;
+                    }
+                },
+            };
+            // e.g. move alignment ends to the Tn5 cut sites
+            startpos = startpos.saturating_add_signed(options.shift_plus);
+            endpos = endpos.saturating_add_signed(options.shift_minus);
+            if !strata.keep_length(startpos, endpos) {
+                skips.record(line_number, SkipReason::LengthFilter, line)?;
+                line_bytes.clear();
+                continue;
+            }
 
-            if let Some(lapper) = &mut current_lapper {
-                // seems to be a problem with seek if lapper has one element
-                // set cursor to 0
-                if lapper.intervals.len() == 1 {
-                    cursor = 0;
+            let stratum = match strata.classify(seqname, startpos, endpos)? {
+                Some(stratum) => stratum,
+                None => {
+                    skips.record(line_number, SkipReason::GcFilter, line)?;
+                    line_bytes.clear();
+                    continue;
                 }
-                for interval in lapper.seek(startpos, startpos + 1, &mut cursor) {
-                    let peak_index = interval.val;
-                    let peak_end = interval.stop;
-                    *peak_cell_counts[peak_index].entry(cell_index).or_insert(0) += 1;
-
-                    // Check if fragment end is behind peak end (if so, it overlaps and we don't need a full search)
-                    if endpos < peak_end {
-                        check_end = false;
-                        *peak_cell_counts[peak_index].entry(cell_index).or_insert(0) += 1;
+            };
+            profile.record(Stage::Parse);
+
+            for (set, set_counts) in counts.iter_mut().enumerate() {
+                let peak_cell_counts = &mut set_counts[stratum];
+                let (first, last) = bounds[set];
+                let in_bounds = |pos: u32| pos >= first && pos < last;
+                // fragments outside the span of the features need no lookup
+                let may_overlap = match mode {
+                    CountMode::Insertions | CountMode::Pic => in_bounds(startpos) || in_bounds(endpos),
+                    CountMode::Fragments => startpos < last && endpos >= first,
+                    CountMode::FivePrime => in_bounds(startpos),
+                };
+                if let Some(lapper) = current_lappers[set].filter(|_| may_overlap) {
+                    let peaks = find_peaks(lapper, &options, &features[set], &mut cursors[set], &mut caches[set], startpos, endpos, strand);
+                    profile.record(Stage::Overlap);
+                    for peak_index in peaks {
+                        peak_cell_counts.add(*peak_index, cell_index, weight)?;
                     }
+                    insertions += peaks.len() as u64;
+                    profile.record(Stage::Accumulate);
                 }
-                if check_end {
-                    for interval in lapper.seek(endpos, endpos + 1, &mut cursor) {
-                        let peak_index = interval.val;
-                        *peak_cell_counts[peak_index].entry(cell_index).or_insert(0) += 1;
+                if let (Some((first, n)), Features::Tiles(tiles)) = (current_tiles[set], &features[set]) {
+                    let enough_overlap = |tile: usize| {
+                        let tile_start = tile as u32 * tiles.size;
+                        options.enough_overlap(startpos, endpos, tile_start, tile_start.saturating_add(tiles.size))
+                    };
+                    match mode {
+                        CountMode::Insertions | CountMode::Pic | CountMode::FivePrime => {
+                            let start_tile = (startpos / tiles.size) as usize;
+                            let end_tile = (endpos / tiles.size) as usize;
+                            // with PIC, a tile holding both insertions is counted once
+                            let count_end = match mode {
+                                CountMode::Insertions => true,
+                                CountMode::Pic => end_tile != start_tile,
+                                _ => false,
+                            };
+                            // with --assign best, only the tile holding more of the fragment
+                            let (start_tile, count_end) = match options.assign {
+                                Assign::Best if count_end && end_tile != start_tile && end_tile < n => {
+                                    let tile_end = (start_tile as u32 + 1).saturating_mul(tiles.size);
+                                    let end_overlap = endpos - end_tile as u32 * tiles.size;
+                                    match end_overlap > tile_end - startpos {
+                                        true => (end_tile, false),
+                                        false => (start_tile, false),
+                                    }
+                                }
+                                _ => (start_tile, count_end),
+                            };
+                            for tile in [Some(start_tile), count_end.then_some(end_tile)].into_iter().flatten() {
+                                if tile < n && enough_overlap(tile) {
+                                    peak_cell_counts.add(first + tile, cell_index, weight)?;
+                                    insertions += 1;
+                                }
+                            }
+                        }
+                        CountMode::Fragments => {
+                            let end_tile = ((endpos / tiles.size) as usize).min(n.saturating_sub(1));
+                            let mut spanned = ((startpos / tiles.size) as usize, end_tile);
+                            // with --assign best, only the tile holding the most of the fragment
+                            if options.assign == Assign::Best {
+                                let overlap = |tile: usize| {
+                                    let tile_start = tile as u32 * tiles.size;
+                                    CountOptions::overlap(startpos, endpos, tile_start, tile_start.saturating_add(tiles.size))
+                                };
+                                let best = (spanned.0..=spanned.1).min_by_key(|tile| (std::cmp::Reverse(overlap(*tile)), *tile));
+                                spanned = best.map_or(spanned, |tile| (tile, tile));
+                            }
+                            for tile in (spanned.0..=spanned.1).filter(|tile| enough_overlap(*tile)) {
+                                peak_cell_counts.add(first + tile, cell_index, weight)?;
+                                insertions += 1;
+                            }
+                        }
                     }
+                    // tile lookups are arithmetic, so the time is counted with accumulation
+                    profile.record(Stage::Accumulate);
                 }
             }
         }
-        line_str.clear();
+        line_bytes.clear();
     }
-    eprintln!();
-    
-    // write count matrix
-    let counts_path = output.join("matrix.mtx.gz");
-    info!("Writing output counts file: {:?}", &counts_path);
-    write_matrix_market(&counts_path, &peak_cell_counts, total_peaks, cells.len(), num_threads)
-        .expect("Failed to write matrix"); // features stored as rows
+    progress.finish(line_count, total_fragments);
 
-    // write cells
-    let cell_path = output.join("barcodes.tsv");
-    info!("Writing output cells file: {:?}", &cell_path);
-    write_cells(&cell_path, cell_file)
-        .expect("Failed to write cells");
+    if line_count == 0 {
+        warn!("No fragments in {:?}, the matrices are empty", frag_file);
+    } else if cell_fragments == 0 {
+        warn!("None of the {} cell barcodes are in the fragment file, the matrices are empty", cells.len());
+    } else if feature_chrom_fragments == 0 {
+        warn!("No cell fragments are on chromosomes with features (check the chromosome names match), the matrices are empty");
+    } else if insertions == 0 {
+        warn!("No cell fragments overlap any feature, the matrices are empty");
+    }
 
     Ok(())
 }
 
-fn write_cells(
-    outfile: &Path,
-    cells: &Path,
+/// Flanks of `width` bp on each side of each feature, `gap` bp away from it, less any part
+/// within a feature, as intervals sharing the feature index. Returns the trees and, for each
+/// feature, the bases of the feature and of its flanks.
+fn flank_intervals(trees: &PeakTrees, total: usize, width: u32, gap: u32) -> (PeakTrees, Vec<(u64, u64)>) {
+    let mut widths: Vec<(u64, u64)> = vec![(0, 0); total];
+    let mut flank_trees = PeakTrees::default();
+    for (chrom, lapper) in trees {
+        let mut intervals: Vec<Interval<u32, usize>> = Vec::new();
+        for interval in lapper.iter() {
+            widths[interval.val].0 += (interval.stop - interval.start) as u64;
+            let left = (interval.start.saturating_sub(gap.saturating_add(width)), interval.start.saturating_sub(gap));
+            let right = (interval.stop.saturating_add(gap), interval.stop.saturating_add(gap).saturating_add(width));
+            for (start, end) in [left, right] {
+                // cut out the features within the flank
+                let mut pieces: Vec<(u32, u32)> = vec![(start, end)];
+                for feature in lapper.find(start, end) {
+                    pieces = pieces
+                        .into_iter()
+                        .flat_map(|(a, b)| [(a, b.min(feature.start)), (a.max(feature.stop), b)])
+                        .filter(|(a, b)| a < b)
+                        .collect();
+                }
+                for (a, b) in pieces {
+                    widths[interval.val].1 += (b - a) as u64;
+                    intervals.push(Interval { start: a, stop: b, val: interval.val });
+                }
+            }
+        }
+        flank_trees.insert(chrom.clone(), Lapper::new(intervals));
+    }
+    let without = widths.iter().filter(|(_, flank)| *flank == 0).count();
+    if without > 0 {
+        warn!("{} features have no background flanks outside other features", without);
+    }
+    (flank_trees, widths)
+}
+
+/// Counts of each feature relative to its local background, per bp of each: the count over the
+/// background count plus one, scaled by the bases of the feature over the bases of the background.
+/// Zero where the feature has no counts or no background flanks.
+fn background_ratios(counts: &[FxHashMap<u32, u32>], background: &[FxHashMap<u32, u32>], widths: &[(u64, u64)]) -> Vec<FxHashMap<u32, f64>> {
+    counts
+        .iter()
+        .zip(background.iter())
+        .zip(widths.iter())
+        .map(|((counts, background), (width, flank))| match flank {
+            0 => FxHashMap::default(),
+            flank => counts
+                .iter()
+                .map(|(cell, count)| {
+                    let expected = (*background.get(cell).unwrap_or(&0) as f64 + 1.0) * *width as f64 / *flank as f64;
+                    (*cell, *count as f64 / expected)
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+/// First start and last end of the features on a chromosome
+fn feature_bounds(lapper: &Lapper<u32, usize>) -> (u32, u32) {
+    let first = lapper.intervals.first().map_or(0, |interval| interval.start);
+    let last = lapper.intervals.iter().map(|interval| interval.stop).max().unwrap_or(0);
+    (first, last)
+}
+
+/// Peaks containing the insertions of (or overlapping) the last fragment looked up. Sorted fragment files hold runs of
+/// identical coordinates from different cells, which reuse the lookup instead of searching the peaks again.
+#[derive(Default)]
+struct InsertionCache {
+    /// start, end, and strand of the fragment
+    coords: Option<(u32, u32, char)>,
+    /// peak of each insertion, listed twice if it contains both
+    peaks: Vec<usize>,
+}
+
+/// Peaks to count a fragment in: each peak once for each insertion it holds, or once for each
+/// peak the fragment overlaps, depending on the count mode. Genes are only counted on the strand
+/// given by `options.strandedness`.
+#[allow(clippy::too_many_arguments)]
+fn find_peaks<'a>(
+    lapper: &Lapper<u32, usize>,
+    options: &CountOptions,
+    features: &Features,
+    cursor: &mut usize,
+    cache: &'a mut InsertionCache,
+    startpos: u32,
+    endpos: u32,
+    strand: char,
+) -> &'a [usize] {
+    if cache.coords != Some((startpos, endpos, strand)) {
+        let overlapping = matches!(features, Features::Overlapping(..) | Features::Genes(..));
+        let keep = |interval: &Interval<u32, usize>| options.enough_overlap(startpos, endpos, interval.start, interval.stop);
+        match options.mode {
+            CountMode::Insertions => find_peak_insertions(lapper, overlapping, cursor, startpos, endpos, keep, &mut cache.peaks),
+            CountMode::Fragments => find_peak_fragments(lapper, cursor, startpos, endpos, keep, &mut cache.peaks),
+            CountMode::Pic => {
+                find_peak_insertions(lapper, overlapping, cursor, startpos, endpos, keep, &mut cache.peaks);
+                // a peak holding both insertions is counted once
+                cache.peaks.sort_unstable();
+                cache.peaks.dedup();
+            }
+            CountMode::FivePrime => find_peak_starts(lapper, cursor, startpos, keep, &mut cache.peaks),
+        }
+        if let Features::Genes(_, _, strands) = features {
+            cache.peaks.retain(|gene| options.strandedness.keep(strand, strands[*gene]));
+            // a fragment spanning several exons of a gene is counted once
+            if options.mode == CountMode::Fragments {
+                cache.peaks.sort_unstable();
+                cache.peaks.dedup();
+            }
+        }
+        if options.assign == Assign::Best {
+            keep_best_peak(lapper, startpos, endpos, &mut cache.peaks);
+        }
+        cache.coords = Some((startpos, endpos, strand));
+    }
+    &cache.peaks
+}
+
+/// Keep only the peak with the largest overlap with the fragment, the lowest peak index on ties.
+/// A peak listed for both insertions stays listed twice.
+fn keep_best_peak(lapper: &Lapper<u32, usize>, startpos: u32, endpos: u32, peaks: &mut Vec<usize>) {
+    if peaks.iter().all(|peak| *peak == peaks[0]) {
+        return;
+    }
+    // grouped peaks share an index, so their overlaps are summed
+    let mut overlaps: Vec<(usize, u32)> = Vec::new();
+    for interval in lapper.find(startpos, endpos.saturating_add(1)).filter(|interval| peaks.contains(&interval.val)) {
+        let overlap = CountOptions::overlap(startpos, endpos, interval.start, interval.stop);
+        match overlaps.iter_mut().find(|(peak, _)| *peak == interval.val) {
+            Some((_, total)) => *total += overlap,
+            None => overlaps.push((interval.val, overlap)),
+        }
+    }
+    if let Some((best, _)) = overlaps.iter().min_by_key(|(peak, overlap)| (std::cmp::Reverse(*overlap), *peak)) {
+        let best = *best;
+        peaks.retain(|peak| *peak == best);
+    }
+}
+
+/// Find the peaks overlapping a fragment, from its start to its end insertion, that `keep` accepts
+fn find_peak_fragments<F: Fn(&Interval<u32, usize>) -> bool>(
+    lapper: &Lapper<u32, usize>,
+    cursor: &mut usize,
+    startpos: u32,
+    endpos: u32,
+    keep: F,
+    peaks: &mut Vec<usize>,
+) {
+    peaks.clear();
+    if lapper.intervals.len() == 1 {
+        *cursor = 0;
+    }
+    peaks.extend(lapper.seek(startpos, endpos.saturating_add(1), cursor).filter(|interval| keep(interval)).map(|interval| interval.val));
+}
+
+/// Find the peaks containing the start insertion of a fragment that `keep` accepts
+fn find_peak_starts<F: Fn(&Interval<u32, usize>) -> bool>(
+    lapper: &Lapper<u32, usize>,
+    cursor: &mut usize,
+    startpos: u32,
+    keep: F,
+    peaks: &mut Vec<usize>,
+) {
+    peaks.clear();
+    if lapper.intervals.len() == 1 {
+        *cursor = 0;
+    }
+    peaks.extend(lapper.seek(startpos, startpos.saturating_add(1), cursor).filter(|interval| keep(interval)).map(|interval| interval.val));
+}
+
+/// Find the peaks containing each insertion of a fragment that `keep` accepts. With `overlapping`
+/// peaks, the end insertion is always looked up, as it can be in a peak without the start insertion.
+fn find_peak_insertions<F: Fn(&Interval<u32, usize>) -> bool>(
+    lapper: &Lapper<u32, usize>,
+    overlapping: bool,
+    cursor: &mut usize,
+    startpos: u32,
+    endpos: u32,
+    keep: F,
+    peaks: &mut Vec<usize>,
+) {
+    let mut check_end = true;
+    peaks.clear();
+
+    // seems to be a problem with seek if lapper has one element
+    // set cursor to 0
+    if lapper.intervals.len() == 1 {
+        *cursor = 0;
+    }
+    for interval in lapper.seek(startpos, startpos.saturating_add(1), cursor) {
+        // Check if fragment end is behind peak end (if so, it overlaps and we don't need a full search)
+        let contains_end = endpos < interval.stop;
+        if contains_end {
+            check_end = false;
+        }
+        if !keep(interval) {
+            continue;
+        }
+        peaks.push(interval.val);
+        if contains_end {
+            peaks.push(interval.val);
+        }
+    }
+    if check_end || overlapping {
+        // fragment ends are not sorted, and moving the cursor past an overlapping feature would
+        // miss it for the next fragment start, so overlapping features are searched without it
+        let found = match overlapping {
+            true => lapper.find(endpos, endpos.saturating_add(1)),
+            false => lapper.seek(endpos, endpos.saturating_add(1), cursor),
+        };
+        for interval in found {
+            // peaks containing the start were counted above
+            if interval.start > startpos && keep(interval) {
+                peaks.push(interval.val);
+            }
+        }
+    }
+}
+
+/// Split the counts by sample assignment, writing one matrix directory per sample, and a
+/// manifest of the matrices when split by condition.
+/// Doublets and negative cells are not written to any matrix.
+#[allow(clippy::too_many_arguments)]
+fn write_samples(
+    output: &Path,
+    samples: &SampleAssignment,
+    cells: &CellIndex,
+    peak_cell_counts: &[FxHashMap<u32, u32>],
+    total_peaks: usize,
+    output_options: &OutputOptions,
+    partial: &mut PartialOutput,
+    num_threads: usize,
 ) -> io::Result<()> {
-    // Copy cell barcodes to output directory
-    match fs::copy(&cells, &outfile) {
-        Ok(bytes_copied) => info!("Successfully copied {} bytes.", bytes_copied),
-        Err(e) => eprintln!("Failed to copy file: {}", e),
+
+    let compression = output_options.compression;
+    let barcodes: Vec<&str> = (0..cells.len()).map(|i| cells.barcode(i)).collect();
+
+    if samples.from_tags() {
+        let assignment_path = partial.file(output.join("assignments.tsv"));
+        info!("Writing sample assignments: {:?}", &assignment_path);
+        samples.write_assignments(&assignment_path, &barcodes)?;
+    }
+
+    // sample and column within that sample for each cell
+    let mut sample_barcodes: Vec<Vec<&str>> = vec![Vec::new(); samples.samples.len()];
+    let mut cell_columns: Vec<Option<(usize, u32)>> = Vec::with_capacity(barcodes.len());
+    for barcode in &barcodes {
+        match samples.get(barcode) {
+            TagCall::Sample(sample) => {
+                cell_columns.push(Some((sample, sample_barcodes[sample].len() as u32)));
+                sample_barcodes[sample].push(barcode);
+            }
+            _ => cell_columns.push(None),
+        }
+    }
+
+    let mut sample_counts: Vec<Vec<FxHashMap<u32, u32>>> = vec![vec![FxHashMap::default(); total_peaks]; samples.samples.len()];
+    for (peak_index, cell_counts) in peak_cell_counts.iter().enumerate() {
+        for (&cell_index, &count) in cell_counts {
+            if let Some((sample, column)) = cell_columns[cell_index as usize] {
+                sample_counts[sample][peak_index].insert(column, count);
+            }
+        }
+    }
+
+    let features_name = compression.file_name("features.tsv");
+    let feature_path = output.join(&features_name);
+    for (sample, name) in samples.samples.iter().enumerate() {
+        let sample_dir = output.join(name);
+        partial.create_dir(&sample_dir)?;
+        info!("Writing {} cells for sample {}: {:?}", sample_barcodes[sample].len(), name, &sample_dir);
+
+        let matrix_path = partial.file(sample_dir.join(compression.file_name("matrix.mtx")));
+        if output_options.binarize {
+            write_matrix_market(&matrix_path, &binarize(&sample_counts[sample]), total_peaks, sample_barcodes[sample].len(), num_threads)?;
+        } else {
+            write_matrix_market(&matrix_path, &sample_counts[sample], total_peaks, sample_barcodes[sample].len(), num_threads)?;
+        }
+        fs::copy(&feature_path, partial.file(sample_dir.join(&features_name)))?;
+
+        let mut writer = io::BufWriter::new(File::create(partial.file(sample_dir.join("barcodes.tsv")))?);
+        for barcode in &sample_barcodes[sample] {
+            writeln!(writer, "{}", barcode)?;
+        }
+        writer.flush()?;
+    }
+
+    if let Some((column, members)) = samples.conditions() {
+        let nnz: Vec<usize> = sample_counts.iter().map(|counts| counts.iter().map(|map| map.len()).sum()).collect();
+        let entries: Vec<String> = samples.samples.iter().enumerate().map(|(index, name)| {
+            let sample_names: Vec<String> = members[index].iter().map(|x| matrix::json_string(x)).collect();
+            format!(
+                "    {{\"condition\": {}, \"samples\": [{}], \"matrix\": {}, \"barcodes\": {}, \"cells\": {}, \"nnz\": {}}}",
+                matrix::json_string(name),
+                sample_names.join(", "),
+                matrix::json_string(&format!("{}/{}", name, compression.file_name("matrix.mtx"))),
+                matrix::json_string(&format!("{}/barcodes.tsv", name)),
+                sample_barcodes[index].len(), nnz[index],
+            )
+        }).collect();
+
+        // every condition matrix has the rows of the shared features file, in its order
+        let manifest_path = partial.file(output.join("manifest.json"));
+        info!("Writing condition manifest: {:?}", manifest_path);
+        let mut writer = io::BufWriter::new(File::create(manifest_path)?);
+        writeln!(writer, "{{")?;
+        writeln!(writer, "  \"format\": \"mtx\",")?;
+        writeln!(writer, "  \"software_version\": \"fragtk-{}\",", env!("CARGO_PKG_VERSION"))?;
+        writeln!(writer, "  \"condition_column\": {},", matrix::json_string(column))?;
+        writeln!(writer, "  \"features\": {},", matrix::json_string(&features_name))?;
+        writeln!(writer, "  \"n_features\": {},", total_peaks)?;
+        writeln!(writer, "  \"conditions\": [\n{}\n  ]", entries.join(",\n"))?;
+        writeln!(writer, "}}")?;
+        writer.flush()?;
     }
+
     Ok(())
 }
 
-fn write_matrix_market(
+/// Write a feature x column count matrix in gzip-compressed Matrix Market format
+pub fn write_matrix_market<T: MatrixValue>(
     outfile: &Path,
-    peak_cell_counts: &[FxHashMap<u32, u32>],
+    peak_cell_counts: &[FxHashMap<u32, T>],
     nrow: usize,
     ncol: usize,
     num_threads: usize,
@@ -263,28 +2067,22 @@ fn write_matrix_market(
     // get nonzero value count
     let nonzero: usize = peak_cell_counts.iter().map(|map| map.len()).sum();
 
-    // create output file
-    let writer = File::create(outfile)?;
-    let mut encoder: ParCompress<Gzip> = ParCompressBuilder::new()
-        .compression_level(Compression::default())  // Set compression level
-        .num_threads(num_threads)
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))? 
-        .from_writer(writer);
+    // create output file, compressed according to its extension
+    let mut encoder = matrix::compressed_writer(outfile, num_threads)?;
 
     // Create a string buffer to collect all lines
     let mut output = String::new();
 
     // Write the header for the Matrix Market format
-    output.push_str("%%MatrixMarket matrix coordinate integer general\n");
-    output.push_str("%%metadata json: {{\"software_version\": \"fragtk-1.1.0\"}}\n");
-    output.push_str(&format!("{} {} {}\n", nrow, ncol, nonzero));
+    // field (integer/real) follows the count value type
+    output.push_str(&mtx_header(T::FIELD, nrow, ncol, nonzero));
     encoder.write_all(output.as_bytes())?;
     output.clear();
 
     // Collect each peak-cell-count entry into the string buffer
     for (index, hashmap) in peak_cell_counts.iter().enumerate() {
         for (key, value) in hashmap.iter() {
-            output.push_str(&format!("{} {} {}\n", index + 1, key + 1, value)); // +1 to convert 0-based to 1-based indices
+            output.push_str(&value.entry(index + 1, *key as usize + 1)); // +1 to convert 0-based to 1-based indices
         }
         // write chunk, clear string
         if index % 5000 == 0 {
@@ -298,51 +2096,54 @@ fn write_matrix_market(
         encoder.write_all(output.as_bytes())?;
     }
 
-    encoder.finish().map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    encoder.finish().map_err(io::Error::other)?;
 
     Ok(())
 }
 
-fn peak_intervals(
+/// Read features from a BED, narrowPeak, or broadPeak file into interval trees for each chromosome,
+/// writing the feature names to `outfile`. Returns the number of features and the trees.
+pub fn peak_intervals(
     bed_file: &Path,
-    group: bool,
+    options: &BedOptions,
     outfile: &Path,
+    format: FeaturesFormat,
     num_threads: usize,
-) -> io::Result<(usize, FxHashMap<String, Lapper<u32, usize>>)> {
+) -> io::Result<(usize, PeakTrees)> {
 
     // feature file
-    let writer = File::create(outfile)?;
-    let mut writer: ParCompress<Gzip> = ParCompressBuilder::new()
-        .compression_level(Compression::default())
-        .num_threads(num_threads)
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
-        .from_writer(writer);
+    let mut writer = matrix::compressed_writer(outfile, num_threads)?;
     
-    // bed file reader
-    let file = File::open(bed_file)?;
-    let reader = BufReader::new(file);
+    // bed file reader, optionally gzipped
+    let reader = matrix::open_text(bed_file)?;
     
     // hashmap of peak intervals for each chromosome
     let mut chromosome_trees: FxHashMap<String, Vec<Interval<u32, usize>>> = FxHashMap::default();
+    // chromosomes in order of appearance, to write merged features in file order
+    let mut chromosomes: Vec<String> = Vec::new();
     
-    // Store peak group name and corresponding index
+    // Store peak group name and the order it was first seen
     let mut peak_group_index: FxHashMap<String, usize> = FxHashMap::default();
+    let mut peak_groups: Vec<String> = Vec::new();
     
-    // track total number of peaks
+    // track total number of peaks, also the index of the next peak
     let mut total_peaks: usize = 0;
 
-    // index for peak groups
-    let mut current_index: usize = 0;
+    // peaks below the q-value threshold
+    let mut filtered: usize = 0;
 
-    // skipped lines
-    let mut skipped_lines: usize = 0;
-
-    for (index, line) in reader.lines().enumerate() {
+    for (index, line) in reader.split(b'\n').enumerate() {
 
         match line {
             Ok(line) => {
+                let line = match decode_line(&line) {
+                    Some(line) => line,
+                    None => {
+                        error!("Line {}: Not valid UTF-8", index + 1);
+                        continue;
+                    }
+                };
                 if line.starts_with('#') {
-                    skipped_lines += 1;
                     continue;
                 }
                 let fields: Vec<&str> = line.split('\t').collect();
@@ -362,29 +2163,69 @@ fn peak_intervals(
                             continue;
                         }
                     };
+                    let (start, end) = match options.coords.to_half_open(start, end) {
+                        Some(coords) => coords,
+                        None => {
+                            error!("Line {}: Start position must be at least 1 for 1-based coordinates", index + 1);
+                            continue;
+                        }
+                    };
+
+                    if fields.len() < options.format.min_fields() {
+                        error!("Line {}: Expected {} fields for {:?}", index + 1, options.format.min_fields(), options.format);
+                        continue;
+                    }
+                    if let Some(min_qvalue) = options.min_qvalue {
+                        if options.format.qvalue(&fields).is_none_or(|q| q < min_qvalue) {
+                            filtered += 1;
+                            continue;
+                        }
+                    }
 
-                    let intervals = chromosome_trees.entry(chromosome.clone()).or_insert_with(Vec::new);
+                    let (start, end) = match &options.resize {
+                        Some(resize) => match resize.apply(start, end, &fields) {
+                            Some(coords) => coords,
+                            None => {
+                                error!("Line {}: Failed to parse summit position", index + 1);
+                                continue;
+                            }
+                        },
+                        None => (start, end),
+                    };
+                    // upstream is after the end on the minus strand; extensions stop at zero
+                    let (upstream, downstream) = match fields.get(5) {
+                        Some(&"-") => (options.extend.1, options.extend.0),
+                        _ => options.extend,
+                    };
+                    let (start, end) = (start.saturating_sub(upstream), end.saturating_add(downstream));
+                    if !chromosome_trees.contains_key(&chromosome) {
+                        chromosomes.push(chromosome.clone());
+                    }
 
-                    if group && (fields.len() >= 4) {
-                        let peakgroup: String = match fields[3].parse() {
-                            Ok(num) => num,
-                            Err(_) => {
-                                error!("Line {}: Failed to parse group information", index + 1);
+                    if options.group.is_some() {
+                        let peakgroup = match fields.get(3) {
+                            Some(name) => name.to_string(),
+                            None => {
+                                error!("Line {}: Missing group information", index + 1);
                                 continue;
                             }
                         };
 
-                        let group_index = peak_group_index.entry(peakgroup.clone()).or_insert_with(|| {
-                            writeln!(writer, "{}", peakgroup).expect("Failed to write");
-                            let idx: usize = current_index - skipped_lines;
-                            current_index += 1;
-                            idx
+                        // indices are assigned in order of appearance, then remapped below
+                        let group_index = *peak_group_index.entry(peakgroup.clone()).or_insert_with(|| {
+                            peak_groups.push(peakgroup);
+                            peak_groups.len() - 1
                         });
 
-                        intervals.push(Interval { start, stop: end, val: *group_index });
+                        chromosome_trees.entry(chromosome).or_default()
+                            .push(Interval { start, stop: end, val: group_index });
                     } else {
-                        intervals.push(Interval { start, stop: end, val: index - skipped_lines});
-                        writeln!(writer, "{}-{}-{}", chromosome, start, end)?;
+                        // merged features are written once they are known, below
+                        if !options.merge_overlapping {
+                            writeln!(writer, "{}", format.region(&chromosome, start, end))?;
+                        }
+                        chromosome_trees.entry(chromosome).or_default()
+                            .push(Interval { start, stop: end, val: total_peaks });
                     }
                     total_peaks += 1;
                 } else {
@@ -398,16 +2239,517 @@ fn peak_intervals(
         }
     }
 
-    let lapper_map = chromosome_trees.into_iter()
+    if filtered > 0 {
+        info!("Removed {} peaks below the q-value threshold", filtered);
+    }
+
+    if options.merge_overlapping {
+        let grouped = options.group.is_some();
+        let mut merged: usize = 0;
+        for chromosome in &chromosomes {
+            let intervals = chromosome_trees.get_mut(chromosome).unwrap();
+            merge_intervals(intervals, grouped);
+            if !grouped {
+                for interval in intervals.iter_mut() {
+                    writeln!(writer, "{}", format.region(chromosome, interval.start, interval.stop))?;
+                    interval.val = merged;
+                    merged += 1;
+                }
+            }
+        }
+        if !grouped {
+            info!("Merged {} overlapping features into {}", total_peaks, merged);
+            total_peaks = merged;
+        }
+    }
+
+    if let Some(order) = &options.group {
+        let (names, remap) = order_groups(&peak_groups, order);
+        for name in &names {
+            match format {
+                FeaturesFormat::TenX => writeln!(writer, "{0}\t{0}\tPeaks", name)?,
+                _ => writeln!(writer, "{}", name)?,
+            }
+        }
+        for intervals in chromosome_trees.values_mut() {
+            intervals.retain_mut(|iv| match remap[iv.val] {
+                Some(new_index) => {
+                    iv.val = new_index;
+                    true
+                }
+                None => false,
+            });
+        }
+        total_peaks = names.len();
+    }
+
+    let lapper_map: PeakTrees = chromosome_trees.into_iter()
         .map(|(chr, intervals)| (chr, Lapper::new(intervals)))
         .collect();
 
-    if group {
-        total_peaks = current_index;
+    // peaks of different groups may overlap
+    let overlapping: usize = lapper_map.values().map(overlapping_intervals).sum();
+    if overlapping > 0 && options.group.is_none() {
+        warn!(
+            "{} features overlap an earlier feature. Insertions in overlapping features are counted in each \
+            of them; use --merge-overlapping to merge them into one feature",
+            overlapping,
+        );
     }
 
     // Finalize the compression, converting GzpError to io::Error
-    writer.finish().map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    writer.finish().map_err(io::Error::other)?;
 
     Ok((total_peaks, lapper_map))
-}
\ No newline at end of file
+}
+
+/// Number of intervals overlapping an interval that starts before them
+fn overlapping_intervals(lapper: &Lapper<u32, usize>) -> usize {
+    let mut stop: u32 = 0;
+    let mut overlapping: usize = 0;
+    for interval in &lapper.intervals {
+        if interval.start < stop {
+            overlapping += 1;
+        }
+        stop = stop.max(interval.stop);
+    }
+    overlapping
+}
+
+/// Merge overlapping intervals into one, sorted by start. With `grouped`, only intervals of the
+/// same group (value) are merged; otherwise the merged values are left to the caller.
+fn merge_intervals(intervals: &mut Vec<Interval<u32, usize>>, grouped: bool) {
+    let group = |interval: &Interval<u32, usize>| if grouped { interval.val } else { 0 };
+    intervals.sort_unstable_by_key(|interval| (group(interval), interval.start));
+    let mut merged: Vec<Interval<u32, usize>> = Vec::with_capacity(intervals.len());
+    for interval in intervals.drain(..) {
+        match merged.last_mut() {
+            Some(last) if group(last) == group(&interval) && interval.start < last.stop => {
+                last.stop = last.stop.max(interval.stop);
+            }
+            _ => merged.push(interval),
+        }
+    }
+    merged.sort_unstable_by_key(|interval| interval.start);
+    *intervals = merged;
+}
+
+/// Final group names and the new index of each group in order of appearance.
+/// Groups missing from a group list are dropped.
+fn order_groups(groups: &[String], order: &GroupOrder) -> (Vec<String>, Vec<Option<usize>>) {
+    let names: Vec<String> = match order {
+        GroupOrder::Appearance => groups.to_vec(),
+        GroupOrder::Sorted => {
+            let mut names = groups.to_vec();
+            names.sort_unstable();
+            names
+        }
+        GroupOrder::List(names) => names.clone(),
+    };
+    let index: FxHashMap<&str, usize> = names.iter().enumerate().map(|(i, x)| (x.as_str(), i)).collect();
+    let remap = groups
+        .iter()
+        .map(|name| {
+            let new_index = index.get(name.as_str()).copied();
+            if new_index.is_none() {
+                warn!("Peak group {} not found in group list, skipping its peaks", name);
+            }
+            new_index
+        })
+        .collect();
+    (names, remap)
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CELLS: [&str; 2] = ["A", "B"];
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("fragtk_f2m_{}_{}", std::process::id(), name))
+    }
+
+    /// Interval trees of BED features, numbered in the order given
+    fn peak_trees(features: &[(&str, u32, u32)]) -> PeakTrees {
+        let mut intervals: FxHashMap<String, Vec<Interval<u32, usize>>> = FxHashMap::default();
+        for (index, (chrom, start, stop)) in features.iter().enumerate() {
+            intervals.entry(chrom.to_string()).or_default().push(Interval { start: *start, stop: *stop, val: index });
+        }
+        intervals.into_iter().map(|(chrom, intervals)| (chrom, Lapper::new(intervals))).collect()
+    }
+
+    /// Count fragments given as (chrom, start, end, barcode) in the features, returning
+    /// the (cell, count) entries of each feature, sorted by cell
+    fn count(name: &str, fragments: &[(&str, u32, u32, &str)], features: &Features, options: CountOptions) -> Vec<Vec<(u32, u32)>> {
+        let text: String = fragments
+            .iter()
+            .map(|(chrom, start, end, barcode)| format!("{}\t{}\t{}\t{}\t1\n", chrom, start, end, barcode))
+            .collect();
+        count_lines(name, &text, features, options)
+    }
+
+    /// Counts for fragment lines given as text, e.g. with a strand column
+    fn count_lines(name: &str, text: &str, features: &Features, options: CountOptions) -> Vec<Vec<(u32, u32)>> {
+        let path = temp_path(name);
+        fs::write(&path, text).unwrap();
+        let cells = CellIndex::from_barcodes(CELLS.iter().map(|x| x.to_string()).collect());
+        let counts = count_features(
+            &path, &ReadOptions::default(), std::slice::from_ref(features), options, &cells,
+            &mut Strata::none(), &mut SkipLog::new(), &mut QcCollector::disabled(), &Progress::default(),
+            &mut Profile::disabled(),
+        );
+        fs::remove_file(&path).unwrap();
+        counts
+            .unwrap()
+            .swap_remove(0)
+            .swap_remove(0)
+            .into_iter()
+            .map(|feature| {
+                let mut entries: Vec<(u32, u32)> = feature.into_iter().collect();
+                entries.sort_unstable();
+                entries
+            })
+            .collect()
+    }
+
+    fn mode(mode: CountMode) -> CountOptions {
+        CountOptions { mode, ..CountOptions::default() }
+    }
+
+    #[test]
+    fn fragment_mode_counts_each_overlapping_fragment_once() {
+        let trees = peak_trees(&[("chr1", 100, 200), ("chr1", 300, 400)]);
+        let features = Features::from_peaks(&trees, 2);
+        let fragments = [
+            // both insertions in the first feature
+            ("chr1", 120, 180, "A"),
+            // spans the first feature without an insertion in it
+            ("chr1", 50, 250, "A"),
+            // spans both features
+            ("chr1", 150, 350, "B"),
+            // ends one base before the second feature
+            ("chr1", 250, 299, "A"),
+        ];
+        let counts = count("fragment_mode", &fragments, &features, mode(CountMode::Fragments));
+        assert_eq!(counts, vec![vec![(0, 2), (1, 1)], vec![(1, 1)]]);
+
+        // counting insertions, the spanning fragment adds nothing
+        let counts = count("fragment_mode_spanning", &fragments[1..2], &features, mode(CountMode::Insertions));
+        assert_eq!(counts, vec![vec![], vec![]]);
+    }
+
+    #[test]
+    fn fragment_mode_boundaries() {
+        let trees = peak_trees(&[("chr1", 100, 200)]);
+        let features = Features::from_peaks(&trees, 1);
+        // the end insertion is at the fragment end, so a fragment ending at the feature start overlaps it,
+        // and one starting at the feature end does not
+        let fragments = [("chr1", 50, 100, "A"), ("chr1", 200, 250, "A"), ("chr1", 199, 250, "B"), ("chr1", 40, 99, "B")];
+        let counts = count("fragment_mode_boundaries", &fragments, &features, mode(CountMode::Fragments));
+        assert_eq!(counts, vec![vec![(0, 1), (1, 1)]]);
+    }
+
+    #[test]
+    fn pic_counts_a_fragment_once_per_feature_holding_an_insertion() {
+        let trees = peak_trees(&[("chr1", 100, 200), ("chr1", 300, 400)]);
+        let features = Features::from_peaks(&trees, 2);
+        let fragments = [
+            // both insertions in one feature
+            ("chr1", 120, 180, "A"),
+            // one insertion in each feature
+            ("chr1", 150, 350, "B"),
+            // spans the first feature without an insertion in it
+            ("chr1", 50, 250, "B"),
+            // start insertion on the last base of the first feature
+            ("chr1", 199, 250, "A"),
+        ];
+        let counts = count("pic", &fragments, &features, mode(CountMode::Pic));
+        assert_eq!(counts, vec![vec![(0, 2), (1, 1)], vec![(1, 1)]]);
+        let counts = count("pic_insertions", &fragments, &features, mode(CountMode::Insertions));
+        assert_eq!(counts, vec![vec![(0, 3), (1, 1)], vec![(1, 1)]]);
+
+        // overlapping features: both insertions in the first, the end insertion also in the second
+        let overlapping = peak_trees(&[("chr1", 100, 200), ("chr1", 150, 250)]);
+        let features = Features::from_peaks(&overlapping, 2);
+        let counts = count("pic_overlapping", &fragments[..1], &features, mode(CountMode::Pic));
+        assert_eq!(counts, vec![vec![(0, 1)], vec![(0, 1)]]);
+    }
+
+    #[test]
+    fn min_overlap_requires_a_fraction_of_the_fragment_in_the_feature() {
+        let options = CountOptions { min_overlap: Some(0.5), ..CountOptions::default() };
+        // half of the fragment in the feature
+        assert!(options.enough_overlap(150, 250, 100, 200));
+        assert!(!CountOptions { min_overlap: Some(0.6), ..options }.enough_overlap(150, 250, 100, 200));
+        // a zero-length fragment has no bases in the feature
+        assert!(!options.enough_overlap(150, 150, 100, 200));
+        assert_eq!(CountOptions::overlap(50, 100, 100, 200), 0);
+        assert!(CountOptions::default().enough_overlap(50, 100, 100, 200));
+
+        let trees = peak_trees(&[("chr1", 100, 200), ("chr1", 240, 400)]);
+        let features = Features::from_peaks(&trees, 2);
+        let fragments = [("chr1", 150, 250, "A"), ("chr1", 110, 190, "B")];
+        let options = CountOptions { mode: CountMode::Fragments, min_overlap: Some(0.5), ..CountOptions::default() };
+        let counts = count("min_overlap", &fragments, &features, options);
+        assert_eq!(counts, vec![vec![(0, 1), (1, 1)], vec![]]);
+        // without it, the fragment touching the second feature is counted there too
+        let counts = count("min_overlap_none", &fragments, &features, mode(CountMode::Fragments));
+        assert_eq!(counts, vec![vec![(0, 1), (1, 1)], vec![(0, 1)]]);
+        // insertions are only counted in features holding enough of the fragment
+        let options = CountOptions { min_overlap: Some(0.5), ..CountOptions::default() };
+        let counts = count("min_overlap_insertions", &fragments, &features, options);
+        assert_eq!(counts, vec![vec![(0, 1), (1, 2)], vec![]]);
+    }
+
+    #[test]
+    fn five_prime_counts_only_the_fragment_start() {
+        let trees = peak_trees(&[("chr1", 100, 200), ("chr1", 300, 400)]);
+        let features = Features::from_peaks(&trees, 2);
+        let fragments = [
+            // both insertions in the first feature
+            ("chr1", 120, 180, "A"),
+            // only the end in the second feature
+            ("chr1", 250, 350, "A"),
+            // start on the first base of the second feature
+            ("chr1", 300, 450, "B"),
+            // start on the base after the first feature
+            ("chr1", 200, 320, "B"),
+        ];
+        let counts = count("five_prime", &fragments, &features, mode(CountMode::FivePrime));
+        assert_eq!(counts, vec![vec![(0, 1)], vec![(1, 1)]]);
+    }
+
+    #[test]
+    fn assign_best_keeps_the_feature_with_the_largest_overlap() {
+        let best = |mode| CountOptions { mode, assign: Assign::Best, ..CountOptions::default() };
+        let trees = peak_trees(&[("chr1", 100, 200), ("chr1", 300, 400)]);
+        let features = Features::from_peaks(&trees, 2);
+        let fragments = [
+            // both insertions in the first feature stay counted twice
+            ("chr1", 120, 180, "A"),
+            // 50 bp in each feature: the first wins the tie
+            ("chr1", 150, 350, "A"),
+            // 20 bp in the first feature, 90 bp in the second
+            ("chr1", 180, 390, "B"),
+        ];
+        let counts = count("assign_best", &fragments, &features, best(CountMode::Insertions));
+        assert_eq!(counts, vec![vec![(0, 3)], vec![(1, 1)]]);
+        let counts = count("assign_best_fragments", &fragments, &features, best(CountMode::Fragments));
+        assert_eq!(counts, vec![vec![(0, 2)], vec![(1, 1)]]);
+
+        // overlapping features: the fragment lies mostly in the second
+        let overlapping = peak_trees(&[("chr1", 100, 200), ("chr1", 150, 400)]);
+        let features = Features::from_peaks(&overlapping, 2);
+        let counts = count("assign_best_overlapping", &[("chr1", 120, 300, "A")], &features, best(CountMode::Fragments));
+        assert_eq!(counts, vec![vec![], vec![(0, 1)]]);
+    }
+
+    #[test]
+    fn best_peak_sums_the_intervals_of_a_group() {
+        // the two intervals of group 0 hold 60 bp of the fragment, group 1 holds 50 bp
+        let lapper = Lapper::new(vec![
+            Interval { start: 100, stop: 130, val: 0 },
+            Interval { start: 140, stop: 190, val: 1 },
+            Interval { start: 190, stop: 220, val: 0 },
+        ]);
+        let mut peaks = vec![0, 1, 0];
+        keep_best_peak(&lapper, 100, 220, &mut peaks);
+        assert_eq!(peaks, vec![0, 0]);
+        // a single peak is left as it is
+        let mut peaks = vec![1, 1];
+        keep_best_peak(&lapper, 150, 180, &mut peaks);
+        assert_eq!(peaks, vec![1, 1]);
+    }
+
+    fn bed_options() -> BedOptions {
+        BedOptions {
+            group: None,
+            resize: None,
+            format: PeakFormat::Bed,
+            min_qvalue: None,
+            coords: CoordinateSystem::Bed,
+            extend: (0, 0),
+            merge_overlapping: false,
+        }
+    }
+
+    /// Chromosome, start, end, and feature index of an interval
+    type Feature = (String, u32, u32, usize);
+
+    /// Read BED text with `peak_intervals`, returning the feature names and the intervals of each chromosome
+    fn read_peaks(name: &str, bed: &str, options: &BedOptions) -> (Vec<String>, Vec<Feature>) {
+        let bed_path = temp_path(&format!("{}.bed", name));
+        let features_path = temp_path(&format!("{}.features.tsv.gz", name));
+        fs::write(&bed_path, bed).unwrap();
+        let (total, trees) = peak_intervals(&bed_path, options, &features_path, FeaturesFormat::Names, 1).unwrap();
+        let names = matrix::read_names(&features_path).unwrap();
+        fs::remove_file(&bed_path).unwrap();
+        fs::remove_file(&features_path).unwrap();
+        assert_eq!(names.len(), total);
+        let mut intervals: Vec<Feature> = trees
+            .iter()
+            .flat_map(|(chrom, lapper)| lapper.iter().map(|x| (chrom.clone(), x.start, x.stop, x.val)))
+            .collect();
+        intervals.sort_unstable();
+        (names, intervals)
+    }
+
+    #[test]
+    fn merge_overlapping_flattens_features() {
+        let bed = "chr1\t100\t200\nchr1\t150\t250\nchr1\t250\t300\nchr2\t10\t20\nchr1\t400\t500\n";
+        let options = BedOptions { merge_overlapping: true, ..bed_options() };
+        let (names, intervals) = read_peaks("merge", bed, &options);
+        // features that only touch are not merged
+        assert_eq!(names, ["chr1-100-250", "chr1-250-300", "chr1-400-500", "chr2-10-20"]);
+        assert_eq!(intervals, [
+            ("chr1".to_string(), 100, 250, 0),
+            ("chr1".to_string(), 250, 300, 1),
+            ("chr1".to_string(), 400, 500, 2),
+            ("chr2".to_string(), 10, 20, 3),
+        ]);
+
+        // without merging, each line is a feature
+        let (names, _) = read_peaks("no_merge", bed, &bed_options());
+        assert_eq!(names.len(), 5);
+    }
+
+    #[test]
+    fn merging_keeps_groups_apart() {
+        let mut intervals = vec![
+            Interval { start: 100, stop: 200, val: 0 },
+            Interval { start: 150, stop: 250, val: 1 },
+            Interval { start: 180, stop: 300, val: 0 },
+        ];
+        merge_intervals(&mut intervals, true);
+        let found: Vec<(u32, u32, usize)> = intervals.iter().map(|x| (x.start, x.stop, x.val)).collect();
+        assert_eq!(found, [(100, 300, 0), (150, 250, 1)]);
+    }
+
+    #[test]
+    fn overlapping_features_each_count_an_insertion() {
+        let trees = peak_trees(&[("chr1", 100, 200), ("chr1", 150, 250)]);
+        let features = Features::from_peaks(&trees, 2);
+        assert!(matches!(features, Features::Overlapping(..)));
+        let fragments = [
+            // start in the first feature only, end in both
+            ("chr1", 120, 160, "A"),
+            // end in the second feature only: found without the start lookup
+            ("chr1", 50, 220, "B"),
+            // start in both, end in neither
+            ("chr1", 170, 300, "B"),
+        ];
+        let counts = count("overlapping", &fragments, &features, mode(CountMode::Insertions));
+        assert_eq!(counts, vec![vec![(0, 2), (1, 1)], vec![(0, 1), (1, 2)]]);
+    }
+
+    #[test]
+    fn gff_features_are_converted_before_counting() {
+        let options = BedOptions { coords: CoordinateSystem::Gff, ..bed_options() };
+        // a start of 0 is invalid in 1-based coordinates, and the line is skipped
+        let (names, intervals) = read_peaks("gff", "chr1\t101\t200\nchr1\t0\t50\n", &options);
+        assert_eq!(names, ["chr1-100-200"]);
+        assert_eq!(intervals, [("chr1".to_string(), 100, 200, 0)]);
+
+        // an insertion on the last base, 200 in 1-based coordinates, is counted; one after it is not
+        let trees = peak_trees(&[("chr1", 100, 200)]);
+        let features = Features::from_peaks(&trees, 1);
+        let counts = count("gff_counts", &[("chr1", 199, 300, "A"), ("chr1", 200, 300, "B")], &features, CountOptions::default());
+        assert_eq!(counts, vec![vec![(0, 1)]]);
+    }
+
+
+    #[test]
+    fn narrowpeak_features_are_filtered_by_qvalue_and_centered_on_summits() {
+        let options = BedOptions {
+            format: PeakFormat::NarrowPeak,
+            min_qvalue: Some(2.0),
+            resize: Some(Resize::parse("width=100,anchor=summit").unwrap()),
+            ..bed_options()
+        };
+        let bed = "chr1\t1000\t1500\tp1\t0\t.\t5\t4\t3\t100\n\
+                   chr1\t2000\t2500\tp2\t0\t.\t5\t4\t1\t100\n\
+                   chr1\t3000\t3500\tp3\t0\t.\t5\t4\t-1\t100\n\
+                   chr1\t4000\t4500\tp4\t0\t.\t5\t4\t2\t-1\n\
+                   chr1\t5000\t5500\tp5\t0\t.\t5\t4\t9\n";
+        // p2 is below the threshold, p3 has no q-value, and p5 is missing the summit column
+        let (names, intervals) = read_peaks("narrowpeak", bed, &options);
+        assert_eq!(names, ["chr1-1050-1150", "chr1-4200-4300"]);
+        assert_eq!(intervals, [("chr1".to_string(), 1050, 1150, 0), ("chr1".to_string(), 4200, 4300, 1)]);
+    }
+
+
+    #[test]
+    fn shifts_move_both_insertions() {
+        let trees = peak_trees(&[("chr1", 100, 110)]);
+        let features = Features::from_peaks(&trees, 1);
+        let tn5 = CountOptions { shift_plus: 4, shift_minus: -5, ..CountOptions::default() };
+        // A: start 96 moves to 100; B: end 114 moves to 109; the unshifted insertions are outside the peak
+        let counts = count("shift", &[("chr1", 96, 300, "A"), ("chr1", 20, 114, "B")], &features, tn5);
+        assert_eq!(counts, vec![vec![(0, 1), (1, 1)]]);
+        // end 115 moves to 110, just past the peak
+        let counts = count("shift_end", &[("chr1", 20, 115, "B")], &features, tn5);
+        assert_eq!(counts, vec![vec![]]);
+    }
+
+    #[test]
+    fn shifts_saturate_at_zero() {
+        let trees = peak_trees(&[("chr1", 0, 2)]);
+        let features = Features::from_peaks(&trees, 1);
+        let options = CountOptions { shift_plus: -5, shift_minus: -5, ..CountOptions::default() };
+        // both insertions stop at 0 instead of wrapping around
+        let counts = count("shift_zero", &[("chr1", 2, 3, "A")], &features, options);
+        assert_eq!(counts, vec![vec![(0, 2)]]);
+    }
+
+
+    #[test]
+    fn repeated_fragments_reuse_cached_peaks_for_each_cell() {
+        let trees = peak_trees(&[("chr1", 100, 200), ("chr1", 150, 250)]);
+        let features = Features::Overlapping(&trees, 2);
+        // the end insertion at 180 is in both features; each repeat must count all of them
+        let fragments = [("chr1", 120, 180, "A"), ("chr1", 120, 180, "B"), ("chr1", 120, 180, "A")];
+        let counts = count("cache", &fragments, &features, CountOptions::default());
+        assert_eq!(counts, vec![vec![(0, 4), (1, 2)], vec![(0, 2), (1, 1)]]);
+
+        let counts = count("cache_fragments", &fragments, &features, mode(CountMode::Fragments));
+        assert_eq!(counts, vec![vec![(0, 2), (1, 1)], vec![(0, 2), (1, 1)]]);
+
+        let best = CountOptions { assign: Assign::Best, ..CountOptions::default() };
+        let counts = count("cache_best", &fragments, &features, best);
+        assert_eq!(counts, vec![vec![(0, 4), (1, 2)], vec![]]);
+    }
+
+    #[test]
+    fn cached_peaks_are_not_reused_across_strands() {
+        let trees = peak_trees(&[("chr1", 100, 200), ("chr1", 150, 250)]);
+        let strands = ['+', '-'];
+        let features = Features::Genes(&trees, 2, &strands);
+        let options = CountOptions { strandedness: Strandedness::Forward, ..CountOptions::default() };
+        // the same coordinates on the other strand are counted in the other gene only
+        let text = "chr1\t120\t180\tA\t1\t+\nchr1\t120\t180\tB\t1\t-\nchr1\t120\t180\tA\t1\t+\n";
+        let counts = count_lines("cache_strand", text, &features, options);
+        assert_eq!(counts, vec![vec![(0, 4)], vec![(1, 1)]]);
+    }
+
+
+    #[test]
+    fn fragments_outside_the_feature_span_are_skipped() {
+        let trees = peak_trees(&[("chr1", 100, 200), ("chr1", 300, 400)]);
+        let features = Features::from_peaks(&trees, 2);
+        let fragments = [
+            ("chr1", 10, 50, "A"),
+            ("chr1", 10, 100, "A"),
+            ("chr1", 50, 450, "A"),
+            ("chr1", 399, 500, "B"),
+            ("chr1", 400, 500, "B"),
+            ("chr2", 150, 350, "B"),
+        ];
+        // the end insertion at the first feature start and the start insertion on the last base are counted
+        let counts = count("bounds", &fragments, &features, CountOptions::default());
+        assert_eq!(counts, vec![vec![(0, 1)], vec![(1, 1)]]);
+
+        // a fragment ending at the first feature start, or spanning all features, overlaps them
+        let counts = count("bounds_fragments", &fragments, &features, mode(CountMode::Fragments));
+        assert_eq!(counts, vec![vec![(0, 2)], vec![(0, 1), (1, 1)]]);
+    }
+}
@@ -0,0 +1,162 @@
+use std::{
+    io,
+    fs::File,
+    path::Path,
+    io::BufRead,
+    io::BufReader,
+    io::Seek,
+    io::SeekFrom,
+};
+use log::{info, warn};
+use rustc_hash::FxHashMap;
+use crate::matrix;
+
+/// Uncompressed FASTA file, read one chromosome at a time
+pub struct Fasta {
+    reader: BufReader<File>,
+    /// byte offset of the first base of each sequence
+    offsets: FxHashMap<String, u64>,
+    chrom: String,
+    seq: Vec<u8>,
+}
+
+impl Fasta {
+    /// Open a FASTA file, using the samtools faidx index (`<path>.fai`) if present
+    pub fn open(path: &Path) -> io::Result<Fasta> {
+        if path.extension().is_some_and(|ext| ext == "gz") {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Compressed FASTA files are not supported"));
+        }
+        let mut reader = BufReader::with_capacity(1024 * 1024, File::open(path)?);
+
+        let mut fai = path.as_os_str().to_owned();
+        fai.push(".fai");
+        let offsets = if Path::new(&fai).exists() {
+            read_fai(Path::new(&fai))?
+        } else {
+            warn!("No FASTA index found for {:?}, scanning file. Create one with samtools faidx", path);
+            scan_offsets(&mut reader)?
+        };
+        info!("Loaded FASTA index with {} sequences", offsets.len());
+
+        Ok(Fasta { reader, offsets, chrom: String::new(), seq: Vec::new() })
+    }
+
+    /// Load a chromosome, returning false if it is not in the FASTA file
+    pub fn load(&mut self, chrom: &str) -> io::Result<bool> {
+        if chrom == self.chrom {
+            return Ok(!self.seq.is_empty());
+        }
+        self.chrom = chrom.to_string();
+        self.seq.clear();
+        let offset = match self.offsets.get(chrom) {
+            Some(offset) => *offset,
+            None => return Ok(false),
+        };
+
+        self.reader.seek(SeekFrom::Start(offset))?;
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            if self.reader.read_until(b'\n', &mut line)? == 0 || line.starts_with(b">") {
+                break;
+            }
+            self.seq.extend(line.iter().filter(|b| !b.is_ascii_whitespace()).map(|b| b.to_ascii_uppercase()));
+        }
+        Ok(true)
+    }
+
+    /// Fraction of G/C among unambiguous bases in [start, end) of the loaded chromosome.
+    /// None if the range has no A/C/G/T bases.
+    pub fn gc_fraction(&self, start: u32, end: u32) -> Option<f64> {
+        let end = (end as usize).min(self.seq.len());
+        let start = (start as usize).min(end);
+        let (mut gc, mut total) = (0u32, 0u32);
+        for base in &self.seq[start..end] {
+            match base {
+                b'G' | b'C' => {
+                    gc += 1;
+                    total += 1;
+                }
+                b'A' | b'T' => total += 1,
+                _ => {}
+            }
+        }
+        (total > 0).then(|| gc as f64 / total as f64)
+    }
+}
+
+/// Name and length of each sequence in a FASTA file, in file order, from its index (`<path>.fai`)
+/// if present, or by reading through the file
+pub fn sequence_lengths(path: &Path) -> io::Result<Vec<(String, u32)>> {
+    let mut fai = path.as_os_str().to_owned();
+    fai.push(".fai");
+    let mut lengths: Vec<(String, u32)> = Vec::new();
+    if Path::new(&fai).exists() {
+        info!("Reading sequence lengths from the FASTA index: {:?}", fai);
+        for (index, line) in BufReader::new(File::open(&fai)?).lines().enumerate() {
+            let line = line?;
+            let fields: Vec<&str> = line.split('\t').collect();
+            match fields.get(1).and_then(|x| x.parse().ok()) {
+                Some(length) => lengths.push((fields[0].to_string(), length)),
+                None => warn!("FASTA index line {}: Failed to parse length", index + 1),
+            }
+        }
+        return Ok(lengths);
+    }
+
+    info!("Reading sequence lengths from FASTA file: {:?}", path);
+    let mut line = Vec::new();
+    let mut reader = matrix::open_text(path)?;
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        if line.starts_with(b">") {
+            // sequence name is the first word of the header
+            let header = String::from_utf8_lossy(&line[1..]);
+            lengths.push((header.split_whitespace().next().unwrap_or("").to_string(), 0));
+        } else if let Some((_, length)) = lengths.last_mut() {
+            let bases = line.iter().filter(|b| !b.is_ascii_whitespace()).count() as u32;
+            *length = length.saturating_add(bases);
+        }
+    }
+    Ok(lengths)
+}
+
+fn read_fai(path: &Path) -> io::Result<FxHashMap<String, u64>> {
+    let mut offsets = FxHashMap::default();
+    for (index, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        let fields: Vec<&str> = line.split('\t').collect();
+        match fields.get(2).and_then(|x| x.parse().ok()) {
+            Some(offset) => {
+                offsets.insert(fields[0].to_string(), offset);
+            }
+            None => warn!("FASTA index line {}: Failed to parse offset", index + 1),
+        }
+    }
+    Ok(offsets)
+}
+
+/// Record the sequence offsets by reading through the whole file
+fn scan_offsets(reader: &mut BufReader<File>) -> io::Result<FxHashMap<String, u64>> {
+    let mut offsets = FxHashMap::default();
+    let mut position: u64 = 0;
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let n = reader.read_until(b'\n', &mut line)?;
+        if n == 0 {
+            break;
+        }
+        position += n as u64;
+        if line.starts_with(b">") {
+            // sequence name is the first word of the header
+            let header = String::from_utf8_lossy(&line[1..]);
+            let name = header.split_whitespace().next().unwrap_or("").to_string();
+            offsets.insert(name, position);
+        }
+    }
+    Ok(offsets)
+}
@@ -1,82 +1,73 @@
-use std::fs::File;
-use std::io::{BufRead, BufReader, Write};
+use std::io::{BufRead, Write};
 use std::path::Path;
-use flate2::read::MultiGzDecoder;
 use rustc_hash::FxHashSet;
+use crate::cells::load_cells;
+use crate::fragments::{open_fragments, ReadOptions};
+use crate::progress::Progress;
 
-pub fn run(matches: &clap::ArgMatches) -> std::io::Result<()> {
+pub fn run(matches: &clap::ArgMatches, progress: &Progress) -> std::io::Result<()> {
     // Get file paths from command-line arguments
-    let cells_file = matches.get_one::<String>("cells").unwrap();
     let fragments_file = matches.get_one::<String>("fragments").unwrap();
+    let read_options = ReadOptions::from_matches(matches)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
 
     // Load the cell barcodes into a FxHashSet for fast lookups
-    let cell_barcodes = load_cells(cells_file)?;
+    let cell_barcodes = load_cells(matches)?.unwrap();
 
     // Filter the fragment file based on the cell barcodes
-    filter_fragments(fragments_file, &cell_barcodes)?;
+    filter_fragments(fragments_file, &cell_barcodes, &read_options, progress)?;
 
     Ok(())
 }
 
-fn load_cells<P: AsRef<Path>>(path: P) -> std::io::Result<FxHashSet<String>> {
-    let file = File::open(path)?;
-    let reader = BufReader::new(file);
-    let mut cell_barcodes = FxHashSet::default(); // Change this line
-
-    for line in reader.lines() {
-        let line = line?;
-        cell_barcodes.insert(line);
-    }
-
-    Ok(cell_barcodes)
-}
-
 fn filter_fragments<P: AsRef<Path>>(
     fragments_path: P,
     cell_barcodes: &FxHashSet<String>,
+    read_options: &ReadOptions,
+    progress: &Progress,
 ) -> std::io::Result<()> {
-    let fragments_file = File::open(fragments_path)?;
-    let mut fragments_reader = BufReader::with_capacity(1024 * 1024, MultiGzDecoder::new(fragments_file));
+    let mut fragments_reader = open_fragments(fragments_path.as_ref(), read_options)?;
 
     let stdout = std::io::stdout();
     let mut output_writer = stdout.lock();
 
     let mut line_count: u64 = 0;
-    let mut buffer = String::with_capacity(1024);
+    // lines are kept as bytes, so fragments of listed cells are written unchanged even if
+    // other fields are not valid UTF-8
+    let mut buffer: Vec<u8> = Vec::with_capacity(1024);
 
     loop {
         buffer.clear();
-        match fragments_reader.read_line(&mut buffer) {
+        match fragments_reader.read_until(b'\n', &mut buffer) {
             Ok(0) => break, // End of file
             Ok(_) => {
                 // Remove trailing newline
-                if buffer.ends_with('\n') {
+                if buffer.ends_with(b"\n") {
                     buffer.pop();
                 }
-                if buffer.ends_with('\r') {
+                if buffer.ends_with(b"\r") {
                     buffer.pop();
                 }
 
                 // Skip comment lines
-                if buffer.starts_with('#') {
+                if buffer.starts_with(b"#") {
                     continue;
                 }
 
-                if let Some(barcode) = buffer.split('\t').nth(3) {
-                    if cell_barcodes.contains(barcode) {
-                        writeln!(output_writer, "{}", buffer)?;
+                if let Some(barcode) = buffer.split(|byte| *byte == b'\t').nth(3) {
+                    if std::str::from_utf8(barcode).is_ok_and(|barcode| cell_barcodes.contains(barcode)) {
+                        output_writer.write_all(&buffer)?;
+                        output_writer.write_all(b"\n")?;
                     }
                 }
 
                 line_count += 1;
-                if line_count % 1_000_000 == 0 {
-                    eprint!("\rProcessed {} M lines", line_count / 1_000_000);
-                    std::io::stderr().flush().expect("Can't flush stderr");
-                }
+                progress.update(line_count, None)?;
             }
             Err(e) => return Err(e),
         }
     }
+    progress.finish(line_count, None);
 
     Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,518 @@
+use std::{
+    io,
+    fs::File,
+    path::{Path, PathBuf},
+    sync::Arc,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    sync::mpsc,
+    thread,
+    io::BufRead,
+    io::BufReader,
+    io::Read,
+    io::Seek,
+    io::SeekFrom,
+};
+use flate2::read::MultiGzDecoder;
+use gzp::{
+    deflate::Bgzf,
+    par::decompress::{ParDecompress, ParDecompressBuilder},
+};
+use log::warn;
+use crate::bam::{self, BamOptions};
+use crate::progress::Progress;
+use crate::threads::ThreadConfig;
+
+/// Standard empty BGZF block that ends a complete BGZF file
+const BGZF_EOF: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43,
+    0x02, 0x00, 0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// How fragment files are opened and decoded
+#[derive(Clone, Debug)]
+pub struct ReadOptions {
+    /// threads decompressing BGZF input (and decoding CRAM)
+    pub io_threads: usize,
+    /// end a truncated or corrupt input after its last complete line, with a warning, instead of failing
+    pub allow_truncated: bool,
+    /// how read pairs of BAM and CRAM input are turned into fragments
+    pub bam: BamOptions,
+    /// whether standard input has been opened with these options (or a clone), as it cannot be read twice
+    stdin_opened: Arc<AtomicBool>,
+}
+
+impl Default for ReadOptions {
+    fn default() -> ReadOptions {
+        ReadOptions {
+            io_threads: 1,
+            allow_truncated: false,
+            bam: BamOptions::default(),
+            stdin_opened: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+impl ReadOptions {
+    pub fn from_matches(matches: &clap::ArgMatches) -> Result<ReadOptions, String> {
+        Ok(ReadOptions {
+            io_threads: ThreadConfig::from_matches(matches).io,
+            allow_truncated: matches.try_get_one::<bool>("allow_truncated").ok().flatten().copied().unwrap_or(false),
+            bam: BamOptions::from_matches(matches)?,
+            ..ReadOptions::default()
+        })
+    }
+}
+
+/// Whether a fragment path refers to standard input
+pub fn is_stdin(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
+
+/// Absolute path of a fragment file argument, or `-` for standard input
+pub fn resolve_path(path: &str) -> io::Result<PathBuf> {
+    if is_stdin(Path::new(path)) {
+        return Ok(PathBuf::from(path));
+    }
+    Path::new(path)
+        .canonicalize()
+        .map_err(|e| io::Error::new(e.kind(), format!("Can't find fragment file {}: {}", path, e)))
+}
+
+/// A parsed fragment file entry
+pub struct Fragment<'a> {
+    pub chrom: &'a str,
+    pub start: u32,
+    pub end: u32,
+    pub barcode: &'a str,
+}
+
+/// Open a fragment file for reading. The compression is detected from the file contents, so
+/// uncompressed, gzip, and BGZF files can be used with any name. BGZF files are decompressed
+/// in parallel when more than one io thread is configured.
+/// BAM files are read as the fragment lines of their read pairs.
+/// Decompression errors report the compressed offset and line where the file is damaged.
+/// The path `-` reads standard input, which can only be read once with the same `options`.
+pub fn open_fragments(path: &Path, options: &ReadOptions) -> io::Result<Box<dyn BufRead + Send>> {
+    // CRAM input is decoded by htslib, then converted to fragment lines like BAM
+    if bam::is_cram(path) {
+        let cram = bam::open_cram(path, &options.bam, options.io_threads)?;
+        let reader = bam::BamFragments::new(cram, &options.bam, options.io_threads)?;
+        return Ok(Box::new(BufReader::with_capacity(1024 * 1024, reader)));
+    }
+
+    let (source, compression): (Box<dyn Read + Send>, InputCompression) = if is_stdin(path) {
+        if options.stdin_opened.swap(true, Ordering::Relaxed) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Standard input can only be read once, this command needs a fragment file",
+            ));
+        }
+        let mut stdin = BufReader::with_capacity(1024 * 1024, io::stdin());
+        let compression = InputCompression::from_header(stdin.fill_buf()?);
+        (Box::new(stdin), compression)
+    } else {
+        let mut file = File::open(path)?;
+        let compression = InputCompression::detect(&mut file)?;
+        if compression == InputCompression::Bgzf && !has_bgzf_eof(&mut file)? {
+            warn!("{:?} has no BGZF end-of-file marker and may be truncated", path);
+        }
+        (Box::new(file), compression)
+    };
+
+    let offset = Arc::new(AtomicU64::new(0));
+    let file = CountingReader { inner: source, offset: offset.clone() };
+    // parallel decompression reads ahead, so truncated files are read serially to keep every complete line
+    let reader: Box<dyn Read + Send> = match compression {
+        InputCompression::Bgzf if options.io_threads > 1 && !options.allow_truncated => {
+            let reader: ParDecompress<Bgzf> = ParDecompressBuilder::new()
+                .num_threads(options.io_threads)
+                .map_err(io::Error::other)?
+                .from_reader(file);
+            Box::new(reader)
+        }
+        InputCompression::Bgzf | InputCompression::Gzip => Box::new(MultiGzDecoder::new(file)),
+        InputCompression::Zstd => Box::new(zstd::Decoder::new(file)?),
+        InputCompression::Plain => Box::new(file),
+    };
+
+    // BAM input is converted to fragment lines as it is read
+    if bam::is_bam(path) {
+        let reader = bam::BamFragments::new(reader, &options.bam, options.io_threads)?;
+        return Ok(Box::new(BufReader::with_capacity(1024 * 1024, reader)));
+    }
+
+    let reader = CheckedReader {
+        inner: reader,
+        allow_truncated: options.allow_truncated,
+        path: path.to_path_buf(),
+        offset,
+        lines: 0,
+        partial: Vec::new(),
+        done: false,
+    };
+    Ok(Box::new(BufReader::with_capacity(1024 * 1024, reader)))
+}
+
+/// Lines of a fragment file, decompressed on a background thread.
+/// Read errors, and a panic in the decompression thread, are returned as the last item
+/// so the consumer never mistakes a failed read for the end of the file.
+pub struct LineStream {
+    rx: mpsc::Receiver<io::Result<String>>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl LineStream {
+    pub fn spawn(path: &Path, options: &ReadOptions) -> LineStream {
+        let (tx, rx) = mpsc::sync_channel(500);
+        let path = path.to_path_buf();
+        let options = options.clone();
+        let handle = thread::spawn(move || {
+            let mut reader = match open_fragments(&path, &options) {
+                Ok(reader) => reader,
+                Err(e) => {
+                    let _ = tx.send(Err(e));
+                    return;
+                }
+            };
+            let mut buffer: Vec<u8> = Vec::with_capacity(1024);
+            let mut line_count: u64 = 0;
+            loop {
+                buffer.clear();
+                let line = match reader.read_until(b'\n', &mut buffer) {
+                    Ok(0) => break,
+                    Ok(_) => {
+                        line_count += 1;
+                        match decode_line(&buffer) {
+                            Some(line) => Ok(line.to_string()),
+                            None => {
+                                warn!("Line {}: Not valid UTF-8, skipping", line_count);
+                                continue;
+                            }
+                        }
+                    }
+                    Err(e) => Err(e),
+                };
+                let failed = line.is_err();
+                // a closed channel means the consumer stopped early
+                if tx.send(line).is_err() || failed {
+                    break;
+                }
+            }
+        });
+        LineStream { rx, handle: Some(handle) }
+    }
+}
+
+impl Iterator for LineStream {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<io::Result<String>> {
+        if let Ok(line) = self.rx.recv() {
+            return Some(line);
+        }
+        // channel closed: the thread has finished or panicked
+        match self.handle.take()?.join() {
+            Ok(()) => None,
+            Err(panic) => {
+                let msg = panic
+                    .downcast_ref::<&str>()
+                    .map(|x| x.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown error".to_string());
+                Some(Err(io::Error::other(format!("Decompression thread failed: {}", msg))))
+            }
+        }
+    }
+}
+
+/// Counts the compressed bytes read from a file
+struct CountingReader<R> {
+    inner: R,
+    offset: Arc<AtomicU64>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.offset.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+/// Decompressed stream that reports where a truncated or corrupt file fails.
+/// Only complete lines are passed on, so a truncated file never yields a partial fragment.
+struct CheckedReader<R> {
+    inner: R,
+    /// end at the last complete line instead of failing on damage
+    allow_truncated: bool,
+    path: PathBuf,
+    /// compressed bytes read so far
+    offset: Arc<AtomicU64>,
+    /// complete lines decompressed so far
+    lines: u64,
+    /// bytes after the last newline, held back until the line is complete
+    partial: Vec<u8>,
+    done: bool,
+}
+
+impl<R: Read> Read for CheckedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.done || buf.is_empty() {
+                return Ok(0);
+            }
+            let held = self.partial.len();
+            if held >= buf.len() {
+                // line longer than the buffer
+                buf.copy_from_slice(&self.partial[..buf.len()]);
+                self.partial.drain(..buf.len());
+                return Ok(buf.len());
+            }
+            buf[..held].copy_from_slice(&self.partial);
+            self.partial.clear();
+
+            let n = match self.inner.read(&mut buf[held..]) {
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {
+                    self.partial.extend_from_slice(&buf[..held]);
+                    return Err(e);
+                }
+                Err(e) => return self.fail(e),
+            };
+            if n == 0 {
+                // end of input; a final line without a newline is complete
+                return Ok(held);
+            }
+
+            let total = held + n;
+            match buf[..total].iter().rposition(|&b| b == b'\n') {
+                Some(last) => {
+                    self.lines += buf[held..=last].iter().filter(|&&b| b == b'\n').count() as u64;
+                    self.partial.extend_from_slice(&buf[last + 1..total]);
+                    return Ok(last + 1);
+                }
+                None if total == buf.len() => return Ok(total),
+                None => self.partial.extend_from_slice(&buf[..total]),
+            }
+        }
+    }
+}
+
+impl<R> CheckedReader<R> {
+    /// Report a decompression error, or end the stream after the last complete line with --allow-truncated
+    fn fail(&mut self, e: io::Error) -> io::Result<usize> {
+        let msg = format!(
+            "{:?} is truncated or corrupt near compressed byte {}, after line {}: {}",
+            self.path, self.offset.load(Ordering::Relaxed), self.lines, e,
+        );
+        if !self.allow_truncated {
+            return Err(io::Error::new(e.kind(), format!("{}. Use --allow-truncated to keep partial results", msg)));
+        }
+        warn!("{}", msg);
+        warn!("Continuing with --allow-truncated, results only include the first {} lines", self.lines);
+        self.done = true;
+        Ok(0)
+    }
+}
+
+/// Whether a file header is a BGZF block header (gzip with a BC extra subfield)
+fn is_bgzf(header: &[u8]) -> bool {
+    header.len() >= 18
+        && header[..3] == [0x1f, 0x8b, 0x08]
+        && header[3] & 0x04 != 0
+        && header[12..14] == *b"BC"
+}
+
+/// Compression of an input file, from its first bytes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum InputCompression {
+    Plain,
+    Gzip,
+    Bgzf,
+    Zstd,
+}
+
+impl InputCompression {
+    /// Sniff the magic bytes, leaving the file at the start
+    fn detect(file: &mut File) -> io::Result<InputCompression> {
+        let mut header = [0u8; 18];
+        let mut n = 0;
+        while n < header.len() {
+            match file.read(&mut header[n..])? {
+                0 => break,
+                read => n += read,
+            }
+        }
+        file.seek(SeekFrom::Start(0))?;
+        Ok(InputCompression::from_header(&header[..n]))
+    }
+
+    fn from_header(header: &[u8]) -> InputCompression {
+        if is_bgzf(header) {
+            InputCompression::Bgzf
+        } else if header.starts_with(&[0x1f, 0x8b]) {
+            InputCompression::Gzip
+        } else if header.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            InputCompression::Zstd
+        } else {
+            InputCompression::Plain
+        }
+    }
+}
+
+/// Check for the BGZF end-of-file block, leaving the file at the start
+fn has_bgzf_eof(file: &mut File) -> io::Result<bool> {
+    let mut tail = [0u8; 28];
+    if file.metadata()?.len() < tail.len() as u64 {
+        return Ok(false);
+    }
+    file.seek(SeekFrom::End(-(tail.len() as i64)))?;
+    file.read_exact(&mut tail)?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(tail == BGZF_EOF)
+}
+
+/// Outcome of decompressing a whole file with `verify_compression`
+pub struct CompressionCheck {
+    /// detected format: plain, gzip, bgzf, or zstd
+    pub format: &'static str,
+    /// gzip members (BGZF blocks) read, each with a matching CRC32 and size
+    pub members: u64,
+    pub compressed_bytes: u64,
+    pub uncompressed_bytes: u64,
+    /// whether a BGZF file ends with the end-of-file block
+    pub bgzf_eof: bool,
+}
+
+/// Byte offset of a buffered reader, for locating damaged gzip members
+struct OffsetReader<R> {
+    inner: R,
+    offset: u64,
+}
+
+impl<R: BufRead> Read for OffsetReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for OffsetReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.offset += amt as u64;
+        self.inner.consume(amt);
+    }
+}
+
+/// Decompress a whole file, checking the CRC32 and size of every gzip member (or the checksums of
+/// zstd frames that have them). A failure is returned as an error giving the member and its
+/// compressed offset. Uncompressed files are read without checks.
+pub fn verify_compression(path: &Path) -> io::Result<CompressionCheck> {
+    let mut file = File::open(path)?;
+    let compression = InputCompression::detect(&mut file)?;
+    let bgzf_eof = compression == InputCompression::Bgzf && has_bgzf_eof(&mut file)?;
+    let compressed_bytes = file.metadata()?.len();
+    let mut reader = OffsetReader { inner: BufReader::with_capacity(1024 * 1024, file), offset: 0 };
+
+    let mut members: u64 = 0;
+    let mut uncompressed_bytes: u64 = 0;
+    let format = match compression {
+        InputCompression::Gzip | InputCompression::Bgzf => {
+            while !reader.fill_buf()?.is_empty() {
+                let start = reader.offset;
+                let mut decoder = flate2::bufread::GzDecoder::new(&mut reader);
+                match io::copy(&mut decoder, &mut io::sink()) {
+                    Ok(n) => uncompressed_bytes += n,
+                    Err(e) => return Err(io::Error::new(
+                        e.kind(),
+                        format!("{:?}: gzip member {} at compressed offset {} is damaged: {}", path, members + 1, start, e),
+                    )),
+                }
+                members += 1;
+            }
+            if compression == InputCompression::Bgzf { "bgzf" } else { "gzip" }
+        }
+        InputCompression::Zstd => {
+            let mut decoder = zstd::Decoder::with_buffer(&mut reader)?;
+            uncompressed_bytes = io::copy(&mut decoder, &mut io::sink())
+                .map_err(|e| io::Error::new(e.kind(), format!("{:?}: zstd data is damaged: {}", path, e)))?;
+            "zstd"
+        }
+        InputCompression::Plain => {
+            uncompressed_bytes = io::copy(&mut reader, &mut io::sink())?;
+            "plain"
+        }
+    };
+    Ok(CompressionCheck { format, members, compressed_bytes, uncompressed_bytes, bgzf_eof })
+}
+
+/// A line read with `read_until`, without its line ending, or None if it is not valid UTF-8.
+/// Fragment lines are read as bytes so that a line from a legacy pipeline with non-UTF-8
+/// barcodes or chromosome names is skipped, rather than failing the whole file.
+pub fn decode_line(buffer: &[u8]) -> Option<&str> {
+    let end = buffer.iter().rposition(|byte| *byte != b'\n' && *byte != b'\r').map_or(0, |i| i + 1);
+    std::str::from_utf8(&buffer[..end]).ok()
+}
+
+/// Parse a fragment line, returning None if fields are missing or coordinates are invalid
+pub fn parse_fragment(line: &str) -> Option<Fragment<'_>> {
+    let mut fields = line.split('\t');
+    let chrom = fields.next()?;
+    let start = fields.next()?.trim().parse().ok()?;
+    let end = fields.next()?.trim().parse().ok()?;
+    let barcode = fields.next()?;
+    Some(Fragment { chrom, start, end, barcode })
+}
+
+/// Call `f` on every fragment in the file, skipping header lines and malformed entries.
+/// Progress is reported on stderr. Returns the number of fragments read.
+pub fn for_each_fragment<F>(path: &Path, options: &ReadOptions, f: F) -> io::Result<u64>
+where
+    F: FnMut(&Fragment) -> io::Result<()>,
+{
+    for_each_fragment_with_progress(path, options, &Progress::default(), f)
+}
+
+/// As `for_each_fragment`, reporting progress to `progress` and stopping with
+/// a `Cancelled` error if it is cancelled
+pub fn for_each_fragment_with_progress<F>(path: &Path, options: &ReadOptions, progress: &Progress, mut f: F) -> io::Result<u64>
+where
+    F: FnMut(&Fragment) -> io::Result<()>,
+{
+    let mut reader = open_fragments(path, options)?;
+    let mut line_count: u64 = 0;
+    let mut buffer: Vec<u8> = Vec::with_capacity(1024);
+
+    loop {
+        buffer.clear();
+        if reader.read_until(b'\n', &mut buffer)? == 0 {
+            break;
+        }
+        if buffer.starts_with(b"#") {
+            continue;
+        }
+
+        line_count += 1;
+        progress.update(line_count, None)?;
+
+        let line = match decode_line(&buffer) {
+            Some(line) => line,
+            None => {
+                warn!("Fragment {}: Not valid UTF-8, skipping", line_count);
+                continue;
+            }
+        };
+        match parse_fragment(line) {
+            Some(fragment) => f(&fragment)?,
+            None => warn!("Fragment {}: Failed to parse entry", line_count),
+        }
+    }
+    progress.finish(line_count, None);
+
+    Ok(line_count)
+}
@@ -0,0 +1,208 @@
+use std::{
+    io,
+    path::Path,
+    io::BufRead,
+    io::Write,
+};
+use gzp::ZWriter;
+use log::{info, warn};
+use rust_lapper::{Interval, Lapper};
+use rustc_hash::FxHashMap;
+use crate::bed::RegionTrees;
+use crate::matrix::{self, FeaturesFormat};
+
+/// How gene regions are built from a GTF file
+#[derive(Clone, Copy, Debug)]
+pub struct GeneOptions {
+    /// bp added before the TSS
+    pub upstream: u32,
+    /// bp added after the gene end
+    pub downstream: u32,
+    pub model: GeneModel,
+}
+
+/// Regions of a gene that are counted
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum GeneModel {
+    /// the span of all records of the gene, extended upstream and downstream
+    #[default]
+    Body,
+    /// the union of the exons of all transcripts of the gene, for RNA-like counting
+    Exons,
+}
+
+impl GeneModel {
+    pub fn from_name(name: &str) -> Option<GeneModel> {
+        match name {
+            "body" => Some(GeneModel::Body),
+            "exons" => Some(GeneModel::Exons),
+            _ => None,
+        }
+    }
+}
+
+/// A gene body, in half-open coordinates
+struct Gene {
+    id: String,
+    name: String,
+    chrom: String,
+    start: u32,
+    end: u32,
+    strand: char,
+    /// exon records, with `GeneModel::Exons`
+    exons: Vec<(u32, u32)>,
+}
+
+/// Read gene bodies from a GTF file, optionally gzipped, extended by the promoter
+/// and downstream distances, into interval trees for each chromosome. Writes the gene
+/// IDs and names to `outfile`. Returns the number of genes, the trees, and the strand of each gene.
+/// Each gene spans all records with its gene_id, so GTF files without gene lines also work.
+/// With `GeneModel::Exons`, each gene is instead the union of its exons, without extension,
+/// as several intervals sharing the gene index.
+pub fn gene_intervals(
+    gtf_file: &Path,
+    options: &GeneOptions,
+    outfile: &Path,
+    format: FeaturesFormat,
+    num_threads: usize,
+) -> io::Result<(usize, RegionTrees, Vec<char>)> {
+    let mut genes = read_genes(gtf_file, options.model)?;
+    info!("Loaded {} genes", genes.len());
+
+    let mut writer = matrix::compressed_writer(outfile, num_threads)?;
+    let mut intervals: FxHashMap<String, Vec<Interval<u32, usize>>> = FxHashMap::default();
+    let mut without_exons: usize = 0;
+    for (index, gene) in genes.iter_mut().enumerate() {
+        let regions = match options.model {
+            GeneModel::Body if gene.strand == '-' => {
+                vec![(gene.start.saturating_sub(options.downstream), gene.end.saturating_add(options.upstream))]
+            }
+            GeneModel::Body => vec![(gene.start.saturating_sub(options.upstream), gene.end.saturating_add(options.downstream))],
+            GeneModel::Exons => exon_union(&mut gene.exons),
+        };
+        if regions.is_empty() {
+            without_exons += 1;
+        }
+        // the counted region, from the first start to the last end, for bed features
+        let start = regions.iter().map(|x| x.0).min().unwrap_or(gene.start);
+        let end = regions.iter().map(|x| x.1).max().unwrap_or(gene.end);
+        match format {
+            FeaturesFormat::Names => writeln!(writer, "{}\t{}", gene.id, gene.name)?,
+            FeaturesFormat::Bed => writeln!(writer, "{}\t{}\t{}\t{}\t{}", gene.chrom, start, end, gene.id, gene.name)?,
+            FeaturesFormat::TenX if options.model == GeneModel::Exons => writeln!(writer, "{}\t{}\tGene Expression", gene.id, gene.name)?,
+            FeaturesFormat::TenX => writeln!(writer, "{}\t{}\tGene Activity", gene.id, gene.name)?,
+        }
+        let chrom_intervals = intervals.entry(gene.chrom.clone()).or_default();
+        for (start, end) in regions {
+            chrom_intervals.push(Interval { start, stop: end, val: index });
+        }
+    }
+    writer.finish().map_err(io::Error::other)?;
+    if without_exons > 0 {
+        warn!("{} genes have no exon records and are not counted", without_exons);
+    }
+
+    let trees = intervals
+        .into_iter()
+        .map(|(chrom, intervals)| (chrom, Lapper::new(intervals)))
+        .collect();
+    Ok((genes.len(), trees, genes.iter().map(|gene| gene.strand).collect()))
+}
+
+/// Merge the exons of the transcripts of a gene into non-overlapping regions
+fn exon_union(exons: &mut [(u32, u32)]) -> Vec<(u32, u32)> {
+    exons.sort_unstable();
+    let mut merged: Vec<(u32, u32)> = Vec::new();
+    for (start, end) in exons.iter() {
+        match merged.last_mut() {
+            Some(last) if *start <= last.1 => last.1 = last.1.max(*end),
+            _ => merged.push((*start, *end)),
+        }
+    }
+    merged
+}
+
+/// Genes in order of first appearance, each spanning all of its records, with their exon
+/// records for `GeneModel::Exons`
+fn read_genes(gtf_file: &Path, model: GeneModel) -> io::Result<Vec<Gene>> {
+    let mut genes: Vec<Gene> = Vec::new();
+    let mut gene_index: FxHashMap<String, usize> = FxHashMap::default();
+
+    for (index, line) in matrix::open_text(gtf_file)?.lines().enumerate() {
+        let line = line?;
+        if line.starts_with('#') || line.is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 9 {
+            warn!("Line {}: Less than nine fields", index + 1);
+            continue;
+        }
+        // 1-based, end included
+        let (start, end) = match (fields[3].parse::<u32>(), fields[4].parse::<u32>()) {
+            (Ok(start), Ok(end)) if start >= 1 && end >= start => (start - 1, end),
+            _ => {
+                warn!("Line {}: Invalid coordinates", index + 1);
+                continue;
+            }
+        };
+        let id = match attribute(fields[8], "gene_id") {
+            Some(id) => id,
+            None => {
+                warn!("Line {}: Missing gene_id", index + 1);
+                continue;
+            }
+        };
+
+        let gene = match gene_index.get(id) {
+            Some(&i) => {
+                let gene = &mut genes[i];
+                if gene.chrom != fields[0] {
+                    warn!("Line {}: Gene {} is on more than one chromosome, keeping {}", index + 1, id, gene.chrom);
+                    continue;
+                }
+                gene.start = gene.start.min(start);
+                gene.end = gene.end.max(end);
+                gene
+            }
+            None => {
+                gene_index.insert(id.to_string(), genes.len());
+                genes.push(Gene {
+                    id: id.to_string(),
+                    name: attribute(fields[8], "gene_name").unwrap_or(id).to_string(),
+                    chrom: fields[0].to_string(),
+                    start,
+                    end,
+                    strand: fields[6].chars().next().unwrap_or('.'),
+                    exons: Vec::new(),
+                });
+                genes.last_mut().unwrap()
+            }
+        };
+        if model == GeneModel::Exons && fields[2] == "exon" {
+            gene.exons.push((start, end));
+        }
+    }
+
+    if genes.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("No genes with a gene_id attribute in {:?}", gtf_file),
+        ));
+    }
+    if model == GeneModel::Exons && genes.iter().all(|gene| gene.exons.is_empty()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("No exon records in {:?}, needed for --gene-model exons", gtf_file),
+        ));
+    }
+    Ok(genes)
+}
+
+/// Value of a GTF attribute such as `gene_id "ENSG00000243485";`
+fn attribute<'a>(attributes: &'a str, key: &str) -> Option<&'a str> {
+    attributes.split(';').find_map(|attribute| {
+        let (name, value) = attribute.trim().split_once(' ')?;
+        (name == key).then(|| value.trim().trim_matches('"'))
+    })
+}
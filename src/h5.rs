@@ -0,0 +1,539 @@
+use std::{
+    io,
+    error::Error,
+    path::Path,
+};
+use hdf5::{
+    types::{FixedAscii, FixedUnicode, TypeDescriptor, VarLenAscii, VarLenUnicode},
+    Dataset, File, Group,
+};
+use log::info;
+use ndarray::Array2;
+use crate::convert::MatrixFormat;
+use crate::matrix::{transpose, ColumnSpill, Columns, SparseMatrix, SpilledMatrix};
+
+type H5Result<T> = Result<T, Box<dyn Error>>;
+
+/// Entries of a sparse matrix read at once when spilling it
+const READ_ENTRIES: usize = 1 << 22;
+
+/// Values of a dense Loom matrix written at once, as a block of columns
+const LOOM_BLOCK: usize = 1 << 24;
+
+/// Read a string dataset regardless of fixed/variable-length storage
+fn read_strings(ds: &Dataset) -> H5Result<Vec<String>> {
+    let strings = match ds.dtype()?.to_descriptor()? {
+        TypeDescriptor::VarLenUnicode => ds
+            .read_raw::<VarLenUnicode>()?
+            .iter()
+            .map(|s| s.as_str().to_string())
+            .collect(),
+        TypeDescriptor::VarLenAscii => ds
+            .read_raw::<VarLenAscii>()?
+            .iter()
+            .map(|s| s.as_str().to_string())
+            .collect(),
+        TypeDescriptor::FixedAscii(n) if n <= 64 => ds
+            .read_raw::<FixedAscii<64>>()?
+            .iter()
+            .map(|s| s.as_str().to_string())
+            .collect(),
+        TypeDescriptor::FixedAscii(_) => ds
+            .read_raw::<FixedAscii<1024>>()?
+            .iter()
+            .map(|s| s.as_str().to_string())
+            .collect(),
+        TypeDescriptor::FixedUnicode(_) => ds
+            .read_raw::<FixedUnicode<1024>>()?
+            .iter()
+            .map(|s| s.as_str().to_string())
+            .collect(),
+        other => return Err(format!("Dataset {} is not a string array ({})", ds.name(), other).into()),
+    };
+    Ok(strings)
+}
+
+fn is_integer(ds: &Dataset) -> H5Result<bool> {
+    Ok(matches!(
+        ds.dtype()?.to_descriptor()?,
+        TypeDescriptor::Integer(_) | TypeDescriptor::Unsigned(_) | TypeDescriptor::Boolean
+    ))
+}
+
+fn to_varlen(strings: &[String]) -> H5Result<Vec<VarLenUnicode>> {
+    strings
+        .iter()
+        .map(|s| s.parse::<VarLenUnicode>().map_err(|e| format!("Invalid string {:?}: {}", s, e).into()))
+        .collect()
+}
+
+fn write_strings(group: &Group, name: &str, strings: &[String]) -> H5Result<Dataset> {
+    let values = to_varlen(strings)?;
+    let ds = group
+        .new_dataset_builder()
+        .deflate(4)
+        .with_data(values.as_slice())
+        .create(name)?;
+    Ok(ds)
+}
+
+fn set_str_attr(obj: &hdf5::Location, name: &str, value: &str) -> H5Result<()> {
+    let value: VarLenUnicode = value.parse().map_err(|e| format!("Invalid attribute {:?}: {}", value, e))?;
+    obj.new_attr::<VarLenUnicode>().shape(()).create(name)?.write_scalar(&value)?;
+    Ok(())
+}
+
+/// Write the CSC arrays over cells, storing values as int32 or float32.
+/// Entries are written a batch of columns at a time, so only one batch is converted at once.
+fn write_sparse(group: &Group, matrix: &Columns) -> H5Result<()> {
+    let nnz = matrix.nnz();
+    let data = if matrix.integer() {
+        group.new_dataset::<i32>().deflate(4).shape(nnz).create("data")?
+    } else {
+        group.new_dataset::<f32>().deflate(4).shape(nnz).create("data")?
+    };
+    let indices = group.new_dataset::<i64>().deflate(4).shape(nnz).create("indices")?;
+    let mut start = 0;
+    matrix.for_each_batch(|_, batch_indices, batch_data| -> H5Result<()> {
+        let end = start + batch_indices.len();
+        if matrix.integer() {
+            let values: Vec<i32> = batch_data.iter().map(|&x| x as i32).collect();
+            data.write_slice(values.as_slice(), start..end)?;
+        } else {
+            let values: Vec<f32> = batch_data.iter().map(|&x| x as f32).collect();
+            data.write_slice(values.as_slice(), start..end)?;
+        }
+        let values: Vec<i64> = batch_indices.iter().map(|&x| x as i64).collect();
+        indices.write_slice(values.as_slice(), start..end)?;
+        start = end;
+        Ok(())
+    })?;
+    let indptr: Vec<i64> = matrix.indptr().iter().map(|&x| x as i64).collect();
+    group.new_dataset_builder().deflate(4).with_data(indptr.as_slice()).create("indptr")?;
+    Ok(())
+}
+
+/// Write a CellRanger-style HDF5 matrix (CSC, features x barcodes)
+pub fn write_10x_h5(path: &Path, matrix: &Columns) -> H5Result<()> {
+    info!("Writing 10x HDF5 matrix: {:?}", path);
+    let file = File::create(path)?;
+    let group = file.create_group("matrix")?;
+    let (nrow, ncol) = (matrix.features().len(), matrix.barcodes().len());
+
+    write_sparse(&group, matrix)?;
+    let shape = [nrow as i32, ncol as i32];
+    group.new_dataset_builder().with_data(&shape[..]).create("shape")?;
+    write_strings(&group, "barcodes", matrix.barcodes())?;
+
+    let features = group.create_group("features")?;
+    write_strings(&features, "id", matrix.features())?;
+    write_strings(&features, "name", matrix.features())?;
+    write_strings(&features, "feature_type", &vec!["Peaks".to_string(); nrow])?;
+    write_strings(&features, "genome", &vec![String::new(); nrow])?;
+    write_strings(&features, "_all_tag_keys", &["genome".to_string()])?;
+
+    set_str_attr(&file, "filetype", "matrix")?;
+    set_str_attr(&file, "software_version", &format!("fragtk-{}", env!("CARGO_PKG_VERSION")))?;
+    file.close()?;
+    Ok(())
+}
+
+/// Read a CellRanger-style HDF5 matrix
+pub fn read_10x_h5(path: &Path) -> H5Result<SparseMatrix> {
+    info!("Reading 10x HDF5 matrix: {:?}", path);
+    let file = File::open(path)?;
+    let group = file.group("matrix")?;
+
+    let barcodes = read_strings(&group.dataset("barcodes")?)?;
+    let features = group.group("features")?;
+    let features = read_strings(&features.dataset("id")?)?;
+
+    let data_ds = group.dataset("data")?;
+    let integer = is_integer(&data_ds)?;
+    let data = data_ds.read_raw::<f64>()?;
+    let indices = group.dataset("indices")?.read_raw::<u32>()?;
+    let indptr = group.dataset("indptr")?.read_raw::<u64>()?;
+
+    check_dims(&indptr, &indices, &data, barcodes.len())?;
+    Ok(SparseMatrix { features, barcodes, indptr, indices, data, integer })
+}
+
+fn check_dims(indptr: &[u64], indices: &[u32], data: &[f64], n_major: usize) -> H5Result<()> {
+    if indptr.len() != n_major + 1 {
+        return Err(format!("indptr has length {}, expected {}", indptr.len(), n_major + 1).into());
+    }
+    if indices.len() != data.len() || indptr[n_major] as usize != data.len() {
+        return Err("Inconsistent sparse matrix arrays".into());
+    }
+    Ok(())
+}
+
+/// Check the lengths of compressed sparse arrays before reading the indices and data in slices
+fn check_slices(indptr: &[u64], indices: &Dataset, data: &Dataset, n_major: usize) -> H5Result<()> {
+    if indptr.len() != n_major + 1 {
+        return Err(format!("indptr has length {}, expected {}", indptr.len(), n_major + 1).into());
+    }
+    if indptr.windows(2).any(|w| w[0] > w[1]) || indices.size() != data.size() || indptr[n_major] as usize != data.size() {
+        return Err("Inconsistent sparse matrix arrays".into());
+    }
+    Ok(())
+}
+
+/// Call `f(major, minor, value)` for each entry of compressed sparse arrays, reading the
+/// indices and data a slice at a time
+fn for_each_compressed(
+    indptr: &[u64],
+    indices: &Dataset,
+    data: &Dataset,
+    mut f: impl FnMut(usize, usize, f64) -> io::Result<()>,
+) -> H5Result<()> {
+    let nnz = data.size();
+    let mut major = 0;
+    let mut start = 0;
+    while start < nnz {
+        let end = (start + READ_ENTRIES).min(nnz);
+        let minor = indices.read_slice_1d::<u32, _>(start..end)?;
+        let values = data.read_slice_1d::<f64, _>(start..end)?;
+        for (pos, (&index, &value)) in (start..end).zip(minor.iter().zip(values.iter())) {
+            while indptr[major + 1] as usize <= pos {
+                major += 1;
+            }
+            f(major, index as usize, value)?;
+        }
+        start = end;
+    }
+    Ok(())
+}
+
+/// Write a 10x h5, h5ad, or Loom matrix to temporary files named `prefix`.<batch>.tmp of at most
+/// `max_entries` entries each, reading the input in slices rather than all at once
+pub fn spill_matrix(path: &Path, format: MatrixFormat, prefix: &Path, max_entries: u64) -> H5Result<SpilledMatrix> {
+    match format {
+        MatrixFormat::TenxH5 => spill_10x_h5(path, prefix, max_entries),
+        MatrixFormat::H5ad => spill_h5ad(path, prefix, max_entries),
+        MatrixFormat::Loom => spill_loom(path, prefix, max_entries),
+        MatrixFormat::Mtx => Err("Matrix Market input is not an HDF5 file".into()),
+    }
+}
+
+fn spill_10x_h5(path: &Path, prefix: &Path, max_entries: u64) -> H5Result<SpilledMatrix> {
+    info!("Reading 10x HDF5 matrix: {:?}", path);
+    let file = File::open(path)?;
+    let group = file.group("matrix")?;
+
+    let barcodes = read_strings(&group.dataset("barcodes")?)?;
+    let features = read_strings(&group.group("features")?.dataset("id")?)?;
+
+    let data = group.dataset("data")?;
+    let integer = is_integer(&data)?;
+    let indices = group.dataset("indices")?;
+    let indptr = group.dataset("indptr")?.read_raw::<u64>()?;
+    check_slices(&indptr, &indices, &data, barcodes.len())?;
+
+    let mut spill = ColumnSpill::create(prefix, features, barcodes, indptr.clone(), integer, max_entries)?;
+    for_each_compressed(&indptr, &indices, &data, |cell, feature, value| spill.push(feature, cell, value))?;
+    Ok(spill.finish()?)
+}
+
+fn spill_h5ad(path: &Path, prefix: &Path, max_entries: u64) -> H5Result<SpilledMatrix> {
+    info!("Reading AnnData matrix: {:?}", path);
+    let file = File::open(path)?;
+    let barcodes = read_frame_index(&file.group("obs")?)?;
+    let features = read_frame_index(&file.group("var")?)?;
+
+    let x = file.group("X").map_err(|_| "Only sparse X matrices are supported")?;
+    let encoding: VarLenUnicode = x.attr("encoding-type")?.read_scalar()?;
+
+    let data = x.dataset("data")?;
+    let integer = is_integer(&data)?;
+    let indices = x.dataset("indices")?;
+    let major_indptr = x.dataset("indptr")?.read_raw::<u64>()?;
+
+    match encoding.as_str() {
+        // rows are cells, so the arrays are already compressed over cells
+        "csr_matrix" => {
+            check_slices(&major_indptr, &indices, &data, barcodes.len())?;
+            let mut spill = ColumnSpill::create(prefix, features, barcodes, major_indptr.clone(), integer, max_entries)?;
+            for_each_compressed(&major_indptr, &indices, &data, |cell, feature, value| spill.push(feature, cell, value))?;
+            Ok(spill.finish()?)
+        }
+        "csc_matrix" => {
+            check_slices(&major_indptr, &indices, &data, features.len())?;
+            // entries per cell, counted with a first pass over the arrays
+            let ncol = barcodes.len();
+            let mut indptr: Vec<u64> = vec![0; ncol + 1];
+            for_each_compressed(&major_indptr, &indices, &data, |_, cell, _| {
+                if cell >= ncol {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Cell index {} out of bounds", cell)));
+                }
+                indptr[cell + 1] += 1;
+                Ok(())
+            })?;
+            for i in 0..ncol {
+                indptr[i + 1] += indptr[i];
+            }
+            let mut spill = ColumnSpill::create(prefix, features, barcodes, indptr, integer, max_entries)?;
+            for_each_compressed(&major_indptr, &indices, &data, |feature, cell, value| spill.push(feature, cell, value))?;
+            Ok(spill.finish()?)
+        }
+        other => Err(format!("Unsupported X encoding: {}", other).into()),
+    }
+}
+
+fn spill_loom(path: &Path, prefix: &Path, max_entries: u64) -> H5Result<SpilledMatrix> {
+    info!("Reading Loom matrix: {:?}", path);
+    let file = File::open(path)?;
+    let features = read_strings(&file.group("row_attrs")?.dataset("Gene")?)?;
+    let barcodes = read_strings(&file.group("col_attrs")?.dataset("CellID")?)?;
+
+    let ds = file.dataset("matrix")?;
+    let integer = is_integer(&ds)?;
+    let shape = ds.shape();
+    let (nrow, ncol) = (features.len(), barcodes.len());
+    if shape.len() != 2 || shape[0] != nrow || shape[1] != ncol {
+        return Err(format!("Loom matrix shape {:?} does not match attributes", shape).into());
+    }
+
+    // entries per cell, counted with a first pass over the feature rows
+    let mut indptr: Vec<u64> = vec![0; ncol + 1];
+    for row in 0..nrow {
+        let values = ds.read_slice_1d::<f64, _>((row, ..))?;
+        for (col, &value) in values.iter().enumerate() {
+            if value != 0.0 {
+                indptr[col + 1] += 1;
+            }
+        }
+    }
+    for i in 0..ncol {
+        indptr[i + 1] += indptr[i];
+    }
+
+    let mut spill = ColumnSpill::create(prefix, features, barcodes, indptr, integer, max_entries)?;
+    for row in 0..nrow {
+        let values = ds.read_slice_1d::<f64, _>((row, ..))?;
+        for (col, &value) in values.iter().enumerate() {
+            if value != 0.0 {
+                spill.push(row, col, value)?;
+            }
+        }
+    }
+    Ok(spill.finish()?)
+}
+
+fn write_index_frame(file: &File, name: &str, index: &[String]) -> H5Result<()> {
+    let group = file.create_group(name)?;
+    set_str_attr(&group, "encoding-type", "dataframe")?;
+    set_str_attr(&group, "encoding-version", "0.2.0")?;
+    set_str_attr(&group, "_index", "_index")?;
+    group.new_attr::<VarLenUnicode>().shape(0).create("column-order")?;
+    let ds = write_strings(&group, "_index", index)?;
+    set_str_attr(&ds, "encoding-type", "string-array")?;
+    set_str_attr(&ds, "encoding-version", "0.2.0")?;
+    Ok(())
+}
+
+fn write_empty_dict(group: &Group, name: &str) -> H5Result<Group> {
+    let group = group.create_group(name)?;
+    set_str_attr(&group, "encoding-type", "dict")?;
+    set_str_attr(&group, "encoding-version", "0.1.0")?;
+    Ok(group)
+}
+
+/// Write a scalar string element of an AnnData dict
+fn write_string_element(group: &Group, name: &str, value: &str) -> H5Result<()> {
+    let value: VarLenUnicode = value.parse().map_err(|e| format!("Invalid string {:?}: {}", value, e))?;
+    let ds = group.new_dataset::<VarLenUnicode>().shape(()).create(name)?;
+    ds.write_scalar(&value)?;
+    set_str_attr(&ds, "encoding-type", "string")?;
+    set_str_attr(&ds, "encoding-version", "0.2.0")?;
+    Ok(())
+}
+
+/// Write an AnnData file with X stored as CSR (cells x features). The fragtk version, the
+/// command line, and the run `parameters` are stored as strings in `uns`.
+pub fn write_h5ad(path: &Path, matrix: &Columns, parameters: &[(String, String)]) -> H5Result<()> {
+    info!("Writing AnnData matrix: {:?}", path);
+    let file = File::create(path)?;
+    set_str_attr(&file, "encoding-type", "anndata")?;
+    set_str_attr(&file, "encoding-version", "0.1.0")?;
+
+    // CSC over features x cells is CSR over cells x features
+    let x = file.create_group("X")?;
+    set_str_attr(&x, "encoding-type", "csr_matrix")?;
+    set_str_attr(&x, "encoding-version", "0.1.0")?;
+    let shape = [matrix.barcodes().len() as i64, matrix.features().len() as i64];
+    x.new_attr_builder().with_data(&shape[..]).create("shape")?;
+    write_sparse(&x, matrix)?;
+
+    write_index_frame(&file, "obs", matrix.barcodes())?;
+    write_index_frame(&file, "var", matrix.features())?;
+    for name in ["obsm", "varm", "obsp", "varp", "layers"] {
+        write_empty_dict(&file, name)?;
+    }
+    let uns = write_empty_dict(&file, "uns")?;
+    write_string_element(&uns, "fragtk_version", env!("CARGO_PKG_VERSION"))?;
+    write_string_element(&uns, "command", &std::env::args().collect::<Vec<String>>().join(" "))?;
+    let group = write_empty_dict(&uns, "parameters")?;
+    for (name, value) in parameters {
+        write_string_element(&group, name, value)?;
+    }
+    file.close()?;
+    Ok(())
+}
+
+/// Read an AnnData file with a sparse X
+pub fn read_h5ad(path: &Path) -> H5Result<SparseMatrix> {
+    info!("Reading AnnData matrix: {:?}", path);
+    let file = File::open(path)?;
+    let barcodes = read_frame_index(&file.group("obs")?)?;
+    let features = read_frame_index(&file.group("var")?)?;
+
+    let x = file.group("X").map_err(|_| "Only sparse X matrices are supported")?;
+    let encoding: VarLenUnicode = x.attr("encoding-type")?.read_scalar()?;
+
+    let data_ds = x.dataset("data")?;
+    let integer = is_integer(&data_ds)?;
+    let data = data_ds.read_raw::<f64>()?;
+    let indices = x.dataset("indices")?.read_raw::<u32>()?;
+    let indptr = x.dataset("indptr")?.read_raw::<u64>()?;
+
+    let (indptr, indices, data) = match encoding.as_str() {
+        "csr_matrix" => {
+            check_dims(&indptr, &indices, &data, barcodes.len())?;
+            (indptr, indices, data)
+        }
+        "csc_matrix" => {
+            check_dims(&indptr, &indices, &data, features.len())?;
+            transpose(&indptr, &indices, &data, barcodes.len())
+        }
+        other => return Err(format!("Unsupported X encoding: {}", other).into()),
+    };
+    Ok(SparseMatrix { features, barcodes, indptr, indices, data, integer })
+}
+
+fn read_frame_index(group: &Group) -> H5Result<Vec<String>> {
+    let index: VarLenUnicode = group
+        .attr("_index")
+        .and_then(|attr| attr.read_scalar())
+        .unwrap_or_else(|_| "_index".parse().unwrap());
+    read_strings(&group.dataset(index.as_str())?)
+}
+
+/// Chromosome, start, and end of features named chrom-start-end, or None if any feature is not a region
+fn feature_regions(features: &[String]) -> Option<(Vec<String>, Vec<i64>, Vec<i64>)> {
+    let mut regions = (Vec::with_capacity(features.len()), Vec::with_capacity(features.len()), Vec::with_capacity(features.len()));
+    for feature in features {
+        let mut fields = feature.rsplitn(3, '-');
+        let end = fields.next()?.parse::<i64>().ok()?;
+        let start = fields.next()?.parse::<i64>().ok()?;
+        regions.0.push(fields.next()?.to_string());
+        regions.1.push(start);
+        regions.2.push(end);
+    }
+    Some(regions)
+}
+
+fn write_numbers<T: hdf5::H5Type>(group: &Group, name: &str, values: &[T]) -> H5Result<()> {
+    group.new_dataset_builder().deflate(4).with_data(values).create(name)?;
+    Ok(())
+}
+
+/// Write a Loom file with a dense features x cells matrix, a block of columns at a time.
+/// Rows carry the feature name (Gene and Accession), the region for chrom-start-end features, and
+/// the total count and cells detected; columns carry the barcode (CellID), total count, and features detected.
+pub fn write_loom(path: &Path, matrix: &Columns) -> H5Result<()> {
+    info!("Writing Loom matrix: {:?}", path);
+    let file = File::create(path)?;
+    let (nrow, ncol) = (matrix.features().len(), matrix.barcodes().len());
+
+    let ds = if matrix.integer() {
+        file.new_dataset::<i32>().deflate(4).shape((nrow, ncol)).create("matrix")?
+    } else {
+        file.new_dataset::<f32>().deflate(4).shape((nrow, ncol)).create("matrix")?
+    };
+    let indptr = matrix.indptr();
+    let mut row_totals: Vec<f64> = vec![0.0; nrow];
+    let mut row_detected: Vec<i64> = vec![0; nrow];
+    let mut col_totals: Vec<f64> = vec![0.0; ncol];
+    // columns in each dense block
+    let width = (LOOM_BLOCK / nrow.max(1)).max(1);
+    matrix.for_each_batch(|columns, indices, data| -> H5Result<()> {
+        let offset = indptr[columns.start];
+        for block_start in columns.clone().step_by(width) {
+            let block_end = (block_start + width).min(columns.end);
+            let mut block: Array2<f64> = Array2::zeros((nrow, block_end - block_start));
+            for col in block_start..block_end {
+                for pos in (indptr[col] - offset) as usize..(indptr[col + 1] - offset) as usize {
+                    let row = indices[pos] as usize;
+                    block[[row, col - block_start]] = data[pos];
+                    row_totals[row] += data[pos];
+                    row_detected[row] += 1;
+                    col_totals[col] += data[pos];
+                }
+            }
+            if matrix.integer() {
+                ds.write_slice(&block.mapv(|x| x as i32), (.., block_start..block_end))?;
+            } else {
+                ds.write_slice(&block.mapv(|x| x as f32), (.., block_start..block_end))?;
+            }
+        }
+        Ok(())
+    })?;
+
+    let row_attrs = file.create_group("row_attrs")?;
+    write_strings(&row_attrs, "Gene", matrix.features())?;
+    write_strings(&row_attrs, "Accession", matrix.features())?;
+    if let Some((chroms, starts, ends)) = feature_regions(matrix.features()) {
+        write_strings(&row_attrs, "Chromosome", &chroms)?;
+        write_numbers(&row_attrs, "Start", &starts)?;
+        write_numbers(&row_attrs, "End", &ends)?;
+    }
+    write_numbers(&row_attrs, "TotalCounts", &row_totals)?;
+    write_numbers(&row_attrs, "CellsDetected", &row_detected)?;
+
+    let col_attrs = file.create_group("col_attrs")?;
+    write_strings(&col_attrs, "CellID", matrix.barcodes())?;
+    let col_detected: Vec<i64> = indptr.windows(2).map(|w| (w[1] - w[0]) as i64).collect();
+    write_numbers(&col_attrs, "TotalCounts", &col_totals)?;
+    write_numbers(&col_attrs, "FeaturesDetected", &col_detected)?;
+    for name in ["layers", "row_graphs", "col_graphs"] {
+        file.create_group(name)?;
+    }
+    let attrs = file.create_group("attrs")?;
+    write_strings(&attrs, "LOOM_SPEC_VERSION", &["3.0.0".to_string()])?;
+    file.close()?;
+    Ok(())
+}
+
+/// Read a Loom file, sparsifying the dense matrix one feature row at a time
+pub fn read_loom(path: &Path) -> H5Result<SparseMatrix> {
+    info!("Reading Loom matrix: {:?}", path);
+    let file = File::open(path)?;
+    let features = read_strings(&file.group("row_attrs")?.dataset("Gene")?)?;
+    let barcodes = read_strings(&file.group("col_attrs")?.dataset("CellID")?)?;
+
+    let ds = file.dataset("matrix")?;
+    let integer = is_integer(&ds)?;
+    let shape = ds.shape();
+    if shape.len() != 2 || shape[0] != features.len() || shape[1] != barcodes.len() {
+        return Err(format!("Loom matrix shape {:?} does not match attributes", shape).into());
+    }
+
+    let mut indptr: Vec<u64> = vec![0];
+    let mut indices: Vec<u32> = Vec::new();
+    let mut data: Vec<f64> = Vec::new();
+    for row in 0..features.len() {
+        let values = ds.read_slice_1d::<f64, _>((row, ..))?;
+        for (col, &value) in values.iter().enumerate() {
+            if value != 0.0 {
+                indices.push(col as u32);
+                data.push(value);
+            }
+        }
+        indptr.push(data.len() as u64);
+    }
+
+    // rows are features here; store with cells as columns
+    let (indptr, indices, data) = transpose(&indptr, &indices, &data, barcodes.len());
+    Ok(SparseMatrix { features, barcodes, indptr, indices, data, integer })
+}
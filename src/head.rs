@@ -0,0 +1,67 @@
+use std::{
+    io,
+    path::Path,
+    error::Error,
+    io::BufRead,
+    io::Write,
+};
+use log::info;
+use crate::fragments::{is_stdin, open_fragments, verify_compression, ReadOptions};
+
+/// Print the header lines and first records of a fragment file, optionally after checking
+/// that every compressed block of the file is intact
+pub fn run(matches: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+    let frag_file = Path::new(matches.get_one::<String>("fragments").unwrap());
+    info!("Received fragment file: {:?}", frag_file);
+    let n = *matches.get_one::<usize>("lines").unwrap();
+
+    if matches.get_flag("verify") {
+        if is_stdin(frag_file) {
+            return Err("--verify needs a file".into());
+        }
+        let check = verify_compression(frag_file)?;
+        match check.format {
+            "plain" => eprintln!("{:?}: not compressed, nothing to verify", frag_file),
+            "zstd" => eprintln!(
+                "{:?}: zstd, {} bytes decompressed to {} bytes, OK",
+                frag_file, check.compressed_bytes, check.uncompressed_bytes,
+            ),
+            format => {
+                eprintln!(
+                    "{:?}: {}, {} members with matching CRC32 and size, {} bytes decompressed to {} bytes, OK",
+                    frag_file, format, check.members, check.compressed_bytes, check.uncompressed_bytes,
+                );
+                if format == "bgzf" && !check.bgzf_eof {
+                    eprintln!("{:?}: no BGZF end-of-file marker, the file may be truncated", frag_file);
+                }
+            }
+        }
+    }
+
+    let mut reader = open_fragments(frag_file, &ReadOptions::from_matches(matches)?)?;
+    let stdout = io::stdout();
+    let mut writer = io::BufWriter::new(stdout.lock());
+    let mut records: usize = 0;
+    // lines are copied as bytes, whatever their encoding
+    let mut line: Vec<u8> = Vec::new();
+    while records < n {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        if !line.starts_with(b"#") {
+            records += 1;
+        }
+        if let Err(e) = writer.write_all(&line) {
+            // the reader of the output, such as `head`, has finished
+            if e.kind() == io::ErrorKind::BrokenPipe {
+                return Ok(());
+            }
+            return Err(e.into());
+        }
+    }
+    match writer.flush() {
+        Err(e) if e.kind() != io::ErrorKind::BrokenPipe => Err(e.into()),
+        _ => Ok(()),
+    }
+}
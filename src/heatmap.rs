@@ -0,0 +1,174 @@
+use std::{
+    io,
+    fs::File,
+    path::Path,
+    error::Error,
+    io::Write,
+};
+use flate2::Compression;
+use gzp::{deflate::Gzip, ZBuilder};
+use log::info;
+use rust_lapper::{Interval, Lapper};
+use rustc_hash::FxHashMap;
+use crate::bed::{read_regions, Region};
+use crate::cells::CellGroups;
+use crate::fragments::{for_each_fragment_with_progress, ReadOptions};
+use crate::matrix::json_string;
+use crate::progress::Progress;
+use crate::threads::ThreadConfig;
+
+/// Window of bins around the reference point of each region
+struct Windows {
+    flank: u32,
+    bins: usize,
+    bin_size: u32,
+    /// window start (may be negative near chromosome starts) and strand for each region
+    starts: Vec<(i64, char)>,
+    trees: FxHashMap<String, Lapper<u32, usize>>,
+}
+
+impl Windows {
+    fn new(regions: &[Region], flank: u32, bins: usize, reference_point: &str) -> Windows {
+        let bin_size = 2 * flank / bins as u32;
+        let mut starts = Vec::with_capacity(regions.len());
+        let mut intervals: FxHashMap<String, Vec<Interval<u32, usize>>> = FxHashMap::default();
+
+        for (index, region) in regions.iter().enumerate() {
+            let reference = match (reference_point, region.strand) {
+                ("center", _) => (region.start + region.end) / 2,
+                ("TSS", '-') | ("TES", '+') | ("TES", '.') => region.end,
+                _ => region.start,
+            };
+            let start = reference as i64 - flank as i64;
+            starts.push((start, region.strand));
+            intervals.entry(region.chrom.clone()).or_default().push(Interval {
+                start: start.max(0) as u32,
+                stop: reference + flank,
+                val: index,
+            });
+        }
+
+        let trees = intervals
+            .into_iter()
+            .map(|(chr, intervals)| (chr, Lapper::new(intervals)))
+            .collect();
+
+        Windows { flank, bins, bin_size, starts, trees }
+    }
+
+    /// Add an insertion to the bins of all windows containing it
+    fn add(&self, counts: &mut [u32], chrom: &str, pos: u32) {
+        if let Some(lapper) = self.trees.get(chrom) {
+            for interval in lapper.find(pos, pos + 1) {
+                let (start, strand) = self.starts[interval.val];
+                let mut bin = ((pos as i64 - start) / self.bin_size as i64) as usize;
+                if strand == '-' {
+                    bin = self.bins - 1 - bin;
+                }
+                counts[interval.val * self.bins + bin] += 1;
+            }
+        }
+    }
+}
+
+pub fn run(matches: &clap::ArgMatches, progress: &Progress) -> Result<(), Box<dyn Error>> {
+
+    let frag_file = Path::new(matches.get_one::<String>("fragments").unwrap());
+    info!("Received fragment file: {:?}", frag_file);
+    let read_options = ReadOptions::from_matches(matches)?;
+
+    let region_file = Path::new(matches.get_one::<String>("regions").unwrap());
+    info!("Received regions file: {:?}", region_file);
+
+    let output = Path::new(matches.get_one::<String>("outfile").unwrap());
+    let flank = *matches.get_one::<u32>("flank").unwrap();
+    let bins = *matches.get_one::<usize>("bins").unwrap();
+    let reference_point = matches.get_one::<String>("reference_point").unwrap();
+    let num_threads = ThreadConfig::from_matches(matches).compress;
+
+    if bins == 0 || !(2 * flank as usize).is_multiple_of(bins) {
+        return Err(format!("Window size ({}) must be divisible by the number of bins ({})", 2 * flank, bins).into());
+    }
+
+    let groups = CellGroups::from_matches(matches, "all")?;
+    let regions = read_regions(region_file)?;
+    info!("Loaded {} regions", regions.len());
+
+    let windows = Windows::new(&regions, flank, bins, reference_point);
+
+    // regions x bins counts for each group
+    let mut counts: Vec<Vec<u32>> = vec![vec![0; regions.len() * bins]; groups.names.len()];
+    for_each_fragment_with_progress(frag_file, &read_options, progress, |fragment| {
+        if let Some(group) = groups.get(fragment.barcode) {
+            windows.add(&mut counts[group], fragment.chrom, fragment.start);
+            windows.add(&mut counts[group], fragment.chrom, fragment.end);
+        }
+        Ok(())
+    })?;
+
+    info!("Writing matrix: {:?}", output);
+    write_matrix(output, &regions, &windows, reference_point, &groups.names, &counts, num_threads)?;
+
+    Ok(())
+}
+
+/// Write a deepTools computeMatrix-compatible gzipped matrix, with one sample per cell group
+fn write_matrix(
+    outfile: &Path,
+    regions: &[Region],
+    windows: &Windows,
+    reference_point: &str,
+    sample_labels: &[String],
+    counts: &[Vec<u32>],
+    num_threads: usize,
+) -> io::Result<()> {
+    let mut writer = ZBuilder::<Gzip, _>::new()
+        .compression_level(Compression::default())
+        .num_threads(num_threads)
+        .from_writer(File::create(outfile)?);
+
+    let n = sample_labels.len();
+    let repeat = |value: String| vec![value; n].join(",");
+    let boundaries: Vec<String> = (0..=n).map(|i| (i * windows.bins).to_string()).collect();
+    let labels: Vec<String> = sample_labels.iter().map(|x| json_string(x)).collect();
+
+    let header = format!(
+        "@{{\"upstream\":[{up}],\"downstream\":[{up}],\"body\":[{zero}],\"bin size\":[{bin}],\
+        \"ref point\":[{refpoint}],\"verbose\":false,\"bin avg type\":\"sum\",\
+        \"missing data as zero\":true,\"min threshold\":null,\"max threshold\":null,\"scale\":1,\
+        \"skip zeros\":false,\"nan after end\":false,\"proc number\":1,\"sort regions\":\"keep\",\
+        \"sort using\":\"mean\",\"unscaled 5 prime\":[{zero}],\"unscaled 3 prime\":[{zero}],\
+        \"group_labels\":[\"regions\"],\"group_boundaries\":[0,{nregions}],\
+        \"sample_labels\":[{labels}],\"sample_boundaries\":[{boundaries}]}}\n",
+        up = repeat(windows.flank.to_string()),
+        zero = repeat("0".to_string()),
+        bin = repeat(windows.bin_size.to_string()),
+        refpoint = repeat(json_string(reference_point)),
+        nregions = regions.len(),
+        labels = labels.join(","),
+        boundaries = boundaries.join(","),
+    );
+    writer.write_all(header.as_bytes())?;
+
+    let mut output = String::new();
+    for (index, region) in regions.iter().enumerate() {
+        output.push_str(&format!(
+            "{}\t{}\t{}\t{}\t.\t{}",
+            region.chrom, region.start, region.end, region.name, region.strand
+        ));
+        for group_counts in counts {
+            for value in &group_counts[index * windows.bins..(index + 1) * windows.bins] {
+                output.push_str(&format!("\t{}", value));
+            }
+        }
+        output.push('\n');
+        if output.len() > 1 << 20 {
+            writer.write_all(output.as_bytes())?;
+            output.clear();
+        }
+    }
+    writer.write_all(output.as_bytes())?;
+    writer.finish().map_err(io::Error::other)?;
+
+    Ok(())
+}
@@ -0,0 +1,143 @@
+use std::{
+    io,
+    fs::File,
+    path::Path,
+    error::Error,
+    io::Write,
+};
+use log::info;
+use rustc_hash::{FxHashMap, FxHashSet};
+use crate::cells::load_cells;
+use crate::fragments::{for_each_fragment_with_progress, ReadOptions};
+use crate::progress::Progress;
+use crate::sketch::{fnv1a, mix};
+
+/// Sampled fragments a barcode must share between samples to be reported, so that a chance
+/// coincidence in a small sample is not reported as a large shared fraction
+const MIN_SHARED_HASHES: usize = 3;
+
+/// Fragments of one barcode in one sample, and the hashes of a fixed fraction of them
+#[derive(Default)]
+struct BarcodeSample {
+    fragments: u64,
+    hashes: Vec<u64>,
+}
+
+/// Compare the fragments of each barcode found in more than one sample's fragment file. Index
+/// hopping and barcode collisions leave the same fragments (identical coordinates) under the same
+/// barcode in two samples, which independent cells almost never share. Fragments are compared by
+/// the hashes of a fixed fraction of them, so memory use stays a fraction of the input.
+pub fn run(matches: &clap::ArgMatches, progress: &Progress) -> Result<(), Box<dyn Error>> {
+
+    let frag_files: Vec<&String> = matches.get_many::<String>("fragments").unwrap().collect();
+    let read_options = ReadOptions::from_matches(matches)?;
+    if frag_files.len() < 2 {
+        return Err("At least two fragment files are needed to compare barcodes across samples".into());
+    }
+    let output = Path::new(matches.get_one::<String>("outfile").unwrap());
+    let min_fragments = *matches.get_one::<u64>("min_fragments").unwrap();
+    let min_shared = *matches.get_one::<f64>("min_shared").unwrap();
+    let fraction = *matches.get_one::<f64>("sample_fraction").unwrap();
+    if !(fraction > 0.0 && fraction <= 1.0) {
+        return Err("--sample-fraction must be in (0, 1]".into());
+    }
+    // fragments with a hash at or below this are compared
+    let threshold = (fraction * u64::MAX as f64) as u64;
+    let cells = load_cells(matches)?;
+
+    let mut samples: Vec<FxHashMap<String, BarcodeSample>> = Vec::with_capacity(frag_files.len());
+    for frag_file in &frag_files {
+        info!("Received fragment file: {:?}", frag_file);
+        let mut barcodes: FxHashMap<String, BarcodeSample> = FxHashMap::default();
+        let mut current_chrom = String::new();
+        let mut chrom_hash: u64 = 0;
+        for_each_fragment_with_progress(Path::new(frag_file), &read_options, progress, |fragment| {
+            if cells.as_ref().is_some_and(|cells| !cells.contains(fragment.barcode)) {
+                return Ok(());
+            }
+            if fragment.chrom != current_chrom {
+                current_chrom = fragment.chrom.to_string();
+                chrom_hash = fnv1a(current_chrom.as_bytes());
+            }
+            let sample = match barcodes.get_mut(fragment.barcode) {
+                Some(sample) => sample,
+                None => barcodes.entry(fragment.barcode.to_string()).or_default(),
+            };
+            sample.fragments += 1;
+            let hash = mix(chrom_hash ^ mix(((fragment.start as u64) << 32) | fragment.end as u64));
+            if hash <= threshold {
+                sample.hashes.push(hash);
+            }
+            Ok(())
+        })?;
+        barcodes.retain(|_, sample| sample.fragments >= min_fragments);
+        for sample in barcodes.values_mut() {
+            sample.hashes.sort_unstable();
+            sample.hashes.dedup();
+        }
+        samples.push(barcodes);
+    }
+
+    // barcodes in more than one sample, in sorted order
+    let mut shared_barcodes: Vec<&String> = Vec::new();
+    let mut seen: FxHashSet<&String> = FxHashSet::default();
+    for (i, barcodes) in samples.iter().enumerate() {
+        for barcode in barcodes.keys() {
+            if !seen.contains(barcode) && samples[i + 1..].iter().any(|other| other.contains_key(barcode)) {
+                shared_barcodes.push(barcode);
+            }
+            seen.insert(barcode);
+        }
+    }
+    shared_barcodes.sort_unstable();
+    info!("{} barcodes with at least {} fragments are in more than one sample", shared_barcodes.len(), min_fragments);
+
+    info!("Writing suspicious barcodes: {:?}", output);
+    let mut writer = io::BufWriter::new(File::create(output)?);
+    writeln!(writer, "barcode\tsample1\tsample2\tfragments1\tfragments2\tshared_fragments\tshared_fraction")?;
+    let mut flagged: usize = 0;
+    for barcode in &shared_barcodes {
+        for i in 0..samples.len() {
+            for j in (i + 1)..samples.len() {
+                let (a, b) = match (samples[i].get(*barcode), samples[j].get(*barcode)) {
+                    (Some(a), Some(b)) => (a, b),
+                    _ => continue,
+                };
+                let shared = count_shared(&a.hashes, &b.hashes);
+                // share of the smaller sample's fragments also found in the other sample
+                let smaller = a.hashes.len().min(b.hashes.len());
+                let shared_fraction = if smaller == 0 { 0.0 } else { shared as f64 / smaller as f64 };
+                if shared < MIN_SHARED_HASHES || shared_fraction < min_shared {
+                    continue;
+                }
+                writeln!(
+                    writer, "{}\t{}\t{}\t{}\t{}\t{:.0}\t{:.4}",
+                    barcode, frag_files[i], frag_files[j], a.fragments, b.fragments, shared as f64 / fraction, shared_fraction,
+                )?;
+                flagged += 1;
+            }
+        }
+    }
+    writer.flush()?;
+    info!("Flagged {} barcode and sample pairs sharing at least {:.1}% of their fragments", flagged, 100.0 * min_shared);
+
+    Ok(())
+}
+
+/// Number of values in both sorted lists
+fn count_shared(a: &[u64], b: &[u64]) -> usize {
+    let (mut i, mut j) = (0, 0);
+    let mut shared: usize = 0;
+    while i < a.len() && j < b.len() {
+        match a[i].cmp(&b[j]) {
+            std::cmp::Ordering::Less => i += 1,
+            std::cmp::Ordering::Greater => j += 1,
+            std::cmp::Ordering::Equal => {
+                shared += 1;
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    shared
+}
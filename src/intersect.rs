@@ -0,0 +1,163 @@
+use std::{
+    io,
+    fs::File,
+    path::Path,
+    error::Error,
+    io::BufRead,
+    io::Write,
+};
+use flate2::Compression;
+use gzp::{
+    deflate::Bgzf,
+    ZWriter,
+    par::compress::{ParCompress, ParCompressBuilder},
+};
+use log::{info, warn};
+use rustc_hash::{FxHashMap, FxHashSet};
+use crate::bed::{region_trees, RegionTrees};
+use crate::cells::load_cells;
+use crate::fragments::{decode_line, open_fragments, ReadOptions};
+use crate::output::PartialOutput;
+use crate::progress::Progress;
+use crate::threads::ThreadConfig;
+
+/// Keep (`keep_overlapping = true`, intersect) or remove (subtract) fragments
+/// overlapping a set of BED regions
+pub fn run(matches: &clap::ArgMatches, keep_overlapping: bool, progress: &Progress) -> Result<(), Box<dyn Error>> {
+
+    let frag_file = Path::new(matches.get_one::<String>("fragments").unwrap());
+    info!("Received fragment file: {:?}", frag_file);
+    let read_options = ReadOptions::from_matches(matches)?;
+
+    let bed_file = Path::new(matches.get_one::<String>("bed").unwrap());
+    info!("Received BED file: {:?}", bed_file);
+
+    let output_file = Path::new(matches.get_one::<String>("outfile").unwrap());
+    info!("Output fragment file: {:?}", output_file);
+
+    let num_threads = ThreadConfig::from_matches(matches).compress;
+
+    let cells = load_cells(matches)?;
+
+    let (n_regions, regions) = region_trees(bed_file)?;
+    info!("Loaded {} regions", n_regions);
+
+    // a failed run removes the partial output and counts
+    let mut partial = PartialOutput::new();
+    let output_file = partial.file(output_file.to_path_buf());
+    let cell_counts = split_fragments(frag_file, &output_file, &regions, cells.as_ref(), keep_overlapping, num_threads, &read_options, progress)?;
+
+    if let Some(stats_file) = matches.get_one::<String>("stats") {
+        info!("Writing per-cell counts: {:?}", stats_file);
+        write_cell_counts(&partial.file(stats_file.into()), &cell_counts)?;
+    }
+    partial.finish();
+
+    Ok(())
+}
+
+/// Stream fragments, writing those that pass to a BGZF-compressed output file.
+/// Returns kept and removed fragment counts for each cell barcode.
+#[allow(clippy::too_many_arguments)]
+fn split_fragments(
+    frag_file: &Path,
+    output_file: &Path,
+    regions: &RegionTrees,
+    cells: Option<&FxHashSet<String>>,
+    keep_overlapping: bool,
+    num_threads: usize,
+    read_options: &ReadOptions,
+    progress: &Progress,
+) -> io::Result<FxHashMap<String, [u64; 2]>> {
+
+    let mut reader = open_fragments(frag_file, read_options)?;
+
+    let writer = File::create(output_file)?;
+    let mut writer: ParCompress<Bgzf> = ParCompressBuilder::new()
+        .compression_level(Compression::default())
+        .num_threads(num_threads)
+        .map_err(io::Error::other)?
+        .from_writer(writer);
+
+    let mut cell_counts: FxHashMap<String, [u64; 2]> = FxHashMap::default();
+    let mut line_count: u64 = 0;
+    let mut buffer: Vec<u8> = Vec::with_capacity(1024);
+
+    loop {
+        buffer.clear();
+        if reader.read_until(b'\n', &mut buffer)? == 0 {
+            break;
+        }
+
+        // Keep header lines
+        if buffer.starts_with(b"#") {
+            writer.write_all(&buffer)?;
+            continue;
+        }
+
+        line_count += 1;
+        progress.update(line_count, None)?;
+
+        let line = match decode_line(&buffer) {
+            Some(line) => line,
+            None => {
+                warn!("Fragment {}: Not valid UTF-8, skipping", line_count);
+                continue;
+            }
+        };
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 4 {
+            warn!("Fragment {}: Less than four fields", line_count);
+            continue;
+        }
+
+        let barcode = fields[3];
+        if let Some(cells) = cells {
+            if !cells.contains(barcode) {
+                continue;
+            }
+        }
+
+        let (start, end): (u32, u32) = match (fields[1].parse(), fields[2].parse()) {
+            (Ok(start), Ok(end)) => (start, end),
+            _ => {
+                warn!("Fragment {}: Failed to parse coordinates", line_count);
+                continue;
+            }
+        };
+
+        let overlaps = regions
+            .get(fields[0])
+            .is_some_and(|lapper| lapper.find(start, end).next().is_some());
+
+        let keep = overlaps == keep_overlapping;
+        if keep {
+            writer.write_all(line.as_bytes())?;
+            writer.write_all(b"\n")?;
+        }
+
+        let counts = match cell_counts.get_mut(barcode) {
+            Some(counts) => counts,
+            None => cell_counts.entry(barcode.to_string()).or_default(),
+        };
+        counts[if keep { 0 } else { 1 }] += 1;
+    }
+    progress.finish(line_count, None);
+
+    writer.finish().map_err(io::Error::other)?;
+
+    Ok(cell_counts)
+}
+
+/// Write the kept and removed fragments of each cell barcode, sorted by barcode
+fn write_cell_counts(path: &Path, cell_counts: &FxHashMap<String, [u64; 2]>) -> io::Result<()> {
+    let mut barcodes: Vec<(&String, &[u64; 2])> = cell_counts.iter().collect();
+    barcodes.sort_unstable_by(|a, b| a.0.cmp(b.0));
+    let mut writer = io::BufWriter::new(File::create(path)?);
+    writeln!(writer, "barcode\tkept\tremoved")?;
+    for (barcode, [kept, removed]) in barcodes {
+        writeln!(writer, "{}\t{}\t{}", barcode, kept, removed)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
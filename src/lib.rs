@@ -0,0 +1,83 @@
+//! Fragment file processing for single-cell chromatin data.
+//!
+//! The `fragtk` binary is a command-line wrapper around these modules, so other tools can count
+//! fragments without running it. For example, to count insertions in peaks for a set of cells:
+//!
+//! ```no_run
+//! use std::path::Path;
+//! use fragtk::bed::{CoordinateSystem, PeakFormat};
+//! use fragtk::cellindex::CellIndex;
+//! use fragtk::f2m::{self, BedOptions};
+//! use fragtk::fragments::ReadOptions;
+//! use fragtk::matrix::FeaturesFormat;
+//! use fragtk::progress::Progress;
+//!
+//! # fn main() -> std::io::Result<()> {
+//! let options = BedOptions {
+//!     group: None,
+//!     resize: None,
+//!     format: PeakFormat::Bed,
+//!     min_qvalue: None,
+//!     coords: CoordinateSystem::Bed,
+//!     extend: (0, 0),
+//!     merge_overlapping: false,
+//! };
+//! let (total_peaks, peaks) = f2m::peak_intervals(
+//!     Path::new("peaks.bed"), &options, Path::new("features.tsv.gz"), FeaturesFormat::Names, 1,
+//! )?;
+//! let cells = CellIndex::from_barcodes(vec!["AAACGAAAGACTCGGA-1".to_string()]);
+//! let counts = f2m::count_fragments(
+//!     Path::new("fragments.tsv.gz"), &ReadOptions::default(), &peaks, &cells, total_peaks, &Progress::default(),
+//! )?;
+//! f2m::write_matrix_market(Path::new("matrix.mtx.gz"), &counts, total_peaks, cells.len(), 1)?;
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod f2m;
+pub mod cellselect;
+pub mod filter;
+pub mod matrix;
+pub mod convert;
+pub mod bed;
+pub mod intersect;
+pub mod cells;
+pub mod bbi;
+pub mod cutsites;
+pub mod fragments;
+pub mod heatmap;
+pub mod append;
+pub mod demux;
+pub mod callpeaks;
+pub mod cellindex;
+pub mod threads;
+pub mod fasta;
+pub mod strata;
+pub mod stats;
+pub mod output;
+pub mod perturb;
+pub mod summary;
+pub mod smooth;
+pub mod trackhub;
+pub mod spill;
+pub mod skips;
+pub mod shard;
+pub mod bam;
+pub mod qc;
+pub mod refine;
+pub mod gtf;
+pub mod spatial;
+pub mod cellfeatures;
+pub mod sketch;
+pub mod hopping;
+pub mod progress;
+pub mod head;
+pub mod profile;
+pub mod promoters;
+pub mod enrichment;
+pub mod pipeline;
+pub mod da;
+#[cfg(feature = "hdf5")]
+pub mod h5;
+#[cfg(feature = "cram")]
+pub mod cram;
@@ -1,18 +1,163 @@
-#[cfg(not(target_os = "windows"))]
+// global allocator chosen by cargo feature; without jemalloc or mimalloc (and always for jemalloc
+// on Windows) the system allocator is used
+#[cfg(all(feature = "jemalloc", not(any(feature = "mimalloc", feature = "system")), not(target_os = "windows")))]
 #[global_allocator]
 static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
 
-#[cfg(target_os = "windows")]
+#[cfg(all(feature = "mimalloc", not(feature = "system")))]
 #[global_allocator]
-static GLOBAL: std::alloc::System = std::alloc::System;
+static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
 use clap::{Command, Arg, ArgAction};
 use std::error::Error;
+use fragtk::{
+    append, callpeaks, cellfeatures, cellselect, convert, cutsites, da, enrichment, f2m, filter, head,
+    heatmap, hopping, intersect, pipeline, progress, refine, sketch, smooth, stats, summary, threads, trackhub,
+};
 
-mod f2m;
-mod cellselect;
-mod filter;
+/// Thread count arguments shared by subcommands
+fn thread_args() -> [Arg; 4] {
+    [
+        Arg::new("threads")
+            .short('t')
+            .long("threads")
+            .help("Number of threads to use for each stage. Defaults to 4, or the available cores if fewer")
+            .value_parser(clap::value_parser!(usize)),
+        Arg::new("io_threads")
+            .long("io-threads")
+            .help("Threads used to decompress BGZF-compressed input")
+            .value_parser(clap::value_parser!(usize)),
+        Arg::new("compute_threads")
+            .long("compute-threads")
+            .help("Threads used for parallel computation")
+            .value_parser(clap::value_parser!(usize)),
+        Arg::new("compress_threads")
+            .long("compress-threads")
+            .help("Threads used to compress output")
+            .value_parser(clap::value_parser!(usize)),
+    ]
+}
 
+/// Options for reading a BAM or CRAM file in place of a fragment file
+fn bam_args() -> [Arg; 4] {
+    [
+        Arg::new("bam_barcode_tag")
+            .long("bam-barcode-tag")
+            .value_name("TAG")
+            .help("BAM input: aux tag holding the cell barcode")
+            .default_value("CB"),
+        Arg::new("bam_min_mapq")
+            .long("bam-min-mapq")
+            .value_name("MAPQ")
+            .help("BAM input: minimum mapping quality of a read pair")
+            .value_parser(clap::value_parser!(u8))
+            .default_value("30"),
+        Arg::new("bam_fragments")
+            .long("write-fragments")
+            .value_name("FILE")
+            .help("BAM input: also write the fragments to this BGZF-compressed fragment file"),
+        Arg::new("reference")
+            .long("reference")
+            .value_name("FASTA")
+            .help("CRAM input: reference FASTA the reads were aligned to, indexed with samtools faidx"),
+    ]
+}
+
+/// Combining several `--cells` files
+fn cells_op_arg() -> Arg {
+    Arg::new("cells_op")
+        .long("cells-op")
+        .help("How to combine barcodes when --cells is given more than once")
+        .long_help("How to combine barcodes when --cells is given more than once: \
+               union (in any file), intersect (in every file), or subtract (in the first file but none of the others)")
+        .value_parser(["union", "intersect", "subtract"])
+        .default_value("union")
+}
+
+/// Parsing of `--cells` and `--groups` tables
+fn table_args() -> [Arg; 3] {
+    [
+        Arg::new("delimiter")
+            .long("delimiter")
+            .help("Field delimiter of cell and group tables: tab, comma, semicolon, space, or a single character")
+            .long_help("Field delimiter of cell and group tables: tab, comma, semicolon, space, or a single \
+                   character. Defaults to comma for .csv files and tab otherwise. Fields may be quoted"),
+        Arg::new("cells_column")
+            .long("cells-column")
+            .value_name("COLUMN")
+            .help("Column of cell and group tables holding the cell barcodes, by header name or 1-based index")
+            .long_help("Column of cell and group tables holding the cell barcodes, by header name or 1-based \
+                   index (default 1). The first row is read as a header when a column is given by name"),
+        Arg::new("group_column")
+            .long("group-column")
+            .value_name("COLUMN")
+            .help("Column of the group table holding the groups, by header name or 1-based index (default 2)"),
+    ]
+}
+
+/// Handling of barcodes that `--groups` lists more than once or that are missing from `--cells`
+fn group_args() -> [Arg; 2] {
+    [
+        Arg::new("duplicate_barcodes")
+            .long("duplicate-barcodes")
+            .value_name("POLICY")
+            .help("What to do with barcodes listed under several groups or missing from --cells")
+            .long_help("What to do with barcodes the group table lists under more than one group, or that are \
+                   missing from the --cells list: error (stop, naming the first), warn-skip (leave them out of \
+                   every group), or last-wins (use the last group listed; barcodes missing from --cells are \
+                   left out). A barcode listed twice under the same group is not a conflict")
+            .value_parser(["error", "warn-skip", "last-wins"])
+            .default_value("error"),
+        Arg::new("reconcile_report")
+            .long("reconcile-report")
+            .value_name("FILE")
+            .help("Write each conflicting, missing, or ungrouped barcode and what was done with it to this TSV file"),
+    ]
+}
+
+/// Shared arguments for the intersect and subtract subcommands
+fn region_filter_command(name: &'static str, about: &'static str) -> Command {
+    Command::new(name)
+        .about(about)
+        .arg(
+            Arg::new("fragments")
+                .short('f')
+                .long("fragments")
+                .help("Path to the fragment file, or a coordinate-sorted BAM or CRAM file")
+                .required(true),
+        )
+        .arg(
+            Arg::new("bed")
+                .short('b')
+                .long("bed")
+                .help("BED file containing genomic regions")
+                .required(true),
+        )
+        .arg(
+            Arg::new("outfile")
+                .short('o')
+                .long("outfile")
+                .help("Output fragment file (BGZF-compressed)")
+                .required(true),
+        )
+        .arg(
+            Arg::new("cells")
+                .short('c')
+                .long("cells")
+                .help("File containing cell barcodes to include, can be given more than once. All cells are included if not set")
+                .action(ArgAction::Append),
+        )
+        .arg(cells_op_arg())
+        .args(table_args())
+        .arg(
+            Arg::new("stats")
+                .short('s')
+                .long("stats")
+                .help("Write number of kept and removed fragments per cell to this file"),
+        )
+        .args(thread_args())
+        .args(bam_args())
+}
 
 fn main() -> Result<(), Box<dyn Error>> {
 
@@ -21,6 +166,13 @@ fn main() -> Result<(), Box<dyn Error>> {
         .author("Tim Stuart")
         .about("Fragment file processing tools")
         .arg_required_else_help(true)
+        .arg(
+            Arg::new("allow_truncated")
+                .long("allow-truncated")
+                .help("Keep partial results from truncated or corrupt gzip input, with a warning, instead of failing")
+                .action(ArgAction::SetTrue)
+                .global(true),
+        )
         .subcommand(
             Command::new("matrix")
                 .about("Create a feature x cell matrix from a fragment file")
@@ -28,22 +180,150 @@ fn main() -> Result<(), Box<dyn Error>> {
                     Arg::new("fragments")
                         .short('f')
                         .long("fragments")
-                        .help("Path to the fragment file")
-                        .required(true),
+                        .help("Path to the fragment file, or a coordinate-sorted BAM or CRAM file, can be given more than once")
+                        .long_help("Path to the fragment file, or a coordinate-sorted BAM or CRAM file. When given more \
+                               than once, the files are counted into one matrix with -1, -2, ... appended to the \
+                               barcodes of each file in order, and the cell barcodes must carry these suffixes")
+                        .required_unless_present("samplesheet")
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("samplesheet")
+                        .long("samplesheet")
+                        .help("Tab-separated file of fragment files to merge, with an optional barcode suffix for each")
+                        .long_help("Tab-separated file with one fragment file per line (relative to the samplesheet) \
+                               and an optional barcode suffix in the second column (default -1, -2, ... by line). \
+                               The files are counted into one matrix, as for --fragments given more than once. \
+                               Further columns are sample covariates written to design.tsv with --pseudobulk, \
+                               named by an optional header line starting with path")
+                        .conflicts_with("fragments")
+                        .conflicts_with_all(["low_memory", "debug_skips", "all_outputs", "raw_matrix", "bam_fragments"]),
                 )
                 .arg(
                     Arg::new("bed")
                         .short('b')
                         .long("bed")
                         .help("BED file containing non-overlapping genomic regions to quantify")
-                        .required(true),
+                        .required_unless_present_any(["bins", "gtf", "tss"]),
+                )
+                .arg(
+                    Arg::new("gtf")
+                        .long("gtf")
+                        .help("GTF file of genes, to count a gene activity matrix instead of --bed")
+                        .long_help("GTF file of genes, optionally gzipped, to count a gene activity matrix instead \
+                               of --bed. Each gene spans all records with its gene_id, extended by \
+                               --gene-upstream before the TSS and --gene-downstream after the gene end. \
+                               Overlapping genes are all counted. features.tsv lists the gene ID and name")
+                        .conflicts_with_all(["bed", "group", "peak_format", "min_qvalue", "resize_features"]),
+                )
+                .arg(
+                    Arg::new("tss")
+                        .long("tss")
+                        .help("BED file of transcripts or TSSs, to count a promoter matrix instead of --bed")
+                        .long_help("BED file of transcripts or TSSs, optionally with the strand in column 6, to \
+                               count a promoter matrix instead of --bed. The TSS is the region start, or the end \
+                               on the minus strand; each promoter spans --promoter-window around it, and \
+                               transcripts sharing a TSS give one promoter. Overlapping promoters are all \
+                               counted. features.tsv lists the promoter region and the name in column 4")
+                        .conflicts_with_all(["bed", "gtf", "group", "peak_format", "min_qvalue", "resize_features"]),
+                )
+                .arg(
+                    Arg::new("promoter_window")
+                        .long("promoter-window")
+                        .help("bp upstream and downstream of the TSS in each promoter, comma-separated")
+                        .default_value("2000,500")
+                        .requires("tss"),
+                )
+                .arg(
+                    Arg::new("gene_upstream")
+                        .long("gene-upstream")
+                        .help("Extend genes by this many bp upstream of the TSS, to include the promoter")
+                        .value_parser(clap::value_parser!(u32))
+                        .default_value("2000"),
+                )
+                .arg(
+                    Arg::new("gene_downstream")
+                        .long("gene-downstream")
+                        .help("Extend genes by this many bp downstream of the gene end")
+                        .value_parser(clap::value_parser!(u32))
+                        .default_value("0"),
+                )
+                .arg(
+                    Arg::new("gene_model")
+                        .long("gene-model")
+                        .help("Regions of each gene counted with --gtf: body (gene activity) or exons (RNA-like)")
+                        .long_help("Regions of each gene counted with --gtf. body counts the whole gene, extended \
+                               by --gene-upstream and --gene-downstream, for gene activity. exons counts the \
+                               union of the exon records of all transcripts of the gene, without extension, \
+                               for RNA-like fragment files from co-assays; with --mode fragments, a fragment \
+                               spanning several exons of a gene is counted once")
+                        .value_parser(["body", "exons"])
+                        .default_value("body")
+                        .requires("gtf"),
+                )
+                .arg(
+                    Arg::new("strandedness")
+                        .long("strandedness")
+                        .help("Count stranded fragments (strand in column 6) only in genes on the same (forward) or opposite (reverse) strand")
+                        .long_help("Count stranded fragments, with + or - in column 6, only in genes on the same \
+                               strand (forward) or the opposite strand (reverse, as for dUTP libraries), leaving \
+                               out antisense fragments. Fragments without a strand are skipped. Genes without a \
+                               strand in the GTF file are counted on either strand")
+                        .value_parser(["forward", "reverse"])
+                        .requires("gtf"),
+                )
+                .arg(
+                    Arg::new("bins")
+                        .long("bins")
+                        .help("Comma-separated genome bin sizes, e.g. 5000,10000,100000")
+                        .long_help("Comma-separated genome bin sizes, e.g. 5000,10000,100000. \
+                               One matrix is written per bin size (and for --bed, --gtf, or --tss if also given) from a \
+                               single pass over the fragments, each in a subdirectory of the output directory \
+                               (peaks, genes, or promoters, bins_<size>)")
+                        .requires("chrom_sizes"),
+                )
+                .arg(
+                    Arg::new("chrom_sizes")
+                        .short('g')
+                        .long("chrom-sizes")
+                        .help("Chromosome sizes file, FASTA index (.fai), or FASTA file, required for --bins")
+                        .long_help("Chromosome sizes file, required for --bins. A FASTA index (.fai) can be used \
+                               directly, or a FASTA file, optionally compressed, whose sequence lengths are taken \
+                               from its index if present and otherwise by reading the sequences"),
                 )
                 .arg(
                     Arg::new("cells")
                         .short('c')
                         .long("cells")
-                        .help("File containing cell barcodes to include")
-                        .required(true),
+                        .help("File containing cell barcodes to include, can be given more than once")
+                        .required_unless_present_any(["load_cells_index", "perturbations", "spatial", "groups", "bulk"])
+                        .action(ArgAction::Append),
+                )
+                .arg(cells_op_arg())
+                .args(table_args())
+                .arg(
+                    Arg::new("save_cells_index")
+                        .long("save-cells-index")
+                        .help("Save the cell barcode index to a file for reuse with --load-cells-index"),
+                )
+                .arg(
+                    Arg::new("barcode_map")
+                        .long("barcode-map")
+                        .help("Multiome barcode translation table: ATAC barcode and paired GEX barcode")
+                        .long_help("Tab- or comma-separated table of ATAC barcode and paired GEX barcode, as in the \
+                               10x multiome translation list. Fragments are counted by their ATAC barcode and \
+                               the matrix columns are named by the GEX barcode, so the matrix aligns with the \
+                               RNA data. Cells may be listed by either barcode")
+                        .conflicts_with_all(["samplesheet", "raw_matrix", "perturbations", "spatial"]),
+                )
+                .arg(
+                    Arg::new("load_cells_index")
+                        .long("load-cells-index")
+                        .help("Load a cell barcode index saved with --save-cells-index instead of --cells")
+                        .long_help("Load a cell barcode index saved with --save-cells-index instead of --cells. \
+                               The index is memory-mapped and queried in place, avoiding re-reading and \
+                               hashing large barcode lists when counting the same cells against many BED files")
+                        .conflicts_with("cells"),
                 )
                 .arg(
                     Arg::new("outdir")
@@ -54,21 +334,560 @@ fn main() -> Result<(), Box<dyn Error>> {
                                The output directory will contain matrix.mtx.gz, features.tsv, barcodes.tsv")
                         .required(true),
                 )
-                .arg(
-                    Arg::new("threads")
-                        .short('t')
-                        .long("threads")
-                        .help("Number of compression threads to use")
-                        .value_parser(clap::value_parser!(usize))
-                        .default_value("4")
-                        .required(false),
-                )
+                .args(thread_args())
+                .args(bam_args())
                 .arg(
                     Arg::new("group")
                         .long("group")
                         .help("Group peaks by variable in fourth BED column")
                         .action(ArgAction::SetTrue),
                 )
+                .arg(
+                    Arg::new("group_fractions")
+                        .long("group-fractions")
+                        .help("With --group, also write the fraction of each cell's counts in each peak group to group_fractions.tsv")
+                        .long_help("With --group, also write group_fractions.tsv next to the matrix: a cell x peak \
+                               group table of the fraction of each cell's counts in each group, so each row sums \
+                               to 1 (e.g. the promoter and distal fractions of each cell). Cells without counts in \
+                               any group have NA fractions")
+                        .action(ArgAction::SetTrue)
+                        .requires("group")
+                        .conflicts_with_all(["low_memory", "hashtags", "split_barcode_suffix", "condition_column"]),
+                )
+                .arg(
+                    Arg::new("group_order")
+                        .long("group-order")
+                        .help("Order of peak groups in the output matrix")
+                        .long_help("Order of peak groups in the output matrix. 'appearance' orders groups \
+                               as they first appear in the BED file, 'sorted' orders them by name so the \
+                               same groups give identical matrices for any BED sort order")
+                        .value_parser(["appearance", "sorted"])
+                        .default_value("appearance")
+                        .requires("group"),
+                )
+                .arg(
+                    Arg::new("group_list")
+                        .long("group-list")
+                        .help("File listing peak groups in output order, one per line")
+                        .long_help("File listing peak groups in output order, one per line. \
+                               Peaks in groups not listed are skipped. Overrides --group-order")
+                        .requires("group"),
+                )
+                .arg(
+                    Arg::new("peak_format")
+                        .long("peak-format")
+                        .help("Format of the features file. Detected from the extension (.narrowPeak, .broadPeak) if not given")
+                        .value_parser(["bed", "narrowPeak", "broadPeak"])
+                        .requires("bed"),
+                )
+                .arg(
+                    Arg::new("coordinate_system")
+                        .long("coordinate-system")
+                        .help("Coordinate convention of the features: bed (0-based, half-open) or gff (1-based, closed)")
+                        .long_help("Coordinate convention of the features: bed (0-based start, end excluded) or \
+                               gff (1-based start, end included), for BED files converted from GFF or GTF \
+                               without adjusting the start. Features are converted to half-open coordinates, \
+                               which are used in the feature names")
+                        .value_parser(["bed", "gff"])
+                        .default_value("bed"),
+                )
+                .arg(
+                    Arg::new("min_qvalue")
+                        .long("min-qvalue")
+                        .help("Keep narrowPeak/broadPeak features with at least this -log10 q-value (column 9)")
+                        .value_parser(clap::value_parser!(f64))
+                        .requires("bed"),
+                )
+                .arg(
+                    Arg::new("resize_features")
+                        .long("resize-features")
+                        .help("Resize BED features to a fixed width before counting, e.g. width=501,anchor=center")
+                        .long_help("Resize BED features to a fixed width before counting. Given as \
+                               width=N,anchor=center to center features on the region midpoint, \
+                               width=N,anchor=summit to center narrowPeak features on their summit, or \
+                               width=N,summit-col=C to center them on the summit given as an offset from \
+                               the start in column C (1-based; column 10 for narrowPeak)")
+                        .requires("bed"),
+                )
+                .arg(
+                    Arg::new("extend_upstream")
+                        .long("extend-upstream")
+                        .help("Extend BED features by this many bp upstream, by the strand in column 6 (before the start if none)")
+                        .long_help("Extend BED features by this many bp upstream before counting: before the \
+                               start, or after the end for features on the minus strand (column 6). Applied \
+                               after --resize-features, and clipped at zero. e.g. 2000 to include promoters \
+                               of gene bodies for gene activity scores")
+                        .value_parser(clap::value_parser!(u32))
+                        .default_value("0")
+                        .requires("bed"),
+                )
+                .arg(
+                    Arg::new("extend_downstream")
+                        .long("extend-downstream")
+                        .help("Extend BED features by this many bp downstream, by the strand in column 6 (after the end if none)")
+                        .value_parser(clap::value_parser!(u32))
+                        .default_value("0")
+                        .requires("bed"),
+                )
+                .arg(
+                    Arg::new("merge_overlapping")
+                        .long("merge-overlapping")
+                        .help("Merge overlapping BED features into one feature (within each group with --group)")
+                        .long_help("Merge overlapping BED features, after resizing and extension, into one feature \
+                               spanning them, so an insertion is counted once. With --group, overlapping features \
+                               of the same group are merged. Without this, an insertion in several overlapping \
+                               features is counted in each of them")
+                        .action(ArgAction::SetTrue)
+                        .requires("bed"),
+                )
+                .arg(
+                    Arg::new("low_memory")
+                        .long("low-memory")
+                        .help("Write counts to disk after each chromosome to bound memory with very many features")
+                        .long_help("Write counts to a temporary file in the output directory after each chromosome \
+                               instead of keeping all counts in memory, for feature sets with tens of millions of \
+                               features. Requires a fragment file sorted by chromosome")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with_all(["hashtags", "split_barcode_suffix", "perturbations"]),
+                )
+                .arg(
+                    Arg::new("permute_cells")
+                        .long("permute-cells")
+                        .value_name("SEED")
+                        .help("Also write a control matrix with cell barcodes randomly permuted, to the permuted subdirectory")
+                        .long_help("Also write a control matrix to the permuted subdirectory of each output matrix, \
+                               with the counts of each cell moved to a randomly chosen barcode (the same \
+                               permutation for every matrix). Cell depths are preserved, giving an empirical \
+                               null for enrichment tests. The same seed gives the same permutation")
+                        .value_parser(clap::value_parser!(u64))
+                        .conflicts_with_all(["low_memory", "hashtags", "split_barcode_suffix"]),
+                )
+                .arg(
+                    Arg::new("raw_matrix")
+                        .long("raw-matrix")
+                        .help("Also write a raw matrix of all barcodes to the raw subdirectory")
+                        .long_help("Also write a raw matrix to the raw subdirectory of each output matrix, with the \
+                               listed cells followed by every other barcode with at least --raw-min-fragments \
+                               fragments, as in Cell Ranger raw and filtered matrices. The barcodes are found \
+                               by an extra pass over the fragment file")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with_all(["low_memory", "hashtags", "split_barcode_suffix", "perturbations"]),
+                )
+                .arg(
+                    Arg::new("raw_min_fragments")
+                        .long("raw-min-fragments")
+                        .help("Minimum fragments for a barcode to be included in the raw matrix")
+                        .value_parser(clap::value_parser!(u64).range(1..))
+                        .default_value("1"),
+                )
+                .arg(
+                    Arg::new("compress")
+                        .long("compress")
+                        .help("Compression of the matrix and feature files: gzip (.gz) or zstd (.zst)")
+                        .value_parser(["gzip", "zstd"])
+                        .default_value("gzip"),
+                )
+                .arg(
+                    Arg::new("features_format")
+                        .long("features-format")
+                        .help("Columns of features.tsv: names (chrom-start-end), bed (chrom, start, end), or 10x (ID, name, type)")
+                        .long_help("Columns of features.tsv, for the loaders that expect each: names writes one \
+                               chrom-start-end name per region, bed writes chrom, start, and end columns, and 10x \
+                               writes the Cell Ranger ID, name, and feature type (Peaks) columns. Genes are \
+                               written as ID and name, with their counted region first for bed and the type \
+                               Gene Activity for 10x. Promoters are written as region and name, with the type \
+                               Promoters for 10x. Peak groups cannot be written as bed")
+                        .value_parser(["names", "bed", "10x"])
+                        .default_value("names"),
+                )
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .help("Matrix format: mtx (matrix.mtx.gz and barcodes.tsv), h5 (filtered_peak_bc_matrix.h5), h5ad (matrix.h5ad), or loom (matrix.loom)")
+                        .long_help("Matrix format: mtx writes matrix.mtx.gz and barcodes.tsv, h5 writes \
+                               filtered_peak_bc_matrix.h5 in the Cell Ranger HDF5 layout (CSC arrays with the \
+                               features and barcodes), which Scanpy and Seurat read directly, h5ad writes \
+                               matrix.h5ad, an AnnData file with cells x features CSR counts in X and the run \
+                               parameters in uns, and loom writes matrix.loom, a dense Loom matrix with feature \
+                               and cell attributes for loompy and SCope. features.tsv is written for each. h5, \
+                               h5ad, and loom require fragtk to be built with the hdf5 feature")
+                        .value_parser(["mtx", "h5", "h5ad", "loom"])
+                        .default_value("mtx")
+                        .conflicts_with_all(["low_memory", "shard_output", "hashtags", "split_barcode_suffix"]),
+                )
+                .arg(
+                    Arg::new("mode")
+                        .long("mode")
+                        .help("Count Tn5 insertions in each feature, each overlapping fragment once, or paired insertions (PIC)")
+                        .long_help("What is counted in each feature. insertions counts both Tn5 insertion \
+                               sites (fragment ends), so a fragment can add 2 to a feature. fragments counts \
+                               each fragment once in every feature it overlaps, matching Signac FeatureMatrix. pic (paired insertion counting, \
+                               as in PICsnATAC) counts each fragment once in every feature holding either of \
+                               its insertions")
+                        .value_parser(["insertions", "fragments", "pic"])
+                        .default_value("insertions"),
+                )
+                .arg(
+                    Arg::new("assign")
+                        .long("assign")
+                        .help("Count a fragment overlapping several features in all of them, or only in the best")
+                        .long_help("Which features a fragment overlapping several of them is counted in: all of \
+                               them, or best, only the feature with the largest overlap with the fragment (the \
+                               first feature in the feature file on ties), as featureCounts --largestOverlap. \
+                               With best, a fragment counts at most once (or twice, for insertions in one feature)")
+                        .value_parser(["all", "best"])
+                        .default_value("all"),
+                )
+                .arg(
+                    Arg::new("five_prime_only")
+                        .long("five-prime-only")
+                        .help("Count only the fragment start as a single insertion, for footprinting and bias-correction tools")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("mode"),
+                )
+                .arg(
+                    Arg::new("weight_by_duplicates")
+                        .long("weight-by-duplicates")
+                        .help("Count each fragment as many times as its duplicate count (column 5), for read-level counts")
+                        .long_help("Count each fragment as many times as its duplicate count, the number of reads \
+                               supporting it in column 5 of 10x fragment files, for read-level rather than \
+                               fragment-level counts. Fragments without a positive count are skipped")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("once_per_fragment")
+                        .long("once-per-fragment")
+                        .help("Count a fragment with both insertions in a feature once rather than twice")
+                        .long_help("Count a fragment with both insertions in a feature once rather than twice, \
+                               as with --mode pic. Fragment, PIC, and --five-prime-only counts already add at \
+                               most 1 per fragment")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("min_overlap")
+                        .long("min-overlap")
+                        .value_name("FRACTION")
+                        .help("Count a fragment in a feature only if at least this fraction of it lies within the feature")
+                        .long_help("Count a fragment in a feature only if at least this fraction of the fragment, \
+                               e.g. 0.5, lies within the feature. For small features such as motif sites or \
+                               footprints, where fragments overlapping the edge are noise")
+                        .value_parser(clap::value_parser!(f64)),
+                )
+                .arg(
+                    Arg::new("shift_plus")
+                        .long("shift-plus")
+                        .value_name("BP")
+                        .help("Add this to fragment starts before counting, e.g. 4 for the Tn5 offset")
+                        .value_parser(clap::value_parser!(i32))
+                        .allow_negative_numbers(true)
+                        .default_value("0"),
+                )
+                .arg(
+                    Arg::new("shift_minus")
+                        .long("shift-minus")
+                        .value_name("BP")
+                        .help("Add this to fragment ends before counting, e.g. -5 for the Tn5 offset")
+                        .value_parser(clap::value_parser!(i32))
+                        .allow_negative_numbers(true)
+                        .default_value("0"),
+                )
+                .arg(
+                    Arg::new("tn5_shift")
+                        .long("tn5-shift")
+                        .help("Shift fragment starts by +4 and ends by -5 to count Tn5 cut sites")
+                        .long_help("Shift fragment starts by +4 bp and ends by -5 bp before counting, so cut sites \
+                               rather than alignment ends are counted, for fragment files that are not already \
+                               shifted (as Cell Ranger ATAC fragment files are)")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with_all(["shift_plus", "shift_minus"]),
+                )
+                .arg(
+                    Arg::new("background_flanks")
+                        .long("background-flanks")
+                        .value_name("BP")
+                        .help("Also count this many bp on each side of each BED feature as its local background, writing background and ratio matrices")
+                        .long_help("Also count the flanks of this many bp on each side of each BED feature, less any \
+                               part within a feature, as its local background, in the same pass. The background \
+                               counts are written to background/ and the ratio of each feature's counts to its \
+                               background to ratio/: the count over the background count plus one, per bp of \
+                               the feature and of the flanks")
+                        .value_parser(clap::value_parser!(u32).range(1..))
+                        .requires("bed")
+                        .conflicts_with_all(["group", "low_memory", "hashtags", "split_barcode_suffix"]),
+                )
+                .arg(
+                    Arg::new("background_gap")
+                        .long("background-gap")
+                        .value_name("BP")
+                        .help("Distance between each BED feature and its background flanks")
+                        .value_parser(clap::value_parser!(u32))
+                        .default_value("0")
+                        .requires("background_flanks"),
+                )
+                .arg(
+                    Arg::new("binarize")
+                        .long("binarize")
+                        .help("Write binary (0/1) matrices with a pattern Matrix Market header")
+                        .long_help("Write binary matrices: each cell and feature pair with any counts is present, \
+                               regardless of the count, and the Matrix Market header declares the pattern field \
+                               so no values are written. For LSI implementations that expect binary accessibility")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("scale_subsample"),
+                )
+                .arg(
+                    Arg::new("top_features")
+                        .long("top-features")
+                        .value_name("N")
+                        .help("Also write a matrix of the N top-ranked features to the top_features subdirectory")
+                        .long_help("Also write a matrix of the N top-ranked features (see --rank-by) to the \
+                               top_features subdirectory of each output matrix, with features in rank order. \
+                               A small matrix for quick clustering prototypes")
+                        .value_parser(clap::value_parser!(usize))
+                        .conflicts_with_all(["low_memory", "hashtags", "split_barcode_suffix"]),
+                )
+                .arg(
+                    Arg::new("rank_by")
+                        .long("rank-by")
+                        .help("How features are ranked for --top-features")
+                        .value_parser(["total", "cells-detected"])
+                        .default_value("total"),
+                )
+                .arg(
+                    Arg::new("all_outputs")
+                        .long("all-outputs")
+                        .help("Also write cell QC, fragment sizes, and chromosome statistics from the same pass")
+                        .long_help("Also write QC metrics computed in the same pass over the fragments: qc.tsv \
+                               (fragments, nucleosome-free and mononucleosome fractions, and FRiP with --bed for \
+                               each cell), fragment_sizes.tsv (histogram of all fragment lengths), and \
+                               chrom_stats.tsv (all and cell fragments per chromosome). The fragment statistics \
+                               are also cached for fragtk stats")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("features_detected")
+                        .long("features-detected")
+                        .help("Write the number of features with nonzero counts in each cell to cells_features_detected.tsv")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with_all(["hashtags", "split_barcode_suffix"]),
+                )
+                .arg(
+                    Arg::new("shard_output")
+                        .long("shard-output")
+                        .help("Write the matrix as this many column shards with a manifest.json, for distributed loading")
+                        .long_help("Write the matrix as this many column shards (shard_<i>/matrix.mtx.gz and \
+                               shard_<i>/barcodes.tsv, each covering a contiguous range of cells) with a \
+                               manifest.json listing the shards, instead of a single matrix.mtx.gz")
+                        .value_parser(clap::value_parser!(u32).range(1..))
+                        .conflicts_with_all(["low_memory", "hashtags", "split_barcode_suffix"]),
+                )
+                .arg(
+                    Arg::new("debug_skips")
+                        .long("debug-skips")
+                        .help("Write fragments that were not counted to skipped.tsv.gz in the output directory")
+                        .long_help("Write fragments that were not counted to skipped.tsv.gz in the output directory, \
+                               with their line number and the reason (malformed line, invalid coordinates, \
+                               chromosome without features, or a subsample or GC filter). \
+                               Fragments from cells not in the cell list are not included")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("profile")
+                        .long("profile")
+                        .help("Write the time spent in each stage of the run to summary.json in the output directory")
+                        .long_help("Write the time spent in each stage of the run (decompress, parse, overlap, \
+                               accumulate, write) to summary.json in the output directory. \
+                               Timing each fragment adds a small overhead")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("force")
+                        .long("force")
+                        .help("Overwrite existing output")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("fasta")
+                        .long("fasta")
+                        .help("Reference FASTA used to compute fragment GC content")
+                        .long_help("Uncompressed reference FASTA used to compute fragment GC content for \
+                               --min-gc, --max-gc and --gc-bins. A samtools faidx index is used if present"),
+                )
+                .arg(
+                    Arg::new("min_gc")
+                        .long("min-gc")
+                        .help("Minimum fragment GC fraction")
+                        .value_parser(clap::value_parser!(f64))
+                        .default_value("0")
+                        .requires("fasta"),
+                )
+                .arg(
+                    Arg::new("max_gc")
+                        .long("max-gc")
+                        .help("Maximum fragment GC fraction")
+                        .value_parser(clap::value_parser!(f64))
+                        .default_value("1")
+                        .requires("fasta"),
+                )
+                .arg(
+                    Arg::new("gc_bins")
+                        .long("gc-bins")
+                        .help("Comma-separated GC fraction boundaries, e.g. 0.4,0.5,0.6. \
+                               Writes one matrix per GC range to subdirectories of the output")
+                        .requires("fasta"),
+                )
+                .arg(
+                    Arg::new("split_by_size")
+                        .long("split-by-size")
+                        .help("Fragment length (bp) separating nucleosome-free and nucleosomal fragments, e.g. 147")
+                        .long_help("Fragment length (bp) separating nucleosome-free and nucleosomal fragments, e.g. 147. \
+                               Fragments shorter than this are counted in the nfr matrix and the rest in the \
+                               nucleosomal matrix, written to subdirectories of the output")
+                        .value_parser(clap::value_parser!(u32).range(1..)),
+                )
+                .arg(
+                    Arg::new("min_length")
+                        .long("min-length")
+                        .help("Count only fragments of at least this many bp, e.g. 147 for mononucleosomal fragments")
+                        .value_parser(clap::value_parser!(u32)),
+                )
+                .arg(
+                    Arg::new("max_length")
+                        .long("max-length")
+                        .help("Count only fragments of at most this many bp, e.g. 294 for mononucleosomal fragments")
+                        .value_parser(clap::value_parser!(u32)),
+                )
+                .arg(
+                    Arg::new("subsample_fraction")
+                        .long("subsample-fraction")
+                        .help("Count only this fraction of fragments, e.g. 0.05, for a quick preview")
+                        .long_help("Count only this fraction of fragments, e.g. 0.05, for a quick preview. \
+                               Fragments are chosen by a hash of the fragment line, so repeated runs \
+                               count the same fragments")
+                        .value_parser(clap::value_parser!(f64)),
+                )
+                .arg(
+                    Arg::new("scale_subsample")
+                        .long("scale-subsample")
+                        .help("Divide subsampled counts by the subsample fraction to estimate full counts")
+                        .action(ArgAction::SetTrue)
+                        .requires("subsample_fraction"),
+                )
+                .arg(
+                    Arg::new("perturbations")
+                        .long("perturbations")
+                        .help("Cell barcode and perturbation (guide) assignments for CRISPR screens")
+                        .long_help("Tab-separated file of cell barcode and perturbation (guide) assignment, \
+                               one pair per line. Matrix columns are cell and perturbation pairs, named \
+                               barcode:perturbation, so a cell with several perturbations has a column for each. \
+                               Cells are taken from this file if -c is not set")
+                        .conflicts_with_all(["hashtags", "split_barcode_suffix"]),
+                )
+                .arg(
+                    Arg::new("spatial")
+                        .long("spatial")
+                        .help("Spot barcode positions for spatial ATAC, written to a spatial subdirectory")
+                        .long_help("Tab- or comma-separated table of spot (or bin) barcode, array row, array \
+                               column, and optionally the pixel row and column in the full-resolution image. \
+                               The positions are written to spatial/tissue_positions.csv in the output \
+                               directory, with scalefactors_json.json, as read by squidpy and Seurat spatial \
+                               loaders. Spots are taken as the cells if -c is not set")
+                        .conflicts_with_all(["perturbations", "hashtags", "split_barcode_suffix"]),
+                )
+                .arg(
+                    Arg::new("groups")
+                        .long("groups")
+                        .help("Table of cell barcodes and groups (e.g. clusters); writes one column per group summing its cells")
+                        .long_help("Table of cell barcodes and groups (e.g. clusters), tab-separated by default. \
+                               Counts are summed over the cells of each group as they are counted, writing a \
+                               feature x group matrix without building the per-cell matrix, and groups.tsv with \
+                               the number of cells in each group. Cells are taken from this table if -c is not \
+                               set; with -c, only grouped cells in the list are counted")
+                        .conflicts_with_all([
+                            "perturbations", "spatial", "pseudobulk", "hashtags", "split_barcode_suffix",
+                            "condition_column", "raw_matrix", "permute_cells", "all_outputs",
+                        ]),
+                )
+                .args(group_args())
+                .arg(
+                    Arg::new("bulk")
+                        .long("bulk")
+                        .help("Ignore cell barcodes and count every fragment into a single column, for bulk ATAC")
+                        .long_help("Ignore cell barcodes and count every fragment into a single matrix column named \
+                               bulk, so a fragment file can be quantified as one sample without a cell list. \
+                               Several fragment files are summed into the column")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with_all([
+                            "cells", "load_cells_index", "save_cells_index", "barcode_map", "groups", "perturbations",
+                            "spatial", "pseudobulk", "hashtags", "split_barcode_suffix", "condition_column",
+                            "raw_matrix", "permute_cells",
+                        ]),
+                )
+                .arg(
+                    Arg::new("pseudobulk")
+                        .long("pseudobulk")
+                        .help("Sum counts over the cells with each perturbation, or of each merged fragment file")
+                        .long_help("Sum counts over the cells with each perturbation, writing one column per \
+                               perturbation and the cells in each to perturbations.tsv. Without --perturbations, \
+                               sum the cells of each fragment file merged with --fragments or --samplesheet, \
+                               writing one column per sample (named by its barcode suffix) and design.tsv with \
+                               the cells in each sample and the samplesheet covariate columns")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("hashtags")
+                        .long("hashtags")
+                        .help("Hashtag (HTO/CellPlex) count table used to split the matrix by sample")
+                        .long_help("Tab-separated hashtag count table with a header line of barcode followed by \
+                               one column per sample tag. Cells are assigned to samples and a matrix directory \
+                               is written for each sample, along with assignments.tsv. Doublets and negative \
+                               cells are excluded from the sample matrices"),
+                )
+                .arg(
+                    Arg::new("split_barcode_suffix")
+                        .long("split-barcode-suffix")
+                        .help("Write a matrix directory for each cell barcode suffix (e.g. 1 in BC-1)")
+                        .long_help("Split the matrix by the suffix after the last --suffix-separator in each cell \
+                               barcode (e.g. BC-1 and BC-2 from different libraries or lanes), writing a matrix \
+                               directory named after each suffix from one pass over the fragments. Cells without \
+                               a suffix are not written")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("hashtags"),
+                )
+                .arg(
+                    Arg::new("condition_column")
+                        .long("condition-column")
+                        .value_name("COLUMN")
+                        .help("Samplesheet column of conditions (e.g. time points) to write a matrix directory for each")
+                        .long_help("Samplesheet covariate column, by header name, giving the condition (e.g. time \
+                               point) of each sample. A matrix directory is written for each condition holding the \
+                               cells of its samples, all with the rows of the shared features file, and \
+                               manifest.json lists the conditions, their samples, and their files")
+                        .requires("samplesheet")
+                        .conflicts_with_all([
+                            "hashtags", "split_barcode_suffix", "pseudobulk", "low_memory", "permute_cells",
+                            "raw_matrix", "top_features", "features_detected", "shard_output", "perturbations", "spatial",
+                        ]),
+                )
+                .arg(
+                    Arg::new("suffix_separator")
+                        .long("suffix-separator")
+                        .help("Separator before the barcode suffix for --split-barcode-suffix")
+                        .default_value("-"),
+                )
+                .arg(
+                    Arg::new("min_tag_count")
+                        .long("min-tag-count")
+                        .help("Minimum total hashtag count for a cell to be assigned")
+                        .value_parser(clap::value_parser!(u32))
+                        .default_value("10"),
+                )
+                .arg(
+                    Arg::new("min_tag_fraction")
+                        .long("min-tag-fraction")
+                        .help("Minimum fraction of hashtag counts in the top tag, otherwise the cell is a doublet")
+                        .value_parser(clap::value_parser!(f64))
+                        .default_value("0.6"),
+                )
         )
         .subcommand(
             Command::new("count")
@@ -78,7 +897,7 @@ fn main() -> Result<(), Box<dyn Error>> {
                     .short('f')
                     .long("fragments")
                     .value_name("FILE")
-                    .help("Path to the fragment file")
+                    .help("Path to the fragment file, or a coordinate-sorted BAM or CRAM file")
                     .required(true),
             )
             .arg(
@@ -105,6 +924,159 @@ fn main() -> Result<(), Box<dyn Error>> {
                     )
                     .default_value("200"),
             )
+            .args(bam_args())
+        )
+        .subcommand(
+            Command::new("stats")
+            .about("Summarize a fragment file")
+            .long_about("Summarize a fragment file: total fragments, cell barcodes, fragments per \
+                   chromosome, and fragment lengths. The summary is cached in <fragments>.fragtk.stats, \
+                   which is also written by count, and reused while the fragment file is unchanged")
+            .args_conflicts_with_subcommands(true)
+            .subcommand_negates_reqs(true)
+            .arg(
+                Arg::new("fragments")
+                    .short('f')
+                    .long("fragments")
+                    .value_name("FILE")
+                    .help("Path to the fragment file")
+                    .required(true),
+            )
+            .arg(
+                Arg::new("histogram")
+                    .long("histogram")
+                    .help("Also print the number of fragments of each length")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("refresh")
+                    .long("refresh")
+                    .help("Recompute statistics even if a current stats file exists")
+                    .action(ArgAction::SetTrue),
+            )
+            .args(thread_args())
+            .args(bam_args())
+            .subcommand(
+                Command::new("peaks-vs-background")
+                .about("Enrichment of insertions in peaks over background regions, per cell and overall")
+                .long_about("Compare Tn5 insertions in peaks with insertions in background regions, per bp of \
+                       each. Background regions are read from a BED file, or placed at random on the chromosome \
+                       of each peak with its width, outside all peaks, and with the closest GC content when a \
+                       FASTA file is given. Prints the overall enrichment, a z-score for the share of insertions \
+                       in peaks against the share expected from the widths, and a summary of the cells, and \
+                       optionally writes the same statistics for each cell")
+                .arg(
+                    Arg::new("fragments")
+                        .short('f')
+                        .long("fragments")
+                        .value_name("FILE")
+                        .help("Path to the fragment file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("peaks")
+                        .short('b')
+                        .long("peaks")
+                        .value_name("FILE")
+                        .help("BED file of peaks")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("background")
+                        .long("background")
+                        .value_name("FILE")
+                        .help("BED file of background regions. Placed at random, matched to the peaks, if not set")
+                        .conflicts_with_all(["fasta", "save_background"]),
+                )
+                .arg(
+                    Arg::new("genome")
+                        .short('g')
+                        .long("genome")
+                        .value_name("FILE")
+                        .help("Chromosome sizes (chrom.sizes, .fai, or FASTA) to place background regions in")
+                        .required_unless_present("background"),
+                )
+                .arg(
+                    Arg::new("fasta")
+                        .long("fasta")
+                        .value_name("FILE")
+                        .help("Reference FASTA file, to match background regions to the GC content of each peak"),
+                )
+                .arg(
+                    Arg::new("background_ratio")
+                        .long("background-ratio")
+                        .help("Background regions placed for each peak")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("2"),
+                )
+                .arg(
+                    Arg::new("seed")
+                        .long("seed")
+                        .help("Seed for placing background regions")
+                        .value_parser(clap::value_parser!(u64))
+                        .default_value("1"),
+                )
+                .arg(
+                    Arg::new("save_background")
+                        .long("save-background")
+                        .value_name("FILE")
+                        .help("Write the placed background regions to this BED file"),
+                )
+                .arg(
+                    Arg::new("outfile")
+                        .short('o')
+                        .long("outfile")
+                        .value_name("FILE")
+                        .help("Write the insertions in peaks and background, enrichment, and z-score of each cell to this TSV file"),
+                )
+                .arg(
+                    Arg::new("cells")
+                        .short('c')
+                        .long("cells")
+                        .help("File containing cell barcodes to report, can be given more than once. \
+                               Barcodes with at least --min-fragments fragments are reported if not set")
+                        .action(ArgAction::Append),
+                )
+                .arg(cells_op_arg())
+                .args(table_args())
+                .arg(
+                    Arg::new("min_fragments")
+                        .long("min-fragments")
+                        .help("Minimum fragments of a barcode to be reported when --cells is not set")
+                        .value_parser(clap::value_parser!(u64))
+                        .default_value("100"),
+                )
+            )
+        )
+        .subcommand(
+            Command::new("head")
+            .about("Print the header and first fragments of a fragment file")
+            .arg(
+                Arg::new("fragments")
+                    .short('f')
+                    .long("fragments")
+                    .value_name("FILE")
+                    .help("Path to the fragment file")
+                    .required(true),
+            )
+            .arg(
+                Arg::new("lines")
+                    .short('n')
+                    .long("lines")
+                    .help("Number of fragments to print after the header lines")
+                    .value_parser(clap::value_parser!(usize))
+                    .default_value("10"),
+            )
+            .arg(
+                Arg::new("verify")
+                    .long("verify")
+                    .help("First decompress the whole file, checking the CRC32 and size of every gzip member")
+                    .long_help("First decompress the whole file, checking the CRC32 and size of every gzip \
+                           member (BGZF block), and report the member and compressed offset of the first \
+                           damaged one. zstd frame checksums are checked when present")
+                    .action(ArgAction::SetTrue),
+            )
+            .args(bam_args())
         )
         .subcommand(
             Command::new("filter")
@@ -116,25 +1088,862 @@ fn main() -> Result<(), Box<dyn Error>> {
                     Arg::new("fragments")
                         .short('f')
                         .long("fragments")
-                        .help("Path to the fragment file")
+                        .help("Path to the fragment file, or a coordinate-sorted BAM or CRAM file")
                         .required(true),
                 )
                 .arg(
                     Arg::new("cells")
                         .short('c')
                         .long("cells")
-                        .help("File containing cell barcodes to include")
+                        .help("File containing cell barcodes to include, can be given more than once")
+                        .required(true)
+                        .action(ArgAction::Append),
+                )
+                .arg(cells_op_arg())
+                .args(table_args())
+                .args(thread_args())
+                .args(bam_args())
+        )
+        .subcommand(region_filter_command(
+            "intersect",
+            "Keep fragments overlapping regions in a BED file",
+        ))
+        .subcommand(region_filter_command(
+            "subtract",
+            "Remove fragments overlapping regions in a BED file",
+        ))
+        .subcommand(
+            Command::new("cutsites")
+                .about("Export Tn5 insertion sites as a sorted BED or bigBed file")
+                .arg(
+                    Arg::new("fragments")
+                        .short('f')
+                        .long("fragments")
+                        .help("Path to the fragment file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("outfile")
+                        .short('o')
+                        .long("outfile")
+                        .help("Output file, or output directory if --groups is set")
+                        .long_help("Output file, or output directory if --groups is set. \
+                               BED output is BGZF-compressed with columns chrom, start, end, and \
+                               number of insertions at that position")
                         .required(true),
                 )
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .help("Output format")
+                        .value_parser(["bed", "bigbed"])
+                        .default_value("bed"),
+                )
+                .arg(
+                    Arg::new("chrom_sizes")
+                        .short('g')
+                        .long("chrom-sizes")
+                        .help("Chromosome sizes file, required for bigBed output"),
+                )
+                .arg(
+                    Arg::new("groups")
+                        .long("groups")
+                        .help("Table of cell barcodes and groups, tab-separated by default. Writes one file per group"),
+                )
+                .arg(
+                    Arg::new("cells")
+                        .short('c')
+                        .long("cells")
+                        .help("File containing cell barcodes to include, can be given more than once. All cells are included if not set")
+                        .long_help("File containing cell barcodes to include, can be given more than once. All cells are \
+                               included if not set. With --groups, only grouped cells in this list are included")
+                        .action(ArgAction::Append),
+                )
+                .arg(cells_op_arg())
+                .args(table_args())
+                .args(group_args())
+                .args(thread_args())
         )
-        .get_matches();
-
-    pretty_env_logger::init_timed();
+        .subcommand(
+            Command::new("callpeaks")
+                .about("Call peaks for each cell group, keeping peaks reproducible across pseudo-replicates")
+                .long_about("Call peaks for each cell group from Tn5 insertion counts in fixed-width bins, \
+                       tested against the larger of the chromosome-wide and local Poisson background. \
+                       Cells in each group are split into pseudo-replicates and only peaks called in enough \
+                       replicates are kept. Writes <group>.narrowPeak for each group and union.bed, \
+                       the merged peaks of all groups, to the output directory")
+                .arg(
+                    Arg::new("fragments")
+                        .short('f')
+                        .long("fragments")
+                        .help("Path to the fragment file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("chrom_sizes")
+                        .short('g')
+                        .long("chrom-sizes")
+                        .help("Chromosome sizes file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("outdir")
+                        .short('o')
+                        .long("outdir")
+                        .help("Output directory name")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("groups")
+                        .long("groups")
+                        .help("Table of cell barcodes and groups, tab-separated by default. Peaks are called for each group"),
+                )
+                .arg(
+                    Arg::new("cells")
+                        .short('c')
+                        .long("cells")
+                        .help("File containing cell barcodes to include, can be given more than once. All cells are included if not set")
+                        .long_help("File containing cell barcodes to include, can be given more than once. All cells are \
+                               included if not set. With --groups, only grouped cells in this list are included")
+                        .action(ArgAction::Append),
+                )
+                .arg(cells_op_arg())
+                .args(table_args())
+                .args(group_args())
+                .arg(
+                    Arg::new("bin_size")
+                        .long("bin-size")
+                        .help("Bin width used to count insertions")
+                        .value_parser(clap::value_parser!(u32))
+                        .default_value("200"),
+                )
+                .arg(
+                    Arg::new("local_window")
+                        .long("local-window")
+                        .help("Width of the window used to estimate local background")
+                        .value_parser(clap::value_parser!(u32))
+                        .default_value("10000"),
+                )
+                .arg(
+                    Arg::new("pvalue")
+                        .long("pvalue")
+                        .help("Poisson p-value threshold for enriched bins")
+                        .value_parser(clap::value_parser!(f64))
+                        .default_value("1e-5"),
+                )
+                .arg(
+                    Arg::new("pseudo_replicates")
+                        .long("pseudo-replicates")
+                        .help("Number of pseudo-replicates to split the cells of each group into. 0 or 1 disables filtering")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("2"),
+                )
+                .arg(
+                    Arg::new("min_reproducible")
+                        .long("min-reproducible")
+                        .help("Minimum number of pseudo-replicates a peak must be called in. Defaults to all")
+                        .value_parser(clap::value_parser!(usize)),
+                )
+                .args(thread_args())
+        )
+        .subcommand(
+            Command::new("refine-peaks")
+                .about("Recenter peaks on the summit of their insertion density")
+                .long_about("Recenter each peak on the position with the most Tn5 insertions within a \
+                       smoothing window, optionally resizing it to a fixed width, and write the refined peaks \
+                       in input order with their other columns (the narrowPeak summit offset is updated). \
+                       Peaks without insertions keep their center. The fragment file must be sorted by chromosome")
+                .arg(
+                    Arg::new("fragments")
+                        .short('f')
+                        .long("fragments")
+                        .help("Path to the fragment file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("bed")
+                        .short('b')
+                        .long("bed")
+                        .help("BED or narrowPeak file of peaks to refine")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("outfile")
+                        .short('o')
+                        .long("outfile")
+                        .help("Output BED file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("width")
+                        .long("width")
+                        .help("Resize refined peaks to this width. Peaks keep their own width if not set")
+                        .value_parser(clap::value_parser!(u32).range(1..)),
+                )
+                .arg(
+                    Arg::new("smooth_window")
+                        .long("smooth-window")
+                        .help("Width of the window used to sum insertions when finding the summit")
+                        .value_parser(clap::value_parser!(u32))
+                        .default_value("51"),
+                )
+                .arg(
+                    Arg::new("cells")
+                        .short('c')
+                        .long("cells")
+                        .help("File containing cell barcodes to include, can be given more than once. All cells are included if not set")
+                        .action(ArgAction::Append),
+                )
+                .arg(cells_op_arg())
+                .args(table_args())
+                .args(thread_args())
+        )
+        .subcommand(
+            Command::new("heatmapdata")
+                .about("Count insertions in bins around reference points for metaplots and heatmaps")
+                .long_about("Count insertions in bins around reference points for metaplots and heatmaps. \
+                       Output is a gzipped regions x bins matrix compatible with deepTools computeMatrix \
+                       output, with one sample per cell group")
+                .arg(
+                    Arg::new("fragments")
+                        .short('f')
+                        .long("fragments")
+                        .help("Path to the fragment file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("regions")
+                        .short('r')
+                        .long("regions")
+                        .help("BED file of regions. Strand is read from the sixth column if present")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("outfile")
+                        .short('o')
+                        .long("outfile")
+                        .help("Output matrix file (gzipped)")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("flank")
+                        .long("flank")
+                        .help("Distance upstream and downstream of the reference point")
+                        .value_parser(clap::value_parser!(u32))
+                        .default_value("2000"),
+                )
+                .arg(
+                    Arg::new("bins")
+                        .long("bins")
+                        .help("Number of bins spanning the window")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("100"),
+                )
+                .arg(
+                    Arg::new("reference_point")
+                        .long("reference-point")
+                        .help("Reference point of each region (strand-aware)")
+                        .value_parser(["TSS", "TES", "center"])
+                        .default_value("TSS"),
+                )
+                .arg(
+                    Arg::new("groups")
+                        .long("groups")
+                        .help("Table of cell barcodes and groups, tab-separated by default. Each group is a sample in the output"),
+                )
+                .arg(
+                    Arg::new("cells")
+                        .short('c')
+                        .long("cells")
+                        .help("File containing cell barcodes to include, can be given more than once. All cells are included if not set")
+                        .long_help("File containing cell barcodes to include, can be given more than once. All cells are \
+                               included if not set. With --groups, only grouped cells in this list are included")
+                        .action(ArgAction::Append),
+                )
+                .arg(cells_op_arg())
+                .args(table_args())
+                .args(group_args())
+                .args(thread_args())
+        )
+        .subcommand(
+            Command::new("append-cells")
+                .about("Count a new fragment file against an existing matrix and append the new cells")
+                .long_about("Count a new fragment file against the features of an existing matrix directory \
+                            and append the new cells as extra columns. Existing counts are copied, not recounted. \
+                            Cells already present in the matrix are skipped.")
+                .arg(
+                    Arg::new("matrix")
+                        .short('m')
+                        .long("matrix")
+                        .help("Existing matrix directory created by fragtk matrix")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("fragments")
+                        .short('f')
+                        .long("fragments")
+                        .help("Path to the new fragment file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("cells")
+                        .short('c')
+                        .long("cells")
+                        .help("File containing cell barcodes to append")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("outdir")
+                        .short('o')
+                        .long("outdir")
+                        .help("Output directory name. The input matrix is updated in place if not given"),
+                )
+                .args(thread_args())
+                .arg(
+                    Arg::new("force")
+                        .long("force")
+                        .help("Overwrite existing output")
+                        .action(ArgAction::SetTrue),
+                )
+        )
+        .subcommand(
+            Command::new("convert-matrix")
+                .about("Convert a feature x cell matrix between mtx, 10x h5, h5ad, and loom formats")
+                .long_about("Convert a feature x cell matrix between mtx, 10x h5, h5ad, and loom formats. \
+                             The input is read once into temporary files next to the output, one per batch \
+                             of cells, and written a batch at a time, so the whole matrix is never held in memory")
+                .arg(
+                    Arg::new("input")
+                        .short('i')
+                        .long("input")
+                        .help("Input matrix (mtx directory, .h5, .h5ad, or .loom file)")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .help("Output matrix (mtx directory, .h5, .h5ad, or .loom file)")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("from")
+                        .long("from")
+                        .help("Input format. Detected from the input path if not given")
+                        .value_parser(["mtx", "h5", "h5ad", "loom"]),
+                )
+                .arg(
+                    Arg::new("to")
+                        .long("to")
+                        .help("Output format. Detected from the output path if not given")
+                        .value_parser(["mtx", "h5", "h5ad", "loom"]),
+                )
+                .args(thread_args())
+                .arg(
+                    Arg::new("force")
+                        .long("force")
+                        .help("Overwrite existing output")
+                        .action(ArgAction::SetTrue),
+                )
+        )
+        .subcommand(
+            Command::new("group-summary")
+                .about("Summarize a feature x cell matrix by cell group for dot plots")
+                .long_about("Summarize a feature x cell matrix by cell group. Writes a tab-separated table \
+                       with one row per feature and group giving the number of cells in the group, the mean \
+                       count, and the percent of cells with a nonzero count (pct.exp)")
+                .arg(
+                    Arg::new("input")
+                        .short('i')
+                        .long("input")
+                        .help("Input matrix (mtx directory, .h5, .h5ad, or .loom file)")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("groups")
+                        .long("groups")
+                        .help("Table of cell barcodes and groups, tab-separated by default")
+                        .required(true),
+                )
+                .args(table_args())
+                .args(group_args())
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .help("Output TSV file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("specificity")
+                        .long("specificity")
+                        .help("Also write the Gini coefficient, entropy, and tau of each feature's group means to this TSV file")
+                        .long_help("Also write the specificity of each feature across groups to this TSV file: \
+                               the group with the highest mean, and the Gini coefficient, Shannon entropy (bits), \
+                               and tau of the group means. Features with no counts have NA scores"),
+                )
+                .arg(
+                    Arg::new("from")
+                        .long("from")
+                        .help("Input format. Detected from the input path if not given")
+                        .value_parser(["mtx", "h5", "h5ad", "loom"]),
+                )
+        )
+        .subcommand(
+            Command::new("da")
+                .about("Test features for differential accessibility between two cell groups")
+                .long_about("Test each feature of a feature x cell matrix for differential accessibility \
+                       between two cell groups, from pseudobulks of each group: --replicates random \
+                       pseudo-replicates, or the replicates (e.g. samples) in --replicate-column of the group \
+                       table. Writes a tab-separated table with the mean CPM of each group, the log2 fold \
+                       change of group 1 over group 2, and the p-value and Benjamini-Hochberg FDR of each \
+                       feature with at least --min-count counts")
+                .arg(
+                    Arg::new("input")
+                        .short('i')
+                        .long("input")
+                        .help("Input matrix (mtx directory, .h5, .h5ad, or .loom file)")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("groups")
+                        .long("groups")
+                        .help("Table of cell barcodes and groups, tab-separated by default")
+                        .required(true),
+                )
+                .args(table_args())
+                .args(group_args())
+                .arg(
+                    Arg::new("group1")
+                        .long("group1")
+                        .help("Group tested against group 2; positive log2 fold changes are higher in this group")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("group2")
+                        .long("group2")
+                        .help("Reference group")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("replicates")
+                        .long("replicates")
+                        .help("Number of random pseudo-replicates the cells of each group are split into")
+                        .value_parser(clap::value_parser!(u32).range(2..))
+                        .default_value("3"),
+                )
+                .arg(
+                    Arg::new("replicate_column")
+                        .long("replicate-column")
+                        .value_name("COLUMN")
+                        .help("Column of the group table holding each cell's replicate, by header name or 1-based index")
+                        .long_help("Column of the group table holding the replicate (e.g. sample or donor) of each \
+                               cell, by header name or 1-based index. Each replicate of a group is one \
+                               pseudobulk, in place of random pseudo-replicates")
+                        .conflicts_with("replicates"),
+                )
+                .arg(
+                    Arg::new("seed")
+                        .long("seed")
+                        .help("Seed for assigning cells to pseudo-replicates")
+                        .value_parser(clap::value_parser!(u64))
+                        .default_value("1"),
+                )
+                .arg(
+                    Arg::new("test")
+                        .long("test")
+                        .help("Test: exact (negative binomial exact test, as edgeR) or wilcoxon (rank-sum test on CPM)")
+                        .long_help("Test of each feature: exact compares the summed counts of the groups, scaled to \
+                               equal library sizes, with a negative binomial exact test and a common dispersion \
+                               estimated from the replicates, as edgeR exactTest. wilcoxon is a rank-sum test on the \
+                               CPM of the pseudobulks, which needs more replicates for small p-values")
+                        .value_parser(["exact", "wilcoxon"])
+                        .default_value("exact"),
+                )
+                .arg(
+                    Arg::new("min_count")
+                        .long("min-count")
+                        .help("Minimum total count of a feature over both groups for it to be tested")
+                        .value_parser(clap::value_parser!(u64))
+                        .default_value("10"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .help("Output TSV file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("from")
+                        .long("from")
+                        .help("Input format. Detected from the input path if not given")
+                        .value_parser(["mtx", "h5", "h5ad", "loom"]),
+                )
+                .args(thread_args())
+        )
+        .subcommand(
+            Command::new("smooth")
+                .about("Average each cell's counts with its nearest neighbors")
+                .long_about("Average each cell's counts with those of its nearest neighbors in a kNN graph \
+                       (simple MAGIC-like smoothing), writing a sparse mtx matrix directory. \
+                       The graph is a tab-separated edge list of cell barcode, neighbor barcode, and an \
+                       optional weight; each cell's neighbors (and the cell itself) are averaged with \
+                       weights normalized to sum to one")
+                .arg(
+                    Arg::new("input")
+                        .short('i')
+                        .long("input")
+                        .help("Input matrix (mtx directory, .h5, .h5ad, or .loom file)")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("knn")
+                        .long("knn")
+                        .help("Tab-separated kNN graph: cell barcode, neighbor barcode, optional weight")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("outdir")
+                        .short('o')
+                        .long("outdir")
+                        .help("Output matrix directory")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("exclude_self")
+                        .long("exclude-self")
+                        .help("Average over the neighbors only, leaving out each cell's own counts")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("from")
+                        .long("from")
+                        .help("Input format. Detected from the input path if not given")
+                        .value_parser(["mtx", "h5", "h5ad", "loom"]),
+                )
+                .arg(
+                    Arg::new("force")
+                        .long("force")
+                        .help("Overwrite existing output")
+                        .action(ArgAction::SetTrue),
+                )
+                .args(thread_args())
+        )
+        .subcommand(
+            Command::new("trackhub")
+                .about("Write a UCSC track hub of coverage and peaks for each cell group")
+                .long_about("Write a UCSC track hub (hub.txt, genomes.txt, and <genome>/trackDb.txt) with a \
+                       bigWig of Tn5 insertions per million for each cell group, and a bigNarrowPeak track \
+                       for each group from a fragtk callpeaks output directory if given. \
+                       The output directory can be copied to a web server and loaded by its hub.txt URL")
+                .arg(
+                    Arg::new("fragments")
+                        .short('f')
+                        .long("fragments")
+                        .help("Path to the fragment file, sorted by chromosome")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("chrom_sizes")
+                        .short('g')
+                        .long("chrom-sizes")
+                        .help("Chromosome sizes file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("genome")
+                        .long("genome")
+                        .help("UCSC genome assembly name, e.g. hg38")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("outdir")
+                        .short('o')
+                        .long("outdir")
+                        .help("Output hub directory")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("groups")
+                        .long("groups")
+                        .help("Table of cell barcodes and groups, tab-separated by default. Writes one track per group"),
+                )
+                .arg(
+                    Arg::new("cells")
+                        .short('c')
+                        .long("cells")
+                        .help("File containing cell barcodes to include, can be given more than once. All cells are included if not set")
+                        .long_help("File containing cell barcodes to include, can be given more than once. All cells are \
+                               included if not set. With --groups, only grouped cells in this list are included")
+                        .action(ArgAction::Append),
+                )
+                .arg(cells_op_arg())
+                .args(table_args())
+                .args(group_args())
+                .arg(
+                    Arg::new("peaks")
+                        .long("peaks")
+                        .help("Output directory of fragtk callpeaks with <group>.narrowPeak files"),
+                )
+                .arg(
+                    Arg::new("bin_size")
+                        .long("bin-size")
+                        .help("Coverage bin size in base pairs")
+                        .value_parser(clap::value_parser!(u32).range(1..))
+                        .default_value("50"),
+                )
+                .arg(
+                    Arg::new("hub_name")
+                        .long("hub-name")
+                        .help("Name of the hub")
+                        .default_value("fragtk"),
+                )
+                .arg(
+                    Arg::new("email")
+                        .long("email")
+                        .help("Contact email for the hub"),
+                )
+                .args(thread_args())
+        )
+        .subcommand(
+            Command::new("cell-features")
+                .about("Write a per-cell feature vector of fragment summaries for QC clustering")
+                .long_about("Write a tab-separated table with one row per cell of the fragment count, the \
+                       fractions of nucleosome-free, mono-, di-, and multinucleosome fragments, FRiP and TSS \
+                       enrichment if --peaks and --tss are given, and the fraction of fragments on each \
+                       chromosome, for quick QC clustering and outlier detection without a count matrix")
+                .arg(
+                    Arg::new("fragments")
+                        .short('f')
+                        .long("fragments")
+                        .help("Path to the fragment file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("outfile")
+                        .short('o')
+                        .long("outfile")
+                        .help("Output TSV file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("cells")
+                        .short('c')
+                        .long("cells")
+                        .help("File containing cell barcodes to include, can be given more than once. \
+                               Barcodes with at least --min-fragments fragments are included if not set")
+                        .action(ArgAction::Append),
+                )
+                .arg(cells_op_arg())
+                .args(table_args())
+                .arg(
+                    Arg::new("min_fragments")
+                        .long("min-fragments")
+                        .help("Minimum fragments of a barcode to be included when -c is not set")
+                        .value_parser(clap::value_parser!(u64))
+                        .default_value("100"),
+                )
+                .arg(
+                    Arg::new("peaks")
+                        .long("peaks")
+                        .help("BED file of peaks, to include the fraction of fragments in peaks (frip)"),
+                )
+                .arg(
+                    Arg::new("tss")
+                        .long("tss")
+                        .help("BED file of transcripts or TSSs, to include the TSS enrichment score")
+                        .long_help("BED file of transcripts or TSSs, with the strand in column 6, to include the \
+                               TSS enrichment score: insertions per bp within 50 bp of a TSS over insertions \
+                               per bp 1900-2000 bp away, with a pseudocount of one"),
+                )
+                .args(thread_args())
+        )
+        .subcommand(
+            Command::new("sketch")
+                .about("Compare cells by MinHash sketches of their insertion sites, without a peak set")
+                .long_about("Compare cells by bottom-k MinHash sketches of their insertion sites. Writes a \
+                       tab-separated table of each pair of cells with an estimated Jaccard similarity of at \
+                       least --min-similarity, and the estimated share of the smaller cell's insertion sites \
+                       found in the other cell (containment), to find duplicate barcodes and sample swaps")
+                .arg(
+                    Arg::new("fragments")
+                        .short('f')
+                        .long("fragments")
+                        .help("Path to the fragment file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("outfile")
+                        .short('o')
+                        .long("outfile")
+                        .help("Output TSV file of cell pairs")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("cells")
+                        .short('c')
+                        .long("cells")
+                        .help("File containing cell barcodes to include, can be given more than once. \
+                               Barcodes with at least --min-fragments fragments are included if not set")
+                        .action(ArgAction::Append),
+                )
+                .arg(cells_op_arg())
+                .args(table_args())
+                .arg(
+                    Arg::new("min_fragments")
+                        .long("min-fragments")
+                        .help("Minimum fragments of a barcode to be included when -c is not set")
+                        .value_parser(clap::value_parser!(u64))
+                        .default_value("100"),
+                )
+                .arg(
+                    Arg::new("sketch_size")
+                        .long("sketch-size")
+                        .help("Insertion site hashes kept for each cell; larger sketches give more precise similarities")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("1024"),
+                )
+                .arg(
+                    Arg::new("bin_size")
+                        .long("bin-size")
+                        .help("Compare insertion sites in bins of this many bp; 1 compares exact positions")
+                        .value_parser(clap::value_parser!(u32))
+                        .default_value("1"),
+                )
+                .arg(
+                    Arg::new("min_similarity")
+                        .long("min-similarity")
+                        .help("Minimum estimated Jaccard similarity of a pair of cells to be written")
+                        .value_parser(clap::value_parser!(f64))
+                        .default_value("0.05"),
+                )
+                .arg(
+                    Arg::new("cell_summary")
+                        .long("cell-summary")
+                        .value_name("FILE")
+                        .help("Also write the fragments and estimated distinct insertion sites of each cell to this TSV file"),
+                )
+                .args(thread_args())
+        )
+        .subcommand(
+            Command::new("hopping")
+                .about("Flag barcodes sharing fragments across samples, from index hopping or barcode collisions")
+                .long_about("Compare the fragments of each barcode found in more than one sample's fragment \
+                       file. Index hopping and barcode collisions leave fragments with identical coordinates \
+                       under the same barcode in two samples, which independent cells almost never share. \
+                       Writes a tab-separated table of each barcode and pair of samples sharing at least \
+                       --min-shared of the smaller sample's fragments")
+                .arg(
+                    Arg::new("fragments")
+                        .short('f')
+                        .long("fragments")
+                        .help("Path to a sample's fragment file, given once for each sample")
+                        .required(true)
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("outfile")
+                        .short('o')
+                        .long("outfile")
+                        .help("Output TSV file of suspicious barcodes")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("cells")
+                        .short('c')
+                        .long("cells")
+                        .help("File containing cell barcodes to compare, can be given more than once. \
+                               Barcodes with at least --min-fragments fragments are compared if not set")
+                        .action(ArgAction::Append),
+                )
+                .arg(cells_op_arg())
+                .args(table_args())
+                .arg(
+                    Arg::new("min_fragments")
+                        .long("min-fragments")
+                        .help("Minimum fragments of a barcode in a sample to be compared")
+                        .value_parser(clap::value_parser!(u64))
+                        .default_value("100"),
+                )
+                .arg(
+                    Arg::new("min_shared")
+                        .long("min-shared")
+                        .help("Minimum fraction of the smaller sample's fragments shared for a barcode to be reported")
+                        .value_parser(clap::value_parser!(f64))
+                        .default_value("0.01"),
+                )
+                .arg(
+                    Arg::new("sample_fraction")
+                        .long("sample-fraction")
+                        .help("Fraction of fragments compared, chosen by hash; lower values use less memory")
+                        .value_parser(clap::value_parser!(f64))
+                        .default_value("0.1"),
+                )
+                .args(thread_args())
+        )
+        .subcommand(
+            Command::new("pipeline")
+                .about("Run validation, cell QC, peak calling or bins, and counting for several samples")
+                .long_about("Run the steps from fragment files to count matrices for each sample of a config \
+                       file: validate the fragment file (fragtk stats), select cells by fragment count (fragtk \
+                       count), call peaks (fragtk callpeaks) if requested, and count the matrix (fragtk matrix), \
+                       then write summary.tsv for all samples. Completed steps are recorded in pipeline.state in \
+                       the output directory, so a failed or interrupted run resumes where it stopped")
+                .arg(
+                    Arg::new("config")
+                        .long("config")
+                        .value_name("FILE")
+                        .help("Pipeline config file (TOML)")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("restart")
+                        .long("restart")
+                        .help("Run every step again, ignoring steps completed by an earlier run")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("dry_run")
+                        .long("dry-run")
+                        .help("Log the command of each step that would run, without running it")
+                        .action(ArgAction::SetTrue),
+                )
+        )
+        .get_matches();
+
+    pretty_env_logger::init_timed();
+
+    if let Some((_, sub_matches)) = matches.subcommand() {
+        threads::ThreadConfig::from_matches(sub_matches).install();
+    }
 
+    let progress = progress::Progress::default();
     match matches.subcommand() {
-        Some(("matrix", sub_matches)) => f2m::f2m(sub_matches)?,
-        Some(("count", sub_matches)) => cellselect::cellselect(sub_matches)?,
-        Some(("filter", sub_matches)) => filter::run(sub_matches)?,
+        Some(("matrix", sub_matches)) => f2m::f2m(sub_matches, &progress)?,
+        Some(("count", sub_matches)) => cellselect::cellselect(sub_matches, &progress)?,
+        Some(("stats", sub_matches)) => match sub_matches.subcommand() {
+            Some(("peaks-vs-background", enrichment_matches)) => enrichment::run(enrichment_matches, &progress)?,
+            _ => stats::run(sub_matches, &progress)?,
+        },
+        Some(("head", sub_matches)) => head::run(sub_matches)?,
+        Some(("filter", sub_matches)) => filter::run(sub_matches, &progress)?,
+        Some(("intersect", sub_matches)) => intersect::run(sub_matches, true, &progress)?,
+        Some(("subtract", sub_matches)) => intersect::run(sub_matches, false, &progress)?,
+        Some(("cutsites", sub_matches)) => cutsites::run(sub_matches, &progress)?,
+        Some(("callpeaks", sub_matches)) => callpeaks::run(sub_matches, &progress)?,
+        Some(("refine-peaks", sub_matches)) => refine::run(sub_matches, &progress)?,
+        Some(("heatmapdata", sub_matches)) => heatmap::run(sub_matches, &progress)?,
+        Some(("append-cells", sub_matches)) => append::run(sub_matches, &progress)?,
+        Some(("convert-matrix", sub_matches)) => convert::run(sub_matches, &progress)?,
+        Some(("group-summary", sub_matches)) => summary::run(sub_matches)?,
+        Some(("da", sub_matches)) => da::run(sub_matches)?,
+        Some(("smooth", sub_matches)) => smooth::run(sub_matches)?,
+        Some(("trackhub", sub_matches)) => trackhub::run(sub_matches, &progress)?,
+        Some(("cell-features", sub_matches)) => cellfeatures::run(sub_matches, &progress)?,
+        Some(("sketch", sub_matches)) => sketch::run(sub_matches, &progress)?,
+        Some(("hopping", sub_matches)) => hopping::run(sub_matches, &progress)?,
+        Some(("pipeline", sub_matches)) => pipeline::run(sub_matches)?,
         _ => {
 
         }
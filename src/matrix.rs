@@ -0,0 +1,848 @@
+use std::{
+    io,
+    fs,
+    fs::File,
+    path::Path,
+    io::BufRead,
+    io::BufReader,
+    io::Read,
+    io::Write,
+};
+use flate2::read::MultiGzDecoder;
+use flate2::Compression;
+use log::{info, warn};
+use rustc_hash::FxHashMap;
+use gzp::{
+    deflate::Gzip,
+    GzpError,
+    ZWriter,
+    par::compress::{ParCompress, ParCompressBuilder},
+};
+use crate::progress::Progress;
+
+/// Value types that can be written to a Matrix Market file
+pub trait MatrixValue: Copy + Default + PartialEq + std::ops::AddAssign + std::fmt::Display {
+    /// Matrix Market field declared in the header
+    const FIELD: &'static str;
+
+    /// Matrix Market entry line for a 1-based row and column
+    fn entry(&self, row: usize, col: usize) -> String {
+        format!("{} {} {}\n", row, col, self)
+    }
+
+    /// Value stored in a SparseMatrix
+    fn as_f64(&self) -> f64;
+}
+
+impl MatrixValue for u32 {
+    const FIELD: &'static str = "integer";
+
+    fn as_f64(&self) -> f64 {
+        *self as f64
+    }
+}
+
+impl MatrixValue for u64 {
+    const FIELD: &'static str = "integer";
+
+    fn as_f64(&self) -> f64 {
+        *self as f64
+    }
+}
+
+impl MatrixValue for f32 {
+    const FIELD: &'static str = "real";
+
+    fn as_f64(&self) -> f64 {
+        *self as f64
+    }
+}
+
+impl MatrixValue for f64 {
+    const FIELD: &'static str = "real";
+
+    fn as_f64(&self) -> f64 {
+        *self
+    }
+}
+
+/// A nonzero entry without a value, for binary matrices written with the `pattern` field
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Pattern;
+
+impl std::ops::AddAssign for Pattern {
+    fn add_assign(&mut self, _: Pattern) {}
+}
+
+impl std::fmt::Display for Pattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "1")
+    }
+}
+
+impl MatrixValue for Pattern {
+    const FIELD: &'static str = "pattern";
+
+    fn entry(&self, row: usize, col: usize) -> String {
+        format!("{} {}\n", row, col)
+    }
+
+    fn as_f64(&self) -> f64 {
+        1.0
+    }
+}
+
+/// Quote a string for JSON output
+pub fn json_string(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Matrix Market banner, metadata, and size line
+pub fn mtx_header(field: &str, nrow: usize, ncol: usize, nnz: usize) -> String {
+    let mut header = String::new();
+    header.push_str(&format!("%%MatrixMarket matrix coordinate {} general\n", field));
+    header.push_str(&format!("%%metadata json: {{\"software_version\": \"fragtk-{}\"}}\n", env!("CARGO_PKG_VERSION")));
+    header.push_str(&format!("{} {} {}\n", nrow, ncol, nnz));
+    header
+}
+
+/// Feature x cell matrix held in compressed sparse column layout.
+/// Columns are cells, so `indices` holds feature (row) indices.
+pub struct SparseMatrix {
+    pub features: Vec<String>,
+    pub barcodes: Vec<String>,
+    pub indptr: Vec<u64>,
+    pub indices: Vec<u32>,
+    pub data: Vec<f64>,
+    pub integer: bool,
+}
+
+impl SparseMatrix {
+    pub fn nrow(&self) -> usize {
+        self.features.len()
+    }
+
+    pub fn ncol(&self) -> usize {
+        self.barcodes.len()
+    }
+
+    pub fn nnz(&self) -> usize {
+        self.data.len()
+    }
+}
+
+/// Build a features x cells matrix (CSC over cells) from counts for each feature, keyed by cell
+pub fn sparse_matrix<T: MatrixValue>(
+    counts: &[FxHashMap<u32, T>],
+    features: Vec<String>,
+    barcodes: Vec<String>,
+) -> SparseMatrix {
+    let mut indptr: Vec<u64> = vec![0; barcodes.len() + 1];
+    for map in counts {
+        for cell in map.keys() {
+            indptr[*cell as usize + 1] += 1;
+        }
+    }
+    for col in 0..barcodes.len() {
+        indptr[col + 1] += indptr[col];
+    }
+    let nnz = indptr[barcodes.len()] as usize;
+    let mut indices: Vec<u32> = vec![0; nnz];
+    let mut data: Vec<f64> = vec![0.0; nnz];
+    // filled in feature order, so features are sorted within each cell
+    let mut next: Vec<u64> = indptr[..barcodes.len()].to_vec();
+    for (feature, map) in counts.iter().enumerate() {
+        for (cell, value) in map {
+            let pos = next[*cell as usize] as usize;
+            indices[pos] = feature as u32;
+            data[pos] = value.as_f64();
+            next[*cell as usize] += 1;
+        }
+    }
+    SparseMatrix { features, barcodes, indptr, indices, data, integer: T::FIELD != "real" }
+}
+
+/// Swap the major and minor axes of a compressed sparse matrix.
+/// Minor indices in the output are sorted within each major slice.
+#[cfg_attr(not(feature = "hdf5"), allow(dead_code))]
+pub fn transpose(
+    indptr: &[u64],
+    indices: &[u32],
+    data: &[f64],
+    n_minor: usize,
+) -> (Vec<u64>, Vec<u32>, Vec<f64>) {
+
+    // count entries per minor index
+    let mut out_ptr: Vec<u64> = vec![0; n_minor + 1];
+    for &idx in indices {
+        out_ptr[idx as usize + 1] += 1;
+    }
+    for i in 0..n_minor {
+        out_ptr[i + 1] += out_ptr[i];
+    }
+
+    // scatter entries, walking the major axis in order
+    let mut next: Vec<u64> = out_ptr[..n_minor].to_vec();
+    let mut out_idx: Vec<u32> = vec![0; indices.len()];
+    let mut out_data: Vec<f64> = vec![0.0; data.len()];
+    for major in 0..indptr.len().saturating_sub(1) {
+        for pos in indptr[major] as usize..indptr[major + 1] as usize {
+            let minor = indices[pos] as usize;
+            let dest = next[minor] as usize;
+            out_idx[dest] = major as u32;
+            out_data[dest] = data[pos];
+            next[minor] += 1;
+        }
+    }
+
+    (out_ptr, out_idx, out_data)
+}
+
+/// Open a file for line-based reading, decompressing gzip or zstd detected from the first bytes,
+/// so compressed files are read whatever their name
+pub fn open_text(path: &Path) -> io::Result<Box<dyn BufRead>> {
+    let mut reader = BufReader::with_capacity(1024 * 1024, File::open(path)?);
+    let header = reader.fill_buf()?;
+    if header.starts_with(&[0x1f, 0x8b]) {
+        Ok(Box::new(BufReader::with_capacity(1024 * 1024, MultiGzDecoder::new(reader))))
+    } else if header.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Ok(Box::new(BufReader::with_capacity(1024 * 1024, zstd::Decoder::with_buffer(reader)?)))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+/// Return the first existing path among `names` in `dir`
+pub fn find_file(dir: &Path, names: &[&str]) -> io::Result<std::path::PathBuf> {
+    for name in names {
+        let path = dir.join(name);
+        if path.exists() {
+            return Ok(path);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("None of {:?} found in {:?}", names, dir),
+    ))
+}
+
+pub fn read_names(path: &Path) -> io::Result<Vec<String>> {
+    let mut names = Vec::new();
+    for line in open_text(path)?.lines() {
+        let line = line?;
+        // keep only the first column (10x features.tsv has three), or chrom-start-end for BED-like lines
+        let fields: Vec<&str> = line.split('\t').collect();
+        let name = match fields[..] {
+            [chrom, start, end] if start.parse::<u32>().is_ok() && end.parse::<u32>().is_ok() => {
+                format!("{}-{}-{}", chrom, start, end)
+            }
+            _ => fields[0].to_string(),
+        };
+        names.push(name);
+    }
+    Ok(names)
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Read a Matrix Market directory (matrix.mtx[.gz], features.tsv[.gz], barcodes.tsv[.gz])
+/// Entries are streamed twice: once to size each column and once to fill it,
+/// so only the final compressed arrays are held in memory.
+pub fn read_mtx_dir(dir: &Path) -> io::Result<SparseMatrix> {
+    let columns = MtxColumns::open(dir)?;
+    let (indices, data) = columns.read()?;
+    let MtxColumns { features, barcodes, indptr, integer, .. } = columns;
+    Ok(SparseMatrix { features, barcodes, indptr, indices, data, integer })
+}
+
+/// Columns of a matrix to write: held in memory, or spilled to temporary files and read back a
+/// batch of columns at a time, stopping between batches if `progress` is cancelled
+pub enum Columns<'a> {
+    Memory(&'a SparseMatrix),
+    Spilled(&'a SpilledMatrix, &'a Progress),
+}
+
+impl Columns<'_> {
+    pub fn features(&self) -> &[String] {
+        match self {
+            Columns::Memory(matrix) => &matrix.features,
+            Columns::Spilled(matrix, _) => &matrix.features,
+        }
+    }
+
+    pub fn barcodes(&self) -> &[String] {
+        match self {
+            Columns::Memory(matrix) => &matrix.barcodes,
+            Columns::Spilled(matrix, _) => &matrix.barcodes,
+        }
+    }
+
+    pub fn indptr(&self) -> &[u64] {
+        match self {
+            Columns::Memory(matrix) => &matrix.indptr,
+            Columns::Spilled(matrix, _) => &matrix.indptr,
+        }
+    }
+
+    pub fn integer(&self) -> bool {
+        match self {
+            Columns::Memory(matrix) => matrix.integer,
+            Columns::Spilled(matrix, _) => matrix.integer,
+        }
+    }
+
+    pub fn nnz(&self) -> usize {
+        self.indptr().last().copied().unwrap_or(0) as usize
+    }
+
+    /// Call `f` with the column range, feature indices, and values of consecutive batches of columns
+    pub fn for_each_batch<E: From<io::Error>>(
+        &self,
+        mut f: impl FnMut(std::ops::Range<usize>, &[u32], &[f64]) -> Result<(), E>,
+    ) -> Result<(), E> {
+        match self {
+            Columns::Memory(matrix) => f(0..matrix.ncol(), &matrix.indices, &matrix.data),
+            Columns::Spilled(matrix, progress) => {
+                for (i, batch) in matrix.batches.iter().enumerate() {
+                    progress.check()?;
+                    if matrix.batches.len() > 1 {
+                        info!(
+                            "Converting columns {}-{} of {} (batch {} of {})",
+                            batch.start + 1, batch.end, matrix.barcodes.len(), i + 1, matrix.batches.len()
+                        );
+                    }
+                    let (indices, data) = matrix.read_batch(i)?;
+                    f(batch.clone(), &indices, &data)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Consecutive column ranges covering a CSC matrix, each with at most `max_entries` entries
+/// unless a single column has more
+pub fn column_batches(indptr: &[u64], max_entries: u64) -> Vec<std::ops::Range<usize>> {
+    let ncol = indptr.len().saturating_sub(1);
+    let mut batches = Vec::new();
+    let mut start = 0;
+    for col in 0..ncol {
+        if col > start && indptr[col + 1] - indptr[start] > max_entries {
+            batches.push(start..col);
+            start = col;
+        }
+    }
+    if start < ncol || batches.is_empty() {
+        batches.push(start..ncol);
+    }
+    batches
+}
+
+/// Sort the feature indices within each column of a batch, given the batch's slice of indptr
+fn sort_columns(indptr: &[u64], indices: &mut [u32], data: &mut [f64]) {
+    let offset = indptr[0];
+    let mut entries: Vec<(u32, f64)> = Vec::new();
+    for bounds in indptr.windows(2) {
+        let (start, end) = ((bounds[0] - offset) as usize, (bounds[1] - offset) as usize);
+        if indices[start..end].windows(2).all(|w| w[0] <= w[1]) {
+            continue;
+        }
+        entries.clear();
+        entries.extend(indices[start..end].iter().copied().zip(data[start..end].iter().copied()));
+        entries.sort_unstable_by_key(|e| e.0);
+        for (i, (idx, value)) in entries.iter().enumerate() {
+            indices[start + i] = *idx;
+            data[start + i] = *value;
+        }
+    }
+}
+
+/// A matrix whose entries were written once to temporary files, one per batch of columns, so that
+/// a conversion holds one batch of entries in memory rather than the whole matrix. Each file is
+/// removed once its batch is read, and any left over when the matrix is dropped.
+pub struct SpilledMatrix {
+    pub features: Vec<String>,
+    pub barcodes: Vec<String>,
+    /// entries before each column, as in a CSC matrix
+    pub indptr: Vec<u64>,
+    pub integer: bool,
+    batches: Vec<std::ops::Range<usize>>,
+    files: Vec<std::path::PathBuf>,
+}
+
+impl SpilledMatrix {
+    /// Feature indices and values of a batch, in column order with the feature indices sorted
+    /// within each column
+    fn read_batch(&self, batch: usize) -> io::Result<(Vec<u32>, Vec<f64>)> {
+        let columns = self.batches[batch].clone();
+        let offset = self.indptr[columns.start];
+        let n = (self.indptr[columns.end] - offset) as usize;
+        let mut next: Vec<u64> = self.indptr[columns.clone()].iter().map(|x| x - offset).collect();
+        let mut indices: Vec<u32> = vec![0; n];
+        let mut data: Vec<f64> = vec![0.0; n];
+
+        let mut reader = BufReader::with_capacity(1024 * 1024, File::open(&self.files[batch])?);
+        let mut record = [0u8; 16];
+        for _ in 0..n {
+            reader.read_exact(&mut record)?;
+            let col = u32::from_le_bytes(record[0..4].try_into().unwrap()) as usize;
+            let dest = &mut next[col - columns.start];
+            if *dest == self.indptr[col + 1] - offset {
+                return Err(invalid(format!("Column {} has more entries than declared", col + 1)));
+            }
+            indices[*dest as usize] = u32::from_le_bytes(record[4..8].try_into().unwrap());
+            data[*dest as usize] = f64::from_le_bytes(record[8..16].try_into().unwrap());
+            *dest += 1;
+        }
+        drop(reader);
+        fs::remove_file(&self.files[batch])?;
+
+        sort_columns(&self.indptr[columns.start..=columns.end], &mut indices, &mut data);
+        Ok((indices, data))
+    }
+}
+
+impl Drop for SpilledMatrix {
+    fn drop(&mut self) {
+        for file in &self.files {
+            if file.exists() {
+                if let Err(e) = fs::remove_file(file) {
+                    warn!("Failed to remove {:?}: {}", file, e);
+                }
+            }
+        }
+    }
+}
+
+/// Writes the entries of a matrix, in any order, to the batch files of a SpilledMatrix
+/// as (column, row, value) records
+pub struct ColumnSpill {
+    // closed before the matrix removes the files on an error
+    writers: Vec<io::BufWriter<File>>,
+    matrix: SpilledMatrix,
+    batch_of: Vec<u32>,
+    entries: u64,
+}
+
+impl ColumnSpill {
+    /// Create one file per batch of at most `max_entries` entries, named `prefix`.<batch>.tmp.
+    /// `indptr` gives the entries before each column, as in a CSC matrix.
+    pub fn create(
+        prefix: &Path,
+        features: Vec<String>,
+        barcodes: Vec<String>,
+        indptr: Vec<u64>,
+        integer: bool,
+        max_entries: u64,
+    ) -> io::Result<ColumnSpill> {
+        if indptr.len() != barcodes.len() + 1 {
+            return Err(invalid(format!("indptr has length {}, expected {}", indptr.len(), barcodes.len() + 1)));
+        }
+        let batches = column_batches(&indptr, max_entries);
+        let mut batch_of: Vec<u32> = vec![0; barcodes.len()];
+        for (i, batch) in batches.iter().enumerate() {
+            batch_of[batch.clone()].fill(i as u32);
+        }
+        let files: Vec<std::path::PathBuf> = (0..batches.len())
+            .map(|i| {
+                let mut name = prefix.as_os_str().to_owned();
+                name.push(format!(".{}.tmp", i));
+                name.into()
+            })
+            .collect();
+        let matrix = SpilledMatrix { features, barcodes, indptr, integer, batches, files };
+        let writers = matrix
+            .files
+            .iter()
+            .map(|path| Ok(io::BufWriter::new(File::create(path)?)))
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(ColumnSpill { writers, matrix, batch_of, entries: 0 })
+    }
+
+    /// Add an entry, given 0-based feature and cell indices
+    pub fn push(&mut self, row: usize, col: usize, value: f64) -> io::Result<()> {
+        if row >= self.matrix.features.len() || col >= self.matrix.barcodes.len() {
+            return Err(invalid(format!("Entry out of bounds: {} {}", row + 1, col + 1)));
+        }
+        let writer = &mut self.writers[self.batch_of[col] as usize];
+        writer.write_all(&(col as u32).to_le_bytes())?;
+        writer.write_all(&(row as u32).to_le_bytes())?;
+        writer.write_all(&value.to_le_bytes())?;
+        self.entries += 1;
+        Ok(())
+    }
+
+    /// Close the batch files, checking that every declared entry was added
+    pub fn finish(self) -> io::Result<SpilledMatrix> {
+        let ColumnSpill { writers, matrix, entries, .. } = self;
+        for writer in writers {
+            writer.into_inner().map_err(|e| e.into_error())?;
+        }
+        let nnz = matrix.indptr.last().copied().unwrap_or(0);
+        if entries != nnz {
+            return Err(invalid(format!("Header declares {} entries but {} were found", nnz, entries)));
+        }
+        Ok(matrix)
+    }
+}
+
+/// A Matrix Market directory whose entries have been counted for each column, to be read into
+/// memory or spilled to column batches with one more pass over the file
+pub struct MtxColumns {
+    matrix_path: std::path::PathBuf,
+    pub features: Vec<String>,
+    pub barcodes: Vec<String>,
+    /// entries before each column, as in a CSC matrix
+    pub indptr: Vec<u64>,
+    pub integer: bool,
+}
+
+impl MtxColumns {
+    pub fn open(dir: &Path) -> io::Result<MtxColumns> {
+        let matrix_path = find_file(dir, &["matrix.mtx.gz", "matrix.mtx.zst", "matrix.mtx"])?;
+        let feature_path = find_file(dir, &["features.tsv.gz", "features.tsv.zst", "features.tsv", "peaks.bed.gz", "peaks.bed"])?;
+        let barcode_path = find_file(dir, &["barcodes.tsv.gz", "barcodes.tsv"])?;
+        info!("Reading matrix: {:?}", matrix_path);
+
+        let features = read_names(&feature_path)?;
+        let barcodes = read_names(&barcode_path)?;
+
+        // header, dimensions, and entries per column
+        let mut reader = open_text(&matrix_path)?;
+        let (integer, nrow, ncol, nnz) = read_header(&mut reader)?;
+
+        if nrow != features.len() || ncol != barcodes.len() {
+            return Err(invalid(format!(
+                "Matrix is {} x {} but found {} features and {} barcodes",
+                nrow, ncol, features.len(), barcodes.len()
+            )));
+        }
+
+        let mut indptr: Vec<u64> = vec![0; ncol + 1];
+        for_each_entry(&mut reader, nrow, ncol, |_, col, _| {
+            indptr[col + 1] += 1;
+        })?;
+        for i in 0..ncol {
+            indptr[i + 1] += indptr[i];
+        }
+        if indptr[ncol] as usize != nnz {
+            return Err(invalid(format!(
+                "Header declares {} entries but {} were found", nnz, indptr[ncol]
+            )));
+        }
+        Ok(MtxColumns { matrix_path, features, barcodes, indptr, integer })
+    }
+
+    pub fn ncol(&self) -> usize {
+        self.barcodes.len()
+    }
+
+    pub fn nnz(&self) -> usize {
+        self.indptr[self.ncol()] as usize
+    }
+
+    /// Feature indices and values of all columns, in column order with the
+    /// feature indices sorted within each column
+    pub fn read(&self) -> io::Result<(Vec<u32>, Vec<f64>)> {
+        let mut reader = open_text(&self.matrix_path)?;
+        read_header(&mut reader)?;
+        let mut next: Vec<u64> = self.indptr[..self.ncol()].to_vec();
+        let mut indices: Vec<u32> = vec![0; self.nnz()];
+        let mut data: Vec<f64> = vec![0.0; self.nnz()];
+        for_each_entry(&mut reader, self.features.len(), self.ncol(), |row, col, value| {
+            let dest = next[col] as usize;
+            indices[dest] = row as u32;
+            data[dest] = value;
+            next[col] += 1;
+        })?;
+        sort_columns(&self.indptr, &mut indices, &mut data);
+        Ok((indices, data))
+    }
+
+    /// Write the entries to temporary files of at most `max_entries` entries each, named
+    /// `prefix`.<batch>.tmp, with one more pass over the file
+    pub fn spill(self, prefix: &Path, max_entries: u64) -> io::Result<SpilledMatrix> {
+        let mut reader = open_text(&self.matrix_path)?;
+        read_header(&mut reader)?;
+        let (nrow, ncol) = (self.features.len(), self.ncol());
+        let mut spill = ColumnSpill::create(prefix, self.features, self.barcodes, self.indptr, self.integer, max_entries)?;
+        let mut result: io::Result<()> = Ok(());
+        for_each_entry(&mut reader, nrow, ncol, |row, col, value| {
+            if result.is_ok() {
+                result = spill.push(row, col, value);
+            }
+        })?;
+        result?;
+        spill.finish()
+    }
+}
+
+/// Parse the banner and size line, returning (integer, nrow, ncol, nnz)
+pub fn read_header(reader: &mut Box<dyn BufRead>) -> io::Result<(bool, usize, usize, usize)> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let header = line.to_lowercase();
+    if !header.starts_with("%%matrixmarket matrix coordinate") {
+        return Err(invalid(format!("Unsupported Matrix Market header: {}", line.trim())));
+    }
+    let integer = header.contains(" pattern") || header.contains(" integer");
+
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(invalid("Matrix Market file has no size line".to_string()));
+        }
+        if line.starts_with('%') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let nrow = parse_field(fields.next(), &line)?;
+        let ncol = parse_field(fields.next(), &line)?;
+        let nnz = parse_field(fields.next(), &line)?;
+        return Ok((integer, nrow, ncol, nnz));
+    }
+}
+
+/// Call `f(row, col, value)` with 0-based indices for every entry line
+pub fn for_each_entry<F: FnMut(usize, usize, f64)>(
+    reader: &mut Box<dyn BufRead>,
+    nrow: usize,
+    ncol: usize,
+    mut f: F,
+) -> io::Result<()> {
+    for line in reader.lines() {
+        let line = line?;
+        if line.starts_with('%') || line.is_empty() {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let row: usize = parse_field(fields.next(), &line)?;
+        let col: usize = parse_field(fields.next(), &line)?;
+        let value: f64 = match fields.next() {
+            Some(v) => v.parse().map_err(|_| invalid(format!("Invalid entry: {}", line)))?,
+            None => 1.0, // pattern matrix
+        };
+        if row == 0 || col == 0 || row > nrow || col > ncol {
+            return Err(invalid(format!("Entry out of bounds: {}", line)));
+        }
+        f(row - 1, col - 1, value);
+    }
+    Ok(())
+}
+
+fn parse_field(field: Option<&str>, line: &str) -> io::Result<usize> {
+    field
+        .and_then(|x| x.parse().ok())
+        .ok_or_else(|| invalid(format!("Invalid line: {}", line.trim())))
+}
+
+/// Files written to a matrix output directory
+const OUTPUT_FILES: &[&str] = &[
+    "matrix.mtx.gz", "matrix.mtx.zst", "matrix.mtx", "features.tsv.gz", "features.tsv.zst", "features.tsv",
+    "barcodes.tsv.gz", "barcodes.tsv",
+];
+
+/// Refuse to write into a directory that already holds matrix output, unless `force` is set
+pub fn check_output_dir(dir: &Path, force: bool) -> io::Result<()> {
+    let paths: Vec<std::path::PathBuf> = OUTPUT_FILES.iter().map(|name| dir.join(name)).collect();
+    check_output_paths(&paths, force)
+}
+
+/// Refuse to write over any of the given output files that already exist, unless `force` is set
+pub fn check_output_paths(paths: &[std::path::PathBuf], force: bool) -> io::Result<()> {
+    let existing: Vec<String> = paths.iter().filter(|path| path.exists()).map(|path| path.display().to_string()).collect();
+    if existing.is_empty() {
+        return Ok(());
+    }
+    if force {
+        warn!("Overwriting existing output: {}", existing.join(", "));
+        return Ok(());
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!("Output already exists: {}. Use --force to overwrite", existing.join(", ")),
+    ))
+}
+
+/// Write a Matrix Market directory with matrix.mtx.gz, features.tsv.gz, barcodes.tsv
+pub fn write_mtx_dir(
+    dir: &Path,
+    matrix: &SparseMatrix,
+    num_threads: usize,
+) -> io::Result<()> {
+    write_mtx_columns(dir, &Columns::Memory(matrix), num_threads)
+}
+
+/// Write a Matrix Market directory a batch of columns at a time
+pub fn write_mtx_columns(
+    dir: &Path,
+    matrix: &Columns,
+    num_threads: usize,
+) -> io::Result<()> {
+
+    let counts_path = dir.join("matrix.mtx.gz");
+    info!("Writing output counts file: {:?}", &counts_path);
+    let mut encoder = compressed_writer(&counts_path, num_threads)?;
+
+    let field = if matrix.integer() { u32::FIELD } else { f64::FIELD };
+    let mut output = mtx_header(field, matrix.features().len(), matrix.barcodes().len(), matrix.nnz());
+
+    let indptr = matrix.indptr();
+    matrix.for_each_batch(|columns, indices, data| -> io::Result<()> {
+        let offset = indptr[columns.start];
+        for col in columns {
+            for pos in (indptr[col] - offset) as usize..(indptr[col + 1] - offset) as usize {
+                let row = indices[pos] as usize;
+                if matrix.integer() {
+                    output.push_str(&format!("{} {} {}\n", row + 1, col + 1, data[pos] as i64));
+                } else {
+                    output.push_str(&format!("{} {} {}\n", row + 1, col + 1, data[pos]));
+                }
+            }
+            // write chunk, clear string
+            if output.len() > 1 << 20 {
+                encoder.write_all(output.as_bytes())?;
+                output.clear();
+            }
+        }
+        Ok(())
+    })?;
+    encoder.write_all(output.as_bytes())?;
+    encoder.finish().map_err(io::Error::other)?;
+
+    write_mtx_names(dir, matrix.features(), matrix.barcodes(), num_threads)
+}
+
+/// Write the features.tsv.gz and barcodes.tsv files of a matrix directory
+pub fn write_mtx_names(
+    dir: &Path,
+    features: &[String],
+    barcodes: &[String],
+    num_threads: usize,
+) -> io::Result<()> {
+    let feature_path = dir.join("features.tsv.gz");
+    info!("Writing output feature file: {:?}", &feature_path);
+    let mut writer = compressed_writer(&feature_path, num_threads)?;
+    for feature in features {
+        writeln!(writer, "{}", feature)?;
+    }
+    writer.finish().map_err(io::Error::other)?;
+
+    let cell_path = dir.join("barcodes.tsv");
+    info!("Writing output cells file: {:?}", &cell_path);
+    let mut writer = io::BufWriter::new(File::create(&cell_path)?);
+    for barcode in barcodes {
+        writeln!(writer, "{}", barcode)?;
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Compression of matrix and feature outputs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputCompression {
+    Gzip,
+    Zstd,
+}
+
+impl OutputCompression {
+    pub fn from_name(name: &str) -> Option<OutputCompression> {
+        match name {
+            "gzip" => Some(OutputCompression::Gzip),
+            "zstd" => Some(OutputCompression::Zstd),
+            _ => None,
+        }
+    }
+
+    /// File name with the extension of this compression, e.g. matrix.mtx.gz
+    pub fn file_name(&self, name: &str) -> String {
+        match self {
+            OutputCompression::Gzip => format!("{}.gz", name),
+            OutputCompression::Zstd => format!("{}.zst", name),
+        }
+    }
+}
+
+/// Columns of a features.tsv file, for the loaders that expect each
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FeaturesFormat {
+    /// a single name column, chrom-start-end for regions
+    #[default]
+    Names,
+    /// chrom, start, and end columns
+    Bed,
+    /// ID, name, and feature type columns, as written by Cell Ranger
+    TenX,
+}
+
+impl FeaturesFormat {
+    pub fn from_name(name: &str) -> Option<FeaturesFormat> {
+        match name {
+            "names" => Some(FeaturesFormat::Names),
+            "bed" => Some(FeaturesFormat::Bed),
+            "10x" => Some(FeaturesFormat::TenX),
+            _ => None,
+        }
+    }
+
+    /// Line for a genomic region
+    pub fn region(&self, chrom: &str, start: u32, end: u32) -> String {
+        match self {
+            FeaturesFormat::Names => format!("{}-{}-{}", chrom, start, end),
+            FeaturesFormat::Bed => format!("{}\t{}\t{}", chrom, start, end),
+            FeaturesFormat::TenX => format!("{0}:{1}-{2}\t{0}:{1}-{2}\tPeaks", chrom, start, end),
+        }
+    }
+}
+
+/// Compressed output file
+pub enum CompressedWriter {
+    Gzip(ParCompress<Gzip>),
+    Zstd(zstd::Encoder<'static, File>),
+}
+
+impl Write for CompressedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            CompressedWriter::Gzip(writer) => writer.write(buf),
+            CompressedWriter::Zstd(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            CompressedWriter::Gzip(writer) => writer.flush(),
+            CompressedWriter::Zstd(writer) => writer.flush(),
+        }
+    }
+}
+
+impl ZWriter for CompressedWriter {
+    fn finish(&mut self) -> Result<(), GzpError> {
+        match self {
+            CompressedWriter::Gzip(writer) => writer.finish(),
+            CompressedWriter::Zstd(writer) => Ok(writer.do_finish()?),
+        }
+    }
+}
+
+/// Create a compressed output file: zstd if the name ends in .zst, gzip otherwise
+pub fn compressed_writer(path: &Path, num_threads: usize) -> io::Result<CompressedWriter> {
+    let writer = File::create(path)?;
+    if path.extension().is_some_and(|ext| ext == "zst") {
+        let mut encoder = zstd::Encoder::new(writer, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+        if num_threads > 1 {
+            encoder.multithread(num_threads as u32)?;
+        }
+        return Ok(CompressedWriter::Zstd(encoder));
+    }
+    let encoder: ParCompress<Gzip> = ParCompressBuilder::new()
+        .compression_level(Compression::default())
+        .num_threads(num_threads)
+        .map_err(io::Error::other)?
+        .from_writer(writer);
+    Ok(CompressedWriter::Gzip(encoder))
+}
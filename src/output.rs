@@ -0,0 +1,66 @@
+use std::{
+    io,
+    fs,
+    path::{Path, PathBuf},
+};
+use log::warn;
+
+/// Output files and directories written by a run, removed again if the run
+/// fails before `finish` so no partial results are left behind
+#[derive(Default)]
+pub struct PartialOutput {
+    files: Vec<PathBuf>,
+    /// directories created by this run
+    dirs: Vec<PathBuf>,
+    finished: bool,
+}
+
+impl PartialOutput {
+    pub fn new() -> PartialOutput {
+        PartialOutput::default()
+    }
+
+    /// Register a file that is about to be written, returning its path
+    pub fn file(&mut self, path: PathBuf) -> PathBuf {
+        self.files.push(path.clone());
+        path
+    }
+
+    /// Create a directory and any missing parents, registering those created
+    pub fn create_dir(&mut self, path: &Path) -> io::Result<()> {
+        let mut missing: Vec<PathBuf> = path
+            .ancestors()
+            .take_while(|dir| !dir.as_os_str().is_empty() && !dir.exists())
+            .map(Path::to_path_buf)
+            .collect();
+        fs::create_dir_all(path)?;
+        missing.reverse();
+        self.dirs.extend(missing);
+        Ok(())
+    }
+
+    /// Keep the output
+    pub fn finish(mut self) {
+        self.finished = true;
+    }
+}
+
+impl Drop for PartialOutput {
+    fn drop(&mut self) {
+        if self.finished || (self.files.is_empty() && self.dirs.is_empty()) {
+            return;
+        }
+        warn!("Run failed, removing partial output");
+        for file in self.files.iter().rev() {
+            if file.exists() {
+                if let Err(e) = fs::remove_file(file) {
+                    warn!("Failed to remove {:?}: {}", file, e);
+                }
+            }
+        }
+        // directories are only removed if nothing else was put in them
+        for dir in self.dirs.iter().rev() {
+            let _ = fs::remove_dir(dir);
+        }
+    }
+}
@@ -0,0 +1,220 @@
+use std::{
+    io,
+    fs::File,
+    path::Path,
+    io::BufRead,
+    io::Write,
+};
+use log::{info, warn};
+use rustc_hash::{FxHashMap, FxHashSet};
+use crate::cellindex::CellIndex;
+use crate::f2m::FeatureCounts;
+use crate::matrix::open_text;
+
+/// Perturbation (guide) assignments of cell barcodes from a CRISPR screen
+pub struct Perturbations {
+    /// perturbation names, in order of first appearance
+    pub names: Vec<String>,
+    /// (barcode, perturbation index) pairs, in file order
+    pairs: Vec<(String, usize)>,
+}
+
+/// Output matrix columns built from cell and perturbation pairs
+pub struct PerturbationColumns {
+    pub names: Vec<String>,
+    /// output columns of each cell index
+    cell_columns: Vec<Vec<u32>>,
+    /// number of cells in each column
+    pub cell_counts: Vec<u32>,
+    /// columns are perturbations rather than cells
+    pub pseudobulk: bool,
+    /// covariate names and values of each column, for pseudobulks of merged samples
+    covariates: Option<(Vec<String>, Vec<Vec<String>>)>,
+}
+
+impl Perturbations {
+    /// Read a tab-separated file of cell barcode and perturbation, one pair per line.
+    /// A cell with several perturbations is listed once for each.
+    pub fn from_file(path: &Path) -> io::Result<Perturbations> {
+        let mut index: FxHashMap<String, usize> = FxHashMap::default();
+        let mut names: Vec<String> = Vec::new();
+        let mut pairs: Vec<(String, usize)> = Vec::new();
+        let mut seen: FxHashSet<(String, usize)> = FxHashSet::default();
+
+        for (line_number, line) in open_text(path)?.lines().enumerate() {
+            let line = line?;
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() < 2 {
+                warn!("Line {}: Expected cell barcode and perturbation", line_number + 1);
+                continue;
+            }
+            let perturbation = *index.entry(fields[1].to_string()).or_insert_with(|| {
+                names.push(fields[1].to_string());
+                names.len() - 1
+            });
+            if seen.insert((fields[0].to_string(), perturbation)) {
+                pairs.push((fields[0].to_string(), perturbation));
+            }
+        }
+        if pairs.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "No perturbation assignments found"));
+        }
+        info!("Loaded {} cell-perturbation pairs across {} perturbations", pairs.len(), names.len());
+        Ok(Perturbations { names, pairs })
+    }
+
+    /// Unique cell barcodes, in file order
+    pub fn barcodes(&self) -> Vec<String> {
+        let mut seen: FxHashSet<&str> = FxHashSet::default();
+        self.pairs
+            .iter()
+            .filter(|(barcode, _)| seen.insert(barcode))
+            .map(|(barcode, _)| barcode.clone())
+            .collect()
+    }
+
+    /// Columns for each (cell, perturbation) pair, named `barcode:perturbation`,
+    /// or one column per perturbation summing its cells when `pseudobulk` is set.
+    /// Cells not in the index are left out.
+    pub fn columns(&self, cells: &CellIndex, pseudobulk: bool) -> PerturbationColumns {
+        let mut names: Vec<String> = Vec::new();
+        let mut cell_columns: Vec<Vec<u32>> = vec![Vec::new(); cells.len()];
+        let mut cell_counts: Vec<u32> = Vec::new();
+        if pseudobulk {
+            names = self.names.clone();
+            cell_counts = vec![0; names.len()];
+        }
+
+        let mut missing: usize = 0;
+        for (barcode, perturbation) in &self.pairs {
+            let cell = match cells.get(barcode) {
+                Some(cell) => cell as usize,
+                None => {
+                    missing += 1;
+                    continue;
+                }
+            };
+            let column = if pseudobulk {
+                cell_counts[*perturbation] += 1;
+                *perturbation
+            } else {
+                names.push(format!("{}:{}", barcode, self.names[*perturbation]));
+                cell_counts.push(1);
+                names.len() - 1
+            };
+            cell_columns[cell].push(column as u32);
+        }
+        if missing > 0 {
+            warn!("{} cell-perturbation pairs have cell barcodes not in the cell list", missing);
+        }
+        PerturbationColumns { names, cell_columns, cell_counts, pseudobulk, covariates: None }
+    }
+}
+
+impl PerturbationColumns {
+    /// One pseudobulk column per sample of a merged matrix, summing the cells with the sample's
+    /// barcode suffix (the longest matching suffix if several match). `covariates` has a row of
+    /// values for each sample, named by `covariate_names`.
+    pub fn from_samples(
+        cells: &CellIndex,
+        names: Vec<String>,
+        suffixes: &[&str],
+        covariate_names: Vec<String>,
+        covariates: Vec<Vec<String>>,
+    ) -> PerturbationColumns {
+        let mut cell_columns: Vec<Vec<u32>> = vec![Vec::new(); cells.len()];
+        let mut cell_counts: Vec<u32> = vec![0; names.len()];
+        for (cell, columns) in cell_columns.iter_mut().enumerate() {
+            let barcode = cells.barcode(cell);
+            let sample = (0..suffixes.len())
+                .filter(|i| barcode.ends_with(suffixes[*i]))
+                .max_by_key(|i| suffixes[*i].len());
+            if let Some(sample) = sample {
+                cell_counts[sample] += 1;
+                columns.push(sample as u32);
+            }
+        }
+        for (name, count) in names.iter().zip(cell_counts.iter()) {
+            if *count == 0 {
+                warn!("Sample {} has no cells", name);
+            }
+        }
+        PerturbationColumns {
+            names,
+            cell_columns,
+            cell_counts,
+            pseudobulk: true,
+            covariates: Some((covariate_names, covariates)),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// Move cell counts to the perturbation columns of each cell, summing pseudobulk columns
+    pub fn remap(&self, counts: &[FxHashMap<u32, u32>]) -> FeatureCounts {
+        counts
+            .iter()
+            .map(|cell_counts| {
+                let mut columns: FxHashMap<u32, u32> = FxHashMap::default();
+                for (&cell, &count) in cell_counts {
+                    for &column in &self.cell_columns[cell as usize] {
+                        *columns.entry(column).or_insert(0) += count;
+                    }
+                }
+                columns
+            })
+            .collect()
+    }
+
+    pub fn write_names(&self, path: &Path) -> io::Result<()> {
+        let mut writer = io::BufWriter::new(File::create(path)?);
+        for name in &self.names {
+            writeln!(writer, "{}", name)?;
+        }
+        writer.flush()
+    }
+
+    /// Name of the pseudobulk column table: `perturbations.tsv`, or `design.tsv` for samples
+    pub fn cell_counts_file(&self) -> &'static str {
+        if self.covariates.is_some() {
+            "design.tsv"
+        } else {
+            "perturbations.tsv"
+        }
+    }
+
+    /// Number of cells in each column, for normalizing pseudobulk counts, followed by the
+    /// covariates of sample columns so the table can be used as a design table
+    pub fn write_cell_counts(&self, path: &Path) -> io::Result<()> {
+        let mut writer = io::BufWriter::new(File::create(path)?);
+        match &self.covariates {
+            Some((covariate_names, _)) => {
+                write!(writer, "sample\tcells")?;
+                for name in covariate_names {
+                    write!(writer, "\t{}", name)?;
+                }
+                writeln!(writer)?;
+            }
+            None => writeln!(writer, "perturbation\tcells")?,
+        }
+        for (column, (name, count)) in self.names.iter().zip(self.cell_counts.iter()).enumerate() {
+            write!(writer, "{}\t{}", name, count)?;
+            if let Some((_, covariates)) = &self.covariates {
+                for value in &covariates[column] {
+                    write!(writer, "\t{}", value)?;
+                }
+            }
+            writeln!(writer)?;
+        }
+        writer.flush()
+    }
+}
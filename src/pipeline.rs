@@ -0,0 +1,614 @@
+use std::{
+    io,
+    fs,
+    fs::File,
+    path::{Path, PathBuf},
+    error::Error,
+    io::BufRead,
+    io::BufReader,
+    io::Write,
+    process::Command,
+};
+use log::{info, warn};
+use rustc_hash::FxHashSet;
+use crate::matrix;
+use crate::sketch::fnv1a;
+
+/// Stages run for each sample, in order
+const STAGES: [&str; 4] = ["validate", "qc", "features", "count"];
+
+/// Names of a Matrix Market file, with each compression `fragtk matrix` writes
+const MATRIX_NAMES: [&str; 3] = ["matrix.mtx.gz", "matrix.mtx.zst", "matrix.mtx"];
+
+/// A value of the pipeline config
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Str(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Array(Vec<Value>),
+}
+
+/// Keys and values of one table of the config, in file order
+pub type Table = Vec<(String, Value)>;
+
+/// Parse the subset of TOML used by pipeline configs: `key = value` pairs at the top level and in
+/// `[[sample]]` tables, with basic and literal strings, integers, floats, booleans, single-line
+/// arrays, and comments. Returns the top-level table and the sample tables.
+pub fn parse_config(text: &str) -> io::Result<(Table, Vec<Table>)> {
+    let error = |line: usize, message: &str| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("Config line {}: {}", line, message))
+    };
+    let mut top: Table = Vec::new();
+    let mut samples: Vec<Table> = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') {
+            if strip_comment(line).trim() != "[[sample]]" {
+                return Err(error(index + 1, "only [[sample]] tables are supported"));
+            }
+            samples.push(Vec::new());
+            continue;
+        }
+        let (key, value) = line.split_once('=').ok_or_else(|| error(index + 1, "expected key = value"))?;
+        let key = key.trim().trim_matches('"').to_string();
+        let (value, rest) = parse_value(value.trim()).ok_or_else(|| error(index + 1, "invalid value"))?;
+        if !strip_comment(rest).trim().is_empty() {
+            return Err(error(index + 1, "unexpected text after the value"));
+        }
+        let table = samples.last_mut().unwrap_or(&mut top);
+        if table.iter().any(|(x, _)| *x == key) {
+            return Err(error(index + 1, &format!("{} is set twice", key)));
+        }
+        table.push((key, value));
+    }
+    Ok((top, samples))
+}
+
+/// Text before a `#` comment
+fn strip_comment(text: &str) -> &str {
+    text.split_once('#').map_or(text, |(before, _)| before)
+}
+
+/// Parse a value at the start of `text`, returning it and the text after it
+fn parse_value(text: &str) -> Option<(Value, &str)> {
+    if let Some(rest) = text.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return Some((Value::Str(value), &rest[i + 1..])),
+                '\\' => value.push(match chars.next()?.1 {
+                    'n' => '\n',
+                    't' => '\t',
+                    c @ ('"' | '\\') => c,
+                    _ => return None,
+                }),
+                c => value.push(c),
+            }
+        }
+        return None;
+    }
+    if let Some(rest) = text.strip_prefix('\'') {
+        let (value, rest) = rest.split_once('\'')?;
+        return Some((Value::Str(value.to_string()), rest));
+    }
+    if let Some(mut rest) = text.strip_prefix('[') {
+        let mut values = Vec::new();
+        loop {
+            rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix(']') {
+                return Some((Value::Array(values), after));
+            }
+            let (value, after) = parse_value(rest)?;
+            values.push(value);
+            rest = after.trim_start();
+            rest = rest.strip_prefix(',').unwrap_or(rest);
+        }
+    }
+    let end = text.find(|c: char| c.is_whitespace() || c == ',' || c == ']' || c == '#').unwrap_or(text.len());
+    let (word, rest) = text.split_at(end);
+    let value = match word {
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        _ => {
+            let number = word.replace('_', "");
+            match number.parse::<i64>() {
+                Ok(x) => Value::Int(x),
+                Err(_) => Value::Float(number.parse::<f64>().ok()?),
+            }
+        }
+    };
+    Some((value, rest))
+}
+
+/// Typed access to a config table, rejecting unknown keys so that typos are not ignored
+struct Settings<'a> {
+    table: &'a Table,
+    name: String,
+    dir: &'a Path,
+}
+
+impl Settings<'_> {
+    fn check_keys(&self, known: &[&str]) -> io::Result<()> {
+        match self.table.iter().find(|(key, _)| !known.contains(&key.as_str())) {
+            Some((key, _)) => Err(self.invalid(&format!("unknown key {}", key))),
+            None => Ok(()),
+        }
+    }
+
+    fn invalid(&self, message: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid {}: {}", self.name, message))
+    }
+
+    fn get(&self, key: &str) -> Option<&Value> {
+        self.table.iter().find(|(x, _)| x == key).map(|(_, value)| value)
+    }
+
+    fn string(&self, key: &str) -> io::Result<Option<String>> {
+        match self.get(key) {
+            Some(Value::Str(x)) => Ok(Some(x.clone())),
+            Some(_) => Err(self.invalid(&format!("{} must be a string", key))),
+            None => Ok(None),
+        }
+    }
+
+    /// A path, relative to the directory of the config file
+    fn path(&self, key: &str) -> io::Result<Option<PathBuf>> {
+        Ok(self.string(key)?.map(|x| self.dir.join(x)))
+    }
+
+    fn int(&self, key: &str) -> io::Result<Option<u64>> {
+        match self.get(key) {
+            Some(Value::Int(x)) if *x >= 0 => Ok(Some(*x as u64)),
+            Some(_) => Err(self.invalid(&format!("{} must be a non-negative integer", key))),
+            None => Ok(None),
+        }
+    }
+
+    fn bool(&self, key: &str) -> io::Result<bool> {
+        match self.get(key) {
+            Some(Value::Bool(x)) => Ok(*x),
+            Some(_) => Err(self.invalid(&format!("{} must be true or false", key))),
+            None => Ok(false),
+        }
+    }
+
+    fn strings(&self, key: &str) -> io::Result<Vec<String>> {
+        match self.get(key) {
+            Some(Value::Array(values)) => values
+                .iter()
+                .map(|value| match value {
+                    Value::Str(x) => Ok(x.clone()),
+                    _ => Err(self.invalid(&format!("{} must be an array of strings", key))),
+                })
+                .collect(),
+            Some(_) => Err(self.invalid(&format!("{} must be an array of strings", key))),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+/// Features counted for every sample
+enum FeatureSource {
+    /// one peak file shared by all samples
+    Peaks(PathBuf),
+    /// peaks called for each sample from its cells
+    CallPeaks,
+    /// genome bins of this size
+    Bins(u64),
+}
+
+struct Sample {
+    name: String,
+    fragments: PathBuf,
+    /// cell barcodes, or None to select cells by fragment count
+    cells: Option<PathBuf>,
+    /// hash of the shared and sample settings, so a sample whose settings change is run again
+    hash: u64,
+}
+
+/// Settings of a pipeline config file
+pub struct Pipeline {
+    output: PathBuf,
+    features: FeatureSource,
+    genome: Option<PathBuf>,
+    min_fragments: u64,
+    threads: Option<u64>,
+    matrix_args: Vec<String>,
+    samples: Vec<Sample>,
+}
+
+impl Pipeline {
+    pub fn from_file(path: &Path) -> io::Result<Pipeline> {
+        let text = fs::read_to_string(path)?;
+        let (top, tables) = parse_config(&text)?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+
+        let settings = Settings { table: &top, name: "config".to_string(), dir };
+        settings.check_keys(&["output", "peaks", "call_peaks", "bins", "genome", "min_fragments", "threads", "matrix_args"])?;
+        let output = settings.path("output")?.ok_or_else(|| settings.invalid("output is required"))?;
+        let genome = settings.path("genome")?;
+        let features = match (settings.path("peaks")?, settings.bool("call_peaks")?, settings.int("bins")?) {
+            (Some(peaks), false, None) => FeatureSource::Peaks(peaks),
+            (None, true, None) => FeatureSource::CallPeaks,
+            (None, false, Some(size)) if size > 0 => FeatureSource::Bins(size),
+            _ => return Err(settings.invalid("set exactly one of peaks, call_peaks = true, or bins (a bin size)")),
+        };
+        if genome.is_none() && !matches!(features, FeatureSource::Peaks(_)) {
+            return Err(settings.invalid("genome (chromosome sizes) is required for call_peaks and bins"));
+        }
+
+        let mut samples: Vec<Sample> = Vec::with_capacity(tables.len());
+        let mut names: FxHashSet<String> = FxHashSet::default();
+        for (index, table) in tables.iter().enumerate() {
+            let settings = Settings { table, name: format!("sample {}", index + 1), dir };
+            settings.check_keys(&["name", "fragments", "cells"])?;
+            let name = settings.string("name")?.ok_or_else(|| settings.invalid("name is required"))?;
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || "_.-".contains(c)) {
+                return Err(settings.invalid("names may only hold letters, digits, _, . and -"));
+            }
+            if !names.insert(name.clone()) {
+                return Err(settings.invalid(&format!("sample name {} is used twice", name)));
+            }
+            let fragments = settings.path("fragments")?.ok_or_else(|| settings.invalid("fragments is required"))?;
+            let hash = fnv1a(format!("{:?}{:?}", top, table).as_bytes());
+            samples.push(Sample { name, fragments, cells: settings.path("cells")?, hash });
+        }
+        if samples.is_empty() {
+            return Err(settings.invalid("no [[sample]] tables"));
+        }
+        let matrix_args = settings.strings("matrix_args")?;
+        check_matrix_args(&matrix_args).map_err(|message| settings.invalid(&message))?;
+
+        Ok(Pipeline {
+            output,
+            features,
+            genome,
+            min_fragments: settings.int("min_fragments")?.unwrap_or(1000),
+            threads: settings.int("threads")?,
+            matrix_args,
+            samples,
+        })
+    }
+
+    /// Arguments of the fragtk command run for a stage of a sample, and the file its standard output is written to
+    fn stage_command(&self, sample: &Sample, stage: &str) -> (Vec<String>, Option<PathBuf>) {
+        let dir = self.output.join(&sample.name);
+        let path = |x: &Path| x.to_string_lossy().into_owned();
+        let threads: Vec<String> = match self.threads {
+            Some(n) => vec!["--threads".to_string(), n.to_string()],
+            None => Vec::new(),
+        };
+        let cells = sample.cells.clone().unwrap_or_else(|| dir.join("cells.txt"));
+        let fragments = path(&sample.fragments);
+        let mut args: Vec<String> = match stage {
+            "validate" => {
+                let args = ["stats", "-f", &fragments, "--refresh"].map(String::from).to_vec();
+                return ([args, threads].concat(), Some(dir.join("stats.tsv")));
+            }
+            "qc" => {
+                let counts = path(&dir.join("barcode_counts.tsv"));
+                let threshold = self.min_fragments.to_string();
+                let args = ["count", "-f", &fragments, "-o", &counts, "-t", &threshold].map(String::from).to_vec();
+                return (args, Some(dir.join("cells.txt")));
+            }
+            "features" => {
+                let genome = path(self.genome.as_deref().unwrap_or(Path::new("")));
+                let peaks = path(&dir.join("peaks"));
+                ["callpeaks", "-f", &fragments, "-g", &genome, "-c", &path(&cells), "-o", &peaks].map(String::from).to_vec()
+            }
+            _ => {
+                let mut args = ["matrix", "-f", &fragments, "-c", &path(&cells), "-o", &path(&dir.join("matrix")), "--force"]
+                    .map(String::from)
+                    .to_vec();
+                match &self.features {
+                    FeatureSource::Peaks(peaks) => args.extend(["-b".to_string(), path(peaks)]),
+                    FeatureSource::CallPeaks => args.extend(["-b".to_string(), path(&dir.join("peaks").join("union.bed"))]),
+                    FeatureSource::Bins(size) => {
+                        let genome = path(self.genome.as_deref().unwrap_or(Path::new("")));
+                        args.extend(["--bins".to_string(), size.to_string(), "-g".to_string(), genome]);
+                    }
+                }
+                args.extend(self.matrix_args.iter().cloned());
+                args
+            }
+        };
+        args.extend(threads);
+        (args, None)
+    }
+
+    /// Whether a stage runs for a sample
+    fn runs(&self, sample: &Sample, stage: &str) -> bool {
+        match stage {
+            "qc" => sample.cells.is_none(),
+            "features" => matches!(self.features, FeatureSource::CallPeaks),
+            _ => true,
+        }
+    }
+}
+
+/// Reject matrix arguments whose output the summary cannot read: it reads the size line of each
+/// Matrix Market file, so the matrix must not be written as HDF5 or as shards
+fn check_matrix_args(args: &[String]) -> Result<(), String> {
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let format = match arg.strip_prefix("--format") {
+            Some("") => args.next().map(String::as_str),
+            Some(value) => value.strip_prefix('='),
+            None => None,
+        };
+        if format.is_some_and(|format| format != "mtx") {
+            return Err("matrix_args: the summary reads Matrix Market files, so --format must be mtx".to_string());
+        }
+        if arg == "--shard-output" || arg.starts_with("--shard-output=") {
+            return Err("matrix_args: the summary reads Matrix Market files, so --shard-output is not supported".to_string());
+        }
+    }
+    Ok(())
+}
+
+/// Directories under `dir`, including itself, holding a Matrix Market file, in sorted order.
+/// Several feature sets, strata, or samples are written to subdirectories of the matrix directory.
+fn matrix_dirs(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut dirs = Vec::new();
+    if MATRIX_NAMES.iter().any(|name| dir.join(name).exists()) {
+        dirs.push(dir.to_path_buf());
+    }
+    let mut subdirs: Vec<PathBuf> = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            subdirs.push(entry.path());
+        }
+    }
+    subdirs.sort();
+    for subdir in subdirs {
+        dirs.extend(matrix_dirs(&subdir)?);
+    }
+    Ok(dirs)
+}
+
+/// Stages completed by an earlier run, for samples with the same settings, as sample and stage
+fn read_state(path: &Path, samples: &[Sample]) -> io::Result<FxHashSet<(String, String)>> {
+    let mut done: FxHashSet<(String, String)> = FxHashSet::default();
+    if !path.exists() {
+        return Ok(done);
+    }
+    let mut changed: FxHashSet<String> = FxHashSet::default();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let fields: Vec<&str> = line.split('\t').collect();
+        let [name, stage, hash] = fields[..] else {
+            continue;
+        };
+        match samples.iter().find(|sample| sample.name == name) {
+            Some(sample) if format!("{:016x}", sample.hash) == hash => {
+                done.insert((name.to_string(), stage.to_string()));
+            }
+            Some(_) if changed.insert(name.to_string()) => {
+                warn!("The settings of sample {} have changed since the last run, running it again", name);
+            }
+            _ => {}
+        }
+    }
+    Ok(done)
+}
+
+/// Run the stages of the pipeline for each sample, skipping stages a previous run with the same
+/// settings completed, then summarize the samples
+pub fn run(matches: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+
+    let config = Path::new(matches.get_one::<String>("config").unwrap());
+    info!("Received pipeline config: {:?}", config);
+    let pipeline = Pipeline::from_file(config)?;
+    let dry_run = matches.get_flag("dry_run");
+    let exe = std::env::current_exe()?;
+
+    let state_path = pipeline.output.join("pipeline.state");
+    let done = match matches.get_flag("restart") {
+        true => FxHashSet::default(),
+        false => read_state(&state_path, &pipeline.samples)?,
+    };
+    // stages still to run for each sample: those after the last of an unbroken run of completed stages
+    let mut todo: Vec<(&Sample, Vec<&str>)> = Vec::with_capacity(pipeline.samples.len());
+    for sample in &pipeline.samples {
+        let stages: Vec<&str> = STAGES.into_iter().filter(|stage| pipeline.runs(sample, stage)).collect();
+        let completed = stages.iter().take_while(|stage| done.contains(&(sample.name.clone(), stage.to_string()))).count();
+        if completed > 0 {
+            info!("Sample {}: {} already done", sample.name, stages[..completed].join(", "));
+        }
+        todo.push((sample, stages[completed..].to_vec()));
+    }
+
+    if dry_run {
+        for (sample, stages) in &todo {
+            for stage in stages {
+                let (args, stdout) = pipeline.stage_command(sample, stage);
+                let redirect = stdout.map_or(String::new(), |path| format!(" > {}", path.display()));
+                println!("{}\t{}\tfragtk {}{}", sample.name, stage, args.join(" "), redirect);
+            }
+        }
+        return Ok(());
+    }
+
+    // rewrite the state file with the stages kept from the last run
+    for sample in &pipeline.samples {
+        fs::create_dir_all(pipeline.output.join(&sample.name))?;
+    }
+    let mut state = File::create(&state_path)?;
+    writeln!(state, "# fragtk pipeline state: sample, completed stage, settings hash")?;
+    for (sample, stages) in &todo {
+        for stage in STAGES.iter().filter(|stage| pipeline.runs(sample, stage) && !stages.contains(stage)) {
+            writeln!(state, "{}\t{}\t{:016x}", sample.name, stage, sample.hash)?;
+        }
+    }
+    state.flush()?;
+
+    for (sample, stages) in &todo {
+        for stage in stages {
+            let (args, stdout) = pipeline.stage_command(sample, stage);
+            info!("Sample {}: {}: fragtk {}", sample.name, stage, args.join(" "));
+            let mut command = Command::new(&exe);
+            command.args(&args);
+            if let Some(path) = &stdout {
+                command.stdout(File::create(path)?);
+            }
+            let status = command.status()?;
+            if !status.success() {
+                return Err(format!("Sample {}: {} failed ({}), fix the problem and run the pipeline again to resume", sample.name, stage, status).into());
+            }
+            writeln!(state, "{}\t{}\t{:016x}", sample.name, stage, sample.hash)?;
+            state.flush()?;
+        }
+    }
+
+    let summary_path = pipeline.output.join("summary.tsv");
+    info!("Writing pipeline summary: {:?}", summary_path);
+    let mut writer = io::BufWriter::new(File::create(&summary_path)?);
+    writeln!(writer, "sample\tfragments\tcells\tfeatures\tnonzero\tmatrix")?;
+    for sample in &pipeline.samples {
+        let dir = pipeline.output.join(&sample.name);
+        let fragments = read_stat(&dir.join("stats.tsv"), "fragments")?;
+        let matrix_dirs = matrix_dirs(&dir.join("matrix"))?;
+        if matrix_dirs.is_empty() {
+            return Err(format!("Sample {}: no Matrix Market file in {:?}", sample.name, dir.join("matrix")).into());
+        }
+        // a row for each matrix, when the matrix arguments write several
+        for matrix_dir in matrix_dirs {
+            let (features, cells, nonzero) = read_dimensions(&matrix::find_file(&matrix_dir, &MATRIX_NAMES)?)?;
+            writeln!(
+                writer, "{}\t{}\t{}\t{}\t{}\t{}",
+                sample.name, fragments.unwrap_or(0), cells, features, nonzero, matrix_dir.display(),
+            )?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// A value of a `fragtk stats` summary
+fn read_stat(path: &Path, key: &str) -> io::Result<Option<u64>> {
+    for line in BufReader::new(File::open(path)?).lines() {
+        if let Some((name, value)) = line?.split_once('\t') {
+            if name == key {
+                return Ok(value.parse().ok());
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Rows, columns, and entries of a Matrix Market file, from its size line
+fn read_dimensions(path: &Path) -> io::Result<(u64, u64, u64)> {
+    for line in matrix::open_text(path)?.lines() {
+        let line = line?;
+        if line.starts_with('%') {
+            continue;
+        }
+        let fields: Vec<u64> = line.split_whitespace().filter_map(|x| x.parse().ok()).collect();
+        if let [rows, columns, entries] = fields[..] {
+            return Ok((rows, columns, entries));
+        }
+        break;
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidData, format!("Missing size line in {:?}", path)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_tables_and_values() {
+        let text = "# pipeline\n\
+                    output = \"results\"\n\
+                    min_fragments = 1_000\n\
+                    ratio = 0.5\n\
+                    call_peaks = true\n\
+                    matrix_args = [\"--mode\", 'pic', ]\n\
+                    \n\
+                    [[sample]] # first\n\
+                    name = \"a\"\n\
+                    \n\
+                    [[sample]]\n\
+                    name = 'b'\n";
+        let (top, samples) = parse_config(text).unwrap();
+        assert_eq!(top, vec![
+            ("output".to_string(), Value::Str("results".to_string())),
+            ("min_fragments".to_string(), Value::Int(1000)),
+            ("ratio".to_string(), Value::Float(0.5)),
+            ("call_peaks".to_string(), Value::Bool(true)),
+            ("matrix_args".to_string(), Value::Array(vec![Value::Str("--mode".to_string()), Value::Str("pic".to_string())])),
+        ]);
+        assert_eq!(samples, vec![
+            vec![("name".to_string(), Value::Str("a".to_string()))],
+            vec![("name".to_string(), Value::Str("b".to_string()))],
+        ]);
+    }
+
+    #[test]
+    fn comments_end_values_but_not_strings() {
+        let text = "a = \"x # y\" # comment\nb = 'p#q'\nc = 3 # comment\nd = [\"#\", 1] # comment\ne = \"say \\\"hi\\\"\\t\"\n";
+        let (top, _) = parse_config(text).unwrap();
+        assert_eq!(top, vec![
+            ("a".to_string(), Value::Str("x # y".to_string())),
+            ("b".to_string(), Value::Str("p#q".to_string())),
+            ("c".to_string(), Value::Int(3)),
+            ("d".to_string(), Value::Array(vec![Value::Str("#".to_string()), Value::Int(1)])),
+            ("e".to_string(), Value::Str("say \"hi\"\t".to_string())),
+        ]);
+    }
+
+    #[test]
+    fn rejects_invalid_configs() {
+        let invalid = [
+            "a = 1\na = 2\n",
+            "[[sample]]\nname = \"x\"\nname = \"y\"\n",
+            "[samples]\n",
+            "a\n",
+            "a = \n",
+            "a = \"unterminated\n",
+            "a = [1, 2\n",
+            "a = \"x\" y\n",
+            "a = \"\\q\"\n",
+        ];
+        for text in invalid {
+            let error = parse_config(text).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{:?}", text);
+        }
+        // a key may be set once in each table
+        assert!(parse_config("name = \"x\"\n[[sample]]\nname = \"x\"\n").is_ok());
+    }
+
+    #[test]
+    fn matrix_args_must_write_matrix_market() {
+        assert!(check_matrix_args(&strings(&["--mode", "pic", "--compress", "zstd"])).is_ok());
+        assert!(check_matrix_args(&strings(&["--format", "mtx"])).is_ok());
+        assert!(check_matrix_args(&strings(&["--format=mtx"])).is_ok());
+        assert!(check_matrix_args(&strings(&["--format", "h5"])).is_err());
+        assert!(check_matrix_args(&strings(&["--format=h5ad"])).is_err());
+        assert!(check_matrix_args(&strings(&["--shard-output", "4"])).is_err());
+    }
+
+    #[test]
+    fn finds_matrices_in_subdirectories() {
+        let dir = std::env::temp_dir().join(format!("fragtk_matrix_dirs_{}", std::process::id()));
+        for subdir in ["bins_5000", "peaks", "peaks/empty"] {
+            fs::create_dir_all(dir.join(subdir)).unwrap();
+        }
+        File::create(dir.join("bins_5000/matrix.mtx.zst")).unwrap();
+        File::create(dir.join("peaks/matrix.mtx.gz")).unwrap();
+        let found = matrix_dirs(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(found, vec![dir.join("bins_5000"), dir.join("peaks")]);
+    }
+}
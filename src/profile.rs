@@ -0,0 +1,95 @@
+use std::{
+    io,
+    fs::File,
+    path::Path,
+    io::Write,
+    time::{Duration, Instant},
+};
+use log::info;
+
+/// Stages of a matrix run timed with `--profile`
+#[derive(Clone, Copy, Debug)]
+pub enum Stage {
+    /// reading (and decompressing) fragment lines
+    Decompress,
+    /// splitting lines, looking up cells, parsing coordinates, and filters
+    Parse,
+    /// finding the features holding each fragment
+    Overlap,
+    /// adding counts to the matrices
+    Accumulate,
+    /// writing the matrices and other outputs
+    Write,
+}
+
+const STAGES: [(Stage, &str); 5] = [
+    (Stage::Decompress, "decompress"),
+    (Stage::Parse, "parse"),
+    (Stage::Overlap, "overlap"),
+    (Stage::Accumulate, "accumulate"),
+    (Stage::Write, "write"),
+];
+
+/// Time spent in each stage of a run. Each stage is timed from the previous mark, so timing adds
+/// a clock read per stage and fragment; a disabled profile never reads the clock.
+pub struct Profile {
+    enabled: bool,
+    started: Instant,
+    last: Instant,
+    stages: [Duration; 5],
+}
+
+impl Profile {
+    pub fn new(enabled: bool) -> Profile {
+        let now = Instant::now();
+        Profile { enabled, started: now, last: now, stages: [Duration::ZERO; 5] }
+    }
+
+    pub fn disabled() -> Profile {
+        Profile::new(false)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Start timing from now
+    #[inline]
+    pub fn mark(&mut self) {
+        if self.enabled {
+            self.last = Instant::now();
+        }
+    }
+
+    /// Add the time since the last mark to `stage`, and start timing the next stage
+    #[inline]
+    pub fn record(&mut self, stage: Stage) {
+        if self.enabled {
+            let now = Instant::now();
+            self.stages[stage as usize] += now - self.last;
+            self.last = now;
+        }
+    }
+
+    /// Write the time in each stage, and the rest of the run time, to a JSON summary
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let total = self.started.elapsed();
+        let timed: Duration = self.stages.iter().sum();
+        info!("Writing stage timings: {:?}", path);
+        let mut writer = io::BufWriter::new(File::create(path)?);
+        writeln!(writer, "{{")?;
+        writeln!(writer, "  \"software_version\": \"fragtk-{}\",", env!("CARGO_PKG_VERSION"))?;
+        writeln!(writer, "  \"profile\": {{")?;
+        writeln!(writer, "    \"total_seconds\": {:.3},", total.as_secs_f64())?;
+        for (stage, name) in STAGES {
+            let seconds = self.stages[stage as usize].as_secs_f64();
+            info!("{}: {:.3} s ({:.1}%)", name, seconds, 100.0 * seconds / total.as_secs_f64().max(1e-9));
+            writeln!(writer, "    \"{}_seconds\": {:.3},", name, seconds)?;
+        }
+        // loading features and cells, skipped lines, and other setup
+        writeln!(writer, "    \"other_seconds\": {:.3}", total.saturating_sub(timed).as_secs_f64())?;
+        writeln!(writer, "  }}")?;
+        writeln!(writer, "}}")?;
+        writer.flush()
+    }
+}
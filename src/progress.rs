@@ -0,0 +1,127 @@
+use std::{
+    fmt,
+    io,
+    io::Write,
+    sync::Arc,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Fragments between checks of the cancellation token
+const CHECK_INTERVAL: u64 = 4096;
+
+/// Fragments between progress reports
+const REPORT_INTERVAL: u64 = 1_000_000;
+
+/// Error returned by a call stopped through its `CancelToken`, wrapped in an `io::Error` of kind
+/// `Other`. `Interrupted` is not used, as std I/O helpers retry on it.
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Whether `error` is the `Cancelled` error of a stopped call
+pub fn is_cancelled(error: &io::Error) -> bool {
+    error.get_ref().is_some_and(|inner| inner.is::<Cancelled>())
+}
+
+/// Flag shared with a running call, to stop it from another thread
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    /// Ask calls holding this token to stop. They return a `Cancelled` error.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Called with the fragments read so far and the total, if known
+type Callback = Arc<dyn Fn(u64, Option<u64>) + Send + Sync>;
+
+/// Progress reporting and cancellation for calls that read a fragment file, so applications embedding
+/// the library can show progress and stop a call without ending the process. The default writes a
+/// fragment counter to stderr, as the command-line tool does, and cannot be cancelled.
+#[derive(Clone, Default)]
+pub struct Progress {
+    cancel: Option<CancelToken>,
+    callback: Option<Callback>,
+}
+
+impl Progress {
+    pub fn new() -> Progress {
+        Progress::default()
+    }
+
+    /// Stop when `token` is cancelled
+    pub fn with_cancel(mut self, token: CancelToken) -> Progress {
+        self.cancel = Some(token);
+        self
+    }
+
+    /// Report progress to `callback` instead of stderr. It is called every million fragments and
+    /// once at the end, on the thread doing the work.
+    pub fn with_callback<F>(mut self, callback: F) -> Progress
+    where
+        F: Fn(u64, Option<u64>) + Send + Sync + 'static,
+    {
+        self.callback = Some(Arc::new(callback));
+        self
+    }
+
+    /// Returns a `Cancelled` error if the token has been cancelled, for calls between units of
+    /// work other than fragments
+    pub fn check(&self) -> io::Result<()> {
+        match self.cancel.as_ref().is_some_and(|token| token.is_cancelled()) {
+            true => Err(io::Error::other(Cancelled)),
+            false => Ok(()),
+        }
+    }
+
+    /// Record that `fragments` have been read, out of `total` if known.
+    /// Returns a `Cancelled` error once cancelled.
+    pub fn update(&self, fragments: u64, total: Option<u64>) -> io::Result<()> {
+        if !fragments.is_multiple_of(CHECK_INTERVAL) && !fragments.is_multiple_of(REPORT_INTERVAL) {
+            return Ok(());
+        }
+        if let Err(e) = self.check() {
+            if self.callback.is_none() {
+                eprintln!();
+            }
+            return Err(e);
+        }
+        if fragments.is_multiple_of(REPORT_INTERVAL) {
+            match (&self.callback, total) {
+                (Some(callback), _) => callback(fragments, total),
+                (None, Some(total)) => eprint!(
+                    "\rProcessed {} M fragments ({:.0}%)",
+                    fragments / 1_000_000, 100.0 * fragments as f64 / total.max(1) as f64,
+                ),
+                (None, None) => eprint!("\rProcessed {} M fragments", fragments / 1_000_000),
+            }
+            io::stderr().flush()?;
+        }
+        Ok(())
+    }
+
+    /// Record the end of the input, after `fragments` were read
+    pub fn finish(&self, fragments: u64, total: Option<u64>) {
+        match &self.callback {
+            Some(callback) => callback(fragments, total),
+            None => eprintln!(),
+        }
+    }
+}
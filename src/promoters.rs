@@ -0,0 +1,92 @@
+use std::{
+    io,
+    path::Path,
+    io::Write,
+};
+use gzp::ZWriter;
+use log::info;
+use rust_lapper::{Interval, Lapper};
+use rustc_hash::{FxHashMap, FxHashSet};
+use crate::bed::{read_regions, RegionTrees};
+use crate::matrix::{self, FeaturesFormat};
+
+/// Promoter window around each TSS, from a spec such as `2000,500` (bp upstream, bp downstream)
+#[derive(Clone, Copy, Debug)]
+pub struct PromoterWindow {
+    pub upstream: u32,
+    pub downstream: u32,
+}
+
+impl PromoterWindow {
+    pub fn parse(spec: &str) -> io::Result<PromoterWindow> {
+        let window = spec
+            .split_once(',')
+            .and_then(|(upstream, downstream)| Some((upstream.trim().parse().ok()?, downstream.trim().parse().ok()?)));
+        match window {
+            Some((upstream, downstream)) if upstream as u64 + downstream as u64 > 0 => {
+                Ok(PromoterWindow { upstream, downstream })
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid promoter window: {}, expected bp upstream and downstream of the TSS, e.g. 2000,500", spec),
+            )),
+        }
+    }
+
+    /// Half-open promoter coordinates of a TSS (0-based), upstream of it by the strand and
+    /// clipped at zero. As with GenomicRanges `promoters()`, the TSS is the first downstream base.
+    pub fn apply(&self, tss: u32, strand: char) -> (u32, u32) {
+        if strand == '-' {
+            let end = tss.saturating_add(1);
+            (end.saturating_sub(self.downstream), end.saturating_add(self.upstream))
+        } else {
+            (tss.saturating_sub(self.upstream), tss.saturating_add(self.downstream))
+        }
+    }
+}
+
+/// Build a promoter window around each TSS of a BED file of transcripts or TSSs, into interval
+/// trees for each chromosome. The TSS is the region start, or the end for minus-strand regions
+/// (column 6). Transcripts sharing a TSS give one promoter, named after the first of them.
+/// Writes the promoter regions and names to `outfile`. Returns the number of promoters and the trees.
+pub fn promoter_intervals(
+    tss_file: &Path,
+    window: &PromoterWindow,
+    outfile: &Path,
+    format: FeaturesFormat,
+    num_threads: usize,
+) -> io::Result<(usize, RegionTrees)> {
+    let regions = read_regions(tss_file)?;
+
+    let mut writer = matrix::compressed_writer(outfile, num_threads)?;
+    let mut intervals: FxHashMap<String, Vec<Interval<u32, usize>>> = FxHashMap::default();
+    let mut seen: FxHashSet<(&str, u32, u32)> = FxHashSet::default();
+    let mut total: usize = 0;
+    for region in &regions {
+        let tss = if region.strand == '-' { region.end.saturating_sub(1) } else { region.start };
+        let (start, end) = window.apply(tss, region.strand);
+        if !seen.insert((&region.chrom, start, end)) {
+            continue;
+        }
+        let id = format!("{}-{}-{}", region.chrom, start, end);
+        let name = if region.name == "." { &id } else { &region.name };
+        match format {
+            FeaturesFormat::Names => writeln!(writer, "{}\t{}", id, name)?,
+            FeaturesFormat::Bed => writeln!(writer, "{}\t{}\t{}\t{}", region.chrom, start, end, name)?,
+            FeaturesFormat::TenX => writeln!(writer, "{}\t{}\tPromoters", id, name)?,
+        }
+        intervals
+            .entry(region.chrom.clone())
+            .or_default()
+            .push(Interval { start, stop: end, val: total });
+        total += 1;
+    }
+    writer.finish().map_err(io::Error::other)?;
+    info!("Built {} promoters from {} TSSs", total, regions.len());
+
+    let trees = intervals
+        .into_iter()
+        .map(|(chrom, intervals)| (chrom, Lapper::new(intervals)))
+        .collect();
+    Ok((total, trees))
+}
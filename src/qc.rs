@@ -0,0 +1,167 @@
+use std::{
+    io,
+    fs::File,
+    path::Path,
+    io::Write,
+};
+use log::info;
+use rust_lapper::Lapper;
+use rustc_hash::{FxHashMap, FxHashSet};
+use crate::cellindex::CellIndex;
+use crate::f2m::PeakTrees;
+use crate::fragments::parse_fragment;
+use crate::output::PartialOutput;
+use crate::stats::{FragmentStats, StatsCollector};
+
+/// Fragments shorter than this are nucleosome-free
+pub const NUCLEOSOME_FREE: u32 = 147;
+
+/// Fragments shorter than this (and at least `NUCLEOSOME_FREE`) span one nucleosome
+pub const MONONUCLEOSOME: u32 = 294;
+
+/// Fragments shorter than this (and at least `MONONUCLEOSOME`) span two nucleosomes
+pub const DINUCLEOSOME: u32 = 441;
+
+/// QC metrics for one cell
+#[derive(Clone, Default)]
+struct CellQc {
+    fragments: u64,
+    /// fragments with an insertion in a peak
+    peak_fragments: u64,
+    nucleosome_free: u64,
+    mononucleosome: u64,
+}
+
+/// File and cell QC collected during the matrix counting pass with `--all-outputs`,
+/// so they need not be computed by separate passes over the fragments
+pub struct QcCollector<'a> {
+    enabled: bool,
+    stats: StatsCollector,
+    barcodes: FxHashSet<String>,
+    peaks: Option<&'a PeakTrees>,
+    current_chrom: String,
+    current_peaks: Option<&'a Lapper<u32, usize>>,
+    /// cell fragments per chromosome
+    cell_chroms: FxHashMap<String, u64>,
+    cells: Vec<CellQc>,
+}
+
+impl<'a> QcCollector<'a> {
+    /// A collector that ignores fragments
+    pub fn disabled() -> QcCollector<'a> {
+        QcCollector {
+            enabled: false,
+            stats: StatsCollector::default(),
+            barcodes: FxHashSet::default(),
+            peaks: None,
+            current_chrom: String::new(),
+            current_peaks: None,
+            cell_chroms: FxHashMap::default(),
+            cells: Vec::new(),
+        }
+    }
+
+    /// Collect QC for `n_cells` cells, with the fraction of fragments in `peaks` if given
+    pub fn new(n_cells: usize, peaks: Option<&'a PeakTrees>) -> QcCollector<'a> {
+        QcCollector { enabled: true, peaks, cells: vec![CellQc::default(); n_cells], ..QcCollector::disabled() }
+    }
+
+    /// Add a fragment line, before any subsampling or filtering. Malformed lines are ignored.
+    pub fn add(&mut self, line: &str, cells: &CellIndex) {
+        if !self.enabled {
+            return;
+        }
+        let fragment = match parse_fragment(line) {
+            Some(fragment) => fragment,
+            None => return,
+        };
+        self.stats.add(&fragment);
+        if !self.barcodes.contains(fragment.barcode) {
+            self.barcodes.insert(fragment.barcode.to_string());
+        }
+
+        let cell = match cells.get(fragment.barcode) {
+            Some(cell) => cell as usize,
+            None => return,
+        };
+        if fragment.chrom != self.current_chrom {
+            self.current_chrom = fragment.chrom.to_string();
+            self.current_peaks = self.peaks.and_then(|peaks| peaks.get(fragment.chrom));
+        }
+        *self.cell_chroms.entry(self.current_chrom.clone()).or_insert(0) += 1;
+
+        let qc = &mut self.cells[cell];
+        qc.fragments += 1;
+        let size = fragment.end.saturating_sub(fragment.start);
+        if size < NUCLEOSOME_FREE {
+            qc.nucleosome_free += 1;
+        } else if size < MONONUCLEOSOME {
+            qc.mononucleosome += 1;
+        }
+        if let Some(lapper) = self.current_peaks {
+            let in_peak = |pos: u32| lapper.find(pos, pos + 1).next().is_some();
+            if in_peak(fragment.start) || in_peak(fragment.end) {
+                qc.peak_fragments += 1;
+            }
+        }
+    }
+
+    /// Write `qc.tsv`, `fragment_sizes.tsv`, and `chrom_stats.tsv` to `output`,
+    /// and save the fragment statistics next to the fragment file for `fragtk stats`
+    pub fn write(self, output: &Path, frag_file: &Path, cells: &CellIndex, partial: &mut PartialOutput) -> io::Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        let stats = self.stats.finish(self.barcodes.len() as u64);
+
+        let path = partial.file(output.join("qc.tsv"));
+        info!("Writing cell QC: {:?}", path);
+        let mut writer = io::BufWriter::new(File::create(path)?);
+        let with_peaks = self.peaks.is_some();
+        write!(writer, "barcode\tfragments\tnucleosome_free_fraction\tmononucleosome_fraction")?;
+        if with_peaks {
+            write!(writer, "\tpeak_fragments\tfrip")?;
+        }
+        writeln!(writer)?;
+        for (cell, qc) in self.cells.iter().enumerate() {
+            let fraction = |x: u64| x as f64 / qc.fragments.max(1) as f64;
+            write!(
+                writer, "{}\t{}\t{:.4}\t{:.4}",
+                cells.barcode(cell), qc.fragments, fraction(qc.nucleosome_free), fraction(qc.mononucleosome),
+            )?;
+            if with_peaks {
+                write!(writer, "\t{}\t{:.4}", qc.peak_fragments, fraction(qc.peak_fragments))?;
+            }
+            writeln!(writer)?;
+        }
+        writer.flush()?;
+
+        let path = partial.file(output.join("fragment_sizes.tsv"));
+        info!("Writing fragment size histogram: {:?}", path);
+        let mut writer = io::BufWriter::new(File::create(path)?);
+        writeln!(writer, "size\tfragments")?;
+        for (size, count) in &stats.sizes {
+            writeln!(writer, "{}\t{}", size, count)?;
+        }
+        writer.flush()?;
+
+        let path = partial.file(output.join("chrom_stats.tsv"));
+        info!("Writing chromosome statistics: {:?}", path);
+        let mut writer = io::BufWriter::new(File::create(path)?);
+        writeln!(writer, "chrom\tfragments\tcell_fragments")?;
+        for (chrom, count) in &stats.chroms {
+            writeln!(writer, "{}\t{}\t{}", chrom, count, self.cell_chroms.get(chrom).unwrap_or(&0))?;
+        }
+        writer.flush()?;
+
+        save_stats(&stats, frag_file);
+        Ok(())
+    }
+}
+
+/// Cache the statistics unless the sidecar is already current
+fn save_stats(stats: &FragmentStats, frag_file: &Path) {
+    if !matches!(FragmentStats::load(frag_file), Ok(Some(_))) {
+        stats.try_save(frag_file);
+    }
+}
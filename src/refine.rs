@@ -0,0 +1,196 @@
+use std::{
+    io,
+    fs::File,
+    path::Path,
+    error::Error,
+    io::BufRead,
+    io::Write,
+};
+use log::{info, warn};
+use rust_lapper::{Interval, Lapper};
+use rustc_hash::{FxHashMap, FxHashSet};
+use crate::bed::PeakFormat;
+use crate::cells::load_cells;
+use crate::fragments::{for_each_fragment_with_progress, ReadOptions};
+use crate::matrix::open_text;
+use crate::progress::Progress;
+
+/// A peak and its BED columns after the coordinates
+struct Peak {
+    chrom: String,
+    start: u32,
+    end: u32,
+    rest: Vec<String>,
+}
+
+/// Recenter each peak on the summit of its insertion density, optionally resizing it to a fixed width,
+/// to give a refined peak set for a second counting pass
+pub fn run(matches: &clap::ArgMatches, progress: &Progress) -> Result<(), Box<dyn Error>> {
+
+    let frag_file = Path::new(matches.get_one::<String>("fragments").unwrap());
+    info!("Received fragment file: {:?}", frag_file);
+    let read_options = ReadOptions::from_matches(matches)?;
+
+    let bed_file = Path::new(matches.get_one::<String>("bed").unwrap());
+    info!("Received BED file: {:?}", bed_file);
+
+    let output = Path::new(matches.get_one::<String>("outfile").unwrap());
+    let width = matches.get_one::<u32>("width").copied();
+    let window = *matches.get_one::<u32>("smooth_window").unwrap();
+    let cells = load_cells(matches)?;
+    let narrowpeak = PeakFormat::detect(bed_file) == PeakFormat::NarrowPeak;
+
+    let peaks = read_peaks(bed_file)?;
+    if peaks.is_empty() {
+        return Err(format!("No peaks in {:?}", bed_file).into());
+    }
+    info!("Loaded {} peaks", peaks.len());
+
+    let summits = find_summits(frag_file, &peaks, cells.as_ref(), window, &read_options, progress)?;
+    let unrefined = summits.iter().filter(|x| x.is_none()).count();
+    if unrefined > 0 {
+        warn!("{} peaks have no insertions and keep their center", unrefined);
+    }
+
+    info!("Writing refined peaks: {:?}", output);
+    let mut writer = io::BufWriter::new(File::create(output)?);
+    for (peak, summit) in peaks.iter().zip(summits.iter()) {
+        let summit = summit.unwrap_or((peak.start + peak.end) / 2);
+        let size = width.unwrap_or(peak.end - peak.start);
+        let start = summit.saturating_sub(size / 2);
+        write!(writer, "{}\t{}\t{}", peak.chrom, start, start + size)?;
+        for (i, field) in peak.rest.iter().enumerate() {
+            // narrowPeak summit offset (column 10)
+            if narrowpeak && i == 6 {
+                write!(writer, "\t{}", summit - start)?;
+            } else {
+                write!(writer, "\t{}", field)?;
+            }
+        }
+        writeln!(writer)?;
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Read peaks from a BED file, optionally gzipped, keeping their other columns
+fn read_peaks(bed_file: &Path) -> io::Result<Vec<Peak>> {
+    let mut peaks = Vec::new();
+    for (index, line) in open_text(bed_file)?.lines().enumerate() {
+        let line = line?;
+        if line.starts_with('#') || line.starts_with("track") || line.starts_with("browser") || line.is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 3 {
+            warn!("Line {}: Less than three fields", index + 1);
+            continue;
+        }
+        match (fields[1].parse::<u32>(), fields[2].parse::<u32>()) {
+            (Ok(start), Ok(end)) if end > start => peaks.push(Peak {
+                chrom: fields[0].to_string(),
+                start,
+                end,
+                rest: fields[3..].iter().map(|x| x.to_string()).collect(),
+            }),
+            _ => warn!("Line {}: Invalid peak coordinates", index + 1),
+        }
+    }
+    Ok(peaks)
+}
+
+/// Position of the highest insertion density in each peak, with insertions summed over a window
+/// of `window` bp, or None if the peak has no insertions. Ties are broken toward the peak center.
+/// Insertions are counted one chromosome at a time, so the fragment file must be sorted by chromosome.
+fn find_summits(
+    frag_file: &Path,
+    peaks: &[Peak],
+    cells: Option<&FxHashSet<String>>,
+    window: u32,
+    read_options: &ReadOptions,
+    progress: &Progress,
+) -> io::Result<Vec<Option<u32>>> {
+    let mut intervals: FxHashMap<String, Vec<Interval<u32, usize>>> = FxHashMap::default();
+    for (index, peak) in peaks.iter().enumerate() {
+        intervals
+            .entry(peak.chrom.clone())
+            .or_default()
+            .push(Interval { start: peak.start, stop: peak.end, val: index });
+    }
+    let trees: FxHashMap<String, Lapper<u32, usize>> = intervals
+        .into_iter()
+        .map(|(chrom, intervals)| (chrom, Lapper::new(intervals)))
+        .collect();
+
+    let mut summits: Vec<Option<u32>> = vec![None; peaks.len()];
+    // insertions at each position of the peaks on the current chromosome
+    let mut counts: FxHashMap<usize, Vec<u32>> = FxHashMap::default();
+    let mut current_chrom = String::new();
+    let mut current_tree: Option<&Lapper<u32, usize>> = None;
+    let mut finished_chroms: FxHashSet<String> = FxHashSet::default();
+
+    let mut finish_chrom = |counts: &mut FxHashMap<usize, Vec<u32>>| {
+        for (index, peak_counts) in counts.drain() {
+            summits[index] = summit(&peak_counts, window).map(|offset| peaks[index].start + offset as u32);
+        }
+    };
+
+    for_each_fragment_with_progress(frag_file, read_options, progress, |fragment| {
+        if cells.is_some_and(|cells| !cells.contains(fragment.barcode)) {
+            return Ok(());
+        }
+        if fragment.chrom != current_chrom {
+            finish_chrom(&mut counts);
+            if !finished_chroms.insert(fragment.chrom.to_string()) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Fragment file is not sorted by chromosome ({} seen twice)", fragment.chrom),
+                ));
+            }
+            current_chrom = fragment.chrom.to_string();
+            current_tree = trees.get(fragment.chrom);
+        }
+        if let Some(tree) = current_tree {
+            for pos in [fragment.start, fragment.end] {
+                for interval in tree.find(pos, pos + 1) {
+                    let peak_counts = counts
+                        .entry(interval.val)
+                        .or_insert_with(|| vec![0; (interval.stop - interval.start) as usize]);
+                    peak_counts[(pos - interval.start) as usize] += 1;
+                }
+            }
+        }
+        Ok(())
+    })?;
+    finish_chrom(&mut counts);
+
+    Ok(summits)
+}
+
+/// Offset of the maximum of the insertion counts summed over a centered window
+fn summit(counts: &[u32], window: u32) -> Option<usize> {
+    let half = (window / 2) as usize;
+    let mut prefix: Vec<u64> = Vec::with_capacity(counts.len() + 1);
+    prefix.push(0);
+    for count in counts {
+        prefix.push(prefix.last().unwrap() + *count as u64);
+    }
+    if *prefix.last().unwrap() == 0 {
+        return None;
+    }
+
+    let center = counts.len() / 2;
+    let mut best: Option<(u64, usize)> = None;
+    for i in 0..counts.len() {
+        let density = prefix[(i + half + 1).min(counts.len())] - prefix[i.saturating_sub(half)];
+        let better = match best {
+            None => true,
+            Some((max, pos)) => density > max || (density == max && i.abs_diff(center) < pos.abs_diff(center)),
+        };
+        if better {
+            best = Some((density, i));
+        }
+    }
+    best.map(|(_, pos)| pos)
+}
@@ -0,0 +1,98 @@
+use std::{
+    io,
+    fs::File,
+    path::Path,
+    io::Write,
+};
+use gzp::ZWriter;
+use log::{info, warn};
+use rustc_hash::FxHashMap;
+use crate::matrix::{compressed_writer, json_string, mtx_header, MatrixValue, OutputCompression};
+use crate::output::PartialOutput;
+
+/// Write a feature x column matrix as column shards in `dir`, each a `shard_<i>` subdirectory
+/// holding `matrix.mtx.gz` (or `.zst`) and `barcodes.tsv` for a contiguous range of columns.
+/// `manifest.json` lists the shards, their column ranges, and the shared features file,
+/// so the shards can be loaded in parallel by distributed readers.
+pub fn write_shards<T: MatrixValue>(
+    dir: &Path,
+    counts: &[FxHashMap<u32, T>],
+    column_names: &[&str],
+    n_shards: usize,
+    compression: OutputCompression,
+    partial: &mut PartialOutput,
+    num_threads: usize,
+) -> io::Result<()> {
+    let ncol = column_names.len();
+    let n_shards = if n_shards > ncol.max(1) {
+        warn!("Only {} columns, writing {} shards instead of {}", ncol, ncol.max(1), n_shards);
+        ncol.max(1)
+    } else {
+        n_shards
+    };
+
+    // contiguous column ranges of equal size
+    let bounds: Vec<usize> = (0..=n_shards).map(|i| i * ncol / n_shards).collect();
+    let shard_of = |column: u32| bounds[1..].partition_point(|end| *end <= column as usize);
+
+    let mut nnz: Vec<usize> = vec![0; n_shards];
+    for map in counts {
+        for column in map.keys() {
+            nnz[shard_of(*column)] += 1;
+        }
+    }
+
+    let mut entries: Vec<String> = Vec::with_capacity(n_shards);
+    for shard in 0..n_shards {
+        let (start, end) = (bounds[shard], bounds[shard + 1]);
+        let name = format!("shard_{}", shard);
+        let shard_dir = dir.join(&name);
+        partial.create_dir(&shard_dir)?;
+
+        let matrix_path = partial.file(shard_dir.join(compression.file_name("matrix.mtx")));
+        info!("Writing shard {} of {}: {:?}", shard + 1, n_shards, matrix_path);
+        let mut encoder = compressed_writer(&matrix_path, num_threads)?;
+        encoder.write_all(mtx_header(T::FIELD, counts.len(), end - start, nnz[shard]).as_bytes())?;
+        let mut output = String::new();
+        for (row, map) in counts.iter().enumerate() {
+            for (column, value) in map {
+                let column = *column as usize;
+                if column >= start && column < end {
+                    output.push_str(&value.entry(row + 1, column - start + 1));
+                }
+            }
+            if output.len() > 1_000_000 {
+                encoder.write_all(output.as_bytes())?;
+                output.clear();
+            }
+        }
+        encoder.write_all(output.as_bytes())?;
+        encoder.finish().map_err(io::Error::other)?;
+
+        let mut writer = io::BufWriter::new(File::create(partial.file(shard_dir.join("barcodes.tsv")))?);
+        for barcode in &column_names[start..end] {
+            writeln!(writer, "{}", barcode)?;
+        }
+        writer.flush()?;
+
+        entries.push(format!(
+            "    {{\"matrix\": {}, \"barcodes\": {}, \"column_start\": {}, \"column_end\": {}, \"nnz\": {}}}",
+            json_string(&format!("{}/{}", name, compression.file_name("matrix.mtx"))),
+            json_string(&format!("{}/barcodes.tsv", name)),
+            start, end, nnz[shard],
+        ));
+    }
+
+    let manifest_path = partial.file(dir.join("manifest.json"));
+    info!("Writing shard manifest: {:?}", manifest_path);
+    let mut writer = io::BufWriter::new(File::create(manifest_path)?);
+    writeln!(writer, "{{")?;
+    writeln!(writer, "  \"format\": \"mtx\",")?;
+    writeln!(writer, "  \"software_version\": \"fragtk-{}\",", env!("CARGO_PKG_VERSION"))?;
+    writeln!(writer, "  \"features\": {},", json_string(&compression.file_name("features.tsv")))?;
+    writeln!(writer, "  \"shape\": [{}, {}],", counts.len(), ncol)?;
+    writeln!(writer, "  \"nnz\": {},", nnz.iter().sum::<usize>())?;
+    writeln!(writer, "  \"shards\": [\n{}\n  ]", entries.join(",\n"))?;
+    writeln!(writer, "}}")?;
+    writer.flush()
+}
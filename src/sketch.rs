@@ -0,0 +1,179 @@
+use std::{
+    io,
+    fs::File,
+    path::Path,
+    error::Error,
+    io::Write,
+    collections::BTreeSet,
+};
+use log::{info, warn};
+use rayon::prelude::*;
+use rustc_hash::FxHashMap;
+use crate::cells::cells_from_matches;
+use crate::fragments::{for_each_fragment_with_progress, ReadOptions};
+use crate::progress::Progress;
+
+/// Bottom-k MinHash sketch of the insertion positions of one cell
+#[derive(Clone, Default)]
+struct CellSketch {
+    fragments: u64,
+    /// the smallest distinct position hashes, at most the sketch size
+    hashes: BTreeSet<u64>,
+}
+
+impl CellSketch {
+    fn insert(&mut self, hash: u64, size: usize) {
+        if self.hashes.len() == size && self.hashes.last().is_some_and(|last| hash >= *last) {
+            return;
+        }
+        if self.hashes.insert(hash) && self.hashes.len() > size {
+            self.hashes.pop_last();
+        }
+    }
+}
+
+/// Write per-cell MinHash sketches of insertion positions and the pairwise Jaccard similarity of
+/// cells, to find duplicate barcodes and sample swaps without a peak set
+pub fn run(matches: &clap::ArgMatches, progress: &Progress) -> Result<(), Box<dyn Error>> {
+
+    let frag_file = Path::new(matches.get_one::<String>("fragments").unwrap());
+    info!("Received fragment file: {:?}", frag_file);
+    let read_options = ReadOptions::from_matches(matches)?;
+
+    let output = Path::new(matches.get_one::<String>("outfile").unwrap());
+    let min_fragments = *matches.get_one::<u64>("min_fragments").unwrap();
+    let size = *matches.get_one::<usize>("sketch_size").unwrap();
+    let bin_size = *matches.get_one::<u32>("bin_size").unwrap();
+    let min_similarity = *matches.get_one::<f64>("min_similarity").unwrap();
+    if size < 2 || bin_size == 0 {
+        return Err("--sketch-size must be at least 2 and --bin-size at least 1".into());
+    }
+
+    // listed cells in order, or every barcode in order of first appearance
+    let listed = cells_from_matches(matches)?;
+    let mut barcodes: Vec<String> = listed.clone().unwrap_or_default();
+    let mut index: FxHashMap<String, usize> = barcodes.iter().enumerate().map(|(i, x)| (x.clone(), i)).collect();
+    let mut sketches: Vec<CellSketch> = vec![CellSketch::default(); barcodes.len()];
+
+    let mut current_chrom = String::new();
+    let mut chrom_hash: u64 = 0;
+    for_each_fragment_with_progress(frag_file, &read_options, progress, |fragment| {
+        let cell = match index.get(fragment.barcode) {
+            Some(cell) => *cell,
+            None if listed.is_some() => return Ok(()),
+            None => {
+                index.insert(fragment.barcode.to_string(), barcodes.len());
+                barcodes.push(fragment.barcode.to_string());
+                sketches.push(CellSketch::default());
+                barcodes.len() - 1
+            }
+        };
+        if fragment.chrom != current_chrom {
+            current_chrom = fragment.chrom.to_string();
+            chrom_hash = fnv1a(current_chrom.as_bytes());
+        }
+        let sketch = &mut sketches[cell];
+        sketch.fragments += 1;
+        for pos in [fragment.start, fragment.end] {
+            sketch.insert(mix(chrom_hash ^ (pos / bin_size) as u64), size);
+        }
+        Ok(())
+    })?;
+
+    // every listed cell is compared, other barcodes only with enough fragments
+    let cells: Vec<usize> = (0..barcodes.len())
+        .filter(|cell| listed.is_some() || sketches[*cell].fragments >= min_fragments)
+        .collect();
+    let empty = cells.iter().filter(|cell| sketches[**cell].fragments == 0).count();
+    if empty > 0 {
+        warn!("{} cells have no fragments", empty);
+    }
+    let sorted: Vec<Vec<u64>> = cells.iter().map(|cell| sketches[*cell].hashes.iter().copied().collect()).collect();
+    let distinct: Vec<f64> = sorted.iter().map(|hashes| distinct_estimate(hashes, size)).collect();
+
+    if let Some(path) = matches.get_one::<String>("cell_summary") {
+        info!("Writing cell sketch summary: {:?}", path);
+        let mut writer = io::BufWriter::new(File::create(path)?);
+        writeln!(writer, "barcode\tfragments\tinsertion_sites")?;
+        for (cell, estimate) in cells.iter().zip(distinct.iter()) {
+            writeln!(writer, "{}\t{}\t{:.0}", barcodes[*cell], sketches[*cell].fragments, estimate)?;
+        }
+        writer.flush()?;
+    }
+
+    info!("Comparing {} cells with sketches of {} positions", cells.len(), size);
+    let pairs: Vec<Vec<(usize, f64)>> = (0..sorted.len())
+        .into_par_iter()
+        .map(|i| {
+            ((i + 1)..sorted.len())
+                .map(|j| (j, jaccard(&sorted[i], &sorted[j], size)))
+                .filter(|(_, similarity)| *similarity >= min_similarity)
+                .collect()
+        })
+        .collect();
+
+    info!("Writing cell similarities: {:?}", output);
+    let mut writer = io::BufWriter::new(File::create(output)?);
+    writeln!(writer, "barcode1\tbarcode2\tjaccard\tcontainment")?;
+    let mut written: usize = 0;
+    for (i, row) in pairs.iter().enumerate() {
+        for (j, similarity) in row {
+            // share of the smaller cell's insertion sites also in the other cell
+            let shared = similarity * (distinct[i] + distinct[*j]) / (1.0 + similarity);
+            let containment = (shared / distinct[i].min(distinct[*j]).max(1.0)).min(1.0);
+            writeln!(
+                writer, "{}\t{}\t{:.4}\t{:.4}",
+                barcodes[cells[i]], barcodes[cells[*j]], similarity, containment,
+            )?;
+            written += 1;
+        }
+    }
+    writer.flush()?;
+    info!("Wrote {} cell pairs with Jaccard similarity of at least {}", written, min_similarity);
+
+    Ok(())
+}
+
+/// FNV-1a hash of a chromosome name, the same on any platform
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
+
+/// splitmix64 finalizer, spreading position hashes evenly over the 64-bit range
+pub fn mix(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// Number of distinct positions in a cell: exact when the sketch is not full, otherwise
+/// estimated from the largest hash kept (the k-minimum values estimator)
+fn distinct_estimate(hashes: &[u64], size: usize) -> f64 {
+    match hashes.last() {
+        Some(last) if hashes.len() == size => (size - 1) as f64 * (u64::MAX as f64 / *last as f64),
+        _ => hashes.len() as f64,
+    }
+}
+
+/// Jaccard similarity of two cells, estimated as the fraction of the smallest hashes of their union
+/// that are in both sketches
+fn jaccard(a: &[u64], b: &[u64], size: usize) -> f64 {
+    let (mut i, mut j) = (0, 0);
+    let mut union: usize = 0;
+    let mut shared: usize = 0;
+    while union < size && (i < a.len() || j < b.len()) {
+        match (a.get(i), b.get(j)) {
+            (Some(x), Some(y)) if x == y => {
+                shared += 1;
+                i += 1;
+                j += 1;
+            }
+            (Some(x), Some(y)) if x < y => i += 1,
+            (Some(_), None) => i += 1,
+            _ => j += 1,
+        }
+        union += 1;
+    }
+    if union == 0 { 0.0 } else { shared as f64 / union as f64 }
+}
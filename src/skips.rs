@@ -0,0 +1,106 @@
+use std::{
+    io,
+    path::Path,
+    io::Write,
+};
+use gzp::ZWriter;
+use log::info;
+use crate::matrix::{compressed_writer, CompressedWriter};
+
+/// Why a fragment line was not counted
+#[derive(Clone, Copy)]
+pub enum SkipReason {
+    /// fewer than four fields
+    Malformed,
+    InvalidStart,
+    InvalidEnd,
+    /// no features on the fragment's chromosome
+    UnknownChrom,
+    /// left out by --subsample-fraction
+    Subsampled,
+    /// outside the GC range, or GC content unavailable
+    GcFilter,
+    /// missing or invalid duplicate count with --weight-by-duplicates
+    InvalidCount,
+    /// outside --min-length and --max-length
+    LengthFilter,
+    /// not valid UTF-8, e.g. barcodes from a legacy encoding
+    InvalidUtf8,
+    /// no + or - strand in column 6 with --strandedness
+    MissingStrand,
+}
+
+const REASONS: [SkipReason; 10] = [
+    SkipReason::Malformed,
+    SkipReason::InvalidStart,
+    SkipReason::InvalidEnd,
+    SkipReason::UnknownChrom,
+    SkipReason::Subsampled,
+    SkipReason::GcFilter,
+    SkipReason::InvalidCount,
+    SkipReason::LengthFilter,
+    SkipReason::InvalidUtf8,
+    SkipReason::MissingStrand,
+];
+
+impl SkipReason {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SkipReason::Malformed => "malformed",
+            SkipReason::InvalidStart => "invalid_start",
+            SkipReason::InvalidEnd => "invalid_end",
+            SkipReason::UnknownChrom => "unknown_chromosome",
+            SkipReason::Subsampled => "subsampled",
+            SkipReason::GcFilter => "gc_filter",
+            SkipReason::InvalidCount => "invalid_count",
+            SkipReason::LengthFilter => "length_filter",
+            SkipReason::InvalidUtf8 => "invalid_utf8",
+            SkipReason::MissingStrand => "missing_strand",
+        }
+    }
+}
+
+/// Counts of skipped fragment lines by reason, optionally writing each line
+/// to a `skipped.tsv.gz` file for troubleshooting with `--debug-skips`
+#[derive(Default)]
+pub struct SkipLog {
+    writer: Option<CompressedWriter>,
+    counts: [u64; REASONS.len()],
+}
+
+impl SkipLog {
+    /// Count skipped lines without writing them
+    pub fn new() -> SkipLog {
+        SkipLog::default()
+    }
+
+    /// Write skipped lines to a gzip-compressed table of line number, reason, and raw line
+    pub fn create(path: &Path, num_threads: usize) -> io::Result<SkipLog> {
+        info!("Writing skipped fragments: {:?}", path);
+        let mut writer = compressed_writer(path, num_threads)?;
+        writer.write_all(b"line\treason\tfragment\n")?;
+        Ok(SkipLog { writer: Some(writer), ..SkipLog::default() })
+    }
+
+    pub fn record(&mut self, line_number: u64, reason: SkipReason, line: &str) -> io::Result<()> {
+        self.counts[reason as usize] += 1;
+        if let Some(writer) = self.writer.as_mut() {
+            writeln!(writer, "{}\t{}\t{}", line_number, reason.name(), line)?;
+        }
+        Ok(())
+    }
+
+    /// Report the number of skipped lines for each reason and close the file
+    pub fn finish(self) -> io::Result<()> {
+        for reason in REASONS {
+            let count = self.counts[reason as usize];
+            if count > 0 {
+                info!("Skipped {} fragments: {}", count, reason.name());
+            }
+        }
+        if let Some(mut writer) = self.writer {
+            writer.finish().map_err(io::Error::other)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,177 @@
+use std::{
+    io,
+    path::Path,
+    error::Error,
+    io::BufRead,
+    io::Write,
+};
+use gzp::ZWriter;
+use log::{info, warn};
+use rustc_hash::FxHashMap;
+use crate::convert::{self, MatrixFormat};
+use crate::matrix::{self, compressed_writer, mtx_header, open_text, MatrixValue, SparseMatrix};
+use crate::output::PartialOutput;
+use crate::threads::ThreadConfig;
+
+/// Average each cell's counts with those of its nearest neighbors, as a simple
+/// kNN smoothing before imputation or visualization
+pub fn run(matches: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+
+    let input = Path::new(matches.get_one::<String>("input").unwrap());
+    let format = match matches.get_one::<String>("from") {
+        Some(name) => MatrixFormat::from_name(name),
+        None => MatrixFormat::detect(input),
+    }
+    .ok_or_else(|| format!("Cannot detect matrix format for {:?}, please specify it", input))?;
+
+    let knn_file = Path::new(matches.get_one::<String>("knn").unwrap());
+    let output = Path::new(matches.get_one::<String>("outdir").unwrap());
+    let num_threads = ThreadConfig::from_matches(matches).compress;
+    matrix::check_output_dir(output, matches.get_flag("force"))?;
+
+    let matrix = convert::read_matrix(input, format)?;
+    info!(
+        "Read {} features x {} cells with {} nonzero entries",
+        matrix.nrow(), matrix.ncol(), matrix.nnz()
+    );
+
+    info!("Reading neighbor graph: {:?}", knn_file);
+    let neighbors = read_neighbors(knn_file, &matrix.barcodes, !matches.get_flag("exclude_self"))?;
+
+    let mut partial = PartialOutput::new();
+    partial.create_dir(output)?;
+    let counts_path = partial.file(output.join("matrix.mtx.gz"));
+    info!("Writing smoothed counts: {:?}", counts_path);
+    write_smoothed(&counts_path, &matrix, &neighbors, num_threads)?;
+    partial.file(output.join("features.tsv.gz"));
+    partial.file(output.join("barcodes.tsv"));
+    matrix::write_mtx_names(output, &matrix.features, &matrix.barcodes, num_threads)?;
+    partial.finish();
+
+    Ok(())
+}
+
+/// Read a tab-separated kNN edge list of cell barcode, neighbor barcode, and an optional weight
+/// (1 if not given). Returns the weighted neighbors of each matrix column, normalized to sum to one.
+fn read_neighbors(path: &Path, barcodes: &[String], include_self: bool) -> io::Result<Vec<Vec<(u32, f64)>>> {
+    let index: FxHashMap<&str, u32> = barcodes
+        .iter()
+        .enumerate()
+        .map(|(i, barcode)| (barcode.as_str(), i as u32))
+        .collect();
+    let mut neighbors: Vec<Vec<(u32, f64)>> = vec![Vec::new(); barcodes.len()];
+    if include_self {
+        for (col, cell_neighbors) in neighbors.iter_mut().enumerate() {
+            cell_neighbors.push((col as u32, 1.0));
+        }
+    }
+
+    let mut unknown: usize = 0;
+    for (line_number, line) in open_text(path)?.lines().enumerate() {
+        let line = line?;
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 2 {
+            warn!("Line {}: Expected cell barcode and neighbor barcode", line_number + 1);
+            continue;
+        }
+        let weight: f64 = match fields.get(2).map(|x| x.parse()) {
+            None => 1.0,
+            Some(Ok(weight)) if weight >= 0.0 => weight,
+            Some(_) => {
+                warn!("Line {}: Invalid neighbor weight", line_number + 1);
+                continue;
+            }
+        };
+        match (index.get(fields[0]), index.get(fields[1])) {
+            (Some(cell), Some(neighbor)) => neighbors[*cell as usize].push((*neighbor, weight)),
+            _ => unknown += 1,
+        }
+    }
+    if unknown > 0 {
+        warn!("{} neighbor pairs have barcodes not in the matrix", unknown);
+    }
+
+    let mut isolated: usize = 0;
+    for cell_neighbors in neighbors.iter_mut() {
+        let total: f64 = cell_neighbors.iter().map(|(_, weight)| weight).sum();
+        if total > 0.0 {
+            for (_, weight) in cell_neighbors.iter_mut() {
+                *weight /= total;
+            }
+        } else {
+            isolated += 1;
+        }
+    }
+    if isolated > 0 {
+        warn!("{} cells have no neighbors, their smoothed counts are empty", isolated);
+    }
+    Ok(neighbors)
+}
+
+/// Write the neighbor-averaged matrix one column at a time, so only the input
+/// matrix is held in memory. A first pass counts the nonzero entries for the header.
+fn write_smoothed(
+    outfile: &Path,
+    matrix: &SparseMatrix,
+    neighbors: &[Vec<(u32, f64)>],
+    num_threads: usize,
+) -> io::Result<()> {
+    let nrow = matrix.nrow();
+    let mut values: Vec<f64> = vec![0.0; nrow];
+    let mut seen: Vec<bool> = vec![false; nrow];
+    let mut rows: Vec<u32> = Vec::new();
+
+    // rows with a nonzero value in any neighbor, sorted
+    let mut column_rows = |col: usize, rows: &mut Vec<u32>| {
+        rows.clear();
+        for (neighbor, _) in &neighbors[col] {
+            let neighbor = *neighbor as usize;
+            for pos in matrix.indptr[neighbor] as usize..matrix.indptr[neighbor + 1] as usize {
+                let row = matrix.indices[pos];
+                if !seen[row as usize] {
+                    seen[row as usize] = true;
+                    rows.push(row);
+                }
+            }
+        }
+        for row in rows.iter() {
+            seen[*row as usize] = false;
+        }
+        rows.sort_unstable();
+    };
+
+    let mut nnz: usize = 0;
+    for col in 0..matrix.ncol() {
+        column_rows(col, &mut rows);
+        nnz += rows.len();
+    }
+
+    let mut encoder = compressed_writer(outfile, num_threads)?;
+    encoder.write_all(mtx_header(f64::FIELD, nrow, matrix.ncol(), nnz).as_bytes())?;
+    let mut output = String::new();
+    for (col, cell_neighbors) in neighbors.iter().enumerate() {
+        column_rows(col, &mut rows);
+        for (neighbor, weight) in cell_neighbors {
+            let neighbor = *neighbor as usize;
+            for pos in matrix.indptr[neighbor] as usize..matrix.indptr[neighbor + 1] as usize {
+                values[matrix.indices[pos] as usize] += weight * matrix.data[pos];
+            }
+        }
+        for row in &rows {
+            let row = *row as usize;
+            output.push_str(&format!("{} {} {}\n", row + 1, col + 1, values[row]));
+            values[row] = 0.0;
+        }
+        if output.len() > 1_000_000 {
+            encoder.write_all(output.as_bytes())?;
+            output.clear();
+        }
+    }
+    encoder.write_all(output.as_bytes())?;
+    encoder.finish().map_err(io::Error::other)?;
+
+    Ok(())
+}
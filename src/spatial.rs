@@ -0,0 +1,113 @@
+use std::{
+    io,
+    fs::File,
+    path::Path,
+    io::BufRead,
+    io::Write,
+};
+use log::{info, warn};
+use rustc_hash::FxHashSet;
+use crate::cellindex::CellIndex;
+use crate::matrix::open_text;
+use crate::output::PartialOutput;
+
+/// A spot (or bin) of a spatial ATAC array
+struct Spot {
+    barcode: String,
+    row: u32,
+    col: u32,
+    /// full-resolution image position, kept as written
+    pixel_row: String,
+    pixel_col: String,
+}
+
+/// Array positions of spatial barcodes, written alongside the matrix as a Space Ranger
+/// `spatial/` folder for squidpy and Seurat spatial loaders
+pub struct SpatialPositions {
+    spots: Vec<Spot>,
+}
+
+impl SpatialPositions {
+    /// Read a tab- or comma-separated table of barcode, array row, array column, and optionally
+    /// the pixel row and column in the full-resolution image (the array position if missing).
+    /// A header line is skipped.
+    pub fn from_file(path: &Path) -> io::Result<SpatialPositions> {
+        let mut spots: Vec<Spot> = Vec::new();
+        let mut seen: FxHashSet<String> = FxHashSet::default();
+        for (index, line) in open_text(path)?.lines().enumerate() {
+            let line = line?;
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let separator = if line.contains('\t') { '\t' } else { ',' };
+            let fields: Vec<&str> = line.split(separator).map(str::trim).collect();
+            let position = match (fields.get(1).map(|x| x.parse::<u32>()), fields.get(2).map(|x| x.parse::<u32>())) {
+                (Some(Ok(row)), Some(Ok(col))) => (row, col),
+                // header
+                _ if index == 0 => continue,
+                _ => {
+                    warn!("Line {}: Expected barcode, array row, and array column", index + 1);
+                    continue;
+                }
+            };
+            if !seen.insert(fields[0].to_string()) {
+                warn!("Line {}: Spot barcode {} is repeated, skipping", index + 1, fields[0]);
+                continue;
+            }
+            spots.push(Spot {
+                barcode: fields[0].to_string(),
+                row: position.0,
+                col: position.1,
+                pixel_row: fields.get(3).map_or(position.0.to_string(), |x| x.to_string()),
+                pixel_col: fields.get(4).map_or(position.1.to_string(), |x| x.to_string()),
+            });
+        }
+        if spots.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("No spot positions found in {:?}", path),
+            ));
+        }
+        info!("Loaded {} spot positions", spots.len());
+        Ok(SpatialPositions { spots })
+    }
+
+    /// Spot barcodes, in file order
+    pub fn barcodes(&self) -> Vec<String> {
+        self.spots.iter().map(|spot| spot.barcode.clone()).collect()
+    }
+
+    /// Write `tissue_positions.csv` and `scalefactors_json.json` to `dir`. Spots counted in the
+    /// matrix are marked in tissue. No image is written, so the scale factors are 1.
+    pub fn write(&self, dir: &Path, cells: &CellIndex, partial: &mut PartialOutput) -> io::Result<()> {
+        partial.create_dir(dir)?;
+
+        let path = partial.file(dir.join("tissue_positions.csv"));
+        info!("Writing spot positions: {:?}", path);
+        let mut writer = io::BufWriter::new(File::create(path)?);
+        writeln!(writer, "barcode,in_tissue,array_row,array_col,pxl_row_in_fullres,pxl_col_in_fullres")?;
+        let mut in_tissue = 0;
+        for spot in &self.spots {
+            let counted = cells.get(&spot.barcode).is_some();
+            in_tissue += counted as usize;
+            writeln!(
+                writer, "{},{},{},{},{},{}",
+                spot.barcode, counted as u8, spot.row, spot.col, spot.pixel_row, spot.pixel_col,
+            )?;
+        }
+        writer.flush()?;
+        if in_tissue < cells.len() {
+            warn!("{} cell barcodes have no spot position", cells.len() - in_tissue);
+        }
+
+        let path = partial.file(dir.join("scalefactors_json.json"));
+        let mut writer = io::BufWriter::new(File::create(path)?);
+        writeln!(
+            writer,
+            "{{\"spot_diameter_fullres\": 1.0, \"fiducial_diameter_fullres\": 1.0, \
+             \"tissue_hires_scalef\": 1.0, \"tissue_lowres_scalef\": 1.0}}",
+        )?;
+        writer.flush()?;
+        Ok(())
+    }
+}
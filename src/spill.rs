@@ -0,0 +1,220 @@
+use std::{
+    io,
+    fs,
+    fs::File,
+    path::{Path, PathBuf},
+    io::BufReader,
+    io::BufWriter,
+    io::Read,
+    io::Write,
+};
+use gzp::ZWriter;
+use rustc_hash::FxHashMap;
+use crate::f2m::FeatureCounts;
+use crate::matrix::{compressed_writer, mtx_header, MatrixValue, Pattern};
+
+/// Destination for insertion counts during a pass over the fragments
+pub trait CountStore {
+    /// Whether fragments must be grouped by chromosome
+    const SORTED_INPUT: bool = false;
+
+    /// Add `count` to a feature and cell, failing if the total no longer fits in a u32
+    fn add(&mut self, feature: usize, cell: u32, count: u32) -> io::Result<()>;
+
+    /// Called when all fragments on a chromosome have been counted
+    fn end_chrom(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Add `count` to a total, or an error naming the feature and cell if it overflows
+fn checked_add(total: &mut u32, count: u32, feature: usize, cell: u32) -> io::Result<()> {
+    *total = total.checked_add(count).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Count of feature {} in cell {} exceeds {}", feature + 1, cell + 1, u32::MAX),
+        )
+    })?;
+    Ok(())
+}
+
+impl CountStore for FeatureCounts {
+    fn add(&mut self, feature: usize, cell: u32, count: u32) -> io::Result<()> {
+        checked_add(self[feature].entry(cell).or_insert(0), count, feature, cell)
+    }
+}
+
+/// Counts for the current chromosome held in memory and written to a temporary
+/// file of (feature, cell, count) triplets when the chromosome is finished,
+/// so memory use does not grow with the number of features
+pub struct SpillCounts {
+    current: FxHashMap<(u32, u32), u32>,
+    writer: BufWriter<File>,
+    path: PathBuf,
+    nnz: usize,
+}
+
+impl SpillCounts {
+    pub fn create(path: PathBuf) -> io::Result<SpillCounts> {
+        let writer = BufWriter::new(File::create(&path)?);
+        Ok(SpillCounts { current: FxHashMap::default(), writer, path, nnz: 0 })
+    }
+
+    /// Write a Matrix Market file from the spilled triplets and remove the temporary file.
+    /// Counts are multiplied by `scale` if given, or left out of a pattern matrix if `binarize`.
+    /// Returns the number of nonzero entries in each column.
+    pub fn write_matrix_market(
+        mut self,
+        outfile: &Path,
+        nrow: usize,
+        ncol: usize,
+        scale: Option<f64>,
+        binarize: bool,
+        num_threads: usize,
+    ) -> io::Result<Vec<u32>> {
+        self.end_chrom()?;
+        self.writer.flush()?;
+
+        let mut encoder = compressed_writer(outfile, num_threads)?;
+        let field = match scale {
+            _ if binarize => Pattern::FIELD,
+            Some(_) => f64::FIELD,
+            None => u32::FIELD,
+        };
+        encoder.write_all(mtx_header(field, nrow, ncol, self.nnz).as_bytes())?;
+
+        let mut reader = BufReader::with_capacity(1024 * 1024, File::open(&self.path)?);
+        let mut triplet = [0u8; 12];
+        let mut output = String::new();
+        let mut detected: Vec<u32> = vec![0; ncol];
+        for _ in 0..self.nnz {
+            reader.read_exact(&mut triplet)?;
+            let value = |i: usize| u32::from_le_bytes(triplet[i * 4..i * 4 + 4].try_into().unwrap());
+            detected[value(1) as usize] += 1;
+            match scale {
+                _ if binarize => output.push_str(&Pattern.entry(value(0) as usize + 1, value(1) as usize + 1)),
+                Some(scale) => output.push_str(&format!("{} {} {}\n", value(0) + 1, value(1) + 1, value(2) as f64 * scale)),
+                None => output.push_str(&format!("{} {} {}\n", value(0) + 1, value(1) + 1, value(2))),
+            }
+            if output.len() > 1_000_000 {
+                encoder.write_all(output.as_bytes())?;
+                output.clear();
+            }
+        }
+        encoder.write_all(output.as_bytes())?;
+        encoder.finish().map_err(io::Error::other)?;
+        fs::remove_file(&self.path)?;
+        Ok(detected)
+    }
+}
+
+impl CountStore for SpillCounts {
+    const SORTED_INPUT: bool = true;
+
+    fn add(&mut self, feature: usize, cell: u32, count: u32) -> io::Result<()> {
+        checked_add(self.current.entry((feature as u32, cell)).or_insert(0), count, feature, cell)
+    }
+
+    fn end_chrom(&mut self) -> io::Result<()> {
+        self.nnz += self.current.len();
+        for ((feature, cell), count) in self.current.drain() {
+            self.writer.write_all(&feature.to_le_bytes())?;
+            self.writer.write_all(&cell.to_le_bytes())?;
+            self.writer.write_all(&count.to_le_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufRead;
+    use crate::f2m::write_matrix_market;
+    use crate::matrix::open_text;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("fragtk_{}_{}", std::process::id(), name))
+    }
+
+    fn read_lines(path: &Path) -> Vec<String> {
+        let lines = open_text(path).unwrap().lines().map(|x| x.unwrap()).collect();
+        fs::remove_file(path).unwrap();
+        lines
+    }
+
+    #[test]
+    fn counts_that_overflow_are_an_error() {
+        let mut counts: FeatureCounts = vec![FxHashMap::default(); 2];
+        counts.add(1, 3, u32::MAX - 1).unwrap();
+        counts.add(1, 3, 1).unwrap();
+        assert_eq!(counts[1][&3], u32::MAX);
+        let error = counts.add(1, 3, 1).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("feature 2 in cell 4"));
+        // other entries are unaffected
+        counts.add(0, 3, u32::MAX).unwrap();
+
+        let path = temp_path("overflow.spill");
+        let mut spill = SpillCounts::create(path.clone()).unwrap();
+        spill.add(0, 0, u32::MAX).unwrap();
+        assert_eq!(spill.add(0, 0, 1).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        drop(spill);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn spilled_counts_are_summed_across_adds() {
+        let path = temp_path("sum.spill");
+        let outfile = temp_path("sum.mtx.gz");
+        let mut spill = SpillCounts::create(path).unwrap();
+        spill.add(1, 0, 2).unwrap();
+        spill.add(1, 0, 3).unwrap();
+        spill.end_chrom().unwrap();
+        spill.add(0, 1, 1).unwrap();
+        let detected = spill.write_matrix_market(&outfile, 2, 2, None, false, 1).unwrap();
+        assert_eq!(detected, vec![1, 1]);
+        let lines = read_lines(&outfile);
+        assert_eq!(lines[0], "%%MatrixMarket matrix coordinate integer general");
+        assert_eq!(lines[2], "2 2 2");
+        assert_eq!(lines[3..], ["2 1 5", "1 2 1"]);
+    }
+
+    #[test]
+    fn scaled_and_binary_matrices_declare_their_field() {
+        let path = temp_path("real.spill");
+        let outfile = temp_path("real.mtx.gz");
+        let mut spill = SpillCounts::create(path).unwrap();
+        spill.add(0, 0, 3).unwrap();
+        spill.write_matrix_market(&outfile, 1, 1, Some(0.5), false, 1).unwrap();
+        let lines = read_lines(&outfile);
+        assert_eq!(lines[0], "%%MatrixMarket matrix coordinate real general");
+        assert_eq!(lines[3], "1 1 1.5");
+
+        let path = temp_path("pattern.spill");
+        let outfile = temp_path("pattern.mtx.gz");
+        let mut spill = SpillCounts::create(path).unwrap();
+        spill.add(0, 0, 3).unwrap();
+        spill.write_matrix_market(&outfile, 1, 1, None, true, 1).unwrap();
+        let lines = read_lines(&outfile);
+        assert_eq!(lines[0], "%%MatrixMarket matrix coordinate pattern general");
+        assert_eq!(lines[3], "1 1");
+    }
+
+    #[test]
+    fn in_memory_matrices_declare_the_value_field() {
+        let outfile = temp_path("memory_real.mtx.gz");
+        let counts: Vec<FxHashMap<u32, f64>> = vec![[(0, 0.25)].into_iter().collect()];
+        write_matrix_market(&outfile, &counts, 1, 1, 1).unwrap();
+        let lines = read_lines(&outfile);
+        assert_eq!(lines[0], "%%MatrixMarket matrix coordinate real general");
+        assert_eq!(lines[3], "1 1 0.25");
+
+        let outfile = temp_path("memory_pattern.mtx.gz");
+        let counts: Vec<FxHashMap<u32, Pattern>> = vec![FxHashMap::default(), [(1, Pattern)].into_iter().collect()];
+        write_matrix_market(&outfile, &counts, 2, 2, 1).unwrap();
+        let lines = read_lines(&outfile);
+        assert_eq!(lines[0], "%%MatrixMarket matrix coordinate pattern general");
+        assert_eq!(lines[2..], ["2 2 1", "2 2"]);
+    }
+}
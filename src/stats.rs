@@ -0,0 +1,210 @@
+use std::{
+    io,
+    fs,
+    fs::File,
+    path::{Path, PathBuf},
+    error::Error,
+    io::BufRead,
+    io::BufReader,
+    io::Write,
+    time::UNIX_EPOCH,
+};
+use log::{info, warn};
+use rustc_hash::{FxHashMap, FxHashSet};
+use crate::fragments::{for_each_fragment_with_progress, is_stdin, Fragment, ReadOptions};
+use crate::progress::Progress;
+
+/// Summary of a fragment file, cached in a `<fragments>.fragtk.stats` sidecar
+/// so later runs can report it without reading the fragments again
+pub struct FragmentStats {
+    pub fragments: u64,
+    pub barcodes: u64,
+    /// fragments per chromosome, in file order
+    pub chroms: Vec<(String, u64)>,
+    /// number of fragments of each length
+    pub sizes: Vec<(u32, u64)>,
+}
+
+/// Accumulates fragment statistics during a pass over the file
+#[derive(Default)]
+pub struct StatsCollector {
+    fragments: u64,
+    chroms: Vec<(String, u64)>,
+    sizes: FxHashMap<u32, u64>,
+}
+
+impl StatsCollector {
+    pub fn add(&mut self, fragment: &Fragment) {
+        self.fragments += 1;
+        match self.chroms.last_mut() {
+            Some((chrom, count)) if chrom == fragment.chrom => *count += 1,
+            _ => match self.chroms.iter_mut().find(|(chrom, _)| chrom == fragment.chrom) {
+                // unsorted file
+                Some((_, count)) => *count += 1,
+                None => self.chroms.push((fragment.chrom.to_string(), 1)),
+            },
+        }
+        *self.sizes.entry(fragment.end.saturating_sub(fragment.start)).or_insert(0) += 1;
+    }
+
+    pub fn finish(self, barcodes: u64) -> FragmentStats {
+        let mut sizes: Vec<(u32, u64)> = self.sizes.into_iter().collect();
+        sizes.sort_unstable();
+        FragmentStats { fragments: self.fragments, barcodes, chroms: self.chroms, sizes }
+    }
+}
+
+/// Sidecar file holding the statistics for a fragment file
+pub fn sidecar_path(frag_file: &Path) -> PathBuf {
+    let mut path = frag_file.as_os_str().to_owned();
+    path.push(".fragtk.stats");
+    PathBuf::from(path)
+}
+
+/// Size and modification time of the fragment file, used to detect a stale sidecar
+fn source_signature(frag_file: &Path) -> io::Result<(u64, u64)> {
+    let metadata = fs::metadata(frag_file)?;
+    let mtime = metadata.modified()?.duration_since(UNIX_EPOCH).map_or(0, |x| x.as_secs());
+    Ok((metadata.len(), mtime))
+}
+
+impl FragmentStats {
+    /// Load cached statistics, or None if there is no sidecar or the fragment file has changed since it was written
+    pub fn load(frag_file: &Path) -> io::Result<Option<FragmentStats>> {
+        let path = sidecar_path(frag_file);
+        if is_stdin(frag_file) || !path.exists() {
+            return Ok(None);
+        }
+
+        let mut stats = FragmentStats { fragments: 0, barcodes: 0, chroms: Vec::new(), sizes: Vec::new() };
+        let mut signature: (u64, u64) = (0, 0);
+        for line in BufReader::new(File::open(&path)?).lines() {
+            let line = line?;
+            if line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            let parse = |i: usize| fields.get(i).and_then(|x| x.parse::<u64>().ok());
+            let ok = match (fields[0], fields.len()) {
+                ("source_size", 2) => parse(1).map(|x| signature.0 = x),
+                ("source_mtime", 2) => parse(1).map(|x| signature.1 = x),
+                ("fragments", 2) => parse(1).map(|x| stats.fragments = x),
+                ("barcodes", 2) => parse(1).map(|x| stats.barcodes = x),
+                ("chrom", 3) => parse(2).map(|x| stats.chroms.push((fields[1].to_string(), x))),
+                ("size", 3) => parse(1).zip(parse(2)).map(|(size, x)| stats.sizes.push((size as u32, x))),
+                _ => None,
+            };
+            if ok.is_none() {
+                warn!("Ignoring malformed stats file {:?}", path);
+                return Ok(None);
+            }
+        }
+
+        if signature != source_signature(frag_file)? {
+            info!("Stats file {:?} is out of date", path);
+            return Ok(None);
+        }
+        Ok(Some(stats))
+    }
+
+    /// Write the sidecar next to the fragment file
+    pub fn save(&self, frag_file: &Path) -> io::Result<()> {
+        let path = sidecar_path(frag_file);
+        let (size, mtime) = source_signature(frag_file)?;
+        let mut writer = io::BufWriter::new(File::create(&path)?);
+        writeln!(writer, "# fragtk-{} fragment file statistics", env!("CARGO_PKG_VERSION"))?;
+        writeln!(writer, "source_size\t{}", size)?;
+        writeln!(writer, "source_mtime\t{}", mtime)?;
+        writeln!(writer, "fragments\t{}", self.fragments)?;
+        writeln!(writer, "barcodes\t{}", self.barcodes)?;
+        for (chrom, count) in &self.chroms {
+            writeln!(writer, "chrom\t{}\t{}", chrom, count)?;
+        }
+        for (size, count) in &self.sizes {
+            writeln!(writer, "size\t{}\t{}", size, count)?;
+        }
+        writer.flush()?;
+        info!("Wrote fragment statistics: {:?}", path);
+        Ok(())
+    }
+
+    /// Save the sidecar, warning rather than failing (e.g. for a read-only data directory)
+    pub fn try_save(&self, frag_file: &Path) {
+        // there is nowhere to cache statistics for standard input
+        if is_stdin(frag_file) {
+            return;
+        }
+        if let Err(e) = self.save(frag_file) {
+            warn!("Failed to write fragment statistics for {:?}: {}", frag_file, e);
+        }
+    }
+
+    /// Length below which half of the fragments fall
+    pub fn median_size(&self) -> u32 {
+        let mut seen: u64 = 0;
+        for (size, count) in &self.sizes {
+            seen += count;
+            if seen * 2 >= self.fragments {
+                return *size;
+            }
+        }
+        0
+    }
+
+    /// Fraction of fragments shorter than `size`
+    pub fn fraction_below(&self, size: u32) -> f64 {
+        let below: u64 = self.sizes.iter().filter(|(x, _)| *x < size).map(|(_, count)| count).sum();
+        below as f64 / self.fragments.max(1) as f64
+    }
+
+    pub fn write_summary<W: Write>(&self, writer: &mut W, histogram: bool) -> io::Result<()> {
+        writeln!(writer, "fragments\t{}", self.fragments)?;
+        writeln!(writer, "barcodes\t{}", self.barcodes)?;
+        writeln!(writer, "median_fragment_length\t{}", self.median_size())?;
+        writeln!(writer, "nucleosome_free_fraction\t{:.4}", self.fraction_below(147))?;
+        for (chrom, count) in &self.chroms {
+            writeln!(writer, "chrom\t{}\t{}", chrom, count)?;
+        }
+        if histogram {
+            for (size, count) in &self.sizes {
+                writeln!(writer, "size\t{}\t{}", size, count)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Print fragment file statistics, from the sidecar if it is current
+pub fn run(matches: &clap::ArgMatches, progress: &Progress) -> Result<(), Box<dyn Error>> {
+    let frag_file = Path::new(matches.get_one::<String>("fragments").unwrap());
+    info!("Received fragment file: {:?}", frag_file);
+    let read_options = ReadOptions::from_matches(matches)?;
+
+    let cached = if matches.get_flag("refresh") { None } else { FragmentStats::load(frag_file)? };
+    let stats = match cached {
+        Some(stats) => {
+            info!("Using cached statistics: {:?}", sidecar_path(frag_file));
+            stats
+        }
+        None => {
+            let mut collector = StatsCollector::default();
+            let mut barcodes: FxHashSet<String> = FxHashSet::default();
+            for_each_fragment_with_progress(frag_file, &read_options, progress, |fragment| {
+                collector.add(fragment);
+                if !barcodes.contains(fragment.barcode) {
+                    barcodes.insert(fragment.barcode.to_string());
+                }
+                Ok(())
+            })?;
+            let stats = collector.finish(barcodes.len() as u64);
+            stats.try_save(frag_file);
+            stats
+        }
+    };
+
+    let stdout = io::stdout();
+    let mut writer = io::BufWriter::new(stdout.lock());
+    stats.write_summary(&mut writer, matches.get_flag("histogram"))?;
+    writer.flush()?;
+    Ok(())
+}
@@ -0,0 +1,198 @@
+use std::{
+    io,
+    path::Path,
+    hash::{Hash, Hasher},
+};
+use log::{info, warn};
+use rustc_hash::{FxHashSet, FxHasher};
+use crate::fasta::Fasta;
+
+/// Assigns fragments to separately counted output matrices, or drops them
+pub struct Strata {
+    /// output name of each stratum; a single unnamed stratum writes to the output directory
+    pub names: Vec<String>,
+    gc: Option<GcStrata>,
+    /// fragment length separating nucleosome-free from nucleosomal fragments
+    split_size: Option<u32>,
+    /// fraction of fragments kept and whether counts are scaled up by its inverse
+    subsample: Option<(f64, bool)>,
+    /// shortest and longest fragments counted (bp, inclusive)
+    length_range: Option<(u32, u32)>,
+}
+
+/// Fragment GC content from a reference FASTA, used to filter and stratify
+struct GcStrata {
+    fasta: Fasta,
+    min_gc: f64,
+    max_gc: f64,
+    /// upper bounds of each GC stratum
+    breaks: Vec<f64>,
+    missing_chroms: FxHashSet<String>,
+}
+
+impl Strata {
+    /// All fragments counted in a single matrix
+    pub fn none() -> Strata {
+        Strata { names: vec![String::new()], gc: None, split_size: None, subsample: None, length_range: None }
+    }
+
+    pub fn from_matches(matches: &clap::ArgMatches) -> io::Result<Strata> {
+        let mut strata = Strata::none();
+        if let Some(fasta) = matches.get_one::<String>("fasta") {
+            strata = Strata::gc(matches, fasta)?;
+        }
+
+        // each GC stratum is split by fragment size
+        if let Some(size) = matches.get_one::<u32>("split_by_size") {
+            info!("Splitting fragments at {} bp", size);
+            strata.names = strata.names.iter().flat_map(|gc| {
+                ["nfr", "nucleosomal"].map(|name| {
+                    if gc.is_empty() { name.to_string() } else { format!("{}_{}", name, gc) }
+                })
+            }).collect();
+            strata.split_size = Some(*size);
+        }
+
+        if let Some(fraction) = matches.get_one::<f64>("subsample_fraction") {
+            if !(*fraction > 0.0 && *fraction <= 1.0) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "--subsample-fraction must be in (0, 1]"));
+            }
+            info!("Counting approximately {:.1}% of fragments", fraction * 100.0);
+            strata.subsample = Some((*fraction, matches.get_flag("scale_subsample")));
+        }
+
+        let min_length = matches.get_one::<u32>("min_length").copied();
+        let max_length = matches.get_one::<u32>("max_length").copied();
+        if min_length.is_some() || max_length.is_some() {
+            let range = (min_length.unwrap_or(0), max_length.unwrap_or(u32::MAX));
+            if range.0 > range.1 {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "--min-length cannot exceed --max-length"));
+            }
+            info!("Counting fragments of {} to {} bp", range.0, range.1);
+            strata.length_range = Some(range);
+        }
+        Ok(strata)
+    }
+
+    fn gc(matches: &clap::ArgMatches, fasta: &str) -> io::Result<Strata> {
+        info!("Received FASTA file: {:?}", fasta);
+
+        let min_gc = *matches.get_one::<f64>("min_gc").unwrap();
+        let max_gc = *matches.get_one::<f64>("max_gc").unwrap();
+        let mut breaks: Vec<f64> = match matches.get_one::<String>("gc_bins") {
+            Some(bins) => parse_breaks(bins)?,
+            None => Vec::new(),
+        };
+        breaks.retain(|x| *x > min_gc && *x < max_gc);
+        breaks.push(max_gc);
+
+        let names = if breaks.len() == 1 {
+            vec![String::new()]
+        } else {
+            let mut lower = min_gc;
+            breaks.iter().map(|upper| {
+                let name = format!("gc_{:.2}-{:.2}", lower, upper);
+                lower = *upper;
+                name
+            }).collect()
+        };
+
+        let gc = GcStrata {
+            fasta: Fasta::open(Path::new(fasta))?,
+            min_gc,
+            max_gc,
+            breaks,
+            missing_chroms: FxHashSet::default(),
+        };
+        Ok(Strata { names, gc: Some(gc), split_size: None, subsample: None, length_range: None })
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// Whether a fragment line is kept when subsampling.
+    /// Based on a hash of the line, so the same fragments are kept in every run.
+    pub fn keep(&self, line: &str) -> bool {
+        match self.subsample {
+            Some((fraction, _)) => {
+                let mut hasher = FxHasher::default();
+                line.hash(&mut hasher);
+                (hasher.finish() as f64) < fraction * u64::MAX as f64
+            }
+            None => true,
+        }
+    }
+
+    /// Whether a fragment is within the length range, if one was given
+    pub fn keep_length(&self, start: u32, end: u32) -> bool {
+        match self.length_range {
+            Some((min, max)) => (min..=max).contains(&end.saturating_sub(start)),
+            None => true,
+        }
+    }
+
+    /// Factor to multiply counts by to estimate full counts, if scaling was requested
+    pub fn scale(&self) -> Option<f64> {
+        match self.subsample {
+            Some((fraction, true)) => Some(1.0 / fraction),
+            _ => None,
+        }
+    }
+
+    /// Stratum for a fragment, or None if it is filtered out
+    pub fn classify(&mut self, chrom: &str, start: u32, end: u32) -> io::Result<Option<usize>> {
+        let mut stratum = 0;
+        if let Some(gc) = self.gc.as_mut() {
+            match gc.classify(chrom, start, end)? {
+                Some(index) => stratum = index,
+                None => return Ok(None),
+            }
+        }
+        if let Some(size) = self.split_size {
+            stratum = stratum * 2 + (end.saturating_sub(start) >= size) as usize;
+        }
+        Ok(Some(stratum))
+    }
+}
+
+impl GcStrata {
+    fn classify(&mut self, chrom: &str, start: u32, end: u32) -> io::Result<Option<usize>> {
+        if !self.fasta.load(chrom)? {
+            if self.missing_chroms.insert(chrom.to_string()) {
+                warn!("Chromosome {} not found in FASTA, skipping its fragments", chrom);
+            }
+            return Ok(None);
+        }
+        let gc = match self.fasta.gc_fraction(start, end) {
+            Some(gc) => gc,
+            None => return Ok(None),
+        };
+        if gc < self.min_gc || gc > self.max_gc {
+            return Ok(None);
+        }
+        Ok(Some(self.breaks.iter().position(|upper| gc < *upper).unwrap_or(self.breaks.len() - 1)))
+    }
+}
+
+fn parse_breaks(value: &str) -> io::Result<Vec<f64>> {
+    let mut breaks: Vec<f64> = Vec::new();
+    for x in value.split(',') {
+        match x.trim().parse::<f64>() {
+            Ok(x) if (0.0..=1.0).contains(&x) => breaks.push(x),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid GC bin boundary: {}", x),
+                ));
+            }
+        }
+    }
+    breaks.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    breaks.dedup();
+    Ok(breaks)
+}
@@ -0,0 +1,161 @@
+use std::{
+    io,
+    fs::File,
+    path::Path,
+    error::Error,
+    io::Write,
+};
+use log::{info, warn};
+use crate::cells::{load_groups, Reconcile, TableFormat};
+use crate::convert::{self, MatrixFormat};
+
+/// Mean count and percent of cells with a nonzero count for each feature in each cell group,
+/// written as a tidy table (one row per feature and group) for dot plots,
+/// and optionally the specificity of each feature across groups
+pub fn run(matches: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+
+    let input = Path::new(matches.get_one::<String>("input").unwrap());
+    let format = match matches.get_one::<String>("from") {
+        Some(name) => MatrixFormat::from_name(name),
+        None => MatrixFormat::detect(input),
+    }
+    .ok_or_else(|| format!("Cannot detect matrix format for {:?}, please specify it", input))?;
+
+    let group_file = Path::new(matches.get_one::<String>("groups").unwrap());
+    let output = Path::new(matches.get_one::<String>("output").unwrap());
+    info!("Summarizing {:?} by groups in {:?}", input, group_file);
+
+    let matrix = convert::read_matrix(input, format)?;
+    let (group_names, cell_groups) = load_groups(group_file, &TableFormat::from_matches(matches)?, &Reconcile::from_matches(matches), None)?;
+
+    // group of each matrix column
+    let column_groups: Vec<Option<usize>> = matrix
+        .barcodes
+        .iter()
+        .map(|barcode| cell_groups.get(barcode).copied())
+        .collect();
+    let mut group_sizes: Vec<u64> = vec![0; group_names.len()];
+    for group in column_groups.iter().flatten() {
+        group_sizes[*group] += 1;
+    }
+    for (name, size) in group_names.iter().zip(group_sizes.iter()) {
+        if *size == 0 {
+            warn!("Group {} has no cells in the matrix", name);
+        }
+    }
+
+    // sum and number of nonzero cells for each feature and group
+    let n_groups = group_names.len();
+    let mut sums: Vec<f64> = vec![0.0; matrix.nrow() * n_groups];
+    let mut nonzero: Vec<u64> = vec![0; matrix.nrow() * n_groups];
+    for (col, group) in column_groups.iter().enumerate() {
+        let group = match group {
+            Some(group) => *group,
+            None => continue,
+        };
+        for pos in matrix.indptr[col] as usize..matrix.indptr[col + 1] as usize {
+            let value = matrix.data[pos];
+            let slot = matrix.indices[pos] as usize * n_groups + group;
+            sums[slot] += value;
+            if value != 0.0 {
+                nonzero[slot] += 1;
+            }
+        }
+    }
+
+    info!("Writing group summary: {:?}", output);
+    let mut writer = io::BufWriter::new(File::create(output)?);
+    writeln!(writer, "feature\tgroup\tcells\tmean\tpct.exp")?;
+    for (row, feature) in matrix.features.iter().enumerate() {
+        for (group, name) in group_names.iter().enumerate() {
+            let size = group_sizes[group];
+            if size == 0 {
+                continue;
+            }
+            let slot = row * n_groups + group;
+            writeln!(
+                writer,
+                "{}\t{}\t{}\t{}\t{}",
+                feature, name, size,
+                sums[slot] / size as f64,
+                100.0 * nonzero[slot] as f64 / size as f64,
+            )?;
+        }
+    }
+    writer.flush()?;
+
+    if let Some(path) = matches.get_one::<String>("specificity") {
+        let path = Path::new(path);
+        info!("Writing feature specificity: {:?}", path);
+        let mut writer = io::BufWriter::new(File::create(path)?);
+        writeln!(writer, "feature\ttop_group\tgini\tentropy\ttau")?;
+        let groups: Vec<usize> = (0..n_groups).filter(|group| group_sizes[*group] > 0).collect();
+        let mut means: Vec<f64> = Vec::with_capacity(groups.len());
+        for (row, feature) in matrix.features.iter().enumerate() {
+            means.clear();
+            means.extend(groups.iter().map(|group| sums[row * n_groups + group] / group_sizes[*group] as f64));
+            let top = means
+                .iter()
+                .enumerate()
+                .max_by(|a, b| a.1.total_cmp(b.1))
+                .map_or("NA", |(i, _)| &group_names[groups[i]]);
+            let scores = Specificity::new(&means);
+            writeln!(
+                writer,
+                "{}\t{}\t{}\t{}\t{}",
+                feature, top, na(scores.gini), na(scores.entropy), na(scores.tau),
+            )?;
+        }
+        writer.flush()?;
+    }
+
+    Ok(())
+}
+
+/// Specificity of a feature across groups, from its mean count in each group.
+/// Scores are None for features with no counts.
+struct Specificity {
+    /// Gini coefficient, 0 for equal means and approaching 1 when one group has all counts
+    gini: Option<f64>,
+    /// Shannon entropy (bits) of the means as proportions, low for specific features
+    entropy: Option<f64>,
+    /// tau (Yanai et al. 2005), 0 for equal means and 1 when only one group has counts
+    tau: Option<f64>,
+}
+
+impl Specificity {
+    fn new(means: &[f64]) -> Specificity {
+        let n = means.len();
+        let total: f64 = means.iter().sum();
+        let max = means.iter().cloned().fold(0.0, f64::max);
+        if n == 0 || total <= 0.0 {
+            return Specificity { gini: None, entropy: None, tau: None };
+        }
+
+        let mut sorted = means.to_vec();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        let gini = sorted
+            .iter()
+            .enumerate()
+            .map(|(i, x)| (2.0 * (i + 1) as f64 - n as f64 - 1.0) * x)
+            .sum::<f64>()
+            / (n as f64 * total);
+
+        let entropy = -means
+            .iter()
+            .filter(|x| **x > 0.0)
+            .map(|x| {
+                let p = x / total;
+                p * p.log2()
+            })
+            .sum::<f64>();
+
+        let tau = (n > 1).then(|| means.iter().map(|x| 1.0 - x / max).sum::<f64>() / (n - 1) as f64);
+
+        Specificity { gini: Some(gini), entropy: Some(entropy), tau }
+    }
+}
+
+fn na(value: Option<f64>) -> String {
+    value.map_or("NA".to_string(), |x| x.to_string())
+}
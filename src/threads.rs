@@ -0,0 +1,45 @@
+use clap::ArgMatches;
+use log::{info, warn};
+
+/// Threads per stage without `--threads`, so a run does not take every core of a shared machine
+const DEFAULT_THREADS: usize = 4;
+
+/// Thread counts for each stage of a run.
+/// `--threads` sets all stages, and `--io-threads`, `--compute-threads`
+/// and `--compress-threads` override individual stages.
+pub struct ThreadConfig {
+    /// decompression of input files
+    pub io: usize,
+    /// parallel computation (rayon global pool)
+    pub compute: usize,
+    /// compression of output files
+    pub compress: usize,
+}
+
+impl ThreadConfig {
+    pub fn from_matches(matches: &ArgMatches) -> ThreadConfig {
+        let get = |name: &str| matches.try_get_one::<usize>(name).ok().flatten().copied();
+        let threads = get("threads").unwrap_or_else(|| available_cores().min(DEFAULT_THREADS)).max(1);
+        ThreadConfig {
+            // parsing is single-threaded, so more decompression threads rarely help
+            io: get("io_threads").unwrap_or(threads.min(4)).max(1),
+            compute: get("compute_threads").unwrap_or(threads).max(1),
+            compress: get("compress_threads").unwrap_or(threads).max(1),
+        }
+    }
+
+    /// Size the rayon global pool. Input decompression threads are passed with
+    /// `fragments::ReadOptions`, and compression threads to each writer.
+    pub fn install(&self) {
+        if let Err(e) = rayon::ThreadPoolBuilder::new().num_threads(self.compute).build_global() {
+            warn!("Failed to configure compute threads: {}", e);
+        }
+        info!("Threads: {} io, {} compute, {} compress", self.io, self.compute, self.compress);
+    }
+}
+
+/// Number of cores available to this process.
+/// Respects CPU affinity and cgroup CPU quotas (e.g. cluster job limits).
+pub fn available_cores() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
@@ -0,0 +1,244 @@
+use std::{
+    io,
+    fs,
+    fs::File,
+    path::{Path, PathBuf},
+    error::Error,
+    io::Write,
+};
+use log::{info, warn};
+use rustc_hash::{FxHashMap, FxHashSet};
+use crate::bbi::{bed_to_bigbed, bedgraph_to_bigwig};
+use crate::bed::read_chrom_sizes;
+use crate::cells::CellGroups;
+use crate::fragments::{for_each_fragment_with_progress, ReadOptions};
+use crate::output::PartialOutput;
+use crate::progress::Progress;
+use crate::threads::ThreadConfig;
+
+/// Columns of a narrowPeak file, as written by `fragtk callpeaks`
+const NARROWPEAK_AUTOSQL: &str = r#"table bigNarrowPeak
+"BED6+4 Peaks"
+(
+string chrom;        "Reference sequence chromosome or scaffold"
+uint   chromStart;   "Start position in chromosome"
+uint   chromEnd;     "End position in chromosome"
+string name;         "Name given to a region"
+uint   score;        "Score from 0-1000"
+char[1] strand;      "+ or - or . for unknown"
+float  signalValue;  "Measurement of average enrichment for the region"
+float  pValue;       "Statistical significance of signal value (-log10)"
+float  qValue;       "Statistical significance with multiple-test correction applied (-log10)"
+int    peak;         "Point-source called for this peak; 0-based offset from chromStart"
+)
+"#;
+
+/// Track colors, cycled over groups
+const COLORS: &[&str] = &[
+    "31,119,180", "255,127,14", "44,160,44", "214,39,40", "148,103,189",
+    "140,86,75", "227,119,194", "127,127,127", "188,189,34", "23,190,207",
+];
+
+/// Write a UCSC track hub with an insertion coverage bigWig for each cell group,
+/// and peak bigBeds from a `fragtk callpeaks` output directory if given
+pub fn run(matches: &clap::ArgMatches, progress: &Progress) -> Result<(), Box<dyn Error>> {
+
+    let frag_file = Path::new(matches.get_one::<String>("fragments").unwrap());
+    info!("Received fragment file: {:?}", frag_file);
+    let read_options = ReadOptions::from_matches(matches)?;
+
+    let output = Path::new(matches.get_one::<String>("outdir").unwrap());
+    let genome = matches.get_one::<String>("genome").unwrap();
+    let hub_name = matches.get_one::<String>("hub_name").unwrap();
+    let bin_size = *matches.get_one::<u32>("bin_size").unwrap();
+    let num_threads = ThreadConfig::from_matches(matches).compress;
+    let chrom_sizes = read_chrom_sizes(Path::new(matches.get_one::<String>("chrom_sizes").unwrap()))?;
+    let peak_dir = matches.get_one::<String>("peaks").map(Path::new);
+
+    let groups = CellGroups::from_matches(matches, "all")?;
+    info!("Writing tracks for {} groups", groups.names.len());
+
+    let mut partial = PartialOutput::new();
+    let genome_dir = output.join(genome);
+    partial.create_dir(&genome_dir)?;
+
+    // coverage, through temporary bedGraph files of insertions per bin
+    let bedgraphs: Vec<PathBuf> = groups
+        .names
+        .iter()
+        .map(|name| partial.file(genome_dir.join(format!("{}.bedGraph.tmp", name))))
+        .collect();
+    let totals = write_coverage(frag_file, &bedgraphs, &groups, &chrom_sizes, bin_size, &read_options, progress)?;
+    for ((name, bedgraph), total) in groups.names.iter().zip(bedgraphs.iter()).zip(totals.iter()) {
+        if *total == 0 {
+            warn!("Group {} has no insertions", name);
+        }
+        // insertions per million
+        let scale = 1e6 / (*total).max(1) as f64;
+        let bigwig = partial.file(genome_dir.join(format!("{}.bw", name)));
+        bedgraph_to_bigwig(bedgraph, &bigwig, &chrom_sizes, scale, num_threads)?;
+        fs::remove_file(bedgraph)?;
+    }
+
+    // peaks
+    let mut has_peaks: Vec<bool> = vec![false; groups.names.len()];
+    if let Some(peak_dir) = peak_dir {
+        for (group, name) in groups.names.iter().enumerate() {
+            let peak_file = peak_dir.join(format!("{}.narrowPeak", name));
+            if !peak_file.exists() {
+                warn!("No peaks for group {}: {:?} not found", name, peak_file);
+                continue;
+            }
+            let bigbed = partial.file(genome_dir.join(format!("{}.bb", name)));
+            bed_to_bigbed(&peak_file, &bigbed, &chrom_sizes, Some(NARROWPEAK_AUTOSQL), num_threads)?;
+            has_peaks[group] = true;
+        }
+    }
+
+    write_hub(output, &mut partial, hub_name, genome, matches.get_one::<String>("email"))?;
+    write_track_db(&partial.file(genome_dir.join("trackDb.txt")), &groups.names, &has_peaks)?;
+    partial.finish();
+    info!("Track hub written: {:?}", output.join("hub.txt"));
+
+    Ok(())
+}
+
+/// Write Tn5 insertions per bin for each group as a bedGraph, returning the total
+/// insertions in each group. Chromosomes missing from the sizes file are skipped.
+fn write_coverage(
+    frag_file: &Path,
+    outfiles: &[PathBuf],
+    groups: &CellGroups,
+    chrom_sizes: &[(String, u32)],
+    bin_size: u32,
+    read_options: &ReadOptions,
+    progress: &Progress,
+) -> io::Result<Vec<u64>> {
+    let mut writers = Vec::with_capacity(outfiles.len());
+    for path in outfiles {
+        writers.push(io::BufWriter::new(File::create(path)?));
+    }
+    let sizes: FxHashMap<&str, u32> = chrom_sizes.iter().map(|(chrom, size)| (chrom.as_str(), *size)).collect();
+
+    let mut totals: Vec<u64> = vec![0; outfiles.len()];
+    let mut positions: Vec<Vec<u32>> = vec![Vec::new(); outfiles.len()];
+    let mut current_chrom = String::new();
+    let mut finished_chroms: FxHashSet<String> = FxHashSet::default();
+    let mut missing_chroms: FxHashSet<String> = FxHashSet::default();
+
+    let flush = |chrom: &str, positions: &mut [Vec<u32>], writers: &mut [io::BufWriter<File>]| -> io::Result<()> {
+        let size = match sizes.get(chrom) {
+            Some(size) => *size,
+            None => {
+                positions.iter_mut().for_each(|x| x.clear());
+                return Ok(());
+            }
+        };
+        for (group_positions, writer) in positions.iter_mut().zip(writers.iter_mut()) {
+            group_positions.sort_unstable();
+            let mut i = 0;
+            while i < group_positions.len() {
+                let bin = group_positions[i] / bin_size;
+                let mut count = 0;
+                while i < group_positions.len() && group_positions[i] / bin_size == bin {
+                    count += 1;
+                    i += 1;
+                }
+                let start = bin * bin_size;
+                if start < size {
+                    writeln!(writer, "{}\t{}\t{}\t{}", chrom, start, (start + bin_size).min(size), count)?;
+                }
+            }
+            group_positions.clear();
+        }
+        Ok(())
+    };
+
+    for_each_fragment_with_progress(frag_file, read_options, progress, |fragment| {
+        let group = match groups.get(fragment.barcode) {
+            Some(group) => group,
+            None => return Ok(()),
+        };
+
+        if fragment.chrom != current_chrom {
+            flush(&current_chrom, &mut positions, &mut writers)?;
+            if !finished_chroms.insert(fragment.chrom.to_string()) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Fragment file is not sorted by chromosome ({} seen twice)", fragment.chrom),
+                ));
+            }
+            if !sizes.contains_key(fragment.chrom) && missing_chroms.insert(fragment.chrom.to_string()) {
+                warn!("Chromosome {} not found in chromosome sizes, skipping", fragment.chrom);
+            }
+            current_chrom = fragment.chrom.to_string();
+        }
+        if sizes.contains_key(fragment.chrom) {
+            positions[group].push(fragment.start);
+            positions[group].push(fragment.end);
+            totals[group] += 2;
+        }
+        Ok(())
+    })?;
+    flush(&current_chrom, &mut positions, &mut writers)?;
+
+    for mut writer in writers {
+        writer.flush()?;
+    }
+    Ok(totals)
+}
+
+fn write_hub(
+    output: &Path,
+    partial: &mut PartialOutput,
+    hub_name: &str,
+    genome: &str,
+    email: Option<&String>,
+) -> io::Result<()> {
+    let mut writer = io::BufWriter::new(File::create(partial.file(output.join("hub.txt")))?);
+    writeln!(writer, "hub {}", track_name(hub_name))?;
+    writeln!(writer, "shortLabel {}", hub_name)?;
+    writeln!(writer, "longLabel {} chromatin accessibility by cell group", hub_name)?;
+    writeln!(writer, "genomesFile genomes.txt")?;
+    writeln!(writer, "email {}", email.map_or("", |x| x.as_str()))?;
+    writer.flush()?;
+
+    let mut writer = io::BufWriter::new(File::create(partial.file(output.join("genomes.txt")))?);
+    writeln!(writer, "genome {}", genome)?;
+    writeln!(writer, "trackDb {}/trackDb.txt", genome)?;
+    writer.flush()
+}
+
+fn write_track_db(path: &Path, names: &[String], has_peaks: &[bool]) -> io::Result<()> {
+    let mut writer = io::BufWriter::new(File::create(path)?);
+    for (group, name) in names.iter().enumerate() {
+        let color = COLORS[group % COLORS.len()];
+        let track = track_name(name);
+        writeln!(writer, "track {}_coverage", track)?;
+        writeln!(writer, "type bigWig")?;
+        writeln!(writer, "bigDataUrl {}.bw", name)?;
+        writeln!(writer, "shortLabel {}", name)?;
+        writeln!(writer, "longLabel {} Tn5 insertions per million", name)?;
+        writeln!(writer, "visibility full")?;
+        writeln!(writer, "autoScale on")?;
+        writeln!(writer, "maxHeightPixels 100:40:8")?;
+        writeln!(writer, "color {}", color)?;
+        writeln!(writer)?;
+        if has_peaks[group] {
+            writeln!(writer, "track {}_peaks", track)?;
+            writeln!(writer, "type bigNarrowPeak")?;
+            writeln!(writer, "bigDataUrl {}.bb", name)?;
+            writeln!(writer, "shortLabel {} peaks", name)?;
+            writeln!(writer, "longLabel {} peaks", name)?;
+            writeln!(writer, "visibility dense")?;
+            writeln!(writer, "color {}", color)?;
+            writeln!(writer)?;
+        }
+    }
+    writer.flush()
+}
+
+/// Track names may only contain letters, digits, and underscores
+fn track_name(name: &str) -> String {
+    name.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect()
+}
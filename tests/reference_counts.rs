@@ -0,0 +1,150 @@
+//! Compare fragtk matrices on the bundled mini data against reference counts.
+//! Enabled with `--features integration-tests`.
+#![cfg(feature = "integration-tests")]
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+    process::Command,
+};
+use flate2::read::MultiGzDecoder;
+
+type Counts = BTreeMap<(String, String), u32>;
+
+fn data_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/mini")
+}
+
+fn output_dir(name: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+/// Copy of the bundled fragments next to the output, so sidecar files are not written into the source tree
+fn fragments_copy(outdir: &Path) -> PathBuf {
+    let path = outdir.with_extension("tsv.gz");
+    fs::copy(data_dir().join("fragments.tsv.gz"), &path).unwrap();
+    path
+}
+
+fn read_gz(path: &Path) -> String {
+    let mut text = String::new();
+    MultiGzDecoder::new(fs::File::open(path).unwrap()).read_to_string(&mut text).unwrap();
+    text
+}
+
+fn read_lines(path: &Path) -> Vec<String> {
+    BufReader::new(fs::File::open(path).unwrap()).lines().map(|x| x.unwrap()).collect()
+}
+
+/// Read a matrix directory as (feature, barcode) -> count
+fn read_matrix(dir: &Path) -> Counts {
+    let features: Vec<String> = read_gz(&dir.join("features.tsv.gz")).lines().map(String::from).collect();
+    let barcodes = read_lines(&dir.join("barcodes.tsv"));
+    let text = read_gz(&dir.join("matrix.mtx.gz"));
+    let mut lines = text.lines().filter(|x| !x.starts_with('%'));
+
+    let size: Vec<usize> = lines.next().unwrap().split(' ').map(|x| x.parse().unwrap()).collect();
+    assert_eq!(size[0], features.len());
+    assert_eq!(size[1], barcodes.len());
+
+    let mut counts = Counts::new();
+    for line in lines {
+        let fields: Vec<&str> = line.split(' ').collect();
+        let row: usize = fields[0].parse().unwrap();
+        let col: usize = fields[1].parse().unwrap();
+        let value: u32 = fields[2].parse().unwrap();
+        assert!(value > 0, "explicit zero in matrix: {}", line);
+        counts.insert((features[row - 1].clone(), barcodes[col - 1].clone()), value);
+    }
+    assert_eq!(counts.len(), size[2]);
+    counts
+}
+
+/// Read a long-format feature/barcode/count reference table
+fn read_reference(path: &Path) -> Counts {
+    read_lines(path)
+        .iter()
+        .skip(1)
+        .map(|line| {
+            let fields: Vec<&str> = line.split('\t').collect();
+            ((fields[0].to_string(), fields[1].to_string()), fields[2].parse().unwrap())
+        })
+        .collect()
+}
+
+fn run_matrix(outdir: &Path, extra: &[&str]) {
+    let data = data_dir();
+    let status = Command::new(env!("CARGO_BIN_EXE_fragtk"))
+        .arg("matrix")
+        .arg("-f").arg(fragments_copy(outdir))
+        .arg("-b").arg(data.join("peaks.bed"))
+        .arg("-c").arg(data.join("cells.txt"))
+        .arg("-o").arg(outdir)
+        .args(extra)
+        .status()
+        .expect("failed to run fragtk");
+    assert!(status.success());
+}
+
+fn assert_counts_equal(found: &Counts, expected: &Counts) {
+    for (key, value) in expected {
+        assert_eq!(found.get(key), Some(value), "count for {:?}", key);
+    }
+    for key in found.keys() {
+        assert!(expected.contains_key(key), "unexpected count for {:?}", key);
+    }
+}
+
+#[test]
+fn matrix_matches_expected_counts() {
+    let outdir = output_dir("matrix_expected");
+    run_matrix(&outdir, &[]);
+    let expected = read_reference(&data_dir().join("expected_counts.tsv"));
+    assert_counts_equal(&read_matrix(&outdir), &expected);
+}
+
+#[test]
+fn matrix_matches_signac_feature_matrix() {
+    // FeatureMatrix counts each fragment overlapping a feature once
+    let outdir = output_dir("matrix_signac");
+    run_matrix(&outdir, &["--mode", "fragments"]);
+    assert_counts_equal(&read_matrix(&outdir), &read_reference(&data_dir().join("signac_counts.tsv")));
+}
+
+#[test]
+fn matrix_matches_archr_peak_matrix() {
+    // ArchR puts the end insertion at the last base of the fragment and caps counts at 4
+    let outdir = output_dir("matrix_archr");
+    run_matrix(&outdir, &["--shift-minus", "-1"]);
+    let found: Counts = read_matrix(&outdir).into_iter().map(|(key, count)| (key, count.min(4))).collect();
+    assert_counts_equal(&found, &read_reference(&data_dir().join("archr_counts.tsv")));
+}
+
+#[test]
+fn bin_matrix_totals_match_peak_free_counts() {
+    // every insertion of a listed cell falls in exactly one genome bin
+    let outdir = output_dir("matrix_bins");
+    let data = data_dir();
+    let sizes = outdir.with_extension("sizes");
+    fs::write(&sizes, "chr1\t50000\nchr2\t30000\nchr3\t8000\n").unwrap();
+    run_matrix(&outdir, &["--bins", "10000", "-g", sizes.to_str().unwrap()]);
+
+    let cells = read_lines(&data.join("cells.txt"));
+    let mut insertions: BTreeMap<String, u32> = BTreeMap::new();
+    for line in read_gz(&data.join("fragments.tsv.gz")).lines().filter(|x| !x.starts_with('#')) {
+        let barcode = line.split('\t').nth(3).unwrap();
+        if cells.iter().any(|x| x == barcode) {
+            *insertions.entry(barcode.to_string()).or_default() += 2;
+        }
+    }
+
+    let mut totals: BTreeMap<String, u32> = BTreeMap::new();
+    for ((_, barcode), count) in read_matrix(&outdir.join("bins_10000")) {
+        *totals.entry(barcode).or_default() += count;
+    }
+    assert_eq!(totals, insertions);
+}